@@ -0,0 +1,241 @@
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
+use rumqttc::{Client, Connection, Event, MqttOptions, Packet, Publish, QoS, RecvTimeoutError};
+
+/// Scriptable admin interface of the alarm panel over MQTT
+#[derive(Parser)]
+struct Args {
+    /// Broker of the panel, e.g. mqtt://192.168.1.2:1883
+    #[arg(long)]
+    broker: String,
+    /// Seconds to wait for the panel to respond
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Sends an alarm command, e.g. ARM_AWAY or DISARM, and waits for its result
+    Alarm {
+        /// Command topic of the alarm entity
+        #[arg(long)]
+        topic: String,
+        command: String,
+    },
+    /// Prints every message on the topics until interrupted
+    Tail {
+        #[arg(default_value = "#")]
+        topics: Vec<String>,
+    },
+    /// Prints the boot report and the warnings stored in the flash log
+    Diagnostics {
+        #[arg(long)]
+        boot_report_topic: String,
+        /// Command topic of the flash log
+        #[arg(long)]
+        flash_log_topic: Option<String>,
+        /// Response topic of the flash log
+        #[arg(long, requires = "flash_log_topic")]
+        flash_log_response_topic: Option<String>,
+    },
+    /// Lists the days archived on the SD card, or fetches the events of a day
+    History {
+        /// Command topic of the SD card
+        #[arg(long)]
+        topic: String,
+        /// Response topic of the SD card
+        #[arg(long)]
+        response_topic: String,
+        /// Day to fetch, e.g. 20240131, the days are listed if omitted
+        day: Option<String>,
+    },
+    /// Sends a net command, e.g. reconnect-mqtt, and prints the status
+    Net {
+        /// Net command topic
+        #[arg(long)]
+        topic: String,
+        #[arg(required = true)]
+        command: Vec<String>,
+    },
+}
+
+struct Panel {
+    client: Client,
+    connection: Connection,
+    timeout: Duration,
+}
+
+impl Panel {
+    fn connect(broker: &str, timeout: Duration) -> anyhow::Result<Self> {
+        let (host, port) = parse_broker(broker)?;
+        let mut options = MqttOptions::new(format!("alarm-ctl-{}", std::process::id()), host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_max_packet_size(1024 * 1024, 64 * 1024);
+        let (client, connection) = Client::new(options, 10);
+        Ok(Self {
+            client,
+            connection,
+            timeout,
+        })
+    }
+
+    /// Waits for the next message, `None` if the panel didn't respond in time
+    fn next_message(&mut self, timeout: Option<Duration>) -> anyhow::Result<Option<Publish>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let event = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match self.connection.recv_timeout(timeout) {
+                        Ok(event) => event?,
+                        Err(RecvTimeoutError::Timeout) => return Ok(None),
+                        Err(RecvTimeoutError::Disconnected) => bail!("Connection closed"),
+                    }
+                }
+                None => match self.connection.recv() {
+                    Ok(event) => event?,
+                    Err(_) => bail!("Connection closed"),
+                },
+            };
+            if let Event::Incoming(Packet::Publish(publish)) = event {
+                return Ok(Some(publish));
+            }
+        }
+    }
+
+    /// Sends a command and returns the first response on the response topic
+    fn request(
+        &mut self,
+        topic: &str,
+        payload: &str,
+        response_topic: &str,
+    ) -> anyhow::Result<Publish> {
+        self.client.subscribe(response_topic, QoS::AtLeastOnce)?;
+        self.client
+            .publish(topic, QoS::AtLeastOnce, false, payload.as_bytes())?;
+        self.response(response_topic)
+    }
+
+    fn response(&mut self, response_topic: &str) -> anyhow::Result<Publish> {
+        self.try_response(response_topic)?
+            .with_context(|| format!("No response on {}", response_topic))
+    }
+
+    fn try_response(&mut self, response_topic: &str) -> anyhow::Result<Option<Publish>> {
+        loop {
+            match self.next_message(Some(self.timeout))? {
+                Some(publish) if publish.topic == response_topic => return Ok(Some(publish)),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Prints the responses until the empty message which ends a transfer
+    fn print_transfer(&mut self, first: Publish, response_topic: &str) -> anyhow::Result<()> {
+        let mut stdout = std::io::stdout();
+        let mut publish = first;
+        while !publish.payload.is_empty() {
+            stdout.write_all(&publish.payload)?;
+            publish = self.response(response_topic)?;
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let mut panel = Panel::connect(&args.broker, Duration::from_secs(args.timeout))?;
+
+    match args.command {
+        Command::Alarm { topic, command } => {
+            let result_topic = format!("{}/result", topic);
+            let result = panel.request(&topic, &command, &result_topic)?;
+            let result: serde_json::Value = serde_json::from_slice(&result.payload)?;
+            println!("{}", result);
+            if result["status"] != "accepted" {
+                bail!("{} was rejected", command);
+            }
+        }
+        Command::Tail { topics } => {
+            for topic in topics.iter() {
+                panel.client.subscribe(topic, QoS::AtLeastOnce)?;
+            }
+            while let Some(publish) = panel.next_message(None)? {
+                println!(
+                    "{} {}",
+                    publish.topic,
+                    String::from_utf8_lossy(&publish.payload)
+                );
+            }
+        }
+        Command::Diagnostics {
+            boot_report_topic,
+            flash_log_topic,
+            flash_log_response_topic,
+        } => {
+            // The boot report is retained, subscribing is enough to receive it
+            panel
+                .client
+                .subscribe(&boot_report_topic, QoS::AtLeastOnce)?;
+            let report = panel.response(&boot_report_topic)?;
+            println!("{}", String::from_utf8_lossy(&report.payload));
+
+            if let (Some(topic), Some(response_topic)) = (flash_log_topic, flash_log_response_topic)
+            {
+                let first = panel.request(&topic, "dump", &response_topic)?;
+                panel.print_transfer(first, &response_topic)?;
+            }
+        }
+        Command::History {
+            topic,
+            response_topic,
+            day,
+        } => match day {
+            Some(day) => {
+                let first = panel.request(&topic, &format!("fetch {}", day), &response_topic)?;
+                panel.print_transfer(first, &response_topic)?;
+            }
+            None => {
+                let days = panel.request(&topic, "list", &response_topic)?;
+                let days: Vec<String> = serde_json::from_slice(&days.payload)?;
+                for day in days.iter() {
+                    println!("{}", day);
+                }
+            }
+        },
+        Command::Net { topic, command } => {
+            // Only some of the commands report a status
+            let status_topic = format!("{}/status", topic);
+            panel.client.subscribe(&status_topic, QoS::AtLeastOnce)?;
+            panel.client.publish(
+                &topic,
+                QoS::AtLeastOnce,
+                false,
+                command.join(" ").as_bytes(),
+            )?;
+            match panel.try_response(&status_topic)? {
+                Some(status) => println!("{}", String::from_utf8_lossy(&status.payload)),
+                None => println!("Sent, no status was reported"),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_broker(broker: &str) -> anyhow::Result<(String, u16)> {
+    let Some(address) = broker.strip_prefix("mqtt://") else {
+        bail!("broker must start with \"mqtt://\"");
+    };
+    let address = address.trim_end_matches('/');
+    match address.rsplit_once(':') {
+        Some((host, port)) => Ok((host.to_string(), port.parse().context("Invalid port")?)),
+        None => Ok((address.to_string(), 1883)),
+    }
+}
@@ -1,11 +1,15 @@
 #!/usr/bin/env bash
 set -e
 
-if [ -z "${1}" ]; then
-    echo "Usage: $0 <mqtt_endpoint>/<ota_topic>"
+if [ -z "${2}" ]; then
+    echo "Usage: $0 <mqtt_endpoint> <ota_topic> [ota-push options]"
     exit 1
 fi
 
 cargo build
 espflash save-image --chip esp32 target/xtensa-esp32-espidf/debug/rusty-esp-alarm ota.bin
-mosquitto_pub -L "${1}" -f ota.bin -d -q 2
+
+# The firmware's cargo config targets the ESP32, the uploader runs on the host
+host_target=$(rustc -vV | sed -n 's/^host: //p')
+cargo run --manifest-path ota-push/Cargo.toml --target "${host_target}" -- \
+    --broker "${1}" --topic "${2}" "${@:3}" ota.bin
@@ -1,25 +1,98 @@
-use ha_types::HAEntity;
+use ha_types::{
+    BellTestConfig, CanConfig, DailyWindow, DelayChirpConfig, DscConfig, EventExportConfig,
+    FlashLogConfig, HADevice, HAEntity, HAEntityVariant, Language, LoopbackConfig, ModbusConfig,
+    NativeApiConfig, PowerStat, PresenceConfig, ProvisioningApConfig, RtcConfig, SdCardConfig,
+    SirenPwmConfig, TopicBuilder, TransitionTable, ZoneKind, ZoneType,
+};
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 struct Config {
+    /// Can be left empty to enter it through the provisioning access point
+    #[serde(default)]
     mqtt_endpoint: String,
+    #[serde(default)]
+    mqtt_persistent_session: bool,
     entities: Vec<HAEntity>,
     availability_topic: String,
     ota_topic: String,
+    presence: Option<PresenceConfig>,
+    modbus: Option<ModbusConfig>,
+    can: Option<CanConfig>,
+    dsc: Option<DscConfig>,
+    rtc: Option<RtcConfig>,
+    siren_pwm: Option<SirenPwmConfig>,
+    delay_chirps: Option<DelayChirpConfig>,
+    native_api: Option<NativeApiConfig>,
+    sd_card: Option<SdCardConfig>,
+    flash_log: Option<FlashLogConfig>,
+    boot_report_topic: Option<String>,
+    /// Receives a redacted summary of the configuration on every boot, retained
+    config_snapshot_topic: Option<String>,
+    device_namespace: Option<String>,
+    panel_device: Option<HADevice>,
+    loopback: Option<LoopbackConfig>,
+    net_command_topic: Option<String>,
+    resend_command_topic: Option<String>,
+    /// Receives a message whenever the wall clock steps
+    time_jump_topic: Option<String>,
+    /// Rejects changes of the network settings, the timings and the automatic re-arming while
+    /// the alarm is armed or triggered
+    #[serde(default)]
+    lock_settings_while_armed: bool,
+    /// Receives a message for every change of the settings, accepted or not
+    settings_audit_topic: Option<String>,
+    event_export: Option<EventExportConfig>,
+    #[serde(default)]
+    dedupe_publishes: bool,
+    bell_test: Option<BellTestConfig>,
+    /// Restart the exit delay when a delayed zone is closed during it
+    #[serde(default)]
+    exit_delay_restart: bool,
+    /// Panic zones and commands only publish the triggered state, without the siren
+    #[serde(default)]
+    silent_panic: bool,
+    /// Publishes the entities of a device in one discovery message instead of one per entity,
+    /// entities left out of the configuration are removed from HA with it
+    #[serde(default)]
+    device_discovery: bool,
+    /// Allows virtual zones on hardware, they are always allowed in simulation
+    #[serde(default)]
+    debug: bool,
+    provisioning_ap: Option<ProvisioningApConfig>,
+    /// HA publishes each area of its provisioning retained under this topic, the
+    /// bootstrap-zones net command names the zones and groups them into areas by it
+    provisioning_topic: Option<String>,
+    transitions: Option<TransitionTable>,
+    /// Translated names and announcements, the language setting selects one
+    languages: Option<Vec<Language>>,
 }
 
 impl Config {
     fn verify(&self) -> anyhow::Result<()> {
-        if self.mqtt_endpoint.is_empty() {
-            anyhow::bail!("mqtt endpoint cannot be empty");
+        if self.mqtt_endpoint.is_empty() && self.provisioning_ap.is_none() {
+            anyhow::bail!("mqtt endpoint cannot be empty without a provisioning_ap");
         }
-        if !self.mqtt_endpoint.starts_with("mqtt://") {
+        if !self.mqtt_endpoint.is_empty() && !self.mqtt_endpoint.starts_with("mqtt://") {
             anyhow::bail!(
                 "mqtt endpoint must start with \"mqtt://\". no other protocols are supported yet."
             );
         }
 
+        if let Some(ap) = self.provisioning_ap.as_ref() {
+            if ap.ssid.is_empty() || ap.ssid.len() > 32 {
+                anyhow::bail!("provisioning_ap ssid must be 1-32 bytes long");
+            }
+            if let Some(password) = ap.password.as_ref() {
+                if !(8..=64).contains(&password.len()) {
+                    anyhow::bail!("provisioning_ap password must be 8-64 bytes long");
+                }
+            }
+        }
+        if self.provisioning_topic.is_some() && self.net_command_topic.is_none() {
+            anyhow::bail!("provisioning_topic requires a net_command_topic to bootstrap the zones");
+        }
+
         for entity in self.entities.iter() {
             if entity.name.is_empty() {
                 anyhow::bail!("entity name cannot be empty");
@@ -27,12 +100,679 @@ impl Config {
             if entity.unique_id.is_empty() {
                 anyhow::bail!("entity unique_id cannot be empty");
             }
-            if entity.state_topic.is_empty() {
-                anyhow::bail!("entity state_topic cannot be empty");
+            if entity.state_topic.is_empty() != (entity.variant == HAEntityVariant::button) {
+                anyhow::bail!("entity state_topic must be set on every entity except buttons");
+            }
+            // The critical states are published with the topic as a C string
+            if entity.state_topic.contains('\0') {
+                anyhow::bail!("entity state_topic cannot contain a NUL");
+            }
+            if entity.button_action.is_some() != (entity.variant == HAEntityVariant::button) {
+                anyhow::bail!("button entities must have a button_action, other entities can't");
+            }
+            let inputs = [
+                entity.gpio_pin.is_some(),
+                entity.modbus_input.is_some(),
+                entity.can_input.is_some(),
+                entity.dsc_zone.is_some(),
+                entity.virtual_topic.is_some(),
+            ];
+            if inputs.iter().filter(|input| **input).count() > 1 {
+                anyhow::bail!(
+                    "entity can only have one of gpio_pin, modbus_input, can_input, dsc_zone and virtual_topic"
+                );
+            }
+            if (entity.modbus_input.is_some() || entity.modbus_relay.is_some())
+                && self.modbus.is_none()
+            {
+                anyhow::bail!("modbus entities require the modbus section to be configured");
+            }
+            if entity.can_input.is_some() && self.can.is_none() {
+                anyhow::bail!("can entities require the can section to be configured");
+            }
+            if let Some(zone) = entity.dsc_zone {
+                if self.dsc.is_none() {
+                    anyhow::bail!("dsc entities require the dsc section to be configured");
+                }
+                if !(1..=32).contains(&zone) {
+                    anyhow::bail!("dsc_zone must be between 1 and 32");
+                }
+            }
+            if entity.json_state.is_some() && entity.variant != HAEntityVariant::alarm_control_panel
+            {
+                anyhow::bail!("only alarm_control_panel entities can have json_state");
+            }
+            if let Some(length) = entity.event_history {
+                if entity.variant != HAEntityVariant::alarm_control_panel {
+                    anyhow::bail!("only alarm_control_panel entities can have event_history");
+                }
+                if !(1..=50).contains(&length) {
+                    anyhow::bail!("event_history must be between 1 and 50");
+                }
+            }
+            if entity.zone_kind.is_some() && !inputs.iter().any(|input| *input) {
+                anyhow::bail!(
+                    "zone_kind requires a gpio_pin, modbus_input, can_input, dsc_zone or virtual_topic"
+                );
+            }
+            if entity.zone_type.is_some()
+                && !(inputs[..3].iter().any(|input| *input) || entity.virtual_topic.is_some())
+            {
+                anyhow::bail!(
+                    "zone_type requires a gpio_pin, modbus_input, can_input or virtual_topic"
+                );
+            }
+            if entity.inverted.is_some() && !inputs[..3].iter().any(|input| *input) {
+                anyhow::bail!("inverted requires a gpio_pin, modbus_input or can_input");
+            }
+            if let Some(debounce) = entity.debounce {
+                if !inputs[..3].iter().any(|input| *input) {
+                    anyhow::bail!("debounce requires a gpio_pin, modbus_input or can_input");
+                }
+                if debounce > 10_000 {
+                    anyhow::bail!("debounce must be at most 10000 ms");
+                }
+            }
+            if let Some(clear_after) = entity.clear_after {
+                if !inputs.iter().any(|input| *input) {
+                    anyhow::bail!(
+                        "clear_after requires a gpio_pin, modbus_input, can_input, dsc_zone or virtual_topic"
+                    );
+                }
+                if clear_after > 3600 {
+                    anyhow::bail!("clear_after must be at most 3600 seconds");
+                }
+            }
+            if let Some(entry_delay) = entity.entry_delay {
+                if !(inputs[..3].iter().any(|input| *input) || entity.virtual_topic.is_some()) {
+                    anyhow::bail!(
+                        "entry_delay requires a gpio_pin, modbus_input, can_input or virtual_topic"
+                    );
+                }
+                if matches!(
+                    entity.zone_type,
+                    Some(ZoneType::fire | ZoneType::panic | ZoneType::tamper)
+                ) {
+                    anyhow::bail!("fire, panic and tamper zones cannot have an entry_delay");
+                }
+                if entry_delay > 600 {
+                    anyhow::bail!("entry_delay must be at most 600 seconds");
+                }
+            }
+            if entity.virtual_topic.is_some()
+                && !self.debug
+                && std::env::var("CARGO_FEATURE_SIMULATION").is_err()
+            {
+                anyhow::bail!("virtual zones require debug to be enabled");
+            }
+            if entity.siren.is_some()
+                && (entity.variant != HAEntityVariant::binary_sensor
+                    || inputs.iter().any(|input| *input))
+            {
+                anyhow::bail!("siren can only be set on binary_sensor entities without an input");
+            }
+            if let Some(zones) = &entity.follow_zones {
+                if entity.variant != HAEntityVariant::switch {
+                    anyhow::bail!("only switch entities can have follow_zones");
+                }
+                for zone in zones.iter() {
+                    let zone_entity = self.entities.iter().find(|e| e.unique_id == *zone);
+                    if !zone_entity.is_some_and(|e| {
+                        e.gpio_pin.is_some()
+                            || e.modbus_input.is_some()
+                            || e.can_input.is_some()
+                            || e.virtual_topic.is_some()
+                    }) {
+                        anyhow::bail!("follow_zones entry {} is not a zone with an input", zone);
+                    }
+                }
+            } else if entity.follow_duration.is_some() {
+                anyhow::bail!("follow_duration requires follow_zones");
+            }
+            if entity.dialer_contact.is_some() && entity.variant != HAEntityVariant::switch {
+                anyhow::bail!("only switch entities can have a dialer_contact");
+            }
+            if (entity.alarm_toggle.is_some() || entity.bypass_zone.is_some())
+                && entity.variant != HAEntityVariant::switch
+            {
+                anyhow::bail!("only switch entities can have alarm_toggle or bypass_zone");
+            }
+            if entity.arming_profiles.is_some() != (entity.variant == HAEntityVariant::select) {
+                anyhow::bail!("select entities must have arming_profiles, other entities can't");
+            }
+            if let Some(profiles) = &entity.arming_profiles {
+                if profiles.is_empty() {
+                    anyhow::bail!("arming_profiles cannot be empty");
+                }
+                for (index, profile) in profiles.iter().enumerate() {
+                    if profile.name.is_empty() || profile.name.len() > 32 {
+                        anyhow::bail!("arming profile names must be 1-32 bytes long");
+                    }
+                    if profiles[..index].iter().any(|p| p.name == profile.name) {
+                        anyhow::bail!("arming profile {} is defined twice", profile.name);
+                    }
+                    for zone in profile.zones.iter().flatten() {
+                        if !self.entities.iter().any(|e| e.unique_id == *zone) {
+                            anyhow::bail!(
+                                "arming profile {} has an unknown zone {}",
+                                profile.name,
+                                zone
+                            );
+                        }
+                    }
+                }
+            }
+            if let Some(dual_disarm) = &entity.dual_disarm {
+                if entity.variant != HAEntityVariant::alarm_control_panel {
+                    anyhow::bail!("only alarm_control_panel entities can have dual_disarm");
+                }
+                if dual_disarm.codes.len() < 2 {
+                    anyhow::bail!("dual_disarm needs at least two codes");
+                }
+                for (index, code) in dual_disarm.codes.iter().enumerate() {
+                    if code.is_empty() || dual_disarm.codes[..index].contains(code) {
+                        anyhow::bail!("dual_disarm codes must be non-empty and different");
+                    }
+                }
+                if dual_disarm
+                    .window
+                    .is_some_and(|window| !(1..=600).contains(&window))
+                {
+                    anyhow::bail!("dual_disarm window must be between 1 and 600 seconds");
+                }
+            }
+            if let Some(arm_modes) = &entity.arm_modes {
+                if !entity.is_zone()
+                    || entity.dsc_zone.is_some()
+                    || matches!(
+                        entity.zone_type,
+                        Some(ZoneType::fire | ZoneType::panic | ZoneType::tamper)
+                    )
+                {
+                    anyhow::bail!(
+                        "arm_modes requires a zone which is not a DSC, fire, panic or tamper zone"
+                    );
+                }
+                if arm_modes.is_empty() {
+                    anyhow::bail!("arm_modes must list at least one mode");
+                }
+            }
+            if let Some(auto_bypass) = &entity.auto_bypass {
+                if !entity.is_zone()
+                    || matches!(
+                        entity.zone_type,
+                        Some(ZoneType::fire | ZoneType::panic | ZoneType::tamper)
+                    )
+                {
+                    anyhow::bail!(
+                        "auto_bypass requires a zone which is not a fire, panic or tamper zone"
+                    );
+                }
+                if let Some(window) = auto_bypass
+                    .hours
+                    .iter()
+                    .find(|window| DailyWindow::parse(window).is_none())
+                {
+                    anyhow::bail!("auto_bypass hours {} is not like 22:00-07:00", window);
+                }
+                for name in auto_bypass.profiles.iter() {
+                    let known = self
+                        .entities
+                        .iter()
+                        .flat_map(|e| e.arming_profiles.iter().flatten())
+                        .any(|profile| profile.name == *name);
+                    if !known {
+                        anyhow::bail!("auto_bypass has an unknown arming profile {}", name);
+                    }
+                }
+            }
+            if entity.arm_note.unwrap_or(false) != (entity.variant == HAEntityVariant::text) {
+                anyhow::bail!("text entities must have arm_note, other entities can't");
+            }
+            if entity.state_icons.is_some()
+                && !matches!(
+                    entity.variant,
+                    HAEntityVariant::binary_sensor | HAEntityVariant::switch
+                )
+            {
+                anyhow::bail!("only binary_sensor and switch entities can have state_icons");
+            }
+            if let Some(zone) = &entity.bypass_zone {
+                let zone_entity = self.entities.iter().find(|e| e.unique_id == *zone);
+                if !zone_entity.is_some_and(|e| {
+                    e.gpio_pin.is_some()
+                        || e.modbus_input.is_some()
+                        || e.can_input.is_some()
+                        || e.virtual_topic.is_some()
+                }) {
+                    anyhow::bail!("bypass_zone {} is not a zone with an input", zone);
+                }
+            }
+            if entity.mqtt_stat.is_some() && entity.variant != HAEntityVariant::sensor {
+                anyhow::bail!("only sensor entities can have mqtt_stat");
+            }
+            if entity.timing_stat.is_some() && entity.variant != HAEntityVariant::sensor {
+                anyhow::bail!("only sensor entities can have timing_stat");
+            }
+            if let Some(counter) = &entity.zone_counter {
+                if entity.variant != HAEntityVariant::sensor {
+                    anyhow::bail!("only sensor entities can have zone_counter");
+                }
+                if !self
+                    .entities
+                    .iter()
+                    .any(|e| e.unique_id == counter.zone && e.is_zone())
+                {
+                    anyhow::bail!("zone_counter zone {} is not a zone", counter.zone);
+                }
+            }
+            if let Some(expire_after) = entity.link_check {
+                if entity.variant != HAEntityVariant::binary_sensor || entity.is_zone() {
+                    anyhow::bail!("link_check requires a binary_sensor without an input");
+                }
+                if !(10..=86400).contains(&expire_after) {
+                    anyhow::bail!("link_check must be between 10 and 86400 seconds");
+                }
+            }
+            let power_variant = match entity.power_stat {
+                Some(PowerStat::brownouts) => Some(HAEntityVariant::sensor),
+                Some(PowerStat::brownout_trouble) => Some(HAEntityVariant::binary_sensor),
+                None => None,
+            };
+            if power_variant.is_some_and(|variant| variant != entity.variant) {
+                anyhow::bail!(
+                    "power_stat brownouts requires a sensor, brownout_trouble a binary_sensor"
+                );
+            }
+            if entity.alarm_setting.is_some() != (entity.variant == HAEntityVariant::number) {
+                anyhow::bail!("number entities must have an alarm_setting, other entities can't");
+            }
+            match entity.variant {
+                HAEntityVariant::switch => {
+                    if entity.command_topic.is_none() {
+                        anyhow::bail!("switch entity must have a command_topic");
+                    }
+                    let controls = [
+                        entity.modbus_relay.is_some(),
+                        entity.alarm_toggle.is_some(),
+                        entity.bypass_zone.is_some(),
+                    ];
+                    if controls.iter().filter(|control| **control).count() != 1 {
+                        anyhow::bail!(
+                            "switch entity must have one of modbus_relay, alarm_toggle and bypass_zone"
+                        );
+                    }
+                    if entity.follow_zones.is_some() && entity.modbus_relay.is_none() {
+                        anyhow::bail!("follow_zones requires a modbus_relay");
+                    }
+                    if entity.dialer_contact.is_some()
+                        && (entity.modbus_relay.is_none() || entity.follow_zones.is_some())
+                    {
+                        anyhow::bail!(
+                            "dialer_contact requires a modbus_relay without follow_zones"
+                        );
+                    }
+                }
+                HAEntityVariant::number
+                | HAEntityVariant::select
+                | HAEntityVariant::text
+                | HAEntityVariant::button => {
+                    if entity.command_topic.is_none() {
+                        anyhow::bail!("{} entity must have a command_topic", entity.variant);
+                    }
+                }
+                HAEntityVariant::alarm_control_panel => {}
+                _ => {
+                    if entity.command_topic.is_some() {
+                        anyhow::bail!(
+                            "only alarm_control_panel, switch, number, select, text and button entities can have a command_topic"
+                        );
+                    }
+                }
+            }
+        }
+
+        if self
+            .entities
+            .iter()
+            .filter(|entity| entity.siren.unwrap_or(false))
+            .count()
+            > 1
+        {
+            anyhow::bail!("only one entity can mirror the siren");
+        }
+
+        let arm_notes = self
+            .entities
+            .iter()
+            .filter(|entity| entity.arm_note.unwrap_or(false))
+            .count();
+        if arm_notes > 1 {
+            anyhow::bail!("only one entity can be the arm note");
+        }
+        // The note is published as an attribute of the alarm state
+        if arm_notes > 0
+            && !self.entities.iter().any(|entity| {
+                entity.variant == HAEntityVariant::alarm_control_panel
+                    && entity.json_state.unwrap_or(false)
+            })
+        {
+            anyhow::bail!("arm_note requires json_state on the alarm_control_panel entity");
+        }
+
+        if self
+            .entities
+            .iter()
+            .filter(|entity| entity.arming_profiles.is_some())
+            .count()
+            > 1
+        {
+            anyhow::bail!("only one entity can select the arming profile");
+        }
+
+        if let Some(presence) = &self.presence {
+            if presence.topics.is_empty() {
+                anyhow::bail!("presence topics cannot be empty");
+            }
+            if presence.reason_topic.is_empty() {
+                anyhow::bail!("presence reason_topic cannot be empty");
+            }
+            // The disarm code is a setting, the panel checks it when it boots
+            let dual_disarm = self.entities.iter().any(|e| e.dual_disarm.is_some());
+            presence
+                .check_auto_disarm(false, dual_disarm)
+                .map_err(anyhow::Error::msg)?;
+        }
+
+        if let Some(sd_card) = &self.sd_card {
+            if sd_card.command_topic.is_empty() || sd_card.response_topic.is_empty() {
+                anyhow::bail!("sd_card topics cannot be empty");
+            }
+        }
+
+        if let Some(flash_log) = &self.flash_log {
+            if flash_log.command_topic.is_empty() || flash_log.response_topic.is_empty() {
+                anyhow::bail!("flash_log topics cannot be empty");
+            }
+        }
+        if let Some(panel_device) = &self.panel_device {
+            if panel_device
+                .identifiers
+                .as_ref()
+                .map_or(true, |identifiers| identifiers.is_empty())
+            {
+                anyhow::bail!("panel_device must have at least one identifier");
+            }
+        }
+
+        if let Some(loopback) = &self.loopback {
+            if loopback.topic.is_empty() {
+                anyhow::bail!("loopback topic cannot be empty");
+            }
+            if loopback.timeout == 0 || loopback.timeout >= loopback.interval {
+                anyhow::bail!("loopback timeout must be between 0 and the interval");
+            }
+        }
+
+        if let Some(native_api) = &self.native_api {
+            if native_api.password.as_ref().is_some_and(String::is_empty) {
+                anyhow::bail!("native_api password cannot be empty, omit it to accept any client");
+            }
+        }
+        if let Some(event_export) = &self.event_export {
+            if event_export.topic.is_empty() {
+                anyhow::bail!("event_export topic cannot be empty");
+            }
+            if event_export.measurement.is_empty() {
+                anyhow::bail!("event_export measurement cannot be empty");
+            }
+        }
+
+        if let Some(siren_pwm) = &self.siren_pwm {
+            for tone in [siren_pwm.alarm, siren_pwm.chirp] {
+                if !(200..=10_000).contains(&tone.frequency) {
+                    anyhow::bail!("siren_pwm frequencies must be between 200 and 10000 Hz");
+                }
+            }
+        }
+
+        if let Some(delay_chirps) = &self.delay_chirps {
+            if !delay_chirps.exit && !delay_chirps.entry {
+                anyhow::bail!("delay_chirps must enable exit or entry");
+            }
+            // The alarm task runs every 250 ms and a chirp lasts until its next run
+            if delay_chirps.end_interval < 500
+                || delay_chirps.start_interval < delay_chirps.end_interval
+                || delay_chirps.start_interval > 10_000
+            {
+                anyhow::bail!(
+                    "delay_chirps intervals must be between 500 and 10000 ms, the start_interval at least the end_interval"
+                );
             }
         }
+
+        if let Some(bell_test) = &self.bell_test {
+            if bell_test.weekday > 6 || bell_test.hour > 23 || bell_test.minute > 59 {
+                anyhow::bail!("bell_test must have a weekday 0-6, hour 0-23 and minute 0-59");
+            }
+        }
+
+        if let Some(transitions) = &self.transitions {
+            transitions
+                .validate()
+                .map_err(|e| anyhow::anyhow!("invalid transitions: {}", e))?;
+        }
+
+        for (index, language) in self.languages.iter().flatten().enumerate() {
+            ha_types::validate_setting(ha_types::SETTINGS_LANGUAGE, &language.language)
+                .map_err(|e| anyhow::anyhow!("invalid language: {}", e))?;
+            if self
+                .languages
+                .iter()
+                .flatten()
+                .take(index)
+                .any(|l| l.language == language.language)
+            {
+                anyhow::bail!("language {} is listed twice", language.language);
+            }
+            for (index, name) in language.names.iter().enumerate() {
+                if !self.entities.iter().any(|e| e.unique_id == name.unique_id) {
+                    anyhow::bail!(
+                        "language {} names {}, which is not an entity",
+                        language.language,
+                        name.unique_id
+                    );
+                }
+                if name.name.is_empty()
+                    || language.names[..index]
+                        .iter()
+                        .any(|n| n.unique_id == name.unique_id)
+                {
+                    anyhow::bail!(
+                        "language {} must name {} once, with a name",
+                        language.language,
+                        name.unique_id
+                    );
+                }
+            }
+            for (index, announcement) in language.announcements.iter().enumerate() {
+                if announcement.text.is_empty()
+                    || language.announcements[..index]
+                        .iter()
+                        .any(|a| a.state == announcement.state)
+                {
+                    anyhow::bail!(
+                        "language {} must announce {:?} once, with a text",
+                        language.language,
+                        announcement.state
+                    );
+                }
+            }
+        }
+
+        if self.device_namespace.as_ref().is_some_and(|n| n.is_empty()) {
+            anyhow::bail!("device_namespace cannot be empty");
+        }
         Ok(())
     }
+
+    /// Entities without a device are assigned to the panel, or to their expander
+    /// board which is shown under the panel
+    fn apply_device_hierarchy(&mut self) {
+        let Some(panel) = &self.panel_device else {
+            return;
+        };
+        let panel_id = panel.identifiers.as_ref().unwrap()[0].clone();
+        let panel_name = panel.name.clone().unwrap_or_else(|| "Alarm".to_string());
+
+        for entity in self.entities.iter_mut() {
+            if entity.device.is_some() {
+                continue;
+            }
+
+            let expander = entity
+                .modbus_input
+                .as_ref()
+                .or(entity.modbus_relay.as_ref())
+                .map(|point| ("modbus", "Modbus", point.address))
+                .or_else(|| {
+                    entity
+                        .can_input
+                        .as_ref()
+                        .map(|point| ("can", "CAN", point.address))
+                });
+
+            entity.device = Some(match expander {
+                Some((bus, bus_name, address)) => HADevice {
+                    identifiers: Some(vec![format!("{}_{}_{}", panel_id, bus, address)]),
+                    name: Some(format!("{} {} expander {}", panel_name, bus_name, address)),
+                    model: Some(format!("{} expander", bus_name)),
+                    via_device: Some(panel_id.clone()),
+                    ..Default::default()
+                },
+                None => panel.clone(),
+            });
+        }
+    }
+
+    /// Zones get a kind from their device_class, or a device_class from their kind,
+    /// so HA shows them with the right icon and state labels
+    fn apply_zone_kinds(&mut self) {
+        for entity in self.entities.iter_mut() {
+            let is_zone = entity.gpio_pin.is_some()
+                || entity.modbus_input.is_some()
+                || entity.can_input.is_some()
+                || entity.dsc_zone.is_some()
+                || entity.virtual_topic.is_some();
+            if !is_zone {
+                continue;
+            }
+
+            let kind = *entity.zone_kind.get_or_insert_with(|| {
+                entity
+                    .device_class
+                    .as_deref()
+                    .and_then(ZoneKind::from_device_class)
+                    .unwrap_or_default()
+            });
+            entity
+                .device_class
+                .get_or_insert_with(|| kind.device_class().to_string());
+        }
+    }
+
+    fn apply_namespace(&mut self) {
+        let topics = TopicBuilder::new(self.device_namespace.as_deref());
+
+        topics.apply(&mut self.availability_topic);
+        topics.apply(&mut self.ota_topic);
+        for entity in self.entities.iter_mut() {
+            if !entity.state_topic.is_empty() {
+                topics.apply(&mut entity.state_topic);
+            }
+            if let Some(command_topic) = entity.command_topic.as_mut() {
+                topics.apply(command_topic);
+            }
+            if let Some(virtual_topic) = entity.virtual_topic.as_mut() {
+                topics.apply(virtual_topic);
+            }
+        }
+        if let Some(presence) = self.presence.as_mut() {
+            topics.apply(&mut presence.reason_topic);
+        }
+        if let Some(sd_card) = self.sd_card.as_mut() {
+            topics.apply(&mut sd_card.command_topic);
+            topics.apply(&mut sd_card.response_topic);
+        }
+        if let Some(flash_log) = self.flash_log.as_mut() {
+            topics.apply(&mut flash_log.command_topic);
+            topics.apply(&mut flash_log.response_topic);
+        }
+        if let Some(boot_report_topic) = self.boot_report_topic.as_mut() {
+            topics.apply(boot_report_topic);
+        }
+        if let Some(config_snapshot_topic) = self.config_snapshot_topic.as_mut() {
+            topics.apply(config_snapshot_topic);
+        }
+        if let Some(loopback) = self.loopback.as_mut() {
+            topics.apply(&mut loopback.topic);
+        }
+        if let Some(net_command_topic) = self.net_command_topic.as_mut() {
+            topics.apply(net_command_topic);
+        }
+        if let Some(resend_command_topic) = self.resend_command_topic.as_mut() {
+            topics.apply(resend_command_topic);
+        }
+        if let Some(time_jump_topic) = self.time_jump_topic.as_mut() {
+            topics.apply(time_jump_topic);
+        }
+        if let Some(settings_audit_topic) = self.settings_audit_topic.as_mut() {
+            topics.apply(settings_audit_topic);
+        }
+        if let Some(provisioning_topic) = self.provisioning_topic.as_mut() {
+            topics.apply(provisioning_topic);
+        }
+        if let Some(event_export) = self.event_export.as_mut() {
+            topics.apply(&mut event_export.topic);
+        }
+    }
+}
+
+impl Config {
+    /// Names of the optional parts which are configured, for the configuration snapshot
+    fn features(&self) -> Vec<String> {
+        [
+            ("presence", self.presence.is_some()),
+            ("modbus", self.modbus.is_some()),
+            ("can", self.can.is_some()),
+            ("dsc", self.dsc.is_some()),
+            ("rtc", self.rtc.is_some()),
+            ("siren_pwm", self.siren_pwm.is_some()),
+            ("delay_chirps", self.delay_chirps.is_some()),
+            ("native_api", self.native_api.is_some()),
+            ("sd_card", self.sd_card.is_some()),
+            ("flash_log", self.flash_log.is_some()),
+            ("loopback", self.loopback.is_some()),
+            ("net_command", self.net_command_topic.is_some()),
+            ("event_export", self.event_export.is_some()),
+            ("bell_test", self.bell_test.is_some()),
+            ("provisioning_ap", self.provisioning_ap.is_some()),
+            ("zone_bootstrap", self.provisioning_topic.is_some()),
+            ("transitions", self.transitions.is_some()),
+            ("languages", self.languages.is_some()),
+            ("mqtt_persistent_session", self.mqtt_persistent_session),
+            ("lock_settings_while_armed", self.lock_settings_while_armed),
+            ("dedupe_publishes", self.dedupe_publishes),
+            ("exit_delay_restart", self.exit_delay_restart),
+            ("silent_panic", self.silent_panic),
+            ("device_discovery", self.device_discovery),
+            ("debug", self.debug),
+        ]
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name.to_string())
+        .collect()
+    }
 }
 
 macro_rules! config_entry_to_env {
@@ -47,12 +787,63 @@ fn main() {
     println!("cargo:rerun-if-changed=config.yml");
 
     let config_file = std::fs::read_to_string("config.yml").expect("config.yml not found");
-    let config: Config = serde_yaml::from_str(&config_file).expect("config.yml is not valid yaml");
+    let mut config: Config =
+        serde_yaml::from_str(&config_file).expect("config.yml is not valid yaml");
     config.verify().expect("config.yml validation failed");
+    config.apply_device_hierarchy();
+    config.apply_zone_kinds();
+    config.apply_namespace();
+    let features = config.features();
 
     config_entry_to_env!(config, ESP_MQTT_ENDPOINT, mqtt_endpoint);
+    config_entry_to_env!(config, ESP_MQTT_PERSISTENT_SESSION, mqtt_persistent_session);
+    config_entry_to_env!(config, ESP_DEDUPE_PUBLISHES, dedupe_publishes);
+    config_entry_to_env!(config, ESP_EXIT_DELAY_RESTART, exit_delay_restart);
+    config_entry_to_env!(config, ESP_SILENT_PANIC, silent_panic);
+    config_entry_to_env!(config, ESP_DEVICE_DISCOVERY, device_discovery);
+    config_entry_to_env!(
+        config,
+        ESP_LOCK_SETTINGS_WHILE_ARMED,
+        lock_settings_while_armed
+    );
     config_entry_to_env!(config, ESP_AVAILABILITY_TOPIC, availability_topic);
     config_entry_to_env!(config, ESP_OTA_TOPIC, ota_topic);
 
     uneval::to_out_dir(config.entities, "entities.rs").expect("Failed to write entities.rs");
+    uneval::to_out_dir(config.presence, "presence.rs").expect("Failed to write presence.rs");
+    uneval::to_out_dir(config.modbus, "modbus.rs").expect("Failed to write modbus.rs");
+    uneval::to_out_dir(config.can, "can.rs").expect("Failed to write can.rs");
+    uneval::to_out_dir(config.dsc, "dsc.rs").expect("Failed to write dsc.rs");
+    uneval::to_out_dir(config.rtc, "rtc.rs").expect("Failed to write rtc.rs");
+    uneval::to_out_dir(config.siren_pwm, "siren_pwm.rs").expect("Failed to write siren_pwm.rs");
+    uneval::to_out_dir(config.delay_chirps, "delay_chirps.rs")
+        .expect("Failed to write delay_chirps.rs");
+    uneval::to_out_dir(config.native_api, "native_api.rs").expect("Failed to write native_api.rs");
+    uneval::to_out_dir(config.sd_card, "sd_card.rs").expect("Failed to write sd_card.rs");
+    uneval::to_out_dir(config.flash_log, "flash_log.rs").expect("Failed to write flash_log.rs");
+    uneval::to_out_dir(config.loopback, "loopback.rs").expect("Failed to write loopback.rs");
+    uneval::to_out_dir(config.net_command_topic, "net_command_topic.rs")
+        .expect("Failed to write net_command_topic.rs");
+    uneval::to_out_dir(config.resend_command_topic, "resend_command_topic.rs")
+        .expect("Failed to write resend_command_topic.rs");
+    uneval::to_out_dir(config.time_jump_topic, "time_jump_topic.rs")
+        .expect("Failed to write time_jump_topic.rs");
+    uneval::to_out_dir(config.settings_audit_topic, "settings_audit_topic.rs")
+        .expect("Failed to write settings_audit_topic.rs");
+    uneval::to_out_dir(config.provisioning_topic, "provisioning_topic.rs")
+        .expect("Failed to write provisioning_topic.rs");
+    uneval::to_out_dir(config.event_export, "event_export.rs")
+        .expect("Failed to write event_export.rs");
+    uneval::to_out_dir(config.bell_test, "bell_test.rs").expect("Failed to write bell_test.rs");
+    uneval::to_out_dir(config.provisioning_ap, "provisioning_ap.rs")
+        .expect("Failed to write provisioning_ap.rs");
+    uneval::to_out_dir(config.transitions.unwrap_or_default(), "transitions.rs")
+        .expect("Failed to write transitions.rs");
+    uneval::to_out_dir(config.languages.unwrap_or_default(), "languages.rs")
+        .expect("Failed to write languages.rs");
+    uneval::to_out_dir(config.boot_report_topic, "boot_report_topic.rs")
+        .expect("Failed to write boot_report_topic.rs");
+    uneval::to_out_dir(config.config_snapshot_topic, "config_snapshot_topic.rs")
+        .expect("Failed to write config_snapshot_topic.rs");
+    uneval::to_out_dir(features, "features.rs").expect("Failed to write features.rs");
 }
@@ -0,0 +1,15 @@
+#![no_main]
+
+use ha_types::payload::*;
+use libfuzzer_sys::fuzz_target;
+
+// Every parser of the command topics, on the bytes as they arrive from the broker
+fuzz_target!(|data: &[u8]| {
+    let Ok(payload) = decode(data) else {
+        return;
+    };
+    let _ = parse_net_command(payload);
+    let _ = parse_alarm_payload(payload);
+    let _ = parse_switch(payload);
+    let _ = parse_number(payload);
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// A NUL separated key and value, as the settings are stored in NVS
+fuzz_target!(|data: &[u8]| {
+    let data = String::from_utf8_lossy(data);
+    let (key, value) = data.split_once('\0').unwrap_or((&data, ""));
+    let _ = ha_types::validate_setting(key, value);
+});
@@ -1,12 +1,24 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 
+pub mod payload;
+pub mod provisioning;
+pub mod router;
+pub mod timers;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HAEntity {
     pub name: String,
     pub variant: HAEntityVariant,
     pub unique_id: String,
+    /// Buttons have no state
+    #[serde(default)]
     pub state_topic: String,
     pub icon: Option<String>,
+    /// Icons of the on and off states of binary sensors and switches, published as the
+    /// `icon` attribute for templates and dashboard cards
+    pub state_icons: Option<StateIcons>,
     #[serde(skip_deserializing)]
     pub availability: Option<HADeviceAvailability>,
     pub device: Option<HADevice>,
@@ -14,12 +26,75 @@ pub struct HAEntity {
     pub entity_category: Option<String>,
     pub gpio_pin: Option<u8>,
     pub command_topic: Option<String>,
+    pub modbus_input: Option<ExpanderPoint>,
+    pub modbus_relay: Option<ExpanderPoint>,
+    pub can_input: Option<ExpanderPoint>,
+    pub dsc_zone: Option<u8>,
+    /// Zone state is set by `ON`/`OFF` messages on this topic instead of an input,
+    /// for testing without wired sensors
+    pub virtual_topic: Option<String>,
+    /// Publish the alarm state as a JSON object together with its attributes
+    pub json_state: Option<bool>,
+    /// Zone and alarm state changes kept in the JSON array published on
+    /// `<state_topic>/history`, for a history card which doesn't rely on the recorder
+    pub event_history: Option<u8>,
+    /// Disarming the alarm entity takes two different codes
+    pub dual_disarm: Option<DualDisarm>,
+    /// Unique ids of the zones which switch this output on, regardless of the alarm state
+    pub follow_zones: Option<Vec<String>>,
+    /// Seconds the output stays on after the followed zones became inactive
+    pub follow_duration: Option<u64>,
+    /// The output is the trigger contact of an external communicator, e.g. a GSM dialer
+    pub dialer_contact: Option<DialerContact>,
+    pub zone_type: Option<ZoneType>,
+    /// The zone is active while its input is low, e.g. a normally closed sensor
+    pub inverted: Option<bool>,
+    /// Milliseconds the input has to stay at a new level before the zone changes, so noise
+    /// on the line is ignored, the zones are scanned at least every 250 ms
+    pub debounce: Option<u64>,
+    /// Seconds the zone stays active after its input cleared, longer activity restarts it,
+    /// e.g. for the occupancy of a room with a PIR sensor
+    pub clear_after: Option<u64>,
+    /// Seconds of the entry delay when the zone starts it, instead of the pending_timeout setting
+    pub entry_delay: Option<u64>,
+    /// Arm modes in which the alarm monitors the zone, every mode when omitted
+    pub arm_modes: Option<Vec<ArmMode>>,
+    /// What the sensor of the zone detects, derived from the device_class when omitted
+    pub zone_kind: Option<ZoneKind>,
+    /// Binary sensor which is ON while the siren is sounding
+    pub siren: Option<bool>,
+    /// Alarm setting which is shown and changed by a number entity
+    pub alarm_setting: Option<AlarmSetting>,
+    /// Alarm feature which is turned on and off by a switch entity
+    pub alarm_toggle: Option<AlarmToggle>,
+    /// Unique id of the zone which is ignored by the alarm while a switch entity is on
+    pub bypass_zone: Option<String>,
+    /// When the alarm ignores the zone without it being bypassed by hand
+    pub auto_bypass: Option<AutoBypass>,
+    /// Profiles offered by a select entity, the selected one is used on the next arm
+    pub arming_profiles: Option<Vec<ArmingProfile>>,
+    /// Text entity whose value is attached to the next alarm state change, then cleared
+    pub arm_note: Option<bool>,
+    pub button_action: Option<ButtonAction>,
+    /// Counter of the MQTT connection shown by a sensor entity, since boot, the lifetime
+    /// count is published as an attribute
+    pub mqtt_stat: Option<MqttStat>,
+    /// Brown-out diagnostic shown by a sensor or a binary_sensor entity
+    pub power_stat: Option<PowerStat>,
+    /// Timing of the zone processing shown by a sensor entity, the peak of each minute
+    pub timing_stat: Option<TimingStat>,
+    /// Activations of a zone counted by a sensor entity, for spotting false alarms
+    pub zone_counter: Option<ZoneCounter>,
+    /// Binary sensor answering the challenges HA publishes to `<state_topic>/challenge`, it
+    /// becomes unavailable this many seconds after the last answer
+    pub link_check: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HAEntityOut {
     pub name: String,
     pub unique_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub state_topic: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
@@ -32,6 +107,10 @@ pub struct HAEntityOut {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub entity_category: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_template: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub code_arm_required: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -42,6 +121,891 @@ pub struct HAEntityOut {
     pub command_topic: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub supported_features: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expire_after: Option<u64>,
+    /// Hash of the discovery of every entity, identifies the configuration which published it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateIcons {
+    pub on: String,
+    pub off: String,
+}
+
+impl HAEntity {
+    /// Topic of the attributes, the icon of the current state, the activity times of a zone,
+    /// the lifetime count of an MQTT counter or the last answered link challenge
+    pub fn attributes_topic(&self) -> Option<String> {
+        (self.state_icons.is_some()
+            || self.mqtt_stat.is_some()
+            || self.link_check.is_some()
+            || self.is_zone())
+        .then(|| format!("{}/attributes", self.state_topic))
+    }
+
+    /// Topic of the challenges of a link check
+    pub fn challenge_topic(&self) -> Option<String> {
+        self.link_check
+            .map(|_| format!("{}/challenge", self.state_topic))
+    }
+
+    /// Zones have an input which the alarm monitors
+    pub fn is_zone(&self) -> bool {
+        self.gpio_pin.is_some()
+            || self.modbus_input.is_some()
+            || self.can_input.is_some()
+            || self.dsc_zone.is_some()
+            || self.virtual_topic.is_some()
+    }
+}
+
+/// Builds the topics owned by the device under a common namespace, e.g. `alarm/garage`
+///
+/// Topics of other devices, like presence trackers, are not namespaced.
+pub struct TopicBuilder<'a> {
+    namespace: Option<&'a str>,
+}
+
+impl<'a> TopicBuilder<'a> {
+    pub fn new(namespace: Option<&'a str>) -> Self {
+        Self { namespace }
+    }
+
+    pub fn topic(&self, topic: &str) -> String {
+        match self.namespace {
+            Some(namespace) => format!(
+                "{}/{}",
+                namespace.trim_end_matches('/'),
+                topic.trim_start_matches('/')
+            ),
+            None => topic.to_string(),
+        }
+    }
+
+    pub fn apply(&self, topic: &mut String) {
+        *topic = self.topic(topic);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExpanderPoint {
+    /// Bus address of the expander board
+    pub address: u8,
+    /// Index of the discrete input or coil on the board
+    pub index: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModbusConfig {
+    pub tx_pin: u8,
+    pub rx_pin: u8,
+    /// Driver enable pin of the RS485 transceiver
+    pub de_pin: u8,
+    #[serde(default = "default_modbus_baudrate")]
+    pub baudrate: u32,
+    /// Milliseconds between polls of the expander boards
+    #[serde(default = "default_modbus_poll_interval")]
+    pub poll_interval: u64,
+}
+
+fn default_modbus_baudrate() -> u32 {
+    9600
+}
+
+fn default_modbus_poll_interval() -> u64 {
+    100
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanConfig {
+    pub tx_pin: u8,
+    pub rx_pin: u8,
+}
+
+/// DS3231 real time clock on the I2C bus, keeps the time over restarts without network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RtcConfig {
+    pub sda_pin: u8,
+    pub scl_pin: u8,
+}
+
+/// Siren driven through the LEDC with a tone, instead of switching it on and off
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SirenPwmConfig {
+    /// Sounded while triggered, on a fire alarm and during the bell test
+    pub alarm: SirenTone,
+    /// Sounded by the chime, the walk test and the self-test
+    pub chirp: SirenTone,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SirenTone {
+    #[serde(default)]
+    pub pattern: SirenPattern,
+    /// Pitch in Hz, the higher tone of a warble
+    pub frequency: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum SirenPattern {
+    #[default]
+    continuous,
+    /// Half a second on, half a second off
+    pulse,
+    /// Alternates between the tone and one a quarter lower every quarter second
+    warble,
+}
+
+/// How a dialer contact is closed for each kind of alarm, so the communicator can tell
+/// them apart like with the bell output of a traditional panel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DialerContact {
+    #[serde(default = "default_burglary_pattern")]
+    pub burglary: ContactPattern,
+    #[serde(default = "default_fire_pattern")]
+    pub fire: ContactPattern,
+}
+
+fn default_burglary_pattern() -> ContactPattern {
+    ContactPattern::steady
+}
+
+fn default_fire_pattern() -> ContactPattern {
+    ContactPattern::pulse
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum ContactPattern {
+    /// Closed until the alarm ends
+    steady,
+    /// Closed for a second, then open for a second
+    pulse,
+    /// Temporal-3: closed three times for half a second, then open for a second
+    temporal,
+}
+
+impl ContactPattern {
+    pub fn is_closed(&self, elapsed: Duration) -> bool {
+        match self {
+            ContactPattern::steady => true,
+            ContactPattern::pulse => elapsed.as_millis() % 2000 < 1000,
+            ContactPattern::temporal => {
+                let phase = elapsed.as_millis() % 4000;
+                phase < 3000 && phase % 1000 < 500
+            }
+        }
+    }
+}
+
+/// Chirps of the siren during the exit and entry delays, more frequent as the delay runs out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DelayChirpConfig {
+    #[serde(default)]
+    pub exit: bool,
+    #[serde(default)]
+    pub entry: bool,
+    /// Milliseconds between the chirps when a delay starts
+    #[serde(default = "default_delay_chirp_start_interval")]
+    pub start_interval: u64,
+    /// Milliseconds between the chirps when a delay ends
+    #[serde(default = "default_delay_chirp_end_interval")]
+    pub end_interval: u64,
+}
+
+fn default_delay_chirp_start_interval() -> u64 {
+    2000
+}
+
+fn default_delay_chirp_end_interval() -> u64 {
+    500
+}
+
+/// Names and announcements in a language, used when the language setting selects it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Language {
+    /// Code of the language, e.g. `de` or `pt-br`
+    pub language: String,
+    /// Names of the entities, in discovery and wherever the panel names a zone, the name in
+    /// the configuration is kept for the others
+    #[serde(default)]
+    pub names: Vec<TranslatedName>,
+    /// Published to `<state_topic>/announcement` of the alarm entity when it enters the state,
+    /// e.g. for a text-to-speech automation
+    #[serde(default)]
+    pub announcements: Vec<Announcement>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TranslatedName {
+    pub unique_id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Announcement {
+    pub state: AlarmStateName,
+    pub text: String,
+}
+
+impl Language {
+    pub fn name(&self, unique_id: &str) -> Option<&str> {
+        self.names
+            .iter()
+            .find(|name| name.unique_id == unique_id)
+            .map(|name| name.name.as_str())
+    }
+
+    pub fn announcement(&self, state: AlarmStateName) -> Option<&str> {
+        self.announcements
+            .iter()
+            .find(|announcement| announcement.state == state)
+            .map(|announcement| announcement.text.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DscConfig {
+    pub clock_pin: u8,
+    pub data_pin: u8,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceConfig {
+    pub topics: Vec<String>,
+    #[serde(default = "default_home_payload")]
+    pub home_payload: String,
+    #[serde(default)]
+    pub auto_arm: bool,
+    #[serde(default)]
+    pub auto_disarm: bool,
+    /// Seconds to wait after the last person left before auto-arming
+    #[serde(default)]
+    pub grace_period: u64,
+    pub reason_topic: String,
+}
+
+impl PresenceConfig {
+    /// Auto-disarm sends no code, so it would let anyone publishing on a presence topic
+    /// disarm a panel which requires one
+    pub fn check_auto_disarm(&self, disarm_code: bool, dual_disarm: bool) -> Result<(), &'static str> {
+        match (self.auto_disarm, disarm_code, dual_disarm) {
+            (true, true, _) => Err("presence auto_disarm cannot be used with a disarm code"),
+            (true, _, true) => Err("presence auto_disarm cannot be used with dual_disarm"),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn default_home_payload() -> String {
+    "home".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NativeApiConfig {
+    #[serde(default = "default_native_api_port")]
+    pub port: u16,
+    /// Clients have to send it when connecting, any client is accepted without it
+    pub password: Option<String>,
+}
+
+fn default_native_api_port() -> u16 {
+    6053
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlashLogConfig {
+    /// Accepts the `dump` command
+    pub command_topic: String,
+    /// Stored records are published here, the end of a dump is marked by an empty message
+    pub response_topic: String,
+}
+
+/// Publishes every alarm event for time-series databases, e.g. through Telegraf
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventExportConfig {
+    pub topic: String,
+    #[serde(default)]
+    pub format: EventExportFormat,
+    /// Measurement of the line protocol records
+    #[serde(default = "default_event_export_measurement")]
+    pub measurement: String,
+}
+
+fn default_event_export_measurement() -> String {
+    "alarm_event".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum EventExportFormat {
+    /// InfluxDB line protocol, the event and the entity are tags
+    #[default]
+    line_protocol,
+    /// The JSON records of the SD card archive
+    json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoopbackConfig {
+    pub topic: String,
+    /// Seconds between tests
+    #[serde(default = "default_loopback_interval")]
+    pub interval: u64,
+    /// Seconds to wait for the nonce to come back before reconnecting
+    #[serde(default = "default_loopback_timeout")]
+    pub timeout: u64,
+}
+
+fn default_loopback_interval() -> u64 {
+    60
+}
+
+fn default_loopback_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SdCardConfig {
+    /// Chip select of the card, which shares the SPI bus of the ethernet controller
+    pub cs_pin: u8,
+    /// Accepts `list` and `fetch <day>` commands
+    pub command_topic: String,
+    /// Day listings and archive contents are published here, the end of a
+    /// fetched day is marked by an empty message
+    pub response_topic: String,
+}
+
+/// How the alarm reacts to activity of a zone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum ZoneType {
+    /// Starts the entry delay, e.g. the front door
+    #[default]
+    delayed,
+    /// Triggers the alarm immediately and aborts arming
+    instant,
+    /// Monitored in every alarm state, sounds a distinct pattern until acknowledged
+    fire,
+    /// Triggers the alarm in every alarm state, e.g. a wall-mounted panic button
+    panic,
+    /// Triggers the alarm in every alarm state when an enclosure is opened or a sensor loop is cut,
+    /// never silent
+    tamper,
+}
+
+/// Alarm state in the transition table, named like the published states
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum AlarmStateName {
+    disarmed,
+    arming,
+    armed_away,
+    armed_home,
+    armed_night,
+    pending,
+    triggered,
+}
+
+/// How the alarm is armed, it decides which zones are monitored
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum ArmMode {
+    /// Nobody is at home, every zone is monitored
+    away,
+    /// Someone is at home, usually only the perimeter is monitored
+    home,
+    /// Everyone is asleep, e.g. the bedrooms are left out
+    night,
+}
+
+impl ArmMode {
+    /// The published state while armed in this mode
+    pub fn state_name(&self) -> AlarmStateName {
+        match self {
+            ArmMode::away => AlarmStateName::armed_away,
+            ArmMode::home => AlarmStateName::armed_home,
+            ArmMode::night => AlarmStateName::armed_night,
+        }
+    }
+}
+
+impl AlarmStateName {
+    pub fn is_armed(&self) -> bool {
+        matches!(
+            self,
+            AlarmStateName::armed_away | AlarmStateName::armed_home | AlarmStateName::armed_night
+        )
+    }
+}
+
+/// Alarm command in the transition table, named like the command payloads in lower case
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum TransitionCommand {
+    arm_away,
+    arm_home,
+    arm_night,
+    arm_custom_bypass,
+    trigger,
+    untrigger,
+}
+
+/// What an opened zone does to the alarm, from the least to the most severe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum ZoneAction {
+    ignore,
+    /// Disarms the alarm during the exit delay
+    abort_arming,
+    /// Starts the entry delay
+    pending,
+    trigger,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandTransition {
+    pub command: TransitionCommand,
+    /// States in which the command is accepted, replacing the built-in ones
+    pub states: Vec<AlarmStateName>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZoneTransition {
+    pub state: AlarmStateName,
+    pub zone_type: ZoneType,
+    pub action: ZoneAction,
+}
+
+/// Site specific changes to the alarm state machine, e.g. accepting TRIGGER while disarmed
+///
+/// Disarming and the fire and panic zones can't be changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransitionTable {
+    #[serde(default)]
+    pub commands: Vec<CommandTransition>,
+    #[serde(default)]
+    pub zones: Vec<ZoneTransition>,
+}
+
+impl TransitionTable {
+    pub fn allowed_states(&self, command: TransitionCommand) -> &[AlarmStateName] {
+        if let Some(transition) = self.commands.iter().find(|t| t.command == command) {
+            return &transition.states;
+        }
+        match command {
+            TransitionCommand::arm_away
+            | TransitionCommand::arm_home
+            | TransitionCommand::arm_night
+            | TransitionCommand::arm_custom_bypass => &[AlarmStateName::disarmed],
+            TransitionCommand::trigger => &[
+                AlarmStateName::armed_away,
+                AlarmStateName::armed_home,
+                AlarmStateName::armed_night,
+            ],
+            TransitionCommand::untrigger => {
+                &[AlarmStateName::triggered, AlarmStateName::pending]
+            }
+        }
+    }
+
+    pub fn is_overridden(&self, command: TransitionCommand) -> bool {
+        self.commands.iter().any(|t| t.command == command)
+    }
+
+    pub fn zone_action(&self, state: AlarmStateName, zone_type: ZoneType) -> ZoneAction {
+        if let Some(transition) = self
+            .zones
+            .iter()
+            .find(|t| t.state == state && t.zone_type == zone_type)
+        {
+            return transition.action;
+        }
+        match (state, zone_type) {
+            (AlarmStateName::arming, ZoneType::instant) => ZoneAction::abort_arming,
+            (state, ZoneType::instant) if state.is_armed() => ZoneAction::trigger,
+            (state, ZoneType::delayed) if state.is_armed() => ZoneAction::pending,
+            _ => ZoneAction::ignore,
+        }
+    }
+
+    /// Checks the table when it is loaded
+    pub fn validate(&self) -> Result<(), String> {
+        for (index, transition) in self.commands.iter().enumerate() {
+            if self.commands[..index]
+                .iter()
+                .any(|t| t.command == transition.command)
+            {
+                return Err(format!("{:?} is listed twice", transition.command));
+            }
+        }
+        for (index, transition) in self.zones.iter().enumerate() {
+            if self.zones[..index]
+                .iter()
+                .any(|t| t.state == transition.state && t.zone_type == transition.zone_type)
+            {
+                return Err(format!(
+                    "{:?} zones in {:?} are listed twice",
+                    transition.zone_type, transition.state
+                ));
+            }
+            if matches!(
+                transition.zone_type,
+                ZoneType::fire | ZoneType::panic | ZoneType::tamper
+            ) {
+                return Err(format!("{:?} zones can't be changed", transition.zone_type));
+            }
+            let valid = match transition.action {
+                ZoneAction::ignore => true,
+                ZoneAction::abort_arming => transition.state == AlarmStateName::arming,
+                ZoneAction::pending => transition.state.is_armed(),
+                ZoneAction::trigger => transition.state != AlarmStateName::triggered,
+            };
+            if !valid {
+                return Err(format!(
+                    "{:?} is not possible in {:?}",
+                    transition.action, transition.state
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the sensor of a zone detects, it sets the default device_class of the zone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum ZoneKind {
+    #[default]
+    motion,
+    door,
+    window,
+    tamper,
+    smoke,
+}
+
+impl ZoneKind {
+    pub fn device_class(&self) -> &'static str {
+        match self {
+            ZoneKind::motion => "motion",
+            ZoneKind::door => "door",
+            ZoneKind::window => "window",
+            ZoneKind::tamper => "tamper",
+            ZoneKind::smoke => "smoke",
+        }
+    }
+
+    pub fn from_device_class(device_class: &str) -> Option<Self> {
+        match device_class {
+            "motion" | "occupancy" | "presence" => Some(ZoneKind::motion),
+            "door" | "garage_door" | "opening" => Some(ZoneKind::door),
+            "window" => Some(ZoneKind::window),
+            "tamper" => Some(ZoneKind::tamper),
+            "smoke" => Some(ZoneKind::smoke),
+            _ => None,
+        }
+    }
+
+    /// Name of the zone's state change in logs and the event archive
+    pub fn event_name(&self, active: bool) -> &'static str {
+        match (self, active) {
+            (ZoneKind::motion, true) => "motion_detected",
+            (ZoneKind::motion, false) => "motion_cleared",
+            (ZoneKind::door | ZoneKind::window, true) => "opened",
+            (ZoneKind::door | ZoneKind::window, false) => "closed",
+            (ZoneKind::tamper, true) => "tampered",
+            (ZoneKind::tamper, false) => "tamper_cleared",
+            (ZoneKind::smoke, true) => "smoke_detected",
+            (ZoneKind::smoke, false) => "smoke_cleared",
+        }
+    }
+}
+
+/// Weekly siren test, the time is in the local time of the timezone setting
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BellTestConfig {
+    /// Day of the week, 0 is Monday
+    pub weekday: u8,
+    pub hour: u8,
+    pub minute: u8,
+}
+
+/// Wi-Fi access point serving the provisioning form when no MQTT endpoint is configured
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvisioningApConfig {
+    pub ssid: String,
+    /// The access point is open without a password
+    pub password: Option<String>,
+}
+
+/// Alarm timing which can be changed at runtime, in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum AlarmSetting {
+    /// Exit delay
+    arming_timeout,
+    /// Entry delay
+    pending_timeout,
+    /// How long the siren sounds once triggered, 0 sounds it until disarmed
+    siren_timeout,
+}
+
+impl AlarmSetting {
+    pub const ALL: [AlarmSetting; 3] = [
+        AlarmSetting::arming_timeout,
+        AlarmSetting::pending_timeout,
+        AlarmSetting::siren_timeout,
+    ];
+
+    /// Key of the value persisted in NVS
+    pub fn key(&self) -> &'static str {
+        match self {
+            AlarmSetting::arming_timeout => "arming_timeout",
+            AlarmSetting::pending_timeout => "pending_timeout",
+            AlarmSetting::siren_timeout => "siren_timeout",
+        }
+    }
+
+    pub fn default_value(&self) -> u32 {
+        match self {
+            AlarmSetting::arming_timeout => 90,
+            AlarmSetting::pending_timeout => 30,
+            AlarmSetting::siren_timeout => 0,
+        }
+    }
+
+    pub fn range(&self) -> std::ops::RangeInclusive<u32> {
+        match self {
+            AlarmSetting::arming_timeout | AlarmSetting::pending_timeout => 0..=600,
+            AlarmSetting::siren_timeout => 0..=1800,
+        }
+    }
+}
+
+/// Zones which are monitored while armed, e.g. only the doors and windows at night
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArmingProfile {
+    pub name: String,
+    /// Unique ids of the monitored zones, every zone when omitted
+    ///
+    /// Fire and panic zones are monitored regardless of the profile.
+    pub zones: Option<Vec<String>>,
+}
+
+/// Alarm feature which is turned on and off at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum AlarmToggle {
+    /// Chirps the siren when a delayed zone opens while disarmed, like a door chime
+    chime,
+    /// Chirps the siren when any zone opens while disarmed, arming is rejected meanwhile
+    walk_test,
+    /// Silences the chime and the walk test chirps during the quiet hours setting
+    quiet_hours,
+    /// Returns to armed after the siren timeout instead of staying triggered, only when
+    /// the alarm was armed before it triggered
+    auto_rearm,
+    /// Bypasses the zone which triggered the alarm when it is re-armed automatically
+    rearm_bypass,
+}
+
+impl AlarmToggle {
+    pub fn key(&self) -> &'static str {
+        match self {
+            AlarmToggle::chime => "chime",
+            AlarmToggle::walk_test => "walk_test",
+            AlarmToggle::quiet_hours => "quiet_hours",
+            AlarmToggle::auto_rearm => "auto_rearm",
+            AlarmToggle::rearm_bypass => "rearm_bypass",
+        }
+    }
+}
+
+/// Two-person rule for disarming, a single code only silences the siren
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DualDisarm {
+    pub codes: Vec<String>,
+    /// Seconds the second code may follow the first, 60 by default
+    pub window: Option<u64>,
+}
+
+/// Ignores a zone at certain times or with certain arming profiles, e.g. a motion
+/// sensor which pets set off at night
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AutoBypass {
+    /// Daily windows in local time like `22:00-07:00`
+    #[serde(default)]
+    pub hours: Vec<String>,
+    /// Names of the arming profiles, the zone is ignored while armed with one of them
+    #[serde(default)]
+    pub profiles: Vec<String>,
+}
+
+impl AutoBypass {
+    pub fn is_active(&self, profile: Option<&str>, hour: Option<(u8, u8)>) -> bool {
+        profile.is_some_and(|profile| self.profiles.iter().any(|name| name == profile))
+            || hour.is_some_and(|(hour, minute)| {
+                self.hours.iter().any(|window| {
+                    DailyWindow::parse(window).is_some_and(|window| window.contains(hour, minute))
+                })
+            })
+    }
+}
+
+/// Daily time window in local time, e.g. the quiet hours, it may span midnight
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DailyWindow {
+    /// Minutes since midnight
+    start: u16,
+    end: u16,
+}
+
+impl DailyWindow {
+    /// Parses a range like `22:00-07:00`
+    pub fn parse(value: &str) -> Option<Self> {
+        let minutes = |time: &str| {
+            let (hour, minute) = time.trim().split_once(':')?;
+            let (hour, minute) = (hour.parse::<u16>().ok()?, minute.parse::<u16>().ok()?);
+            (hour < 24 && minute < 60).then_some(hour * 60 + minute)
+        };
+        let (start, end) = value.split_once('-')?;
+        Some(Self {
+            start: minutes(start)?,
+            end: minutes(end)?,
+        })
+    }
+
+    pub fn contains(&self, hour: u8, minute: u8) -> bool {
+        let time = u16::from(hour) * 60 + u16::from(minute);
+        if self.start <= self.end {
+            (self.start..self.end).contains(&time)
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Maintenance action of a button entity
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum ButtonAction {
+    /// Checks the zones and chirps the siren, the outcome is published as a command result
+    self_test,
+    /// Sounds the siren for a second, only while disarmed
+    siren_chirp,
+    /// Publishes every entity state again
+    resend,
+    /// Observes the zones for ten minutes and stores the polarity they suggest, only while disarmed
+    learn_zones,
+    /// Applies the polarity of the last zone learning, only while disarmed
+    confirm_learned_zones,
+}
+
+/// Event of the MQTT connection which is counted
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum MqttStat {
+    connects,
+    disconnects,
+    publish_errors,
+    subscribe_failures,
+    /// Alarm events which never reached the broker, dropped from a full queue, or whose state
+    /// was replaced in the state cache before a client published it
+    lost_events,
+}
+
+impl MqttStat {
+    pub const ALL: [MqttStat; 5] = [
+        MqttStat::connects,
+        MqttStat::disconnects,
+        MqttStat::publish_errors,
+        MqttStat::subscribe_failures,
+        MqttStat::lost_events,
+    ];
+
+    /// NVS key of the lifetime count, at most 15 bytes
+    pub fn key(&self) -> &'static str {
+        match self {
+            MqttStat::connects => "connects",
+            MqttStat::disconnects => "disconnects",
+            MqttStat::publish_errors => "publish_errors",
+            MqttStat::subscribe_failures => "subscribe_fails",
+            MqttStat::lost_events => "lost_events",
+        }
+    }
+}
+
+/// Brown-out diagnostic of the power supply
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum PowerStat {
+    /// Lifetime count of brown-out resets, shown by a sensor
+    brownouts,
+    /// Frequent brown-outs, which usually mean a failing power supply, shown by a binary_sensor
+    brownout_trouble,
+}
+
+/// Timing of the zone processing, for spotting regressions of the zone latency
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum TimingStat {
+    /// Duration of a pass of the alarm task over the zones and commands
+    scan_time,
+    /// Delay from the detection of a zone change to its publish
+    event_latency,
+    /// Events waiting for the scheduler
+    queue_depth,
+}
+
+impl TimingStat {
+    pub const ALL: [TimingStat; 3] = [
+        TimingStat::scan_time,
+        TimingStat::event_latency,
+        TimingStat::queue_depth,
+    ];
+
+    pub fn unit(&self) -> Option<&'static str> {
+        match self {
+            TimingStat::scan_time => Some("µs"),
+            TimingStat::event_latency => Some("ms"),
+            TimingStat::queue_depth => None,
+        }
+    }
+}
+
+/// Counter of the activations of a zone
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ZoneCounter {
+    /// Unique id of the zone
+    pub zone: String,
+    pub count: ZoneCount,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum ZoneCount {
+    /// Times the zone moved the alarm into pending or triggered, since midnight UTC, or
+    /// since boot while the clock is not synchronized
+    triggers_today,
+    /// Lifetime count
+    motion_events,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -50,6 +1014,11 @@ pub enum HAEntityVariant {
     binary_sensor,
     sensor,
     alarm_control_panel,
+    switch,
+    number,
+    select,
+    text,
+    button,
 }
 impl std::fmt::Display for HAEntityVariant {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -57,6 +1026,11 @@ impl std::fmt::Display for HAEntityVariant {
             HAEntityVariant::binary_sensor => write!(f, "binary_sensor"),
             HAEntityVariant::sensor => write!(f, "sensor"),
             HAEntityVariant::alarm_control_panel => write!(f, "alarm_control_panel"),
+            HAEntityVariant::switch => write!(f, "switch"),
+            HAEntityVariant::number => write!(f, "number"),
+            HAEntityVariant::select => write!(f, "select"),
+            HAEntityVariant::text => write!(f, "text"),
+            HAEntityVariant::button => write!(f, "button"),
         }
     }
 }
@@ -80,7 +1054,7 @@ pub struct HADeviceAvailabilityOut {
     pub value_template: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct HADevice {
     pub configuration_url: Option<String>,
     pub hw_version: Option<String>,
@@ -118,10 +1092,32 @@ pub struct HADeviceOut {
     pub via_device: Option<String>,
 }
 
+impl HAEntityOut {
+    /// HA asks for a code on disarming and sends it along with the action
+    pub fn with_disarm_code(self) -> Self {
+        HAEntityOut {
+            code: Some("REMOTE_CODE".to_string()),
+            command_template: Some(
+                r#"{"action":"{{ action }}","code":"{{ code }}"}"#.to_string(),
+            ),
+            code_disarm_required: Some(true),
+            ..self
+        }
+    }
+}
+
 impl From<HAEntity> for HAEntityOut {
     fn from(entity: HAEntity) -> Self {
         if entity.variant == HAEntityVariant::alarm_control_panel {
-            HAEntityOut {
+            let json_state = entity.json_state.unwrap_or(false);
+            let dual_disarm = entity.dual_disarm.is_some();
+            // Without a command topic HA only shows the state, e.g. of a DSC panel
+            let commands = entity.command_topic.is_some();
+            let entity_out = HAEntityOut {
+                code: None,
+                command_template: None,
+                value_template: json_state.then(|| "{{ value_json.state }}".to_string()),
+                json_attributes_topic: json_state.then(|| entity.state_topic.clone()),
                 name: entity.name,
                 unique_id: entity.unique_id,
                 state_topic: entity.state_topic,
@@ -131,30 +1127,92 @@ impl From<HAEntity> for HAEntityOut {
                 device: entity.device.map(|d| d.into()),
                 device_class: entity.device_class,
                 entity_category: entity.entity_category,
-                code_arm_required: Some(false),
-                code_disarm_required: Some(false),
-                code_trigger_required: Some(false),
-                supported_features: Some(vec![
-                    "arm_away".to_string(),
-                    "trigger".to_string(),
-                    "arm_custom_bypass".to_string(),
-                ]),
+                code_arm_required: commands.then_some(false),
+                code_disarm_required: commands.then_some(false),
+                code_trigger_required: commands.then_some(false),
+                supported_features: Some(match commands {
+                    true => vec![
+                        "arm_away".to_string(),
+                        "arm_home".to_string(),
+                        "arm_night".to_string(),
+                        "trigger".to_string(),
+                        "arm_custom_bypass".to_string(),
+                    ],
+                    false => Vec::new(),
+                }),
+                min: None,
+                max: None,
+                unit_of_measurement: None,
+                state_class: None,
+                options: None,
+                expire_after: None,
+                config_hash: None,
+            };
+            if dual_disarm && commands {
+                entity_out.with_disarm_code()
+            } else {
+                entity_out
             }
         } else {
+            let json_attributes_topic = entity.attributes_topic();
+            // Number entities take their bounds from the setting they change
+            let range = entity
+                .alarm_setting
+                .filter(|_| entity.variant == HAEntityVariant::number)
+                .map(|setting| setting.range());
             HAEntityOut {
                 name: entity.name,
                 unique_id: entity.unique_id,
                 state_topic: entity.state_topic,
-                command_topic: None,
+                command_topic: entity.command_topic,
                 icon: entity.icon,
                 availability: entity.availability.map(|a| a.into()),
                 device: entity.device.map(|d| d.into()),
-                device_class: entity.device_class,
-                entity_category: entity.entity_category,
+                device_class: entity.device_class.or_else(|| {
+                    (entity.power_stat == Some(PowerStat::brownout_trouble))
+                        .then(|| "problem".to_string())
+                }),
+                // Counters are diagnostics unless configured otherwise
+                entity_category: entity.entity_category.or_else(|| {
+                    (entity.mqtt_stat.is_some()
+                        || entity.power_stat.is_some()
+                        || entity.timing_stat.is_some())
+                    .then(|| "diagnostic".to_string())
+                }),
+                code: None,
+                command_template: None,
                 code_arm_required: None,
                 code_disarm_required: None,
                 code_trigger_required: None,
                 supported_features: None,
+                value_template: None,
+                json_attributes_topic,
+                min: range.as_ref().map(|range| *range.start()),
+                max: range.as_ref().map(|range| *range.end()),
+                unit_of_measurement: range.map(|_| "s".to_string()).or_else(|| {
+                    entity
+                        .timing_stat
+                        .and_then(|stat| stat.unit())
+                        .map(str::to_string)
+                }),
+                state_class: if entity.mqtt_stat.is_some()
+                    || entity.power_stat == Some(PowerStat::brownouts)
+                    || entity.zone_counter.is_some()
+                {
+                    Some("total_increasing".to_string())
+                } else {
+                    entity
+                        .timing_stat
+                        .map(|_| "measurement".to_string())
+                },
+                options: entity.arming_profiles.map(|profiles| {
+                    profiles
+                        .into_iter()
+                        .map(|profile| profile.name)
+                        .collect()
+                }),
+                expire_after: entity.link_check,
+                config_hash: None,
             }
         }
     }
@@ -187,3 +1245,268 @@ impl From<HADevice> for HADeviceOut {
         }
     }
 }
+
+/// NVS namespace of the settings overriding config.yml
+pub const SETTINGS_NAMESPACE: &str = "settings";
+/// Raised whenever a setting is added, removed or its format changes
+pub const SETTINGS_SCHEMA_VERSION: u32 = 2;
+pub const SETTINGS_MQTT_ENDPOINT: &str = "mqtt_endpoint";
+/// Network hostname, also used as the MQTT client id
+pub const SETTINGS_HOSTNAME: &str = "hostname";
+/// MAC address of the Ethernet interface, e.g. `02:00:00:fc:18:01`
+pub const SETTINGS_ETH_MAC: &str = "eth_mac";
+/// SPI clock of the ethernet controller in MHz, `1` to `40`
+pub const SETTINGS_ETH_SPI_MHZ: &str = "eth_spi_mhz";
+/// Largest SPI transfer of the ethernet controller in bytes, a multiple of 4 up to `4096`
+pub const SETTINGS_ETH_DMA_SIZE: &str = "eth_dma_size";
+/// Core the MQTT task is pinned to, `0`, `1` or `any`
+pub const SETTINGS_MQTT_CORE: &str = "mqtt_core";
+/// Daily window of the quiet hours in local time, e.g. `22:00-07:00`
+pub const SETTINGS_QUIET_HOURS: &str = "quiet_hours";
+/// Code HA has to send along with DISARM, disarming needs no code when unset
+///
+/// A dual disarm of the alarm entity takes its own codes instead.
+pub const SETTINGS_DISARM_CODE: &str = "disarm_code";
+/// Language of the names and announcements, one of the languages in config.yml, e.g. `de`
+pub const SETTINGS_LANGUAGE: &str = "language";
+/// POSIX TZ rule of the local time of the quiet hours, the bell test and the auto-bypass
+/// windows, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`, they are in UTC when unset
+pub const SETTINGS_TIMEZONE: &str = "timezone";
+
+/// Settings which redirect the panel to another broker or network identity, or which
+/// guard disarming, they can be locked while the alarm is armed
+pub const SETTINGS_PROTECTED: &[&str] = &[
+    SETTINGS_MQTT_ENDPOINT,
+    SETTINGS_HOSTNAME,
+    SETTINGS_ETH_MAC,
+    SETTINGS_DISARM_CODE,
+];
+
+/// Longest value the panel reads, the buffer holds the terminating zero too
+pub const SETTINGS_MAX_VALUE_LEN: usize = 255;
+
+/// Settings which can be overridden at runtime, without rebuilding the firmware
+pub const SETTINGS_KEYS: &[&str] = &[
+    SETTINGS_MQTT_ENDPOINT,
+    SETTINGS_HOSTNAME,
+    SETTINGS_ETH_MAC,
+    SETTINGS_ETH_SPI_MHZ,
+    SETTINGS_ETH_DMA_SIZE,
+    SETTINGS_MQTT_CORE,
+    SETTINGS_QUIET_HOURS,
+    SETTINGS_DISARM_CODE,
+    SETTINGS_LANGUAGE,
+    SETTINGS_TIMEZONE,
+];
+
+pub fn validate_setting_key(key: &str) -> Result<(), String> {
+    if !SETTINGS_KEYS.contains(&key) {
+        return Err(format!(
+            "Unknown setting {}, known settings: {}",
+            key,
+            SETTINGS_KEYS.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Checks a setting before it is stored, both on the panel and when generating a settings partition
+pub fn validate_setting(key: &str, value: &str) -> Result<(), String> {
+    validate_setting_key(key)?;
+    if value.len() > SETTINGS_MAX_VALUE_LEN {
+        return Err(format!(
+            "{} is longer than {} bytes",
+            key, SETTINGS_MAX_VALUE_LEN
+        ));
+    }
+    // NVS stores C strings, a NUL would cut the value short
+    if value.chars().any(char::is_control) {
+        return Err(format!("{} cannot contain control characters", key));
+    }
+    match key {
+        SETTINGS_MQTT_ENDPOINT if !value.starts_with("mqtt://") => {
+            Err(format!("{} must start with \"mqtt://\"", key))
+        }
+        SETTINGS_HOSTNAME
+            if value.is_empty()
+                || value.len() > 32
+                || !value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-') =>
+        {
+            Err(format!(
+                "{} must be 1-32 letters, digits or hyphens",
+                key
+            ))
+        }
+        SETTINGS_ETH_MAC if parse_mac(value).is_none() => {
+            Err(format!("{} must be six hex octets separated by colons", key))
+        }
+        SETTINGS_ETH_SPI_MHZ
+            if !value
+                .parse::<u32>()
+                .is_ok_and(|mhz| (1..=40).contains(&mhz)) =>
+        {
+            Err(format!("{} must be between 1 and 40", key))
+        }
+        SETTINGS_ETH_DMA_SIZE
+            if !value
+                .parse::<usize>()
+                .is_ok_and(|size| (4..=4096).contains(&size) && size % 4 == 0) =>
+        {
+            Err(format!("{} must be a multiple of 4 up to 4096", key))
+        }
+        SETTINGS_MQTT_CORE if !["0", "1", "any"].contains(&value) => {
+            Err(format!("{} must be 0, 1 or any", key))
+        }
+        SETTINGS_QUIET_HOURS if DailyWindow::parse(value).is_none() => {
+            Err(format!("{} must be a time range like 22:00-07:00", key))
+        }
+        SETTINGS_DISARM_CODE
+            if !(4..=payload::MAX_CODE_LEN).contains(&value.len())
+                || !value.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            Err(format!(
+                "{} must be 4-{} letters or digits",
+                key,
+                payload::MAX_CODE_LEN
+            ))
+        }
+        SETTINGS_LANGUAGE
+            if !(2..=8).contains(&value.len())
+                || !value.chars().all(|c| c.is_ascii_lowercase() || c == '-') =>
+        {
+            Err(format!(
+                "{} must be 2-8 lowercase letters or hyphens",
+                key
+            ))
+        }
+        SETTINGS_TIMEZONE if !is_tz_rule(value) => Err(format!(
+            "{} must be a POSIX TZ rule like CET-1CEST,M3.5.0,M10.5.0/3",
+            key
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the value starts like a POSIX TZ rule, a name of at least three letters or one in
+/// angle brackets followed by the offset, the C library checks the rest
+fn is_tz_rule(value: &str) -> bool {
+    let offset = match value.strip_prefix('<') {
+        Some(quoted) => quoted.split_once('>').map(|(_, offset)| offset),
+        None => {
+            let name_len = value.chars().take_while(char::is_ascii_alphabetic).count();
+            (name_len >= 3).then(|| &value[name_len..])
+        }
+    };
+    offset.is_some_and(|offset| {
+        offset
+            .trim_start_matches(['+', '-'])
+            .starts_with(|c: char| c.is_ascii_digit())
+    })
+}
+
+pub fn parse_mac(value: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut octets = value.split(':');
+    for octet in mac.iter_mut() {
+        let hex = octets.next()?;
+        if hex.len() != 2 {
+            return None;
+        }
+        *octet = u8::from_str_radix(hex, 16).ok()?;
+    }
+    octets.next().is_none().then_some(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presence(auto_disarm: bool) -> PresenceConfig {
+        PresenceConfig {
+            topics: vec!["presence/phone".to_string()],
+            home_payload: default_home_payload(),
+            auto_arm: true,
+            auto_disarm,
+            grace_period: 60,
+            reason_topic: "alarm/presence_reason".to_string(),
+        }
+    }
+
+    #[test]
+    fn auto_disarm_requires_no_code() {
+        assert!(presence(true).check_auto_disarm(false, false).is_ok());
+        assert!(presence(true).check_auto_disarm(true, false).is_err());
+        assert!(presence(true).check_auto_disarm(false, true).is_err());
+        assert!(presence(true).check_auto_disarm(true, true).is_err());
+        assert!(presence(false).check_auto_disarm(true, true).is_ok());
+    }
+
+    #[test]
+    fn setting_keys() {
+        assert!(validate_setting_key(SETTINGS_HOSTNAME).is_ok());
+        assert!(validate_setting_key(SETTINGS_DISARM_CODE).is_ok());
+        assert!(validate_setting_key("").is_err());
+        assert!(validate_setting_key("hostnam").is_err());
+        assert!(validate_setting_key("Hostname").is_err());
+        assert!(validate_setting("unknown", "value").is_err());
+    }
+
+    #[test]
+    fn setting_value_length() {
+        let longest = format!("mqtt://{}", "a".repeat(SETTINGS_MAX_VALUE_LEN - 7));
+        assert!(validate_setting(SETTINGS_MQTT_ENDPOINT, &longest).is_ok());
+        let too_long = format!("{}a", longest);
+        assert!(validate_setting(SETTINGS_MQTT_ENDPOINT, &too_long).is_err());
+    }
+
+    #[test]
+    fn setting_values_without_control_characters() {
+        assert!(validate_setting(SETTINGS_MQTT_ENDPOINT, "mqtt://broker\0:1883").is_err());
+        assert!(validate_setting(SETTINGS_MQTT_ENDPOINT, "mqtt://broker\n").is_err());
+        assert!(validate_setting(SETTINGS_MQTT_ENDPOINT, "mqtt://broker\u{7f}").is_err());
+        assert!(validate_setting(SETTINGS_MQTT_ENDPOINT, "mqtt://broker:1883").is_ok());
+    }
+
+    #[test]
+    fn setting_formats() {
+        assert!(validate_setting(SETTINGS_MQTT_ENDPOINT, "tcp://broker").is_err());
+        assert!(validate_setting(SETTINGS_HOSTNAME, "alarm-1").is_ok());
+        assert!(validate_setting(SETTINGS_HOSTNAME, "").is_err());
+        assert!(validate_setting(SETTINGS_HOSTNAME, "alarm_1").is_err());
+        assert!(validate_setting(SETTINGS_HOSTNAME, &"a".repeat(33)).is_err());
+        assert!(validate_setting(SETTINGS_ETH_MAC, "02:00:00:fc:18:01").is_ok());
+        assert!(validate_setting(SETTINGS_ETH_MAC, "02:00:00:fc:18").is_err());
+        assert!(validate_setting(SETTINGS_ETH_SPI_MHZ, "40").is_ok());
+        assert!(validate_setting(SETTINGS_ETH_SPI_MHZ, "0").is_err());
+        assert!(validate_setting(SETTINGS_ETH_SPI_MHZ, "41").is_err());
+        assert!(validate_setting(SETTINGS_ETH_DMA_SIZE, "4096").is_ok());
+        assert!(validate_setting(SETTINGS_ETH_DMA_SIZE, "4098").is_err());
+        assert!(validate_setting(SETTINGS_ETH_DMA_SIZE, "6").is_err());
+        assert!(validate_setting(SETTINGS_MQTT_CORE, "any").is_ok());
+        assert!(validate_setting(SETTINGS_MQTT_CORE, "2").is_err());
+        assert!(validate_setting(SETTINGS_QUIET_HOURS, "22:00-07:00").is_ok());
+        assert!(validate_setting(SETTINGS_QUIET_HOURS, "22:00").is_err());
+        assert!(validate_setting(SETTINGS_LANGUAGE, "pt-br").is_ok());
+        assert!(validate_setting(SETTINGS_LANGUAGE, "EN").is_err());
+        assert!(validate_setting(SETTINGS_TIMEZONE, "CET-1CEST,M3.5.0,M10.5.0/3").is_ok());
+        assert!(validate_setting(SETTINGS_TIMEZONE, "<+0330>-3:30").is_ok());
+        assert!(validate_setting(SETTINGS_TIMEZONE, "UTC0").is_ok());
+        assert!(validate_setting(SETTINGS_TIMEZONE, "Europe/Budapest").is_err());
+        assert!(validate_setting(SETTINGS_TIMEZONE, "CET").is_err());
+    }
+
+    #[test]
+    fn disarm_code_format() {
+        assert!(validate_setting(SETTINGS_DISARM_CODE, "1234").is_ok());
+        assert!(validate_setting(SETTINGS_DISARM_CODE, "Ab12Cd").is_ok());
+        assert!(validate_setting(SETTINGS_DISARM_CODE, "123").is_err());
+        assert!(validate_setting(SETTINGS_DISARM_CODE, "12 34").is_err());
+        assert!(validate_setting(SETTINGS_DISARM_CODE, "12-34").is_err());
+        assert!(validate_setting(SETTINGS_DISARM_CODE, "1234\0").is_err());
+        let longest = "1".repeat(payload::MAX_CODE_LEN);
+        assert!(validate_setting(SETTINGS_DISARM_CODE, &longest).is_ok());
+        assert!(validate_setting(SETTINGS_DISARM_CODE, &format!("{}1", longest)).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use std::fmt;
+
+/// Size limit of a payload by what its topic carries
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadClass {
+    /// Commands of the alarm panel, switches, numbers and the net command topic
+    Command,
+    /// Values of text entities and messages of other services, e.g. presence
+    Text,
+}
+
+impl PayloadClass {
+    pub const fn max_size(&self) -> usize {
+        match self {
+            PayloadClass::Command => 256,
+            PayloadClass::Text => 1024,
+        }
+    }
+}
+
+/// Largest payload of a message other than an OTA image, larger ones are rejected
+/// before they are copied out of the buffer of the MQTT client
+pub const MAX_PAYLOAD_SIZE: usize = PayloadClass::Text.max_size();
+/// Longest code accepted with an alarm panel command
+pub const MAX_CODE_LEN: usize = 32;
+
+/// Why a received payload was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum PayloadError {
+    TooLarge { size: usize, max_size: usize },
+    InvalidUtf8,
+    Empty,
+    /// Not one of the commands of the topic
+    UnknownCommand,
+    InvalidArgument(&'static str),
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::TooLarge { size, max_size } => write!(
+                f,
+                "payload of {} bytes is larger than {} bytes",
+                size, max_size
+            ),
+            PayloadError::InvalidUtf8 => write!(f, "payload is not valid UTF-8"),
+            PayloadError::Empty => write!(f, "payload is empty"),
+            PayloadError::UnknownCommand => write!(f, "unknown command"),
+            PayloadError::InvalidArgument(reason) => write!(f, "invalid argument: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+pub fn check_size(payload: &[u8], class: PayloadClass) -> Result<(), PayloadError> {
+    if payload.len() > class.max_size() {
+        return Err(PayloadError::TooLarge {
+            size: payload.len(),
+            max_size: class.max_size(),
+        });
+    }
+    Ok(())
+}
+
+/// The payload of a message as text, checked before any parsing
+pub fn decode(data: &[u8]) -> Result<&str, PayloadError> {
+    check_size(data, PayloadClass::Text)?;
+    std::str::from_utf8(data).map_err(|_| PayloadError::InvalidUtf8)
+}
+
+/// Command of the net command topic
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetCommand {
+    ReconnectMqtt,
+    RestartEth,
+    Reboot,
+    ConfirmReboot,
+    CancelReboot,
+    RebootAt(u64),
+    CpuLoad,
+    FirmwareHash,
+    Throughput,
+    /// Names the zones and groups them into areas by the provisioning HA published
+    BootstrapZones,
+    /// Goes back to the names and devices of the zones in config.yml
+    ClearZones,
+}
+
+pub fn parse_net_command(payload: &str) -> Result<NetCommand, PayloadError> {
+    check_size(payload.as_bytes(), PayloadClass::Command)?;
+    let mut args = payload.split_whitespace();
+    let command = match (args.next().ok_or(PayloadError::Empty)?, args.next()) {
+        ("reconnect-mqtt", None) => NetCommand::ReconnectMqtt,
+        ("restart-eth", None) => NetCommand::RestartEth,
+        ("reboot", None) => NetCommand::Reboot,
+        ("reboot", Some("confirm")) => NetCommand::ConfirmReboot,
+        ("reboot", Some("cancel")) => NetCommand::CancelReboot,
+        ("reboot-at", Some(time)) => NetCommand::RebootAt(
+            time.parse()
+                .map_err(|_| PayloadError::InvalidArgument("expected a unix time"))?,
+        ),
+        ("cpu-load", None) => NetCommand::CpuLoad,
+        ("firmware-hash", None) => NetCommand::FirmwareHash,
+        ("throughput", None) => NetCommand::Throughput,
+        ("bootstrap-zones", None) => NetCommand::BootstrapZones,
+        ("bootstrap-zones", Some("clear")) => NetCommand::ClearZones,
+        _ => return Err(PayloadError::UnknownCommand),
+    };
+    match args.next() {
+        Some(_) => Err(PayloadError::InvalidArgument("too many arguments")),
+        None => Ok(command),
+    }
+}
+
+fn check_code(code: &str) -> Result<(), PayloadError> {
+    if code.len() > MAX_CODE_LEN {
+        return Err(PayloadError::InvalidArgument("code too long"));
+    }
+    Ok(())
+}
+
+/// Action and code of an alarm panel command, either plain like `ARM_AWAY` or `DISARM 1234`,
+/// or the `{"action": ..., "code": ...}` JSON HA sends with a code
+pub fn parse_alarm_payload(payload: &str) -> Result<(String, Option<String>), PayloadError> {
+    check_size(payload.as_bytes(), PayloadClass::Command)?;
+    let payload = payload.trim();
+    if payload.is_empty() {
+        return Err(PayloadError::Empty);
+    }
+    if !payload.starts_with('{') {
+        let mut args = payload.split_whitespace();
+        let action = args.next().ok_or(PayloadError::Empty)?;
+        let code = args.next();
+        if args.next().is_some() {
+            return Err(PayloadError::InvalidArgument("too many arguments"));
+        }
+        code.map(check_code).transpose()?;
+        return Ok((action.to_string(), code.map(str::to_string)));
+    }
+    let json: serde_json::Value = serde_json::from_str(payload)
+        .map_err(|_| PayloadError::InvalidArgument("malformed JSON"))?;
+    let action = json["action"]
+        .as_str()
+        .filter(|action| !action.is_empty())
+        .ok_or(PayloadError::InvalidArgument("missing action"))?;
+    let code = match &json["code"] {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(code) if code.is_empty() => None,
+        serde_json::Value::String(code) => {
+            check_code(code)?;
+            Some(code.clone())
+        }
+        _ => return Err(PayloadError::InvalidArgument("code is not a string")),
+    };
+    Ok((action.to_string(), code))
+}
+
+pub fn parse_switch(payload: &str) -> Result<bool, PayloadError> {
+    check_size(payload.as_bytes(), PayloadClass::Command)?;
+    match payload.trim() {
+        "ON" => Ok(true),
+        "OFF" => Ok(false),
+        "" => Err(PayloadError::Empty),
+        _ => Err(PayloadError::UnknownCommand),
+    }
+}
+
+/// Value of a number entity, HA sends whole numbers as floats, e.g. `90.0`
+pub fn parse_number(payload: &str) -> Result<u32, PayloadError> {
+    check_size(payload.as_bytes(), PayloadClass::Command)?;
+    let payload = payload.trim();
+    if payload.is_empty() {
+        return Err(PayloadError::Empty);
+    }
+    payload
+        .parse::<f64>()
+        .ok()
+        .filter(|value| value.fract() == 0.0 && *value >= 0.0 && *value <= u32::MAX as f64)
+        .map(|value| value as u32)
+        .ok_or(PayloadError::InvalidArgument("expected a whole number"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(action: &str, code: Option<&str>) -> Result<(String, Option<String>), PayloadError> {
+        Ok((action.to_string(), code.map(str::to_string)))
+    }
+
+    #[test]
+    fn plain_alarm_payloads() {
+        assert_eq!(parse_alarm_payload("ARM_AWAY"), parsed("ARM_AWAY", None));
+        assert_eq!(
+            parse_alarm_payload(" DISARM 1234\n"),
+            parsed("DISARM", Some("1234"))
+        );
+        assert_eq!(
+            parse_alarm_payload("DISARM 1234 5678"),
+            Err(PayloadError::InvalidArgument("too many arguments"))
+        );
+    }
+
+    #[test]
+    fn json_alarm_payloads() {
+        assert_eq!(
+            parse_alarm_payload(r#"{"action": "DISARM", "code": "1234"}"#),
+            parsed("DISARM", Some("1234"))
+        );
+        assert_eq!(
+            parse_alarm_payload(r#"{"action": "ARM_HOME", "code": ""}"#),
+            parsed("ARM_HOME", None)
+        );
+        assert_eq!(
+            parse_alarm_payload(r#"{"action": "ARM_HOME", "code": null}"#),
+            parsed("ARM_HOME", None)
+        );
+        assert_eq!(
+            parse_alarm_payload(r#"{"action": "ARM_HOME"}"#),
+            parsed("ARM_HOME", None)
+        );
+        assert_eq!(
+            parse_alarm_payload(r#"{"code": "1234"}"#),
+            Err(PayloadError::InvalidArgument("missing action"))
+        );
+        assert_eq!(
+            parse_alarm_payload(r#"{"action": "", "code": "1234"}"#),
+            Err(PayloadError::InvalidArgument("missing action"))
+        );
+        assert_eq!(
+            parse_alarm_payload(r#"{"action": "DISARM", "code": 1234}"#),
+            Err(PayloadError::InvalidArgument("code is not a string"))
+        );
+        assert_eq!(
+            parse_alarm_payload(r#"{"action": "DISARM""#),
+            Err(PayloadError::InvalidArgument("malformed JSON"))
+        );
+    }
+
+    #[test]
+    fn alarm_payload_limits() {
+        assert_eq!(parse_alarm_payload(""), Err(PayloadError::Empty));
+        assert_eq!(parse_alarm_payload("  \n"), Err(PayloadError::Empty));
+        let code = "1".repeat(MAX_CODE_LEN);
+        assert_eq!(
+            parse_alarm_payload(&format!("DISARM {}", code)),
+            parsed("DISARM", Some(&code))
+        );
+        assert_eq!(
+            parse_alarm_payload(&format!("DISARM {}1", code)),
+            Err(PayloadError::InvalidArgument("code too long"))
+        );
+        assert_eq!(
+            parse_alarm_payload(&format!(r#"{{"action": "DISARM", "code": "{}1"}}"#, code)),
+            Err(PayloadError::InvalidArgument("code too long"))
+        );
+        let large = "A".repeat(PayloadClass::Command.max_size() + 1);
+        assert_eq!(
+            parse_alarm_payload(&large),
+            Err(PayloadError::TooLarge {
+                size: large.len(),
+                max_size: PayloadClass::Command.max_size(),
+            })
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{ExpanderPoint, HADevice, HAEntity, HAEntityVariant, ZoneKind, ZoneType};
+
+/// Provisioning published by HA, e.g. by an automation rendering the areas and the entity
+/// names the zones should have
+#[derive(Deserialize)]
+pub struct Provisioning {
+    /// State topics of the zones are `<state_topic_prefix>/<unique_id>`
+    pub state_topic_prefix: String,
+    /// Device of the panel, every area gets a device of its own linked to it
+    pub device: HADevice,
+    pub areas: Vec<Area>,
+}
+
+#[derive(Deserialize)]
+pub struct Area {
+    pub name: String,
+    pub zones: Vec<ProvisionedZone>,
+}
+
+#[derive(Deserialize)]
+pub struct ProvisionedZone {
+    pub name: String,
+    /// Derived from the name when omitted
+    pub unique_id: Option<String>,
+    pub gpio_pin: Option<u8>,
+    pub modbus_input: Option<ExpanderPoint>,
+    pub can_input: Option<ExpanderPoint>,
+    pub zone_type: Option<ZoneType>,
+    pub zone_kind: Option<ZoneKind>,
+}
+
+impl ProvisionedZone {
+    /// Whether the zone is wired to the input of the zone entity
+    pub fn is_wired_to(&self, entity: &HAEntity) -> bool {
+        if entity.variant != HAEntityVariant::binary_sensor {
+            return false;
+        }
+        (self.gpio_pin.is_some() && self.gpio_pin == entity.gpio_pin)
+            || (self.modbus_input.is_some() && self.modbus_input == entity.modbus_input)
+            || (self.can_input.is_some() && self.can_input == entity.can_input)
+    }
+}
+
+/// Name and device a zone of the firmware got from the provisioning of HA, they replace
+/// the ones of config.yml in discovery
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZoneOverride {
+    pub name: String,
+    /// The device of the area, unless the zone has no device to link it to
+    pub device: Option<HADevice>,
+}
+
+/// Device of the zones of an area, linked to the device of the panel
+pub fn area_device(panel: &HADevice, panel_id: &str, area: &str) -> HADevice {
+    HADevice {
+        identifiers: Some(vec![format!("{}_{}", panel_id, slug(area))]),
+        name: Some(match panel.name.as_deref() {
+            Some(name) => format!("{} {}", name, area),
+            None => area.to_string(),
+        }),
+        suggested_area: Some(area.to_string()),
+        via_device: Some(panel_id.to_string()),
+        ..panel.clone()
+    }
+}
+
+/// Overrides of the zones of the firmware wired to the inputs of the area by their unique id,
+/// and the names of the zones of the area which have no such zone
+///
+/// The device of a zone is the panel its area device is linked to.
+pub fn bootstrap_area(
+    area: &Area,
+    entities: &[HAEntity],
+) -> (Vec<(String, ZoneOverride)>, Vec<String>) {
+    let mut overrides = Vec::new();
+    let mut unwired = Vec::new();
+    for zone in area.zones.iter() {
+        let Some(entity) = entities.iter().find(|entity| zone.is_wired_to(entity)) else {
+            unwired.push(zone.name.clone());
+            continue;
+        };
+        let device = entity.device.as_ref().and_then(|panel| {
+            let panel_id = panel.identifiers.as_ref()?.first()?;
+            Some(area_device(panel, panel_id, &area.name))
+        });
+        overrides.push((
+            entity.unique_id.clone(),
+            ZoneOverride {
+                name: zone.name.clone(),
+                device,
+            },
+        ));
+    }
+    (overrides, unwired)
+}
+
+/// Lowercase ASCII letters and digits joined by underscores, like the entity ids of HA
+pub fn slug(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(unique_id: &str, gpio_pin: u8) -> HAEntity {
+        serde_json::from_value(serde_json::json!({
+            "name": unique_id,
+            "variant": "binary_sensor",
+            "unique_id": unique_id,
+            "state_topic": format!("alarm/{}", unique_id),
+            "gpio_pin": gpio_pin,
+            "device": { "identifiers": ["alarm"], "name": "Alarm" },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn zones_are_matched_by_their_input() {
+        let area: Area = serde_json::from_value(serde_json::json!({
+            "name": "Living Room",
+            "zones": [
+                { "name": "Living room window", "gpio_pin": 5 },
+                { "name": "Terrace door", "gpio_pin": 9 },
+            ],
+        }))
+        .unwrap();
+        let entities = [zone("zone_4", 4), zone("zone_5", 5)];
+
+        let (overrides, unwired) = bootstrap_area(&area, &entities);
+        assert_eq!(unwired, ["Terrace door"]);
+        let [(unique_id, zone_override)] = overrides.as_slice() else {
+            panic!("expected one override, got {:?}", overrides);
+        };
+        assert_eq!(unique_id, "zone_5");
+        assert_eq!(zone_override.name, "Living room window");
+        let device = zone_override.device.as_ref().unwrap();
+        assert_eq!(
+            device.identifiers.as_deref(),
+            Some(["alarm_living_room".to_string()].as_slice())
+        );
+        assert_eq!(device.name.as_deref(), Some("Alarm Living Room"));
+        assert_eq!(device.suggested_area.as_deref(), Some("Living Room"));
+        assert_eq!(device.via_device.as_deref(), Some("alarm"));
+    }
+}
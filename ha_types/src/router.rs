@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+/// Dispatches inbound messages to routes by their topic
+///
+/// Routes are added with MQTT topic filters: a plain topic matches itself, `+` matches a
+/// single level and a trailing `#` any number of levels, e.g. everything under a prefix.
+/// Plain topics are looked up first, then the filters with wildcards in the order they
+/// were added.
+pub struct Router<R> {
+    exact: BTreeMap<String, R>,
+    wildcards: Vec<(String, R)>,
+}
+
+impl<R: Copy> Router<R> {
+    pub fn new() -> Self {
+        Self {
+            exact: BTreeMap::new(),
+            wildcards: Vec::new(),
+        }
+    }
+
+    /// The route added first is kept for a topic added twice
+    pub fn add(&mut self, filter: &str, route: R) {
+        if filter.contains(['+', '#']) {
+            self.wildcards.push((filter.to_string(), route));
+        } else if self.exact.contains_key(filter) {
+            log::warn!("{} is routed twice, keeping its first route", filter);
+        } else {
+            self.exact.insert(filter.to_string(), route);
+        }
+    }
+
+    pub fn route(&self, topic: &str) -> Option<R> {
+        self.exact.get(topic).copied().or_else(|| {
+            self.wildcards
+                .iter()
+                .find(|(filter, _)| filter_matches(filter, topic))
+                .map(|(_, route)| *route)
+        })
+    }
+}
+
+impl<R: Copy> Default for Router<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn filter_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            (_, None) => return false,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router<u8> {
+        let mut router = Router::new();
+        router.add("alarm/command", 1);
+        router.add("alarm/+/set", 2);
+        router.add("alarm/zone/#", 3);
+        router.add("alarm/#", 4);
+        router
+    }
+
+    #[test]
+    fn exact_topics() {
+        let router = router();
+        assert_eq!(router.route("alarm/command"), Some(1));
+        assert_eq!(router.route("alarm"), Some(4));
+        assert_eq!(router.route("other/command"), None);
+    }
+
+    #[test]
+    fn exact_topics_win_over_wildcards() {
+        let mut router = router();
+        router.add("alarm/siren/set", 5);
+        assert_eq!(router.route("alarm/siren/set"), Some(5));
+        assert_eq!(router.route("alarm/chime/set"), Some(2));
+    }
+
+    #[test]
+    fn first_route_of_a_topic_is_kept() {
+        let mut router = router();
+        router.add("alarm/command", 6);
+        assert_eq!(router.route("alarm/command"), Some(1));
+    }
+
+    #[test]
+    fn plus_matches_a_single_level() {
+        let mut router = Router::new();
+        router.add("alarm/+/set", 2);
+        assert_eq!(router.route("alarm/chime/set"), Some(2));
+        assert_eq!(router.route("alarm//set"), Some(2));
+        assert_eq!(router.route("alarm/set"), None);
+        assert_eq!(router.route("alarm/a/b/set"), None);
+        assert_eq!(router.route("alarm/chime/set/more"), None);
+    }
+
+    #[test]
+    fn hash_matches_any_number_of_levels() {
+        let mut router = Router::new();
+        router.add("alarm/zone/#", 3);
+        assert_eq!(router.route("alarm/zone"), Some(3));
+        assert_eq!(router.route("alarm/zone/door"), Some(3));
+        assert_eq!(router.route("alarm/zone/door/state"), Some(3));
+        assert_eq!(router.route("alarm/zones"), None);
+    }
+
+    #[test]
+    fn wildcards_are_tried_in_the_order_they_were_added() {
+        let router = router();
+        assert_eq!(router.route("alarm/zone/set"), Some(2));
+        assert_eq!(router.route("alarm/zone/door"), Some(3));
+        assert_eq!(router.route("alarm/status"), Some(4));
+    }
+}
@@ -0,0 +1,423 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::{AlarmStateName, ArmMode, ZoneAction};
+
+/// Monotonic time of the alarm state machine, so timers can be driven artificially
+///
+/// Steps of the wall clock don't affect it, so the delays run for their full length.
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock which only moves when it is advanced, for fast deterministic runs of the
+/// arming, pending and siren timers
+pub struct ManualClock {
+    start: Instant,
+    offset: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            offset: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap_or_else(PoisonError::into_inner) += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<C: Clock + Sync + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        self.as_ref().now()
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum AlarmState {
+    Disarmed,
+    /// Exit delay before arming in the mode
+    Arming((Instant, ArmMode)),
+    Armed((Instant, ArmMode)),
+    Pending(Instant),
+    Triggered,
+}
+
+impl AlarmState {
+    pub fn name(&self) -> AlarmStateName {
+        match self {
+            AlarmState::Disarmed => AlarmStateName::disarmed,
+            AlarmState::Arming(_) => AlarmStateName::arming,
+            AlarmState::Armed((_, mode)) => mode.state_name(),
+            AlarmState::Pending(_) => AlarmStateName::pending,
+            AlarmState::Triggered => AlarmStateName::triggered,
+        }
+    }
+
+    /// Compact representation of the state which is persisted in NVS
+    pub fn nvs_code(&self) -> u8 {
+        match self {
+            AlarmState::Disarmed => 0,
+            AlarmState::Arming(_) => 1,
+            AlarmState::Armed((_, ArmMode::away)) => 2,
+            AlarmState::Armed((_, ArmMode::home)) => 5,
+            AlarmState::Armed((_, ArmMode::night)) => 6,
+            AlarmState::Pending(_) => 3,
+            AlarmState::Triggered => 4,
+        }
+    }
+
+    pub fn nvs_code_name(code: u8) -> Option<&'static str> {
+        match code {
+            0 => Some("disarmed"),
+            1 => Some("arming"),
+            2 => Some("armed_away"),
+            3 => Some("pending"),
+            4 => Some("triggered"),
+            5 => Some("armed_home"),
+            6 => Some("armed_night"),
+            _ => None,
+        }
+    }
+}
+
+/// Timer of the alarm state, by when it started
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timer {
+    /// Exit delay while arming
+    Exit(Instant),
+    /// Entry delay while pending
+    Entry(Instant),
+    /// Siren while triggered
+    Siren(Instant),
+}
+
+/// Lengths of the timers, from the settings and the zone which started the entry delay
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimerLengths {
+    pub exit_delay: Duration,
+    pub entry_delay: Duration,
+    /// Zero sounds the siren until the alarm is disarmed
+    pub siren_timeout: Duration,
+}
+
+/// What happens when a timer runs out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimerTransition {
+    /// Arming becomes armed
+    Armed,
+    /// Pending becomes triggered
+    Triggered,
+    /// The siren stops, or the alarm re-arms if it is set to
+    SirenTimedOut,
+}
+
+/// The transition of the alarm state once its timer ran out by the clock
+pub fn timer_transition(
+    timer: Timer,
+    lengths: &TimerLengths,
+    clock: &dyn Clock,
+) -> Option<TimerTransition> {
+    let (start, length, transition) = match timer {
+        Timer::Exit(start) => (start, lengths.exit_delay, TimerTransition::Armed),
+        Timer::Entry(start) => (start, lengths.entry_delay, TimerTransition::Triggered),
+        Timer::Siren(_) if lengths.siren_timeout.is_zero() => return None,
+        Timer::Siren(start) => (start, lengths.siren_timeout, TimerTransition::SirenTimedOut),
+    };
+    (clock.now().duration_since(start) >= length).then_some(transition)
+}
+
+/// Why the alarm moved to its next state, by the zones or the timers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateChange<'a> {
+    /// The zone triggered the alarm
+    ZoneTriggered(&'a str),
+    /// The zone opened during the exit delay and disarmed the alarm
+    ArmingAborted(&'a str),
+    /// The delayed zone closed during the exit delay and started it again
+    ExitDelayRestarted(&'a str),
+    /// The exit delay ran out
+    ExitDelayElapsed,
+    /// The zone started the entry delay
+    EntryDelayStarted(&'a str),
+    /// The entry delay ran out
+    EntryDelayElapsed,
+}
+
+/// The next state of the alarm by the most severe action of the opened zones, the delayed zone
+/// closed during the exit delay if it restarts the delay, and the timers by the clock
+///
+/// Fire, panic and tamper zones and the commands are handled by the alarm task.
+pub fn next_state<'a>(
+    state: &AlarmState,
+    zone_action: Option<(ZoneAction, &'a str)>,
+    restarted_by: Option<&'a str>,
+    lengths: &TimerLengths,
+    clock: &dyn Clock,
+) -> Option<(AlarmState, StateChange<'a>)> {
+    let now = clock.now();
+    match (state, zone_action, restarted_by) {
+        (_, Some((ZoneAction::trigger, zone)), _) => {
+            Some((AlarmState::Triggered, StateChange::ZoneTriggered(zone)))
+        }
+        (AlarmState::Arming(_), Some((ZoneAction::abort_arming, zone)), _) => {
+            Some((AlarmState::Disarmed, StateChange::ArmingAborted(zone)))
+        }
+        (AlarmState::Arming((_, mode)), _, Some(zone)) => Some((
+            AlarmState::Arming((now, *mode)),
+            StateChange::ExitDelayRestarted(zone),
+        )),
+        (AlarmState::Arming((start, mode)), _, None) => {
+            timer_transition(Timer::Exit(*start), lengths, clock)?;
+            Some((
+                AlarmState::Armed((now, *mode)),
+                StateChange::ExitDelayElapsed,
+            ))
+        }
+        (AlarmState::Armed(_), Some((ZoneAction::pending, zone)), _) => Some((
+            AlarmState::Pending(now),
+            StateChange::EntryDelayStarted(zone),
+        )),
+        (AlarmState::Pending(start), _, _) => {
+            timer_transition(Timer::Entry(*start), lengths, clock)?;
+            Some((AlarmState::Triggered, StateChange::EntryDelayElapsed))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LENGTHS: TimerLengths = TimerLengths {
+        exit_delay: Duration::from_secs(30),
+        entry_delay: Duration::from_secs(20),
+        siren_timeout: Duration::from_secs(180),
+    };
+
+    #[test]
+    fn exit_delay_arms_once_it_ran_out() {
+        let clock = ManualClock::new();
+        let timer = Timer::Exit(clock.now());
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(timer_transition(timer, &LENGTHS, &clock), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            timer_transition(timer, &LENGTHS, &clock),
+            Some(TimerTransition::Armed)
+        );
+    }
+
+    #[test]
+    fn entry_delay_triggers_once_it_ran_out() {
+        let clock = ManualClock::new();
+        clock.advance(Duration::from_secs(100));
+        let timer = Timer::Entry(clock.now());
+        clock.advance(Duration::from_secs(19));
+        assert_eq!(timer_transition(timer, &LENGTHS, &clock), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            timer_transition(timer, &LENGTHS, &clock),
+            Some(TimerTransition::Triggered)
+        );
+    }
+
+    #[test]
+    fn entry_delay_of_the_zone_overrides_the_setting() {
+        let clock = ManualClock::new();
+        let lengths = TimerLengths {
+            entry_delay: Duration::from_secs(45),
+            ..LENGTHS
+        };
+        let timer = Timer::Entry(clock.now());
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(timer_transition(timer, &lengths, &clock), None);
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(
+            timer_transition(timer, &lengths, &clock),
+            Some(TimerTransition::Triggered)
+        );
+    }
+
+    #[test]
+    fn siren_times_out() {
+        let clock = ManualClock::new();
+        let timer = Timer::Siren(clock.now());
+        clock.advance(Duration::from_secs(179));
+        assert_eq!(timer_transition(timer, &LENGTHS, &clock), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            timer_transition(timer, &LENGTHS, &clock),
+            Some(TimerTransition::SirenTimedOut)
+        );
+    }
+
+    #[test]
+    fn zero_siren_timeout_never_times_out() {
+        let clock = Arc::new(ManualClock::new());
+        let lengths = TimerLengths {
+            siren_timeout: Duration::ZERO,
+            ..LENGTHS
+        };
+        let timer = Timer::Siren(clock.now());
+        clock.advance(Duration::from_secs(86400));
+        assert_eq!(timer_transition(timer, &lengths, &clock), None);
+    }
+
+    #[test]
+    fn full_alarm_cycle() {
+        let clock = ManualClock::new();
+        let exit = Timer::Exit(clock.now());
+        clock.advance(LENGTHS.exit_delay);
+        assert_eq!(
+            timer_transition(exit, &LENGTHS, &clock),
+            Some(TimerTransition::Armed)
+        );
+
+        clock.advance(Duration::from_secs(3600));
+        let entry = Timer::Entry(clock.now());
+        clock.advance(LENGTHS.entry_delay);
+        assert_eq!(
+            timer_transition(entry, &LENGTHS, &clock),
+            Some(TimerTransition::Triggered)
+        );
+
+        let siren = Timer::Siren(clock.now());
+        clock.advance(LENGTHS.siren_timeout - Duration::from_secs(1));
+        assert_eq!(timer_transition(siren, &LENGTHS, &clock), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            timer_transition(siren, &LENGTHS, &clock),
+            Some(TimerTransition::SirenTimedOut)
+        );
+    }
+
+    /// One pass of the alarm task, the state changes like it does there
+    fn pass(
+        state: &mut AlarmState,
+        zone_action: Option<(ZoneAction, &'static str)>,
+        restarted_by: Option<&'static str>,
+        clock: &ManualClock,
+    ) -> Option<StateChange<'static>> {
+        let (next, change) = next_state(state, zone_action, restarted_by, &LENGTHS, clock)?;
+        *state = next;
+        Some(change)
+    }
+
+    #[test]
+    fn arming_pending_and_siren_sequence() {
+        let clock = ManualClock::new();
+        let mut state = AlarmState::Arming((clock.now(), ArmMode::away));
+
+        clock.advance(LENGTHS.exit_delay - Duration::from_secs(1));
+        assert_eq!(pass(&mut state, None, None, &clock), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            pass(&mut state, None, None, &clock),
+            Some(StateChange::ExitDelayElapsed)
+        );
+        assert_eq!(state, AlarmState::Armed((clock.now(), ArmMode::away)));
+
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(pass(&mut state, None, None, &clock), None);
+        let front_door = Some((ZoneAction::pending, "front door"));
+        assert_eq!(
+            pass(&mut state, front_door, None, &clock),
+            Some(StateChange::EntryDelayStarted("front door"))
+        );
+        assert_eq!(state, AlarmState::Pending(clock.now()));
+
+        clock.advance(LENGTHS.entry_delay - Duration::from_secs(1));
+        assert_eq!(pass(&mut state, None, None, &clock), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            pass(&mut state, None, None, &clock),
+            Some(StateChange::EntryDelayElapsed)
+        );
+        assert_eq!(state, AlarmState::Triggered);
+
+        // The alarm task keeps when the siren started
+        let siren = Timer::Siren(clock.now());
+        clock.advance(LENGTHS.siren_timeout - Duration::from_secs(1));
+        assert_eq!(pass(&mut state, None, None, &clock), None);
+        assert_eq!(timer_transition(siren, &LENGTHS, &clock), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            timer_transition(siren, &LENGTHS, &clock),
+            Some(TimerTransition::SirenTimedOut)
+        );
+    }
+
+    #[test]
+    fn closing_a_delayed_zone_restarts_the_exit_delay() {
+        let clock = ManualClock::new();
+        let mut state = AlarmState::Arming((clock.now(), ArmMode::home));
+
+        clock.advance(LENGTHS.exit_delay - Duration::from_secs(5));
+        assert_eq!(
+            pass(&mut state, None, Some("front door"), &clock),
+            Some(StateChange::ExitDelayRestarted("front door"))
+        );
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(pass(&mut state, None, None, &clock), None);
+        clock.advance(LENGTHS.exit_delay - Duration::from_secs(5));
+        assert_eq!(
+            pass(&mut state, None, None, &clock),
+            Some(StateChange::ExitDelayElapsed)
+        );
+        assert_eq!(state, AlarmState::Armed((clock.now(), ArmMode::home)));
+    }
+
+    #[test]
+    fn zones_abort_arming_and_trigger() {
+        let clock = ManualClock::new();
+        let mut state = AlarmState::Arming((clock.now(), ArmMode::away));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(
+            pass(
+                &mut state,
+                Some((ZoneAction::abort_arming, "hall")),
+                None,
+                &clock
+            ),
+            Some(StateChange::ArmingAborted("hall"))
+        );
+        assert_eq!(state, AlarmState::Disarmed);
+
+        let mut state = AlarmState::Armed((clock.now(), ArmMode::night));
+        assert_eq!(
+            pass(
+                &mut state,
+                Some((ZoneAction::trigger, "window")),
+                None,
+                &clock
+            ),
+            Some(StateChange::ZoneTriggered("window"))
+        );
+        assert_eq!(state, AlarmState::Triggered);
+    }
+}
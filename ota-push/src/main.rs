@@ -0,0 +1,108 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS, RecvTimeoutError};
+
+/// Pushes a firmware image to the alarm panel over MQTT
+#[derive(Parser)]
+struct Args {
+    /// Broker of the panel, e.g. mqtt://192.168.1.2:1883
+    #[arg(long)]
+    broker: String,
+    /// OTA topic of the panel
+    #[arg(long)]
+    topic: String,
+    /// Boot report topic of the panel, used to check the version after the update
+    #[arg(long)]
+    boot_report_topic: Option<String>,
+    /// Firmware version the panel has to report after the update
+    #[arg(long, requires = "boot_report_topic")]
+    expect_version: Option<String>,
+    /// Seconds to wait for the update to be applied
+    #[arg(long, default_value_t = 180)]
+    timeout: u64,
+    /// Firmware image, as saved by `espflash save-image`
+    firmware: PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let firmware = std::fs::read(&args.firmware)
+        .with_context(|| format!("Failed to read {}", args.firmware.display()))?;
+
+    let (host, port) = parse_broker(&args.broker)?;
+    let mut options = MqttOptions::new(format!("ota-push-{}", std::process::id()), host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    // The image is sent in a single message, the panel receives it in chunks
+    options.set_max_packet_size(64 * 1024, firmware.len() + 1024);
+
+    let (client, mut connection) = Client::new(options, 10);
+    let status_topic = format!("{}/status", args.topic);
+    client.subscribe(&status_topic, QoS::AtLeastOnce)?;
+    if let Some(topic) = args.boot_report_topic.as_ref() {
+        client.subscribe(topic, QoS::AtLeastOnce)?;
+    }
+    client.publish(&args.topic, QoS::ExactlyOnce, false, firmware.clone())?;
+    println!("Sending {} bytes to {}", firmware.len(), args.topic);
+
+    let deadline = Instant::now() + Duration::from_secs(args.timeout);
+    let mut received_all = false;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let event = match connection.recv_timeout(timeout) {
+            Ok(event) => event?,
+            Err(RecvTimeoutError::Timeout) => bail!("Timed out waiting for the update"),
+            Err(RecvTimeoutError::Disconnected) => bail!("Connection closed"),
+        };
+        let Event::Incoming(packet) = event else {
+            continue;
+        };
+        match packet {
+            Packet::PubComp(_) => println!("Firmware delivered to the broker"),
+            Packet::Publish(publish) if publish.topic == status_topic => {
+                let status: serde_json::Value = serde_json::from_slice(&publish.payload)?;
+                let received = status["received"].as_u64().unwrap_or_default();
+                let total = status["total"].as_u64().unwrap_or_default().max(1);
+                println!(
+                    "Panel received {}/{} ({}%)",
+                    received,
+                    total,
+                    received * 100 / total
+                );
+                if received == total {
+                    received_all = true;
+                    if args.boot_report_topic.is_none() {
+                        println!("Update applied, the panel is restarting");
+                        return Ok(());
+                    }
+                }
+            }
+            // The retained report of the previous boot arrives right after subscribing
+            Packet::Publish(publish) if received_all => {
+                let report: serde_json::Value = serde_json::from_slice(&publish.payload)?;
+                let version = report["firmware_version"].as_str().unwrap_or_default();
+                println!("Panel restarted with firmware {}", version);
+                return match args.expect_version.as_deref() {
+                    Some(expected) if expected != version => {
+                        bail!("Expected firmware {}, the update was not applied", expected)
+                    }
+                    _ => Ok(()),
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse_broker(broker: &str) -> anyhow::Result<(String, u16)> {
+    let Some(address) = broker.strip_prefix("mqtt://") else {
+        bail!("broker must start with \"mqtt://\"");
+    };
+    let address = address.trim_end_matches('/');
+    match address.rsplit_once(':') {
+        Some((host, port)) => Ok((host.to_string(), port.parse().context("Invalid port")?)),
+        None => Ok((address.to_string(), 1883)),
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+pub type Device = BTreeMap<String, String>;
+
+/// Reads a device list, a CSV with a header row or a YAML list of maps
+///
+/// The CSV is split on commas, values can't be quoted.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Device>> {
+    let list = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    if path
+        .extension()
+        .is_some_and(|ext| ext == "yml" || ext == "yaml")
+    {
+        return serde_yaml::from_str(&list)
+            .with_context(|| format!("{} is not a YAML list of devices", path.display()));
+    }
+
+    let mut lines = list.lines().filter(|line| !line.trim().is_empty());
+    let header: Vec<&str> = lines
+        .next()
+        .context("The device list is empty")?
+        .split(',')
+        .map(str::trim)
+        .collect();
+    lines
+        .enumerate()
+        .map(|(index, line)| {
+            let values: Vec<&str> = line.split(',').map(str::trim).collect();
+            if values.len() != header.len() {
+                bail!(
+                    "Device {} has {} columns instead of {}",
+                    index + 1,
+                    values.len(),
+                    header.len()
+                );
+            }
+            Ok(header
+                .iter()
+                .zip(values)
+                .map(|(column, value)| (column.to_string(), value.to_string()))
+                .collect())
+        })
+        .collect()
+}
+
+/// Replaces the `{column}` placeholders in the values of the template
+pub fn fill_template(
+    template: &BTreeMap<String, String>,
+    device: &Device,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    template
+        .iter()
+        .map(|(key, value)| {
+            let mut filled = String::new();
+            let mut rest = value.as_str();
+            while let Some(start) = rest.find('{') {
+                let end = rest[start..]
+                    .find('}')
+                    .with_context(|| format!("Unclosed placeholder in {}", key))?;
+                let column = &rest[start + 1..start + end];
+                let value = device.get(column).with_context(|| {
+                    format!(
+                        "{} uses {{{}}}, which the device list has no column for",
+                        key, column
+                    )
+                })?;
+                filled.push_str(&rest[..start]);
+                filled.push_str(value);
+                rest = &rest[start + end + 1..];
+            }
+            filled.push_str(rest);
+            Ok((key.clone(), filled))
+        })
+        .collect()
+}
@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use ha_types::{HAEntity, HAEntityVariant, ZoneType};
+use serde::Deserialize;
+
+/// Device classes of smoke, heat and gas detectors
+const FIRE_DEVICE_CLASSES: &[&str] = &["smoke", "heat", "gas", "carbon_monoxide"];
+
+/// The part of config.yml describing the zones, the firmware build checks the rest
+#[derive(Deserialize)]
+struct Config {
+    entities: Vec<HAEntity>,
+}
+
+/// Warns about zone configurations which build, but are likely mistakes
+pub fn lint(path: &Path, strict: bool) -> anyhow::Result<()> {
+    let config = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let config: Config = serde_yaml::from_str(&config)
+        .with_context(|| format!("{} is not a valid configuration", path.display()))?;
+
+    let mut warnings = Vec::new();
+    let mut unique_ids: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut state_topics: BTreeMap<&str, usize> = BTreeMap::new();
+    for entity in config.entities.iter() {
+        *unique_ids.entry(&entity.unique_id).or_default() += 1;
+        // Buttons have no state topic
+        if !entity.state_topic.is_empty() {
+            *state_topics.entry(&entity.state_topic).or_default() += 1;
+        }
+
+        let has_input = entity.gpio_pin.is_some()
+            || entity.modbus_input.is_some()
+            || entity.can_input.is_some()
+            || entity.dsc_zone.is_some()
+            || entity.virtual_topic.is_some();
+        if entity.variant == HAEntityVariant::binary_sensor
+            && !has_input
+            && !entity.siren.unwrap_or(false)
+        {
+            warnings.push(format!(
+                "{} has no input, its state never changes",
+                entity.unique_id
+            ));
+        }
+
+        // The firmware build derives the device_class from the zone_kind
+        let device_class = entity
+            .device_class
+            .as_deref()
+            .or(entity.zone_kind.map(|kind| kind.device_class()));
+        let fire_class = device_class.is_some_and(|class| FIRE_DEVICE_CLASSES.contains(&class));
+        match (entity.zone_type, fire_class) {
+            (Some(ZoneType::fire), false) => warnings.push(format!(
+                "{} is a fire zone, but its device_class is {}",
+                entity.unique_id,
+                device_class.unwrap_or("not set")
+            )),
+            (zone_type, true) if zone_type != Some(ZoneType::fire) && has_input => {
+                warnings.push(format!(
+                    "{} is a {} detector, but not a fire zone",
+                    entity.unique_id,
+                    device_class.unwrap_or_default()
+                ))
+            }
+            _ => {}
+        }
+    }
+    for (unique_id, count) in unique_ids.iter().filter(|(_, count)| **count > 1) {
+        warnings.push(format!(
+            "unique_id {} is used by {} entities",
+            unique_id, count
+        ));
+    }
+    for (topic, count) in state_topics.iter().filter(|(_, count)| **count > 1) {
+        warnings.push(format!(
+            "state_topic {} is used by {} entities",
+            topic, count
+        ));
+    }
+
+    for warning in warnings.iter() {
+        println!("warning: {}", warning);
+    }
+    if strict && !warnings.is_empty() {
+        bail!("{} warnings in {}", warnings.len(), path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
+use ha_types::{validate_setting, SETTINGS_NAMESPACE};
+
+mod devices;
+mod lint;
+mod nvs;
+mod partitions;
+mod provisioning;
+
+/// Offset of the second stage bootloader on the ESP32
+const BOOTLOADER_OFFSET: usize = 0x1000;
+
+/// Generates the NVS partition holding the settings which override config.yml
+#[derive(Parser)]
+struct Args {
+    /// Partition table of the firmware
+    #[arg(long, default_value = "partitions.csv")]
+    partitions: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Writes the NVS partition image of the settings
+    Generate {
+        /// YAML map of the settings, e.g. `mqtt_endpoint: mqtt://192.168.1.2:1883`
+        settings: PathBuf,
+        /// nvs_keys partition image to encrypt the settings with
+        #[arg(long)]
+        keys: Option<PathBuf>,
+        #[arg(short, long, default_value = "settings.bin")]
+        output: PathBuf,
+    },
+    /// Writes an nvs_keys partition image with new random keys
+    GenerateKeys {
+        #[arg(short, long, default_value = "nvs_keys.bin")]
+        output: PathBuf,
+    },
+    /// Merges the bootloader, the partition table, the app and the settings into one image flashed at 0x0
+    FlashImage {
+        /// Second stage bootloader, bootloader.bin in the esp-idf-sys build output
+        #[arg(long)]
+        bootloader: PathBuf,
+        /// App image, as saved by `espflash save-image`
+        #[arg(long)]
+        app: PathBuf,
+        /// YAML map of the settings, or an NVS partition image ending in .bin
+        #[arg(long)]
+        settings: PathBuf,
+        /// nvs_keys partition image to encrypt the settings with, it is also added to the image
+        #[arg(long)]
+        keys: Option<PathBuf>,
+        #[arg(short, long, default_value = "flash.bin")]
+        output: PathBuf,
+    },
+    /// Reads back a settings partition image and reports the settings the panel would fail to load
+    Verify {
+        settings: PathBuf,
+        /// nvs_keys partition image the settings are encrypted with
+        #[arg(long)]
+        keys: Option<PathBuf>,
+    },
+    /// Warns about likely mistakes in the zones of the firmware configuration
+    Lint {
+        #[arg(default_value = "config.yml")]
+        config: PathBuf,
+        /// Fails on warnings
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Generates the zone entities of config.yml from a provisioning JSON published by HA,
+    /// with the areas of the zones and the names they should have
+    ImportZones {
+        /// Provisioning JSON, `-` reads it from stdin, e.g. piped from mosquitto_sub
+        provisioning: PathBuf,
+        /// Writes the entities to stdout when omitted
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Generates the settings of every device in a device list from a template
+    Devices {
+        /// YAML map of the settings, `{column}` in the values is replaced with the column of the device
+        template: PathBuf,
+        /// CSV with a header row or a YAML list, e.g. with serial, hostname and eth_mac columns
+        devices: PathBuf,
+        /// Column naming the files of a device
+        #[arg(long, default_value = "serial")]
+        name_column: String,
+        #[arg(short, long, default_value = ".")]
+        output_dir: PathBuf,
+        /// Also writes a combined flash image per device
+        #[arg(long, requires = "app")]
+        bootloader: Option<PathBuf>,
+        #[arg(long, requires = "bootloader")]
+        app: Option<PathBuf>,
+        /// Encrypts the settings of every device with new keys, written next to the settings
+        #[arg(long)]
+        encrypt: bool,
+    },
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    // The firmware configuration is linted without the partition table
+    if let Command::Lint { config, strict } = &args.command {
+        return lint::lint(config, *strict);
+    }
+    if let Command::ImportZones {
+        provisioning,
+        output,
+    } = &args.command
+    {
+        let zones = provisioning::import_zones(provisioning)?;
+        return match output {
+            Some(output) => write(output, zones.as_bytes()),
+            None => {
+                print!("{}", zones);
+                Ok(())
+            }
+        };
+    }
+    let partitions = std::fs::read_to_string(&args.partitions)
+        .with_context(|| format!("Failed to read {}", args.partitions.display()))?;
+    let partitions = partitions::parse_csv(&partitions)?;
+    let nvs_partition = nvs_partition(&partitions)?;
+
+    match args.command {
+        Command::Generate {
+            settings,
+            keys,
+            output,
+        } => {
+            let keys = keys.map(|keys| read_keys(&keys)).transpose()?;
+            let image = settings_image(&read_settings(&settings)?, nvs_partition.size, keys)?;
+            write(&output, &image)?;
+        }
+        Command::GenerateKeys { output } => {
+            write(&output, &nvs::NvsKeys::generate()?.to_partition())?;
+        }
+        Command::FlashImage {
+            bootloader,
+            app,
+            settings,
+            keys,
+            output,
+        } => {
+            let keys = keys.map(|keys| read_keys(&keys)).transpose()?;
+            let keys_partition = keys.as_ref().map(nvs::NvsKeys::to_partition);
+            // An NVS partition image is expected to be encrypted already
+            let settings = if settings.extension().is_some_and(|ext| ext == "bin") {
+                read(&settings)?
+            } else {
+                settings_image(&read_settings(&settings)?, nvs_partition.size, keys)?
+            };
+            let image = flash_image(
+                &partitions,
+                &read(&bootloader)?,
+                &read(&app)?,
+                &settings,
+                keys_partition.as_deref(),
+            )?;
+            write(&output, &image)?;
+        }
+        Command::Verify { settings, keys } => {
+            let keys = keys.map(|keys| read_keys(&keys)).transpose()?;
+            let image = read(&settings)?;
+            if image.len() != nvs_partition.size {
+                bail!(
+                    "The image is {} bytes, the nvs partition is {} bytes",
+                    image.len(),
+                    nvs_partition.size
+                );
+            }
+            verify(&nvs::read(&image, keys.as_ref())?)?;
+        }
+        Command::Lint { .. } | Command::ImportZones { .. } => unreachable!(),
+        Command::Devices {
+            template,
+            devices,
+            name_column,
+            output_dir,
+            bootloader,
+            app,
+            encrypt,
+        } => {
+            let template = read_settings(&template)?;
+            let devices = devices::read(&devices)?;
+            let firmware = match (bootloader, app) {
+                (Some(bootloader), Some(app)) => Some((read(&bootloader)?, read(&app)?)),
+                _ => None,
+            };
+            std::fs::create_dir_all(&output_dir)?;
+
+            for device in devices.iter() {
+                let name = device
+                    .get(&name_column)
+                    .with_context(|| format!("The device list has no {} column", name_column))?;
+                let keys = encrypt.then(nvs::NvsKeys::generate).transpose()?;
+                let keys_partition = keys.as_ref().map(nvs::NvsKeys::to_partition);
+                let settings = devices::fill_template(&template, device)
+                    .and_then(|settings| settings_image(&settings, nvs_partition.size, keys))
+                    .with_context(|| format!("Invalid settings for device {}", name))?;
+                if let Some((bootloader, app)) = firmware.as_ref() {
+                    let image = flash_image(
+                        &partitions,
+                        bootloader,
+                        app,
+                        &settings,
+                        keys_partition.as_deref(),
+                    )?;
+                    write(&output_dir.join(format!("{}.flash.bin", name)), &image)?;
+                }
+                if let Some(keys_partition) = keys_partition {
+                    write(
+                        &output_dir.join(format!("{}.keys.bin", name)),
+                        &keys_partition,
+                    )?;
+                }
+                write(&output_dir.join(format!("{}.bin", name)), &settings)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_settings(path: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    serde_yaml::from_slice(&read(path)?)
+        .with_context(|| format!("{} is not a YAML map of settings", path.display()))
+}
+
+/// Checks the settings with the validation the panel uses when changing them
+fn verify(items: &[nvs::Item]) -> anyhow::Result<()> {
+    let mut errors = 0;
+    for item in items.iter() {
+        if item.namespace != SETTINGS_NAMESPACE {
+            println!(
+                "{}/{}: ignored, not in the {} namespace",
+                item.namespace, item.key, SETTINGS_NAMESPACE
+            );
+            continue;
+        }
+        let result = match item.value.as_ref() {
+            Some(value) => validate_setting(&item.key, value),
+            None => Err(format!("{} is not a string", item.key)),
+        };
+        match result {
+            Ok(()) => println!(
+                "{}: {}",
+                item.key,
+                item.value.as_deref().unwrap_or_default()
+            ),
+            Err(e) => {
+                println!("{}: error: {}", item.key, e);
+                errors += 1;
+            }
+        }
+    }
+    if errors > 0 {
+        bail!("{} settings would fail to load", errors);
+    }
+    Ok(())
+}
+
+fn read_keys(path: &Path) -> anyhow::Result<nvs::NvsKeys> {
+    nvs::NvsKeys::from_partition(&read(path)?)
+        .with_context(|| format!("Failed to read the keys from {}", path.display()))
+}
+
+fn settings_image(
+    settings: &BTreeMap<String, String>,
+    size: usize,
+    keys: Option<nvs::NvsKeys>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut partition = nvs::NvsPartition::new(size, keys)?;
+    for (key, value) in settings.iter() {
+        validate_setting(key, value).map_err(|e| anyhow::anyhow!(e))?;
+        partition.set_str(SETTINGS_NAMESPACE, key, value)?;
+    }
+    partition.into_bytes()
+}
+
+fn flash_image(
+    partitions: &[partitions::Partition],
+    bootloader: &[u8],
+    app: &[u8],
+    settings: &[u8],
+    keys: Option<&[u8]>,
+) -> anyhow::Result<Vec<u8>> {
+    let nvs_partition = nvs_partition(partitions)?;
+    // The bootloader boots the first app partition while otadata is erased
+    let app_partition = partitions
+        .iter()
+        .find(|partition| partition.is_app())
+        .context("The partition table has no app partition")?;
+
+    let table = partitions::to_binary(partitions);
+    let mut parts = vec![
+        (
+            "bootloader",
+            BOOTLOADER_OFFSET,
+            partitions::TABLE_OFFSET - BOOTLOADER_OFFSET,
+            bootloader,
+        ),
+        (
+            "partition table",
+            partitions::TABLE_OFFSET,
+            partitions::TABLE_SIZE,
+            &table,
+        ),
+        (
+            &nvs_partition.name,
+            nvs_partition.offset,
+            nvs_partition.size,
+            settings,
+        ),
+        (
+            &app_partition.name,
+            app_partition.offset,
+            app_partition.size,
+            app,
+        ),
+    ];
+    // Partitions flagged as encrypted are encrypted by the bootloader on the first boot
+    if let Some(keys) = keys {
+        let keys_partition = partitions
+            .iter()
+            .find(|partition| partition.is_nvs_keys())
+            .context("The partition table has no nvs_keys partition")?;
+        parts.push((
+            &keys_partition.name,
+            keys_partition.offset,
+            keys_partition.size,
+            keys,
+        ));
+    }
+    parts.sort_by_key(|(_, offset, _, _)| *offset);
+    let mut image = Vec::new();
+    for (name, offset, size, part) in parts.iter() {
+        place(&mut image, name, *offset, *size, part)?;
+    }
+    Ok(image)
+}
+
+fn nvs_partition(partitions: &[partitions::Partition]) -> anyhow::Result<&partitions::Partition> {
+    partitions
+        .iter()
+        .find(|partition| partition.is_nvs())
+        .context("The partition table has no nvs partition")
+}
+
+/// Copies a part into the image at its offset, the gaps are left erased
+fn place(
+    image: &mut Vec<u8>,
+    name: &str,
+    offset: usize,
+    size: usize,
+    part: &[u8],
+) -> anyhow::Result<()> {
+    if part.len() > size {
+        bail!(
+            "{} is {} bytes, larger than its {} byte partition",
+            name,
+            part.len(),
+            size
+        );
+    }
+    if image.len() > offset {
+        bail!("{} at {:#x} overlaps the previous part", name, offset);
+    }
+    image.resize(offset, 0xFF);
+    image.extend_from_slice(part);
+    println!("{:#08x} {} ({} bytes)", offset, name, part.len());
+    Ok(())
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+fn write(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    std::fs::write(path, data).with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("Wrote {} bytes to {}", data.len(), path.display());
+    Ok(())
+}
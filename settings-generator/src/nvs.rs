@@ -0,0 +1,420 @@
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes256;
+use anyhow::bail;
+
+pub const PAGE_SIZE: usize = 4096;
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_PAGE: usize = 126;
+/// The page header and the entry state bitmap precede the entries
+const FIRST_ENTRY_OFFSET: usize = 64;
+
+const PAGE_ACTIVE: u32 = 0xFFFF_FFFE;
+const PAGE_FULL: u32 = 0xFFFF_FFFC;
+/// Version 2, which supports multi-page blobs, in ESP-IDF 4.0 and newer
+const PAGE_VERSION: u8 = 0xFE;
+
+const TYPE_U8: u8 = 0x01;
+const TYPE_STR: u8 = 0x21;
+const MAX_KEY_LEN: usize = 15;
+const MAX_STR_LEN: usize = 4000;
+
+/// XTS-AES keys of an encrypted NVS partition, stored in the nvs_keys partition
+pub struct NvsKeys {
+    data: [u8; 32],
+    tweak: [u8; 32],
+}
+
+impl NvsKeys {
+    pub fn generate() -> anyhow::Result<Self> {
+        let mut keys = [0u8; 64];
+        getrandom::getrandom(&mut keys)
+            .map_err(|e| anyhow::anyhow!("Failed to generate keys: {}", e))?;
+        Self::from_bytes(&keys)
+    }
+
+    /// Reads the keys from an nvs_keys partition image
+    pub fn from_partition(partition: &[u8]) -> anyhow::Result<Self> {
+        if partition.len() < 68 {
+            bail!("The NVS keys partition is too short");
+        }
+        if crc32(&partition[..64]).to_le_bytes() != partition[64..68] {
+            bail!("The NVS keys partition is corrupted");
+        }
+        Self::from_bytes(&partition[..64])
+    }
+
+    /// The nvs_keys partition image, it is encrypted by flash encryption on the first boot
+    pub fn to_partition(&self) -> Vec<u8> {
+        let mut partition = vec![0xFF; PAGE_SIZE];
+        partition[..32].copy_from_slice(&self.data);
+        partition[32..64].copy_from_slice(&self.tweak);
+        let crc = crc32(&partition[..64]);
+        partition[64..68].copy_from_slice(&crc.to_le_bytes());
+        partition
+    }
+
+    fn from_bytes(keys: &[u8]) -> anyhow::Result<Self> {
+        Ok(Self {
+            data: keys[..32].try_into()?,
+            tweak: keys[32..64].try_into()?,
+        })
+    }
+
+    /// XTS-AES-256 with the address of the entry in the partition as the tweak
+    fn encrypt_entry(&self, entry: &mut [u8; ENTRY_SIZE], address: usize) {
+        self.xts(entry, address, true);
+    }
+
+    fn decrypt_entry(&self, entry: &mut [u8; ENTRY_SIZE], address: usize) {
+        self.xts(entry, address, false);
+    }
+
+    fn xts(&self, entry: &mut [u8; ENTRY_SIZE], address: usize, encrypt: bool) {
+        let cipher = Aes256::new(&self.data.into());
+        let mut tweak = GenericArray::from((address as u128).to_le_bytes());
+        Aes256::new(&self.tweak.into()).encrypt_block(&mut tweak);
+        for block in entry.chunks_exact_mut(16) {
+            let mut block_data = GenericArray::clone_from_slice(block);
+            block_data
+                .iter_mut()
+                .zip(tweak.iter())
+                .for_each(|(b, t)| *b ^= t);
+            if encrypt {
+                cipher.encrypt_block(&mut block_data);
+            } else {
+                cipher.decrypt_block(&mut block_data);
+            }
+            block_data
+                .iter_mut()
+                .zip(tweak.iter())
+                .for_each(|(b, t)| *b ^= t);
+            block.copy_from_slice(&block_data);
+
+            // Multiplies the tweak by x in GF(2^128)
+            let value = u128::from_le_bytes(tweak.into());
+            let carry = if value >> 127 == 1 { 0x87 } else { 0 };
+            tweak = GenericArray::from(((value << 1) ^ carry).to_le_bytes());
+        }
+    }
+}
+
+/// NVS partition image in the format of ESP-IDF's nvs_partition_gen.py
+pub struct NvsPartition {
+    size: usize,
+    keys: Option<NvsKeys>,
+    pages: Vec<Vec<u8>>,
+    next_entry: usize,
+    namespaces: Vec<String>,
+}
+
+impl NvsPartition {
+    pub fn new(size: usize, keys: Option<NvsKeys>) -> anyhow::Result<Self> {
+        // NVS keeps a page empty to be able to move entries when erasing
+        if !size.is_multiple_of(PAGE_SIZE) || size < 2 * PAGE_SIZE {
+            bail!(
+                "NVS partition size must be a multiple of {} bytes and at least 2 pages",
+                PAGE_SIZE
+            );
+        }
+        Ok(Self {
+            size,
+            keys,
+            pages: Vec::new(),
+            next_entry: ENTRIES_PER_PAGE,
+            namespaces: Vec::new(),
+        })
+    }
+
+    pub fn set_str(&mut self, namespace: &str, key: &str, value: &str) -> anyhow::Result<()> {
+        let namespace = self.namespace_index(namespace)?;
+        if value.len() > MAX_STR_LEN {
+            bail!("{} is longer than {} bytes", key, MAX_STR_LEN);
+        }
+
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        let mut header = [0xFF; 8];
+        header[0..2].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        header[4..8].copy_from_slice(&crc32(&data).to_le_bytes());
+
+        let data_entries = data.chunks(ENTRY_SIZE).map(|chunk| {
+            let mut entry = [0xFF; ENTRY_SIZE];
+            entry[..chunk.len()].copy_from_slice(chunk);
+            entry
+        });
+        let span = 1 + data.len().div_ceil(ENTRY_SIZE);
+        let mut entries = vec![entry(namespace, TYPE_STR, span as u8, key, header)?];
+        entries.extend(data_entries);
+        self.write(&entries)
+    }
+
+    pub fn into_bytes(mut self) -> anyhow::Result<Vec<u8>> {
+        if self.pages.len() * PAGE_SIZE >= self.size {
+            bail!("Settings don't fit in the {} byte NVS partition", self.size);
+        }
+        let page_count = self.pages.len();
+        let mut image = Vec::with_capacity(self.size);
+        for (index, page) in self.pages.iter_mut().enumerate() {
+            let state = if index + 1 == page_count {
+                PAGE_ACTIVE
+            } else {
+                PAGE_FULL
+            };
+            page[0..4].copy_from_slice(&state.to_le_bytes());
+            image.extend_from_slice(page);
+        }
+        image.resize(self.size, 0xFF);
+        Ok(image)
+    }
+
+    /// Index of the namespace, which is defined by an entry of its own when first used
+    fn namespace_index(&mut self, namespace: &str) -> anyhow::Result<u8> {
+        if let Some(index) = self.namespaces.iter().position(|n| n == namespace) {
+            return Ok(index as u8 + 1);
+        }
+        if self.namespaces.len() >= 254 {
+            bail!("Too many namespaces");
+        }
+        self.namespaces.push(namespace.to_string());
+        let index = self.namespaces.len() as u8;
+        let mut data = [0xFF; 8];
+        data[0] = index;
+        self.write(&[entry(0, TYPE_U8, 1, namespace, data)?])?;
+        Ok(index)
+    }
+
+    /// Writes the entries of an item, items don't cross page boundaries
+    fn write(&mut self, entries: &[[u8; ENTRY_SIZE]]) -> anyhow::Result<()> {
+        if entries.len() > ENTRIES_PER_PAGE {
+            bail!("Item does not fit in a page");
+        }
+        if self.next_entry + entries.len() > ENTRIES_PER_PAGE {
+            self.pages.push(new_page(self.pages.len() as u32));
+            self.next_entry = 0;
+        }
+        // A page was added above if there was none
+        let page_index = self.pages.len() - 1;
+        let page = &mut self.pages[page_index];
+        for entry in entries {
+            let offset = FIRST_ENTRY_OFFSET + self.next_entry * ENTRY_SIZE;
+            let mut entry = *entry;
+            if let Some(keys) = self.keys.as_ref() {
+                keys.encrypt_entry(&mut entry, page_index * PAGE_SIZE + offset);
+            }
+            page[offset..offset + ENTRY_SIZE].copy_from_slice(&entry);
+            // Two bits per entry, 0b10 marks it written
+            let bit = self.next_entry * 2;
+            page[ENTRY_SIZE + bit / 8] &= !(1 << (bit % 8));
+            self.next_entry += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Item read back from an NVS partition image
+pub struct Item {
+    pub namespace: String,
+    pub key: String,
+    /// The value of string items, other types are not read
+    pub value: Option<String>,
+}
+
+/// Reads the items of an NVS partition image, checking it the way the NVS library does when loading it
+pub fn read(image: &[u8], keys: Option<&NvsKeys>) -> anyhow::Result<Vec<Item>> {
+    if image.is_empty() || !image.len().is_multiple_of(PAGE_SIZE) {
+        bail!("The image size is not a multiple of {} bytes", PAGE_SIZE);
+    }
+    let mut namespaces = Vec::new();
+    let mut items = Vec::new();
+    for (page_index, page) in image.chunks(PAGE_SIZE).enumerate() {
+        let state = u32::from_le_bytes(page[0..4].try_into()?);
+        if state == 0xFFFF_FFFF {
+            continue;
+        }
+        if state != PAGE_ACTIVE && state != PAGE_FULL {
+            bail!("Page {} is in an unexpected state {:#x}", page_index, state);
+        }
+        if page[8] != PAGE_VERSION {
+            bail!(
+                "Page {} has an unsupported version {:#x}",
+                page_index,
+                page[8]
+            );
+        }
+        if crc32(&page[4..28]).to_le_bytes() != page[28..32] {
+            bail!("Page {} has an invalid header CRC", page_index);
+        }
+
+        let read_entry = |index: usize| -> [u8; ENTRY_SIZE] {
+            let offset = FIRST_ENTRY_OFFSET + index * ENTRY_SIZE;
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry.copy_from_slice(&page[offset..offset + ENTRY_SIZE]);
+            if let Some(keys) = keys {
+                keys.decrypt_entry(&mut entry, page_index * PAGE_SIZE + offset);
+            }
+            entry
+        };
+        let mut index = 0;
+        while index < ENTRIES_PER_PAGE {
+            // Two bits per entry, 0b10 marks it written
+            let bits = page[ENTRY_SIZE + index * 2 / 8] >> (index * 2 % 8) & 0b11;
+            if bits != 0b10 {
+                index += 1;
+                continue;
+            }
+            let entry = read_entry(index);
+            let key_len = entry[8..24].iter().position(|b| *b == 0).unwrap_or(16);
+            let key = String::from_utf8_lossy(&entry[8..8 + key_len]).into_owned();
+            let mut hasher = crc32fast::Hasher::new_with_initial(0xFFFF_FFFF);
+            hasher.update(&entry[0..4]);
+            hasher.update(&entry[8..32]);
+            if hasher.finalize().to_le_bytes() != entry[4..8] {
+                bail!(
+                    "Entry {} of page {} has an invalid CRC, is the partition encrypted?",
+                    index,
+                    page_index
+                );
+            }
+            let span = (entry[2] as usize).max(1);
+            if index + span > ENTRIES_PER_PAGE {
+                bail!("{} spans past the end of page {}", key, page_index);
+            }
+
+            if entry[0] == 0 {
+                namespaces.push((entry[24], key));
+            } else {
+                let namespace = namespaces
+                    .iter()
+                    .find(|(namespace, _)| *namespace == entry[0])
+                    .map(|(_, name)| name.clone())
+                    .unwrap_or_else(|| format!("#{}", entry[0]));
+                let value = if entry[1] == TYPE_STR {
+                    let size = u16::from_le_bytes(entry[24..26].try_into()?) as usize;
+                    let data: Vec<u8> = (index + 1..index + span).flat_map(read_entry).collect();
+                    if size == 0 || size > data.len() {
+                        bail!("{} has an invalid size {}", key, size);
+                    }
+                    if crc32(&data[..size]).to_le_bytes() != entry[28..32] {
+                        bail!("{} has an invalid data CRC", key);
+                    }
+                    Some(String::from_utf8(data[..size - 1].to_vec())?)
+                } else {
+                    None
+                };
+                items.push(Item {
+                    namespace,
+                    key,
+                    value,
+                });
+            }
+            index += span;
+        }
+    }
+    Ok(items)
+}
+
+fn new_page(sequence: u32) -> Vec<u8> {
+    let mut page = vec![0xFF; PAGE_SIZE];
+    page[4..8].copy_from_slice(&sequence.to_le_bytes());
+    page[8] = PAGE_VERSION;
+    let crc = crc32(&page[4..28]);
+    page[28..32].copy_from_slice(&crc.to_le_bytes());
+    page
+}
+
+fn entry(
+    namespace: u8,
+    kind: u8,
+    span: u8,
+    key: &str,
+    data: [u8; 8],
+) -> anyhow::Result<[u8; ENTRY_SIZE]> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        bail!("Key {} must be 1-{} bytes long", key, MAX_KEY_LEN);
+    }
+    let mut entry = [0xFF; ENTRY_SIZE];
+    entry[0] = namespace;
+    entry[1] = kind;
+    entry[2] = span;
+    entry[8..24].fill(0);
+    entry[8..8 + key.len()].copy_from_slice(key.as_bytes());
+    entry[24..32].copy_from_slice(&data);
+
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xFFFF_FFFF);
+    hasher.update(&entry[0..4]);
+    hasher.update(&entry[8..32]);
+    entry[4..8].copy_from_slice(&hasher.finalize().to_le_bytes());
+    Ok(entry)
+}
+
+/// CRC32 as computed by the ESP32 ROM
+fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xFFFF_FFFF);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Data key 00..1f and tweak key 20..3f
+    fn fixed_keys() -> NvsKeys {
+        let keys: Vec<u8> = (0..64).collect();
+        NvsKeys::from_bytes(&keys).unwrap()
+    }
+
+    #[test]
+    fn xts_matches_ieee_1619_vector_10() {
+        let keys = hex(concat!(
+            "2718281828459045235360287471352662497757247093699959574966967627",
+            "3141592653589793238462643383279502884197169399375105820974944592",
+        ));
+        let keys = NvsKeys::from_bytes(&keys).unwrap();
+        // The first two blocks of the 512 byte data unit
+        let mut entry: [u8; ENTRY_SIZE] = std::array::from_fn(|i| i as u8);
+        keys.encrypt_entry(&mut entry, 0xff);
+        assert_eq!(
+            entry.to_vec(),
+            hex("1c3b3a102f770386e4836c99e370cf9bea00803f5e482357a4ae12d414a3e63b")
+        );
+        keys.decrypt_entry(&mut entry, 0xff);
+        assert_eq!(entry, std::array::from_fn(|i| i as u8));
+    }
+
+    #[test]
+    fn encrypted_entries_match_nvs_partition_gen() {
+        let mut partition = NvsPartition::new(2 * PAGE_SIZE, Some(fixed_keys())).unwrap();
+        partition.set_str("ns", "key", "value").unwrap();
+        let image = partition.into_bytes().unwrap();
+        // The namespace entry, the string entry and its data, encrypted the way
+        // nvs_partition_gen.py --encrypt does: AES-XTS of the cryptography package with
+        // the 64 byte key and the little-endian offset of the entry as the tweak
+        let expected = hex(concat!(
+            "866e60b9bffe5de7adbc19dc1a0074f252f886eabd5d12a2f4f453aa926c86e4",
+            "b2601b161a42d9801a7897cae871a29b262a3cfac63f36984dfd92d21bb20acc",
+            "250fd144b63cce173c0123bf7946b9e3c84254b1d7b2863a87a136cf54294b76",
+        ));
+        assert_eq!(image[64..160].to_vec(), expected);
+    }
+
+    #[test]
+    fn encrypted_partition_reads_back() {
+        let mut partition = NvsPartition::new(2 * PAGE_SIZE, Some(fixed_keys())).unwrap();
+        partition.set_str("ns", "key", "value").unwrap();
+        let image = partition.into_bytes().unwrap();
+        let items = read(&image, Some(&fixed_keys())).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].namespace, "ns");
+        assert_eq!(items[0].key, "key");
+        assert_eq!(items[0].value.as_deref(), Some("value"));
+        assert!(read(&image, None).is_err());
+    }
+}
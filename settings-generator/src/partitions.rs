@@ -0,0 +1,126 @@
+use anyhow::{bail, Context};
+use md5::{Digest, Md5};
+
+/// Offset of the partition table on the flash
+pub const TABLE_OFFSET: usize = 0x8000;
+pub const TABLE_SIZE: usize = 0xC00;
+const ENTRY_MAGIC: [u8; 2] = [0xAA, 0x50];
+const MD5_MAGIC: [u8; 2] = [0xEB, 0xEB];
+
+pub struct Partition {
+    pub name: String,
+    pub kind: u8,
+    pub subtype: u8,
+    pub offset: usize,
+    pub size: usize,
+    pub encrypted: bool,
+}
+
+impl Partition {
+    pub fn is_app(&self) -> bool {
+        self.kind == 0x00
+    }
+
+    pub fn is_nvs(&self) -> bool {
+        self.kind == 0x01 && self.subtype == 0x02
+    }
+
+    pub fn is_nvs_keys(&self) -> bool {
+        self.kind == 0x01 && self.subtype == 0x04
+    }
+}
+
+/// Reads a partition table CSV in the format of ESP-IDF's gen_esp32part.py, offsets are required
+pub fn parse_csv(csv: &str) -> anyhow::Result<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    for (index, line) in csv.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let partition = parse_line(&fields)
+            .with_context(|| format!("Invalid partition on line {}", index + 1))?;
+        partitions.push(partition);
+    }
+    Ok(partitions)
+}
+
+fn parse_line(fields: &[&str]) -> anyhow::Result<Partition> {
+    let [name, kind, subtype, offset, size, rest @ ..] = fields else {
+        bail!("expected name, type, subtype, offset and size");
+    };
+    let kind = match *kind {
+        "app" => 0x00,
+        "data" => 0x01,
+        kind => parse_number(kind)? as u8,
+    };
+    let subtype = match (kind, *subtype) {
+        (0x00, "factory") => 0x00,
+        (0x00, "test") => 0x20,
+        (0x00, ota) if ota.starts_with("ota_") => 0x10 + ota[4..].parse::<u8>()?,
+        (0x01, "ota") => 0x00,
+        (0x01, "phy") => 0x01,
+        (0x01, "nvs") => 0x02,
+        (0x01, "coredump") => 0x03,
+        (0x01, "nvs_keys") => 0x04,
+        (0x01, "efuse") => 0x05,
+        (0x01, "fat") => 0x81,
+        (0x01, "spiffs") => 0x82,
+        (_, subtype) => parse_number(subtype)? as u8,
+    };
+    if name.is_empty() || name.len() > 15 {
+        bail!("name must be 1-15 bytes long");
+    }
+    if offset.is_empty() {
+        bail!("offset is required");
+    }
+    Ok(Partition {
+        name: name.to_string(),
+        kind,
+        subtype,
+        offset: parse_number(offset)?,
+        size: parse_number(size)?,
+        encrypted: rest
+            .first()
+            .is_some_and(|flags| flags.contains("encrypted")),
+    })
+}
+
+fn parse_number(value: &str) -> anyhow::Result<usize> {
+    let (value, multiplier) = match value.strip_suffix(['K', 'k']) {
+        Some(value) => (value, 1024),
+        None => match value.strip_suffix(['M', 'm']) {
+            Some(value) => (value, 1024 * 1024),
+            None => (value, 1),
+        },
+    };
+    let number = match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .with_context(|| format!("invalid number {}", value))?;
+    Ok(number * multiplier)
+}
+
+/// Binary partition table as written by the bootloader's flash tools
+pub fn to_binary(partitions: &[Partition]) -> Vec<u8> {
+    let mut table = Vec::with_capacity(TABLE_SIZE);
+    for partition in partitions {
+        table.extend_from_slice(&ENTRY_MAGIC);
+        table.push(partition.kind);
+        table.push(partition.subtype);
+        table.extend_from_slice(&(partition.offset as u32).to_le_bytes());
+        table.extend_from_slice(&(partition.size as u32).to_le_bytes());
+        let mut name = [0u8; 16];
+        name[..partition.name.len()].copy_from_slice(partition.name.as_bytes());
+        table.extend_from_slice(&name);
+        table.extend_from_slice(&u32::from(partition.encrypted).to_le_bytes());
+    }
+    let digest = Md5::digest(&table);
+    table.extend_from_slice(&MD5_MAGIC);
+    table.extend_from_slice(&[0xFF; 14]);
+    table.extend_from_slice(&digest);
+    table.resize(TABLE_SIZE, 0xFF);
+    table
+}
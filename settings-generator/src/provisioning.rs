@@ -0,0 +1,105 @@
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use ha_types::provisioning::{area_device, slug, Provisioning};
+use ha_types::{ExpanderPoint, HADevice, HAEntity, ZoneKind, ZoneType};
+use serde::Serialize;
+
+/// Entity of config.yml, the fields which are not set are left out
+#[derive(Serialize)]
+struct ZoneRecord {
+    name: String,
+    variant: &'static str,
+    unique_id: String,
+    state_topic: String,
+    device: HADevice,
+    gpio_pin: Option<u8>,
+    modbus_input: Option<ExpanderPoint>,
+    can_input: Option<ExpanderPoint>,
+    zone_type: Option<ZoneType>,
+    zone_kind: Option<ZoneKind>,
+}
+
+/// Generates the zone entities of config.yml from a provisioning JSON, `-` reads it from stdin
+pub fn import_zones(path: &Path) -> anyhow::Result<String> {
+    let mut provisioning = String::new();
+    if path == Path::new("-") {
+        std::io::stdin().read_to_string(&mut provisioning)?;
+    } else {
+        provisioning = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+    }
+    let provisioning: Provisioning = serde_json::from_str(&provisioning)
+        .with_context(|| format!("{} is not a valid provisioning", path.display()))?;
+    let zones = zone_records(provisioning)?;
+
+    let mut zones = serde_yaml::to_value(zones)?;
+    remove_nulls(&mut zones);
+    // The records have to load as entities of the firmware configuration
+    let yaml = serde_yaml::to_string(&zones)?;
+    serde_yaml::from_str::<Vec<HAEntity>>(&yaml).context("Generated an invalid entity")?;
+    Ok(yaml)
+}
+
+fn zone_records(provisioning: Provisioning) -> anyhow::Result<Vec<ZoneRecord>> {
+    let panel_id = provisioning
+        .device
+        .identifiers
+        .as_ref()
+        .and_then(|identifiers| identifiers.first())
+        .context("The device of the provisioning has no identifiers")?
+        .clone();
+    let prefix = provisioning.state_topic_prefix.trim_end_matches('/');
+
+    let mut unique_ids = BTreeSet::new();
+    let mut records = Vec::new();
+    for area in provisioning.areas {
+        let device = area_device(&provisioning.device, &panel_id, &area.name);
+        for zone in area.zones {
+            let inputs = [
+                zone.gpio_pin.is_some(),
+                zone.modbus_input.is_some(),
+                zone.can_input.is_some(),
+            ];
+            if inputs.iter().filter(|input| **input).count() != 1 {
+                bail!(
+                    "{} needs exactly one of gpio_pin, modbus_input and can_input",
+                    zone.name
+                );
+            }
+            let unique_id = zone.unique_id.unwrap_or_else(|| slug(&zone.name));
+            if unique_id.is_empty() {
+                bail!("{} has no unique id", zone.name);
+            }
+            if !unique_ids.insert(unique_id.clone()) {
+                bail!("{} is provisioned twice", unique_id);
+            }
+            records.push(ZoneRecord {
+                name: zone.name,
+                variant: "binary_sensor",
+                state_topic: format!("{}/{}", prefix, unique_id),
+                unique_id,
+                device: device.clone(),
+                gpio_pin: zone.gpio_pin,
+                modbus_input: zone.modbus_input,
+                can_input: zone.can_input,
+                zone_type: zone.zone_type,
+                zone_kind: zone.zone_kind,
+            });
+        }
+    }
+    Ok(records)
+}
+
+fn remove_nulls(value: &mut serde_yaml::Value) {
+    match value {
+        serde_yaml::Value::Mapping(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(remove_nulls);
+        }
+        serde_yaml::Value::Sequence(list) => list.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
@@ -1,163 +1,1444 @@
-use esp_idf_hal::gpio::{InputMode, InputPin, Output, OutputPin, PinDriver};
+use esp_idf_hal::delay::TickType;
+use esp_idf_hal::gpio::{Input, InputMode, InputPin, InterruptType, OutputPin, PinDriver};
+use esp_idf_hal::task::notification::{Notification, Notifier};
 use esp_idf_svc::nvs::*;
+pub use ha_types::timers::AlarmState;
+use ha_types::timers::{next_state, timer_transition, StateChange, Timer, TimerLengths};
 use ha_types::*;
-use std::sync::mpsc::Receiver;
+use std::collections::BTreeSet;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+use crate::clock::{self, Clock};
+use crate::event_queue::{Critical, EventQueue};
+use crate::lock::LockRecover;
+use crate::modbus::ExpanderCommand;
+use crate::settings::{Settings, LOCKED_REASON, MAX_JSON_LEN};
+use crate::siren::{SirenOutput, SirenSound};
+use crate::timing_stats;
+use crate::zone_counters;
+use crate::zone_learn::{self, LearnedZone, ZoneLearner};
+
+#[derive(Debug, Clone)]
 pub enum AlarmEvent {
-    MotionDetected(HAEntity),
-    MotionCleared(HAEntity),
-    AlarmStateChanged((HAEntity, AlarmState)),
+    /// The zone entities are shared with the alarm task, so queued zone changes don't copy them
+    MotionDetected(Arc<HAEntity>),
+    MotionCleared(Arc<HAEntity>),
+    /// The new state and what changed it, e.g. a command or the zone which was opened
+    AlarmStateChanged((HAEntity, AlarmState, String)),
+    OutputStateChanged((HAEntity, bool)),
+    /// The fire zone which raised the fire alarm, `None` once acknowledged
+    FireAlarmChanged((HAEntity, Option<String>)),
+    /// Suggestions of the last zone learning waiting for confirmation, empty once confirmed
+    ZonesLearned((HAEntity, Vec<LearnedZone>)),
+    /// Outcome of an alarm command and where it came from, rejected commands carry the reason
+    CommandResult((CommandSource, AlarmCommand, Result<(), &'static str>)),
+    /// Current value of the setting shown by a number or select entity
+    SettingChanged((HAEntity, String)),
+    /// A single code of a dual disarm silenced the siren, the current state and whether
+    /// it is still waiting for the second code
+    PartialDisarmChanged((HAEntity, AlarmState, bool)),
+    /// A command overrode a conflicting one sent shortly before or after it,
+    /// the winning command first
+    CommandConflict(((CommandSource, AlarmCommand), (CommandSource, AlarmCommand))),
+}
+
+/// A source of zone activity, e.g. a GPIO pin or an input on an expander board
+pub trait ZoneInput: Send {
+    fn is_active(&self) -> bool;
+
+    /// Wakes the alarm task when the input changes, inputs which can't are only scanned
+    fn watch(&mut self, _notifier: Arc<Notifier>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Whether the input changed since the last call, also when it changed back since
+    fn take_edge(&mut self) -> bool {
+        false
+    }
 }
 
-pub struct AlarmMotionEntity<'a, T, MODE>
+impl<T, MODE> ZoneInput for PinDriver<'_, T, MODE>
 where
     T: InputPin + OutputPin,
     MODE: InputMode,
 {
-    pub entity: HAEntity,
-    pub pin_driver: PinDriver<'a, T, MODE>,
+    fn is_active(&self) -> bool {
+        self.is_high()
+    }
+}
+
+/// A GPIO zone which wakes the alarm task on its edges
+pub struct GpioZone<'d, T: InputPin> {
+    driver: PinDriver<'d, T, Input>,
+    edge: Arc<AtomicBool>,
+}
+
+impl<'d, T: InputPin> GpioZone<'d, T> {
+    pub fn new(driver: PinDriver<'d, T, Input>) -> Self {
+        Self {
+            driver,
+            edge: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl<T: InputPin> ZoneInput for GpioZone<'_, T> {
+    fn is_active(&self) -> bool {
+        self.driver.is_high()
+    }
+
+    fn watch(&mut self, notifier: Arc<Notifier>) -> anyhow::Result<()> {
+        self.driver.set_interrupt_type(InterruptType::AnyEdge)?;
+        let edge = self.edge.clone();
+        // SAFETY: the callback only uses ISR safe calls and does not allocate
+        unsafe {
+            self.driver.subscribe(move || {
+                edge.store(true, Ordering::Relaxed);
+                notifier.notify_and_yield(NonZeroU32::MIN);
+            })?;
+        }
+        self.driver.enable_interrupt()?;
+        Ok(())
+    }
+
+    fn take_edge(&mut self) -> bool {
+        let edge = self.edge.swap(false, Ordering::Relaxed);
+        // The interrupt is disabled once it fired
+        if edge {
+            self.driver
+                .enable_interrupt()
+                .unwrap_or_else(|e| log::error!("Failed to enable a zone interrupt: {:?}", e));
+        }
+        edge
+    }
+}
+
+/// Zone state which is updated by another task
+impl ZoneInput for Arc<AtomicBool> {
+    fn is_active(&self) -> bool {
+        self.load(Ordering::Relaxed)
+    }
+}
+
+pub struct AlarmMotionEntity<'a> {
+    pub entity: Arc<HAEntity>,
+    pub input: Box<dyn ZoneInput + 'a>,
     pub motion: bool,
 }
 
-#[derive(Clone, PartialEq, Debug)]
-pub enum AlarmState {
-    Disarmed,
-    Arming(Instant),
-    Armed(Instant),
-    Pending(Instant),
-    Triggered,
+/// Output which follows its zones regardless of the alarm state, e.g. a floodlight
+/// turned on by a driveway PIR
+pub struct FollowOutput {
+    entity: HAEntity,
+    zones: Vec<String>,
+    /// How long the output stays on after the zones became inactive
+    duration: Duration,
+    expander_command_tx: Sender<ExpanderCommand>,
+    on: bool,
+    off_at: Option<Instant>,
+}
+
+impl FollowOutput {
+    pub fn new(
+        entity: HAEntity,
+        zones: Vec<String>,
+        duration: Duration,
+        expander_command_tx: Sender<ExpanderCommand>,
+    ) -> Self {
+        Self {
+            entity,
+            zones,
+            duration,
+            expander_command_tx,
+            on: false,
+            off_at: None,
+        }
+    }
+
+    fn update(&mut self, motion_entities: &[AlarmMotionEntity], now: Instant) {
+        let active = motion_entities
+            .iter()
+            .any(|e| e.motion && self.zones.contains(&e.entity.unique_id));
+        if active {
+            self.off_at = None;
+            if !self.on {
+                self.set(true);
+            }
+        } else if self.on {
+            let off_at = *self.off_at.get_or_insert_with(|| now + self.duration);
+            if now >= off_at {
+                self.off_at = None;
+                self.set(false);
+            }
+        }
+    }
+
+    fn set(&mut self, on: bool) {
+        log::info!("{} following its zones: {}", self.entity.name, on);
+        self.on = on;
+        self.expander_command_tx
+            .send(ExpanderCommand::SetRelay(self.entity.clone(), on))
+            .unwrap_or_else(|e| log::error!("Failed to send expander command: {:?}", e));
+    }
+}
+
+/// Trigger contact of an external communicator, closed in the pattern of the alarm, a fire
+/// alarm takes precedence over a burglary
+///
+/// Silent and timed out alarms are reported too, until the alarm is disarmed.
+pub struct DialerOutput {
+    entity: HAEntity,
+    contact: DialerContact,
+    expander_command_tx: Sender<ExpanderCommand>,
+    closed: bool,
+    /// Pattern of the current alarm and since when
+    alarm: Option<(ContactPattern, Instant)>,
+}
+
+impl DialerOutput {
+    pub fn new(
+        entity: HAEntity,
+        contact: DialerContact,
+        expander_command_tx: Sender<ExpanderCommand>,
+    ) -> Self {
+        Self {
+            entity,
+            contact,
+            expander_command_tx,
+            closed: false,
+            alarm: None,
+        }
+    }
+
+    fn update(&mut self, burglary: bool, fire: bool, now: Instant) {
+        let pattern = if fire {
+            Some(self.contact.fire)
+        } else if burglary {
+            Some(self.contact.burglary)
+        } else {
+            None
+        };
+        if pattern != self.alarm.map(|(pattern, _)| pattern) {
+            self.alarm = pattern.map(|pattern| (pattern, now));
+        }
+        let closed = self
+            .alarm
+            .is_some_and(|(pattern, start)| pattern.is_closed(now.duration_since(start)));
+        if closed != self.closed {
+            self.closed = closed;
+            self.expander_command_tx
+                .send(ExpanderCommand::SetRelay(self.entity.clone(), closed))
+                .unwrap_or_else(|e| log::error!("Failed to send expander command: {:?}", e));
+        }
+    }
+}
+
+pub const NVS_NAMESPACE: &str = "alarm";
+pub const NVS_STATE_KEY: &str = "state";
+const NVS_PROFILE_KEY: &str = "profile";
+/// Unique ids of the bypassed zones, as JSON
+const NVS_BYPASSED_KEY: &str = "bypassed";
+
+/// Settings of the alarm which are changed at runtime, the timings, the toggles except the
+/// walk test and the bypassed zones are persisted next to the alarm state
+struct AlarmSettings {
+    arming_timeout: Duration,
+    pending_timeout: Duration,
+    /// Zero sounds the siren until the alarm is disarmed
+    siren_timeout: Duration,
+    chime: bool,
+    walk_test: bool,
+    quiet_hours: bool,
+    auto_rearm: bool,
+    rearm_bypass: bool,
+    /// Unique ids of the zones ignored by the alarm, a faulty zone stays bypassed after a reboot
+    bypassed: BTreeSet<String>,
+    profiles: Vec<ArmingProfile>,
+    /// Name of the profile used on the next arm
+    profile: Option<String>,
+}
+
+impl AlarmSettings {
+    fn load(nvs: Option<&EspNvs<NvsDefault>>, profiles: Vec<ArmingProfile>) -> Self {
+        let load = |setting: AlarmSetting| {
+            let value = nvs
+                .and_then(|nvs| {
+                    nvs.get_u32(setting.key())
+                        .map_err(|e| log::error!("Failed to read {}: {:?}", setting.key(), e))
+                        .ok()
+                        .flatten()
+                })
+                .filter(|value| setting.range().contains(value))
+                .unwrap_or_else(|| setting.default_value());
+            Duration::from_secs(value.into())
+        };
+        let load_toggle = |toggle: AlarmToggle| {
+            nvs.is_some_and(|nvs| {
+                nvs.get_u8(toggle.key())
+                    .map_err(|e| log::error!("Failed to read {}: {:?}", toggle.key(), e))
+                    .ok()
+                    .flatten()
+                    == Some(1)
+            })
+        };
+        let mut buf = [0u8; 64];
+        // Falls back to the first profile if the stored one was removed from the config
+        let profile = nvs
+            .and_then(|nvs| {
+                nvs.get_str(NVS_PROFILE_KEY, &mut buf)
+                    .map_err(|e| log::error!("Failed to read arming profile: {:?}", e))
+                    .ok()
+                    .flatten()
+            })
+            .filter(|name| profiles.iter().any(|profile| profile.name == *name))
+            .map(str::to_string)
+            .or_else(|| profiles.first().map(|profile| profile.name.clone()));
+        let mut buf = vec![0u8; MAX_JSON_LEN];
+        let bypassed = nvs
+            .and_then(|nvs| {
+                nvs.get_str(NVS_BYPASSED_KEY, &mut buf)
+                    .map_err(|e| log::error!("Failed to read bypassed zones: {:?}", e))
+                    .ok()
+                    .flatten()
+            })
+            .and_then(|json| {
+                serde_json::from_str(json)
+                    .map_err(|e| log::error!("Invalid bypassed zones: {:?}", e))
+                    .ok()
+            })
+            .unwrap_or_default();
+        Self {
+            arming_timeout: load(AlarmSetting::arming_timeout),
+            pending_timeout: load(AlarmSetting::pending_timeout),
+            siren_timeout: load(AlarmSetting::siren_timeout),
+            chime: load_toggle(AlarmToggle::chime),
+            walk_test: false,
+            quiet_hours: load_toggle(AlarmToggle::quiet_hours),
+            auto_rearm: load_toggle(AlarmToggle::auto_rearm),
+            rearm_bypass: load_toggle(AlarmToggle::rearm_bypass),
+            bypassed,
+            profiles,
+            profile,
+        }
+    }
+
+    fn store_bypassed(&self, nvs: Option<&mut EspNvs<NvsDefault>>) {
+        let Some(nvs) = nvs else {
+            return;
+        };
+        serde_json::to_string(&self.bypassed)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(nvs.set_str(NVS_BYPASSED_KEY, &json)?))
+            .unwrap_or_else(|e| log::error!("Failed to persist bypassed zones: {:?}", e));
+    }
+
+    /// Zones monitored while armed with the selected profile, `None` if all of them are
+    fn profile_zones(&self) -> Option<Vec<String>> {
+        let name = self.profile.as_ref()?;
+        self.profiles
+            .iter()
+            .find(|profile| profile.name == *name)?
+            .zones
+            .clone()
+    }
+
+    fn get(&self, setting: AlarmSetting) -> u32 {
+        let duration = match setting {
+            AlarmSetting::arming_timeout => self.arming_timeout,
+            AlarmSetting::pending_timeout => self.pending_timeout,
+            AlarmSetting::siren_timeout => self.siren_timeout,
+        };
+        duration.as_secs() as u32
+    }
+
+    fn set(&mut self, setting: AlarmSetting, value: u32) {
+        let duration = Duration::from_secs(value.into());
+        match setting {
+            AlarmSetting::arming_timeout => self.arming_timeout = duration,
+            AlarmSetting::pending_timeout => self.pending_timeout = duration,
+            AlarmSetting::siren_timeout => self.siren_timeout = duration,
+        }
+    }
+
+    fn toggle(&mut self, toggle: AlarmToggle) -> &mut bool {
+        match toggle {
+            AlarmToggle::chime => &mut self.chime,
+            AlarmToggle::walk_test => &mut self.walk_test,
+            AlarmToggle::quiet_hours => &mut self.quiet_hours,
+            AlarmToggle::auto_rearm => &mut self.auto_rearm,
+            AlarmToggle::rearm_bypass => &mut self.rearm_bypass,
+        }
+    }
+
+    /// State of an entity which shows a setting
+    fn state(&self, entity: &HAEntity) -> Option<AlarmEvent> {
+        if let Some(setting) = entity.alarm_setting {
+            Some(AlarmEvent::SettingChanged((
+                entity.clone(),
+                self.get(setting).to_string(),
+            )))
+        } else if entity.arming_profiles.is_some() {
+            Some(AlarmEvent::SettingChanged((
+                entity.clone(),
+                self.profile.clone()?,
+            )))
+        } else if let Some(toggle) = entity.alarm_toggle {
+            let on = match toggle {
+                AlarmToggle::chime => self.chime,
+                AlarmToggle::walk_test => self.walk_test,
+                AlarmToggle::quiet_hours => self.quiet_hours,
+                AlarmToggle::auto_rearm => self.auto_rearm,
+                AlarmToggle::rearm_bypass => self.rearm_bypass,
+            };
+            Some(AlarmEvent::OutputStateChanged((entity.clone(), on)))
+        } else {
+            let on = self.bypassed.contains(entity.bypass_zone.as_ref()?);
+            Some(AlarmEvent::OutputStateChanged((entity.clone(), on)))
+        }
+    }
+}
+
+/// Timing settings as the alarm task loads them, the persisted values or the defaults
+pub fn timing_settings(nvs: &EspDefaultNvsPartition) -> [(AlarmSetting, u32); 3] {
+    let nvs = EspNvs::new(nvs.clone(), NVS_NAMESPACE, true)
+        .map_err(|e| log::error!("Failed to open alarm NVS namespace: {:?}", e))
+        .ok();
+    let settings = AlarmSettings::load(nvs.as_ref(), Vec::new());
+    AlarmSetting::ALL.map(|setting| (setting, settings.get(setting)))
+}
+
+/// Where an alarm command came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandSource {
+    /// A keypad on the CAN bus
+    Keypad,
+    Mqtt,
+    NativeApi,
+    /// Arming and disarming as people leave and arrive
+    Presence,
+    /// The weekly bell test
+    Schedule,
+}
+
+impl CommandSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandSource::Keypad => "keypad",
+            CommandSource::Mqtt => "mqtt",
+            CommandSource::NativeApi => "native_api",
+            CommandSource::Presence => "presence",
+            CommandSource::Schedule => "schedule",
+        }
+    }
+
+    /// Commands entered at the premises win over conflicting remote ones
+    fn is_local(&self) -> bool {
+        *self == CommandSource::Keypad
+    }
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum AlarmCommand {
     Arm,
+    ArmHome,
+    ArmNight,
     ArmInstantly,
-    Disarm,
+    /// Arms away even with active zones
+    ArmForce,
+    /// Disarm with the code entered, if any, every transport passes it on to be checked
+    Disarm(Option<String>),
     ManualTrigger,
     Untrigger,
+    /// Sounds the siren briefly, only while disarmed
+    BellTest,
+    /// Silences the fire alarm, a disarm doesn't
+    FireAck,
+    /// Triggers the alarm in every state
+    Panic,
+    /// Changes a timing setting, in seconds
+    UpdateSettings((AlarmSetting, u32)),
+    SetToggle((AlarmToggle, bool)),
+    /// Ignores a zone by its unique id, only while disarmed
+    Bypass((String, bool)),
+    /// Selects the arming profile by its name
+    SelectProfile(String),
+    /// Checks the zones and chirps the siren, only while disarmed
+    SelfTest,
+    /// Sounds the siren for a second, only while disarmed
+    SirenChirp,
+    /// Observes the zones to suggest their polarity, only while disarmed
+    LearnZones,
+    /// Applies the suggested polarity of the zones, only while disarmed
+    ConfirmLearnedZones,
+}
+
+impl AlarmCommand {
+    /// Command payload of the alarm entity
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlarmCommand::Arm => "ARM_AWAY",
+            AlarmCommand::ArmHome => "ARM_HOME",
+            AlarmCommand::ArmNight => "ARM_NIGHT",
+            AlarmCommand::ArmInstantly => "ARM_CUSTOM_BYPASS",
+            AlarmCommand::ArmForce => "ARM_FORCE",
+            AlarmCommand::Disarm(_) => "DISARM",
+            AlarmCommand::ManualTrigger => "TRIGGER",
+            AlarmCommand::Untrigger => "UNTRIGGER",
+            AlarmCommand::BellTest => "BELL_TEST",
+            AlarmCommand::FireAck => "FIRE_ACK",
+            AlarmCommand::Panic => "PANIC",
+            AlarmCommand::UpdateSettings(_) => "UPDATE_SETTINGS",
+            AlarmCommand::SetToggle(_) => "SET_TOGGLE",
+            AlarmCommand::Bypass(_) => "BYPASS",
+            AlarmCommand::SelectProfile(_) => "SELECT_PROFILE",
+            AlarmCommand::SelfTest => "SELF_TEST",
+            AlarmCommand::SirenChirp => "SIREN_CHIRP",
+            AlarmCommand::LearnZones => "LEARN_ZONES",
+            AlarmCommand::ConfirmLearnedZones => "CONFIRM_LEARNED_ZONES",
+        }
+    }
+
+    /// The command in the transition table, `None` if its transitions are fixed
+    fn transition(&self) -> Option<TransitionCommand> {
+        match self {
+            AlarmCommand::Arm | AlarmCommand::ArmForce => Some(TransitionCommand::arm_away),
+            AlarmCommand::ArmHome => Some(TransitionCommand::arm_home),
+            AlarmCommand::ArmNight => Some(TransitionCommand::arm_night),
+            AlarmCommand::ArmInstantly => Some(TransitionCommand::arm_custom_bypass),
+            AlarmCommand::ManualTrigger => Some(TransitionCommand::trigger),
+            AlarmCommand::Untrigger => Some(TransitionCommand::untrigger),
+            _ => None,
+        }
+    }
+
+    /// Reason of rejecting the command in the current state
+    fn rejection(&self, transitions: &TransitionTable) -> &'static str {
+        match self.transition() {
+            Some(command) if transitions.is_overridden(command) => {
+                "not allowed in this state by the transition table"
+            }
+            Some(TransitionCommand::trigger) => "alarm is not armed",
+            Some(TransitionCommand::untrigger) => "alarm is not triggered",
+            _ => "alarm is not disarmed",
+        }
+    }
+
+    /// Whether the command arms or triggers the alarm, `None` for commands which can't conflict
+    ///
+    /// Panic is left out, it is never overridden.
+    fn arms(&self) -> Option<bool> {
+        match self {
+            AlarmCommand::Arm
+            | AlarmCommand::ArmHome
+            | AlarmCommand::ArmNight
+            | AlarmCommand::ArmInstantly
+            | AlarmCommand::ArmForce
+            | AlarmCommand::ManualTrigger => Some(true),
+            AlarmCommand::Disarm(_) | AlarmCommand::Untrigger => Some(false),
+            _ => None,
+        }
+    }
+
+    fn conflicts_with(&self, other: &AlarmCommand) -> bool {
+        matches!((self.arms(), other.arms()), (Some(a), Some(b)) if a != b)
+    }
+
+    /// Command of a switch entity which controls the alarm instead of a relay
+    pub fn from_switch(entity: &HAEntity, on: bool) -> Option<Self> {
+        if let Some(toggle) = entity.alarm_toggle {
+            return Some(AlarmCommand::SetToggle((toggle, on)));
+        }
+        let zone = entity.bypass_zone.clone()?;
+        Some(AlarmCommand::Bypass((zone, on)))
+    }
+}
+
+/// Names of the active zones which the alarm would monitor when armed in the mode
+fn zones_not_ready(
+    motion_entities: &[AlarmMotionEntity],
+    settings: &AlarmSettings,
+    mode: ArmMode,
+) -> Vec<String> {
+    let profile_zones = settings.profile_zones();
+    let time = clock::is_synchronized().then(|| {
+        let (_, hour, minute) = clock::local_weekday_time(clock::unix_time());
+        (hour, minute)
+    });
+    motion_entities
+        .iter()
+        .filter(|e| e.motion && !settings.bypassed.contains(&e.entity.unique_id))
+        .filter(|e| {
+            matches!(
+                e.entity.zone_type.unwrap_or_default(),
+                ZoneType::fire | ZoneType::panic | ZoneType::tamper
+            ) || profile_zones
+                .as_ref()
+                .map_or(true, |zones| zones.contains(&e.entity.unique_id))
+        })
+        .filter(|e| {
+            e.entity
+                .arm_modes
+                .as_ref()
+                .map_or(true, |modes| modes.contains(&mode))
+        })
+        .filter(|e| {
+            !e.entity
+                .auto_bypass
+                .as_ref()
+                .is_some_and(|auto_bypass| auto_bypass.is_active(settings.profile.as_deref(), time))
+        })
+        .map(|e| e.entity.name.clone())
+        .collect()
+}
+
+/// Counts the trip of the alarm by the zone, by its name
+fn count_trip(motion_entities: &[AlarmMotionEntity], zone: &str) {
+    if let Some(e) = motion_entities.iter().find(|e| e.entity.name == zone) {
+        zone_counters::zone_tripped(&e.entity.unique_id);
+    }
 }
 
-pub fn alarm_task<T, MODE>(
-    event_queue: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<AlarmEvent>>>,
-    command_rx: Receiver<AlarmCommand>,
-    _nvs_default_partition: EspDefaultNvsPartition,
-    motion_entities: &mut [AlarmMotionEntity<T, MODE>],
+/// Temporal-3 pattern of fire alarms: three half second beeps, then a pause
+fn fire_siren_pattern(elapsed: Duration) -> bool {
+    ContactPattern::temporal.is_closed(elapsed)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn alarm_task(
+    event_queue: Arc<EventQueue>,
+    command_rx: Receiver<(CommandSource, AlarmCommand)>,
+    nvs_default_partition: EspDefaultNvsPartition,
+    motion_entities: &mut [AlarmMotionEntity],
     alarm_entity: HAEntity,
-    mut siren_pin: PinDriver<impl OutputPin, Output>,
-) -> !
-where
-    T: InputPin + OutputPin,
-    MODE: InputMode,
-{
-    // TODO: state persistence with NVS
-    //let nvs = EspNvs::new(nvs_default_partition, "alarm", true).unwrap();
+    mut siren: Box<dyn SirenOutput + Send>,
+    shared_state: Arc<Mutex<AlarmState>>,
+    mut follow_outputs: Vec<FollowOutput>,
+    mut dialer_outputs: Vec<DialerOutput>,
+    siren_entity: Option<HAEntity>,
+    setting_entities: Vec<HAEntity>,
+    transitions: TransitionTable,
+    quiet_hours: DailyWindow,
+    disarm_code: Option<String>,
+    clock: impl Clock,
+) -> ! {
+    // TODO: restore the persisted state on boot
+    let mut nvs = EspNvs::new(nvs_default_partition, NVS_NAMESPACE, true)
+        .map_err(|e| log::error!("Failed to open alarm NVS namespace: {:?}", e))
+        .ok();
     let mut alarm_state = AlarmState::Disarmed;
 
-    // TODO: make these configurable
-    const ARMING_TIMEOUT: Duration = Duration::from_secs(90);
-    const PENDING_TIMEOUT: Duration = Duration::from_secs(30);
-
-    // FIXME: a VecDeque is not suitable for emitting alarm events.
-    // We need a more sophisticated data structure that can handle
-    // only emitting the latest motion detected event for a given entity.
+    let profiles = setting_entities
+        .iter()
+        .find_map(|entity| entity.arming_profiles.clone())
+        .unwrap_or_default();
+    let mut settings = AlarmSettings::load(nvs.as_ref(), profiles);
+    // Zones removed from the config since they were bypassed
+    settings
+        .bypassed
+        .retain(|zone| motion_entities.iter().any(|e| e.entity.unique_id == *zone));
+    // Edges of the zones wake the task before the next scan is due
+    let notification = Notification::new();
+    for e in motion_entities.iter_mut() {
+        e.input
+            .watch(notification.notifier())
+            .unwrap_or_else(|err| log::error!("Failed to watch {}: {:?}", e.entity.name, err));
+    }
+    if let Some(nvs) = nvs.as_ref() {
+        let polarity = zone_learn::load_polarity(nvs).unwrap_or_else(|e| {
+            log::error!("Failed to load the learned zone polarity: {:?}", e);
+            Default::default()
+        });
+        for e in motion_entities.iter_mut() {
+            if let Some(inverted) = polarity.get(&e.entity.unique_id) {
+                Arc::make_mut(&mut e.entity).inverted = Some(*inverted);
+            }
+        }
+    }
+    event_queue.extend(
+        setting_entities
+            .iter()
+            .filter_map(|entity| settings.state(entity)),
+    );
+    const BELL_TEST_DURATION: Duration = Duration::from_millis(1500);
+    const CHIRP_DURATION: Duration = Duration::from_millis(200);
+    const SIREN_CHIRP_DURATION: Duration = Duration::from_secs(1);
+    // The zones are scanned this often, or sooner when an input changes
+    const SCAN_INTERVAL: Duration = Duration::from_millis(250);
+    const EDGE_SETTLE_TIME: Duration = Duration::from_millis(10);
+    // Conflicting commands closer than this are resolved by their source
+    const COMMAND_CONFLICT_WINDOW: Duration = Duration::from_secs(3);
+    let exit_delay_restart = env!("ESP_EXIT_DELAY_RESTART") == "true";
+    let silent_panic = env!("ESP_SILENT_PANIC") == "true";
+    let delay_chirps: Option<DelayChirpConfig> =
+        include!(concat!(env!("OUT_DIR"), "/delay_chirps.rs"));
+    // Last chirp of the current exit or entry delay
+    let mut last_delay_chirp: Option<Instant> = None;
+    // Set while the alarm is triggered by a silent panic
+    let mut silenced = false;
+    let mut bell_test_start: Option<Instant> = None;
+    let mut chirp_end: Option<Instant> = None;
+    // Zone which raised the fire alarm and when
+    let mut fire_alarm: Option<(String, Instant)> = None;
+    let mut siren_on = false;
+    // What the siren sounds and since when, for its pattern
+    let mut sound: Option<(SirenSound, Instant)> = None;
+    // When the alarm was last triggered, for the siren timeout
+    let mut triggered_at = None;
+    // Zones of the profile the alarm was armed with, `None` if all of them are monitored
+    let mut armed_zones: Option<Vec<String>> = None;
+    // Mode of the last arm, the alarm returns to it when untriggered
+    let mut arm_mode = ArmMode::away;
+    // Zone which started the pending state or triggered the alarm
+    let mut tripped_by: Option<String> = None;
+    // Whether the alarm was armed before it triggered, only then it is re-armed automatically
+    let mut rearm = false;
+    let dual_disarm = alarm_entity.dual_disarm.clone();
+    // A dual disarm takes its own codes
+    let disarm_code = disarm_code.filter(|_| dual_disarm.is_none());
+    // First code of a dual disarm and when it was entered, the siren is silent meanwhile
+    let mut partial_disarm: Option<(String, Instant)> = None;
+    // Entry delay of the zone which started the pending state
+    let mut entry_delay = settings.pending_timeout;
+    // Profile the alarm was armed with, for the automatic bypasses
+    let mut armed_profile: Option<String> = None;
+    // Commands received but not processed yet, local ones are processed first
+    let mut pending_commands: Vec<(CommandSource, AlarmCommand)> = Vec::new();
+    // The last accepted command which arms or disarms, for resolving conflicts
+    let mut last_command: Option<(Instant, CommandSource, AlarmCommand)> = None;
+    // Observes the raw levels of the zones while learning them
+    let mut learner: Option<ZoneLearner> = None;
+    // Since when each zone reads a level other than its state, for the debounce and the
+    // clear_after time
+    let mut changing_since: Vec<Option<Instant>> = vec![None; motion_entities.len()];
 
     loop {
-        let mut motion_detected = false;
-        for e in motion_entities.iter_mut() {
-            let motion = e.pin_driver.is_high();
+        let now = clock.now();
+        // Real time, the clock can be driven artificially
+        let scan_start = Instant::now();
+        // Zones which were opened or closed in this iteration
+        let mut opened = Vec::new();
+        let mut closed = Vec::new();
+        for (index, e) in motion_entities.iter_mut().enumerate() {
+            let edge = e.input.take_edge();
+            let high = e.input.is_active();
+            if let Some(learner) = learner.as_mut() {
+                learner.sample(index, high, now);
+            }
+            let mut motion = high != e.entity.inverted.unwrap_or(false);
+            // A pulse shorter than a scan still opens the zone until the next one, unless
+            // the zone is debounced
+            if edge && !motion && !e.motion && e.entity.debounce.is_none() {
+                motion = true;
+            }
             if motion == e.motion {
+                changing_since[index] = None;
                 continue;
             }
+            // A cleared zone is held active for its clear_after time
+            let hold = match motion {
+                true => Duration::ZERO,
+                false => Duration::from_secs(e.entity.clear_after.unwrap_or(0)),
+            };
+            let stable_for = hold.max(Duration::from_millis(e.entity.debounce.unwrap_or(0)));
+            if !stable_for.is_zero() {
+                let since = *changing_since[index].get_or_insert(now);
+                if now.duration_since(since) < stable_for {
+                    continue;
+                }
+            }
+            changing_since[index] = None;
 
-            log::info!("Motion at {}: {}", e.entity.name, motion);
+            log::info!(
+                "{}: {}",
+                e.entity.name,
+                e.entity.zone_kind.unwrap_or_default().event_name(motion)
+            );
             e.motion = motion;
-            let mut queue = event_queue.lock().unwrap();
+            let zone = (
+                e.entity.name.clone(),
+                e.entity.zone_type.unwrap_or_default(),
+            );
+            // Bypassed zones and zones outside the arming profile or the arm mode are still reported,
+            // but the alarm ignores them
+            let outside_profile = alarm_state != AlarmState::Disarmed
+                && !matches!(zone.1, ZoneType::fire | ZoneType::panic | ZoneType::tamper)
+                && armed_zones
+                    .as_ref()
+                    .is_some_and(|zones| !zones.contains(&e.entity.unique_id));
+            let outside_mode = alarm_state != AlarmState::Disarmed
+                && e.entity
+                    .arm_modes
+                    .as_ref()
+                    .is_some_and(|modes| !modes.contains(&arm_mode));
+            let auto_bypassed = alarm_state != AlarmState::Disarmed
+                && e.entity.auto_bypass.as_ref().is_some_and(|auto_bypass| {
+                    let time = clock::is_synchronized().then(|| {
+                        let (_, hour, minute) = clock::local_weekday_time(clock::unix_time());
+                        (hour, minute)
+                    });
+                    auto_bypass.is_active(armed_profile.as_deref(), time)
+                });
+            let bypassed = outside_profile
+                || outside_mode
+                || auto_bypassed
+                || settings.bypassed.contains(&e.entity.unique_id);
             if motion {
-                motion_detected = true;
-                queue.push_back(AlarmEvent::MotionDetected(e.entity.clone()));
+                if !bypassed {
+                    opened.push(zone);
+                }
+                event_queue.push(AlarmEvent::MotionDetected(e.entity.clone()));
             } else {
-                queue.push_back(AlarmEvent::MotionCleared(e.entity.clone()));
+                if !bypassed {
+                    closed.push(zone);
+                }
+                event_queue.push(AlarmEvent::MotionCleared(e.entity.clone()));
+            }
+        }
+
+        let learning_done = learner.as_ref().is_some_and(|learner| learner.is_done(now));
+        if let Some(done) = learning_done.then(|| learner.take()).flatten() {
+            let learned = done.finish(motion_entities);
+            log::info!("Learned {} zones, waiting for confirmation", learned.len());
+            if let Some(nvs) = nvs.as_mut() {
+                zone_learn::store_learned(nvs, &learned)
+                    .unwrap_or_else(|e| log::error!("Failed to store the learned zones: {:?}", e));
             }
+            event_queue.push(AlarmEvent::ZonesLearned((alarm_entity.clone(), learned)));
+        }
+
+        // Evaluated here, so outputs keep following their zones while MQTT is down
+        for output in follow_outputs.iter_mut() {
+            output.update(motion_entities, now);
         }
 
         let last_state = alarm_state.clone();
+        let mut changed_by = String::new();
 
-        match command_rx.try_recv() {
-            Ok(command) => match command {
-                AlarmCommand::Arm => {
-                    if alarm_state == AlarmState::Disarmed {
-                        alarm_state = AlarmState::Arming(Instant::now());
-                    }
+        loop {
+            match command_rx.try_recv() {
+                Ok(command) => pending_commands.push(command),
+                Err(std::sync::mpsc::TryRecvError::Empty) => break,
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    panic!("command_rx disconnected")
+                }
+            }
+        }
+        let next_command = pending_commands
+            .iter()
+            .position(|(source, _)| source.is_local())
+            .or((!pending_commands.is_empty()).then_some(0));
+        if let Some(index) = next_command {
+            let (source, command) = pending_commands.remove(index);
+            // Of a local and a remote command conflicting shortly after each other, the local one wins
+            let conflict = last_command.as_ref().filter(|(at, last_source, last)| {
+                now.duration_since(*at) < COMMAND_CONFLICT_WINDOW
+                    && last.conflicts_with(&command)
+                    && last_source.is_local() != source.is_local()
+            });
+            if let Some((_, last_source, last)) = conflict {
+                let (winner, loser) = if last_source.is_local() {
+                    ((*last_source, last.clone()), (source, command.clone()))
+                } else {
+                    ((source, command.clone()), (*last_source, last.clone()))
+                };
+                log::warn!(
+                    "{} from {} conflicts with {} from {}, the {} command wins",
+                    command.name(),
+                    source.name(),
+                    last.name(),
+                    last_source.name(),
+                    winner.0.name()
+                );
+                event_queue.push(AlarmEvent::CommandConflict((winner, loser)));
+            }
+            let overridden = conflict.is_some_and(|(_, last_source, _)| last_source.is_local());
+            let allowed = command.transition().map_or(true, |transition| {
+                transitions
+                    .allowed_states(transition)
+                    .contains(&alarm_state.name())
+            });
+            // Active zones which would be monitored, only a forced arm ignores them
+            let not_ready = match command {
+                AlarmCommand::Arm | AlarmCommand::ArmInstantly => {
+                    zones_not_ready(motion_entities, &settings, ArmMode::away)
+                }
+                AlarmCommand::ArmHome => zones_not_ready(motion_entities, &settings, ArmMode::home),
+                AlarmCommand::ArmNight => {
+                    zones_not_ready(motion_entities, &settings, ArmMode::night)
+                }
+                _ => Vec::new(),
+            };
+            let result = match command {
+                _ if overridden => Err("overridden by a local command"),
+                _ if !allowed => Err(command.rejection(&transitions)),
+                AlarmCommand::Arm
+                | AlarmCommand::ArmHome
+                | AlarmCommand::ArmNight
+                | AlarmCommand::ArmInstantly
+                | AlarmCommand::ArmForce
+                    if settings.walk_test =>
+                {
+                    Err("walk test is running")
+                }
+                _ if !not_ready.is_empty() => {
+                    log::warn!("Not ready to arm, active zones: {}", not_ready.join(", "));
+                    Err("zones are active, ARM_FORCE arms anyway")
+                }
+                AlarmCommand::Arm
+                | AlarmCommand::ArmHome
+                | AlarmCommand::ArmNight
+                | AlarmCommand::ArmForce => {
+                    arm_mode = match command {
+                        AlarmCommand::ArmHome => ArmMode::home,
+                        AlarmCommand::ArmNight => ArmMode::night,
+                        _ => ArmMode::away,
+                    };
+                    alarm_state = AlarmState::Arming((now, arm_mode));
+                    armed_zones = settings.profile_zones();
+                    armed_profile.clone_from(&settings.profile);
+                    Ok(())
                 }
                 AlarmCommand::ArmInstantly => {
-                    if alarm_state == AlarmState::Disarmed {
-                        alarm_state = AlarmState::Armed(Instant::now());
-                    }
+                    arm_mode = ArmMode::away;
+                    alarm_state = AlarmState::Armed((now, arm_mode));
+                    armed_zones = settings.profile_zones();
+                    armed_profile.clone_from(&settings.profile);
+                    Ok(())
                 }
-                AlarmCommand::Disarm => {
+                AlarmCommand::Disarm(None) if dual_disarm.is_some() => {
+                    Err("two codes are required")
+                }
+                AlarmCommand::Disarm(ref code) if disarm_code.is_some() && *code != disarm_code => {
+                    Err("invalid code")
+                }
+                AlarmCommand::Disarm(None) => {
                     alarm_state = AlarmState::Disarmed;
+                    Ok(())
                 }
+                AlarmCommand::Disarm(Some(ref code)) => match dual_disarm.as_ref() {
+                    Some(dual_disarm) if !dual_disarm.codes.contains(code) => Err("invalid code"),
+                    Some(_) if alarm_state == AlarmState::Disarmed => Ok(()),
+                    Some(_) if partial_disarm.is_none() => {
+                        log::info!("Partially disarmed, waiting for a second code");
+                        partial_disarm = Some((code.clone(), now));
+                        event_queue.push(AlarmEvent::PartialDisarmChanged((
+                            alarm_entity.clone(),
+                            alarm_state.clone(),
+                            true,
+                        )));
+                        Ok(())
+                    }
+                    Some(_)
+                        if partial_disarm
+                            .as_ref()
+                            .is_some_and(|(first, _)| first == code) =>
+                    {
+                        Err("a second, different code is required")
+                    }
+                    _ => {
+                        alarm_state = AlarmState::Disarmed;
+                        Ok(())
+                    }
+                },
                 AlarmCommand::ManualTrigger => {
-                    if let AlarmState::Armed(_) = alarm_state {
-                        alarm_state = AlarmState::Triggered;
+                    alarm_state = AlarmState::Triggered;
+                    Ok(())
+                }
+                AlarmCommand::Untrigger => {
+                    alarm_state = AlarmState::Armed((now, arm_mode));
+                    Ok(())
+                }
+                AlarmCommand::BellTest if alarm_state != AlarmState::Disarmed => {
+                    Err("alarm is not disarmed")
+                }
+                AlarmCommand::BellTest if bell_test_start.is_some() => {
+                    Err("bell test is already running")
+                }
+                AlarmCommand::BellTest => {
+                    log::info!("Bell test started");
+                    bell_test_start = Some(now);
+                    Ok(())
+                }
+                AlarmCommand::SelfTest
+                | AlarmCommand::SirenChirp
+                | AlarmCommand::LearnZones
+                | AlarmCommand::ConfirmLearnedZones
+                    if alarm_state != AlarmState::Disarmed =>
+                {
+                    Err("alarm is not disarmed")
+                }
+                AlarmCommand::SelfTest => {
+                    let open_zones = motion_entities
+                        .iter()
+                        .filter(|e| e.motion)
+                        .map(|e| e.entity.name.as_str())
+                        .collect::<Vec<_>>();
+                    log::info!("Self-test, open zones: {:?}", open_zones);
+                    // Lets the user hear that the siren works
+                    chirp_end = Some(now + CHIRP_DURATION);
+                    if nvs.is_none() {
+                        Err("alarm NVS namespace is unavailable")
+                    } else if fire_alarm.is_some() {
+                        Err("fire alarm is active")
+                    } else if !open_zones.is_empty() {
+                        Err("zones are open")
+                    } else {
+                        Ok(())
                     }
                 }
-                AlarmCommand::Untrigger => match alarm_state {
-                    AlarmState::Triggered | AlarmState::Pending(_) => {
-                        alarm_state = AlarmState::Armed(Instant::now());
+                AlarmCommand::SirenChirp => {
+                    chirp_end = Some(now + SIREN_CHIRP_DURATION);
+                    Ok(())
+                }
+                AlarmCommand::LearnZones if nvs.is_none() => {
+                    Err("alarm NVS namespace is unavailable")
+                }
+                AlarmCommand::LearnZones if learner.is_some() => {
+                    Err("zones are already being learned")
+                }
+                AlarmCommand::LearnZones => {
+                    log::info!("Learning the zones for {:?}", zone_learn::LEARN_DURATION);
+                    learner = Some(ZoneLearner::new(motion_entities.len(), now));
+                    Ok(())
+                }
+                AlarmCommand::ConfirmLearnedZones => match nvs.as_mut() {
+                    None => Err("alarm NVS namespace is unavailable"),
+                    Some(nvs) => match zone_learn::confirm_learned(nvs) {
+                        Err(e) => {
+                            log::error!("Failed to confirm the learned zones: {:?}", e);
+                            Err("failed to store the learned zones")
+                        }
+                        Ok(learned) if learned.is_empty() => Err("there are no learned zones"),
+                        Ok(learned) => {
+                            for zone in learned.iter() {
+                                let entity = motion_entities
+                                    .iter_mut()
+                                    .find(|e| e.entity.unique_id == zone.unique_id);
+                                if let Some(e) = entity {
+                                    Arc::make_mut(&mut e.entity).inverted = Some(zone.inverted);
+                                }
+                            }
+                            log::info!("Confirmed the polarity of {} zones", learned.len());
+                            event_queue
+                                .push(AlarmEvent::ZonesLearned((alarm_entity.clone(), Vec::new())));
+                            Ok(())
+                        }
+                    },
+                },
+                AlarmCommand::FireAck => match fire_alarm.take() {
+                    Some((zone, _)) => {
+                        log::info!("Fire alarm of {} acknowledged", zone);
+                        event_queue
+                            .push(AlarmEvent::FireAlarmChanged((alarm_entity.clone(), None)));
+                        Ok(())
                     }
-                    _ => {}
+                    None => Err("there is no fire alarm"),
                 },
-            },
-            Err(e) => {
-                if e == std::sync::mpsc::TryRecvError::Disconnected {
-                    panic!("command_rx disconnected");
+                AlarmCommand::Panic => {
+                    if alarm_state != AlarmState::Triggered {
+                        alarm_state = AlarmState::Triggered;
+                        silenced = silent_panic;
+                    }
+                    Ok(())
+                }
+                AlarmCommand::UpdateSettings(_)
+                | AlarmCommand::SetToggle((
+                    AlarmToggle::auto_rearm | AlarmToggle::rearm_bypass,
+                    _,
+                )) if Settings::locked(&alarm_state) => Err(LOCKED_REASON),
+                AlarmCommand::UpdateSettings((setting, value))
+                    if !setting.range().contains(&value) =>
+                {
+                    Err("setting is out of range")
+                }
+                AlarmCommand::UpdateSettings((setting, value)) => {
+                    log::info!("{} set to {}s", setting.key(), value);
+                    settings.set(setting, value);
+                    if let Some(nvs) = nvs.as_ref() {
+                        nvs.set_u32(setting.key(), value).unwrap_or_else(|e| {
+                            log::error!("Failed to persist {}: {:?}", setting.key(), e);
+                        });
+                    }
+                    Ok(())
+                }
+                AlarmCommand::SetToggle((AlarmToggle::walk_test, true))
+                    if alarm_state != AlarmState::Disarmed =>
+                {
+                    Err("alarm is not disarmed")
+                }
+                AlarmCommand::SetToggle((toggle, on)) => {
+                    log::info!("{}: {}", toggle.key(), on);
+                    *settings.toggle(toggle) = on;
+                    // A reboot ends the walk test
+                    if let (true, Some(nvs)) = (toggle != AlarmToggle::walk_test, nvs.as_ref()) {
+                        nvs.set_u8(toggle.key(), on.into()).unwrap_or_else(|e| {
+                            log::error!("Failed to persist {}: {:?}", toggle.key(), e);
+                        });
+                    }
+                    Ok(())
+                }
+                AlarmCommand::Bypass(_) if alarm_state != AlarmState::Disarmed => {
+                    Err("alarm is not disarmed")
                 }
+                AlarmCommand::Bypass((ref zone, _))
+                    if !motion_entities.iter().any(|e| e.entity.unique_id == *zone) =>
+                {
+                    Err("unknown zone")
+                }
+                AlarmCommand::SelectProfile(ref name)
+                    if !settings
+                        .profiles
+                        .iter()
+                        .any(|profile| profile.name == *name) =>
+                {
+                    Err("unknown profile")
+                }
+                AlarmCommand::SelectProfile(ref name) => {
+                    log::info!("Arming profile {} selected", name);
+                    settings.profile = Some(name.clone());
+                    if let Some(nvs) = nvs.as_mut() {
+                        nvs.set_str(NVS_PROFILE_KEY, name).unwrap_or_else(|e| {
+                            log::error!("Failed to persist arming profile: {:?}", e);
+                        });
+                    }
+                    Ok(())
+                }
+                AlarmCommand::Bypass((ref zone, bypass)) => {
+                    log::info!("Bypass of {}: {}", zone, bypass);
+                    if bypass {
+                        settings.bypassed.insert(zone.clone());
+                    } else {
+                        settings.bypassed.remove(zone);
+                    }
+                    settings.store_bypassed(nvs.as_mut());
+                    Ok(())
+                }
+            };
+            if let Err(reason) = result {
+                log::warn!("Rejected alarm command {}: {}", command.name(), reason);
+            } else if command.arms().is_some() {
+                last_command = Some((now, source, command.clone()));
+            }
+            if matches!(
+                command,
+                AlarmCommand::UpdateSettings(_)
+                    | AlarmCommand::SetToggle(_)
+                    | AlarmCommand::Bypass(_)
+                    | AlarmCommand::SelectProfile(_)
+            ) {
+                // Also sent for rejected changes, so switches flip back in HA
+                event_queue.extend(
+                    setting_entities
+                        .iter()
+                        .filter_map(|entity| settings.state(entity)),
+                );
             }
+            event_queue.push(AlarmEvent::CommandResult((source, command, result)));
+        }
+        if alarm_state != last_state {
+            changed_by = "command".to_string();
         }
 
-        match alarm_state {
-            AlarmState::Disarmed => {}
-            AlarmState::Arming(start) => {
-                if start.elapsed() >= ARMING_TIMEOUT {
-                    alarm_state = AlarmState::Armed(Instant::now());
-                }
+        if bell_test_start.is_some_and(|start| now.duration_since(start) >= BELL_TEST_DURATION) {
+            bell_test_start = None;
+            log::info!("Bell test finished");
+        }
+
+        // Only the chirps of opened zones are silenced, not the siren
+        let quiet = settings.quiet_hours && clock::is_synchronized() && {
+            let (_, hour, minute) = clock::local_weekday_time(clock::unix_time());
+            quiet_hours.contains(hour, minute)
+        };
+        if alarm_state == AlarmState::Disarmed
+            && !quiet
+            && opened
+                .iter()
+                .any(|(_, t)| settings.walk_test || (settings.chime && *t == ZoneType::delayed))
+        {
+            chirp_end = Some(now + CHIRP_DURATION);
+        }
+        if chirp_end.is_some_and(|end| now >= end) {
+            chirp_end = None;
+        }
+
+        // Fire zones are monitored regardless of the alarm state
+        if let Some((zone, _)) = opened.iter().find(|(_, t)| *t == ZoneType::fire) {
+            if fire_alarm.is_none() {
+                log::warn!("Fire alarm raised by {}", zone);
+                fire_alarm = Some((zone.clone(), now));
+                event_queue.push(AlarmEvent::FireAlarmChanged((
+                    alarm_entity.clone(),
+                    Some(zone.clone()),
+                )));
             }
-            AlarmState::Armed(_start) => {
-                if motion_detected {
-                    alarm_state = AlarmState::Pending(Instant::now());
+        }
+
+        if let Some((zone, _)) = opened.iter().find(|(_, t)| *t == ZoneType::panic) {
+            if alarm_state != AlarmState::Triggered {
+                log::warn!("Panic raised by {}", zone);
+                alarm_state = AlarmState::Triggered;
+                silenced = silent_panic;
+                changed_by = format!("panic: {}", zone);
+                tripped_by = Some(zone.clone());
+                count_trip(motion_entities, zone);
+            }
+        }
+
+        if let Some((zone, _)) = opened.iter().find(|(_, t)| *t == ZoneType::tamper) {
+            if alarm_state != AlarmState::Triggered {
+                log::warn!("Tamper detected by {}", zone);
+                alarm_state = AlarmState::Triggered;
+                silenced = false;
+                changed_by = format!("tamper: {}", zone);
+                tripped_by = Some(zone.clone());
+                count_trip(motion_entities, zone);
+            }
+        }
+
+        // The most severe action of the opened zones, fire, panic and tamper zones are handled above
+        let zone_action = opened
+            .iter()
+            .filter(|(_, t)| !matches!(t, ZoneType::fire | ZoneType::panic | ZoneType::tamper))
+            .map(|(zone, t)| (transitions.zone_action(alarm_state.name(), *t), zone))
+            .filter(|(action, _)| *action != ZoneAction::ignore)
+            .max_by_key(|(action, _)| *action);
+
+        let timer_lengths = TimerLengths {
+            exit_delay: settings.arming_timeout,
+            entry_delay,
+            siren_timeout: settings.siren_timeout,
+        };
+        // Closing a delayed zone restarts the exit delay, like someone still leaving
+        let restarted_by = closed
+            .iter()
+            .find(|(_, t)| exit_delay_restart && *t == ZoneType::delayed)
+            .map(|(zone, _)| zone.as_str());
+        let next = next_state(
+            &alarm_state,
+            zone_action.map(|(action, zone)| (action, zone.as_str())),
+            restarted_by,
+            &timer_lengths,
+            &clock,
+        );
+        if let Some((next, change)) = next {
+            match change {
+                StateChange::ZoneTriggered(zone) => {
+                    if alarm_state != AlarmState::Triggered {
+                        count_trip(motion_entities, zone);
+                    }
+                    changed_by = zone.to_string();
+                    tripped_by = Some(zone.to_string());
+                }
+                // Like real panels, don't arm with someone still inside
+                StateChange::ArmingAborted(zone) => {
+                    log::warn!("Arming aborted, {} opened during the exit delay", zone);
+                    changed_by = format!("{} opened during the exit delay", zone);
+                    // Arming is only started by a command, which is the last one accepted
+                    if let Some((_, source, command)) = &last_command {
+                        event_queue.push(AlarmEvent::CommandResult((
+                            *source,
+                            command.clone(),
+                            Err("arming aborted by a zone"),
+                        )));
+                    }
+                }
+                StateChange::ExitDelayRestarted(zone) => {
+                    log::info!("Exit delay restarted, {} was closed", zone);
+                    changed_by = format!("{} closed during the exit delay", zone);
+                }
+                StateChange::ExitDelayElapsed => changed_by = "exit_delay".to_string(),
+                StateChange::EntryDelayStarted(zone) => {
+                    changed_by = zone.to_string();
+                    tripped_by = Some(zone.to_string());
+                    count_trip(motion_entities, zone);
+                    entry_delay = motion_entities
+                        .iter()
+                        .find(|e| e.entity.name == zone)
+                        .and_then(|e| e.entity.entry_delay)
+                        .map(Duration::from_secs)
+                        .unwrap_or(settings.pending_timeout);
                 }
+                StateChange::EntryDelayElapsed => changed_by = "entry_delay".to_string(),
             }
-            AlarmState::Pending(start) => {
-                if start.elapsed() >= PENDING_TIMEOUT {
-                    alarm_state = AlarmState::Triggered;
+            alarm_state = next;
+        }
+
+        let delay = match (&alarm_state, delay_chirps.as_ref()) {
+            (AlarmState::Arming((start, _)), Some(chirps)) if chirps.exit => {
+                Some((chirps, *start, settings.arming_timeout))
+            }
+            (AlarmState::Pending(start), Some(chirps)) if chirps.entry => {
+                Some((chirps, *start, entry_delay))
+            }
+            _ => None,
+        };
+        if let Some((chirps, start, length)) = delay {
+            let remaining = length.saturating_sub(now.duration_since(start));
+            let fraction = if length.is_zero() {
+                0.0
+            } else {
+                remaining.as_secs_f64() / length.as_secs_f64()
+            };
+            let interval = Duration::from_millis(chirps.end_interval)
+                + Duration::from_millis(chirps.start_interval - chirps.end_interval)
+                    .mul_f64(fraction);
+            if last_delay_chirp.map_or(true, |last| now.duration_since(last) >= interval) {
+                last_delay_chirp = Some(now);
+                chirp_end = Some(now + CHIRP_DURATION);
+            }
+        } else {
+            last_delay_chirp = None;
+        }
+
+        let window = Duration::from_secs(
+            dual_disarm
+                .as_ref()
+                .and_then(|dual_disarm| dual_disarm.window)
+                .unwrap_or(60),
+        );
+        if alarm_state == AlarmState::Disarmed {
+            partial_disarm = None;
+        } else if partial_disarm
+            .as_ref()
+            .is_some_and(|(_, start)| now.duration_since(*start) >= window)
+        {
+            log::warn!("No second code was entered, the partial disarm expired");
+            partial_disarm = None;
+            event_queue.push(AlarmEvent::PartialDisarmChanged((
+                alarm_entity.clone(),
+                alarm_state.clone(),
+                false,
+            )));
+        }
+
+        if alarm_state == AlarmState::Triggered && last_state != AlarmState::Triggered {
+            rearm = matches!(last_state, AlarmState::Armed(_) | AlarmState::Pending(_));
+        }
+        let siren_timed_out = triggered_at.is_some_and(|start| {
+            timer_transition(Timer::Siren(start), &timer_lengths, &clock).is_some()
+        });
+        let rearm_due = rearm && settings.auto_rearm && siren_timed_out;
+        if rearm_due {
+            let zone = tripped_by.as_ref().and_then(|name| {
+                motion_entities
+                    .iter()
+                    .find(|e| e.entity.name == *name)
+                    .map(|e| e.entity.unique_id.clone())
+            });
+            match zone {
+                Some(zone) if settings.rearm_bypass => {
+                    log::warn!("Siren timed out, re-arming without {}", zone);
+                    settings.bypassed.insert(zone.clone());
+                    settings.store_bypassed(nvs.as_mut());
+                    event_queue.extend(
+                        setting_entities
+                            .iter()
+                            .filter(|entity| entity.bypass_zone.as_ref() == Some(&zone))
+                            .filter_map(|entity| settings.state(entity)),
+                    );
                 }
+                _ => log::warn!("Siren timed out, re-arming"),
             }
-            AlarmState::Triggered => {
-                siren_pin.set_high().unwrap_or_else(|e| {
-                    log::error!("Failed to set siren pin high: {:?}", e);
-                });
+            alarm_state = AlarmState::Armed((now, arm_mode));
+            changed_by = "auto_rearm".to_string();
+        }
+        if alarm_state != AlarmState::Triggered {
+            silenced = false;
+            triggered_at = None;
+            rearm = false;
+        } else if triggered_at.is_none() {
+            triggered_at = Some(now);
+        }
+        if matches!(alarm_state, AlarmState::Disarmed | AlarmState::Arming(_)) {
+            tripped_by = None;
+        }
+        let siren_timed_out = triggered_at.is_some_and(|start| {
+            timer_transition(Timer::Siren(start), &timer_lengths, &clock).is_some()
+        });
+        let alarm_sound = (alarm_state == AlarmState::Triggered
+            && !silenced
+            && partial_disarm.is_none()
+            && !siren_timed_out)
+            || bell_test_start.is_some()
+            || fire_alarm
+                .as_ref()
+                .is_some_and(|(_, start)| fire_siren_pattern(now.duration_since(*start)));
+        let current_sound = if alarm_sound {
+            Some(SirenSound::Alarm)
+        } else {
+            chirp_end.map(|_| SirenSound::Chirp)
+        };
+        if current_sound != sound.map(|(sound, _)| sound) {
+            sound = current_sound.map(|sound| (sound, now));
+        }
+        siren
+            .set(
+                current_sound,
+                sound.map_or(Duration::ZERO, |(_, start)| now.duration_since(start)),
+            )
+            .unwrap_or_else(|e| log::error!("Failed to drive siren: {:?}", e));
+        for output in dialer_outputs.iter_mut() {
+            output.update(
+                alarm_state == AlarmState::Triggered,
+                fire_alarm.is_some(),
+                now,
+            );
+        }
+        let siren = current_sound.is_some();
+        if siren != siren_on {
+            siren_on = siren;
+            if let Some(entity) = siren_entity.as_ref() {
+                if siren {
+                    event_queue.signal(Critical::Siren);
+                }
+                event_queue.push(AlarmEvent::OutputStateChanged((entity.clone(), siren)));
             }
         }
 
         if last_state != alarm_state {
+            // Before anything which allocates, the scheduler publishes it as soon as it can
+            if alarm_state == AlarmState::Triggered {
+                event_queue.signal(Critical::Triggered);
+            }
             log::info!("Alarm state changed: {:?}", alarm_state);
-
-            if last_state == AlarmState::Triggered {
-                siren_pin.set_low().unwrap_or_else(|e| {
-                    log::error!("Failed to set siren pin low: {:?}", e);
-                });
+            *shared_state.lock_recover() = alarm_state.clone();
+            if let Some(nvs) = nvs.as_ref() {
+                nvs.set_u8(NVS_STATE_KEY, alarm_state.nvs_code())
+                    .unwrap_or_else(|e| {
+                        log::error!("Failed to persist alarm state: {:?}", e);
+                    });
             }
 
-            let mut queue = event_queue.lock().unwrap();
-            queue.push_back(AlarmEvent::AlarmStateChanged((
+            event_queue.push(AlarmEvent::AlarmStateChanged((
                 alarm_entity.clone(),
                 alarm_state.clone(),
+                changed_by,
             )));
+            // Still reported as partially disarmed in the new state
+            if partial_disarm.is_some() {
+                event_queue.push(AlarmEvent::PartialDisarmChanged((
+                    alarm_entity.clone(),
+                    alarm_state.clone(),
+                    true,
+                )));
+            }
         }
 
-        std::thread::sleep(std::time::Duration::from_millis(250));
+        timing_stats::record(
+            TimingStat::scan_time,
+            scan_start.elapsed().as_micros() as u32,
+        );
+        if notification
+            .wait(TickType::from(SCAN_INTERVAL).ticks())
+            .is_some()
+        {
+            // Lets the edges of a bouncing contact settle, it also limits how often a noisy
+            // line wakes the task
+            std::thread::sleep(EDGE_SETTLE_TIME);
+        }
     }
 }
@@ -0,0 +1,214 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use esp_idf_sys::*;
+use ha_types::ArmMode;
+use serde_json::json;
+
+use crate::clock::{self, unix_time};
+use crate::{AlarmEvent, AlarmState};
+
+pub const MOUNT_POINT: &str = "/sdcard";
+
+const SDMMC_HOST_FLAG_SPI: u32 = 1 << 3;
+
+const FETCH_CHUNK_SIZE: usize = 4096;
+
+/// Mounts a FAT formatted card on the already initialized SPI bus of the ethernet controller
+pub fn mount(cs_pin: u8) -> anyhow::Result<()> {
+    let host = sdmmc_host_t {
+        flags: SDMMC_HOST_FLAG_SPI,
+        slot: spi_host_device_t_SPI2_HOST as _,
+        max_freq_khz: SDMMC_FREQ_DEFAULT as _,
+        io_voltage: 3.3,
+        init: Some(sdspi_host_init),
+        set_card_clk: Some(sdspi_host_set_card_clk),
+        do_transaction: Some(sdspi_host_do_transaction),
+        io_int_enable: Some(sdspi_host_io_int_enable),
+        io_int_wait: Some(sdspi_host_io_int_wait),
+        command_timeout_ms: 0,
+        get_real_freq: Some(sdspi_host_get_real_freq),
+        ..Default::default()
+    };
+    let slot = sdspi_device_config_t {
+        host_id: spi_host_device_t_SPI2_HOST,
+        gpio_cs: cs_pin as _,
+        gpio_cd: -1,
+        gpio_wp: -1,
+        gpio_int: -1,
+        ..Default::default()
+    };
+    let mount_config = esp_vfs_fat_mount_config_t {
+        format_if_mount_failed: false,
+        max_files: 4,
+        allocation_unit_size: 16 * 1024,
+        ..Default::default()
+    };
+
+    let base_path = CString::new(MOUNT_POINT)?;
+    let mut card: *mut sdmmc_card_t = std::ptr::null_mut();
+    // SAFETY: all configs outlive the call, the card handle is kept by the VFS
+    esp!(unsafe {
+        esp_vfs_fat_sdspi_mount(base_path.as_ptr(), &host, &slot, &mount_config, &mut card)
+    })?;
+    log::info!("SD card mounted at {}", MOUNT_POINT);
+    Ok(())
+}
+
+/// Name of the archive file of the current day, files have 8.3 names to not need LFN support
+fn day_file_name(time: u64) -> String {
+    if time < clock::MIN_VALID_TIME {
+        return "UNSYNCED.LOG".to_string();
+    }
+
+    let (year, month, day) = clock::civil_date(time);
+    format!("{:04}{:02}{:02}.LOG", year, month, day)
+}
+
+pub fn event_record(event: &AlarmEvent) -> serde_json::Value {
+    let (kind, entity, state) = match event {
+        AlarmEvent::MotionDetected(entity) => (
+            entity.zone_kind.unwrap_or_default().event_name(true),
+            entity.as_ref(),
+            json!(true),
+        ),
+        AlarmEvent::MotionCleared(entity) => (
+            entity.zone_kind.unwrap_or_default().event_name(false),
+            entity.as_ref(),
+            json!(false),
+        ),
+        AlarmEvent::AlarmStateChanged((entity, state, _)) => {
+            let state = match state {
+                AlarmState::Disarmed => "disarmed",
+                AlarmState::Arming(_) => "arming",
+                AlarmState::Armed((_, ArmMode::away)) => "armed_away",
+                AlarmState::Armed((_, ArmMode::home)) => "armed_home",
+                AlarmState::Armed((_, ArmMode::night)) => "armed_night",
+                AlarmState::Pending(_) => "pending",
+                AlarmState::Triggered => "triggered",
+            };
+            ("alarm_state_changed", entity, json!(state))
+        }
+        AlarmEvent::OutputStateChanged((entity, state)) => {
+            ("output_state_changed", entity, json!(state))
+        }
+        AlarmEvent::FireAlarmChanged((entity, zone)) => ("fire_alarm_changed", entity, json!(zone)),
+        AlarmEvent::SettingChanged((entity, value)) => ("setting_changed", entity, json!(value)),
+        AlarmEvent::PartialDisarmChanged((entity, _, partial)) => {
+            ("partial_disarm_changed", entity, json!(partial))
+        }
+        AlarmEvent::ZonesLearned((entity, learned)) => ("zones_learned", entity, json!(learned)),
+        AlarmEvent::CommandResult((source, command, result)) => {
+            return json!({
+                "time": unix_time(),
+                "type": "event",
+                "event": "command_result",
+                "command": command.name(),
+                "source": source.name(),
+                "accepted": result.is_ok(),
+                "reason": result.err(),
+            });
+        }
+        AlarmEvent::CommandConflict(((winner_source, winner), (loser_source, loser))) => {
+            return json!({
+                "time": unix_time(),
+                "type": "event",
+                "event": "command_conflict",
+                "command": winner.name(),
+                "source": winner_source.name(),
+                "overridden_command": loser.name(),
+                "overridden_source": loser_source.name(),
+            });
+        }
+    };
+    json!({
+        "time": unix_time(),
+        "type": "event",
+        "event": kind,
+        "entity": entity.unique_id,
+        "state": state,
+    })
+}
+
+/// Appends alarm events and log records to a JSON lines file per day
+pub fn archive_task(event_rx: Receiver<AlarmEvent>, log_rx: Receiver<String>) -> ! {
+    let mut current: Option<(String, File)> = None;
+    loop {
+        let mut lines = Vec::new();
+        match event_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(event) => lines.push(event_record(&event).to_string()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => panic!("event_rx disconnected"),
+        }
+        lines.extend(
+            event_rx
+                .try_iter()
+                .map(|event| event_record(&event).to_string()),
+        );
+        lines.extend(log_rx.try_iter());
+        if lines.is_empty() {
+            continue;
+        }
+
+        let name = day_file_name(unix_time());
+        if current
+            .as_ref()
+            .map_or(true, |(current, _)| *current != name)
+        {
+            let path = format!("{}/{}", MOUNT_POINT, name);
+            current = match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => Some((name, file)),
+                Err(e) => {
+                    log::error!("Failed to open archive {}: {}", path, e);
+                    None
+                }
+            };
+        }
+
+        if let Some((_, file)) = current.as_mut() {
+            let written = lines
+                .iter()
+                .try_for_each(|line| writeln!(file, "{}", line))
+                .and_then(|_| file.flush());
+            if let Err(e) = written {
+                log::error!("Failed to write archive: {}", e);
+                current = None;
+            }
+        }
+    }
+}
+
+/// Names of the archived days, without the file extension
+pub fn list_days() -> anyhow::Result<Vec<String>> {
+    let mut days = std::fs::read_dir(MOUNT_POINT)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            name.strip_suffix(".LOG").map(|day| day.to_string())
+        })
+        .collect::<Vec<_>>();
+    days.sort();
+    Ok(days)
+}
+
+/// Streams an archived day in chunks which fit into an MQTT message
+pub fn fetch_day(
+    day: &str,
+    mut send_chunk: impl FnMut(&[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    if day.is_empty() || !day.chars().all(|c| c.is_ascii_alphanumeric()) {
+        anyhow::bail!("invalid day: {}", day);
+    }
+    let mut file = File::open(format!("{}/{}.LOG", MOUNT_POINT, day.to_uppercase()))?;
+    let mut chunk = vec![0u8; FETCH_CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut chunk)?;
+        if n == 0 {
+            return Ok(());
+        }
+        send_chunk(&chunk[..n])?;
+    }
+}
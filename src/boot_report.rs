@@ -0,0 +1,65 @@
+use std::ffi::CStr;
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_sys::*;
+use serde_json::json;
+
+use crate::alarm::{self, AlarmState};
+use crate::power::PowerReport;
+
+fn reset_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+/// Describes why the device has booted and what it was doing before
+///
+/// Has to be built before the alarm task starts, as it reads the alarm state
+/// persisted by the previous boot.
+pub fn boot_report(nvs: &EspDefaultNvsPartition, power: &PowerReport) -> String {
+    let persisted = EspNvs::new(nvs.clone(), alarm::NVS_NAMESPACE, true)
+        .and_then(|nvs| nvs.get_u8(alarm::NVS_STATE_KEY));
+    let (nvs_loaded, last_alarm_state) = match persisted {
+        Ok(code) => (true, code.and_then(AlarmState::nvs_code_name)),
+        Err(e) => {
+            log::error!("Failed to read persisted alarm state: {:?}", e);
+            (false, None)
+        }
+    };
+
+    json!({
+        "reset_reason": reset_reason(),
+        "last_alarm_state": last_alarm_state,
+        "firmware_version": env!("CARGO_PKG_VERSION"),
+        "nvs_loaded": nvs_loaded,
+        "brownouts": power.brownouts,
+        "brownout_trouble": power.trouble(),
+    })
+    .to_string()
+}
+
+/// Label of the running app partition and the SHA-256 of its image in hex, to verify
+/// the deployed firmware against a build
+pub fn firmware_sha256() -> anyhow::Result<(String, String)> {
+    let partition = unsafe { esp_ota_get_running_partition() };
+    if partition.is_null() {
+        anyhow::bail!("running partition not found");
+    }
+    let mut sha256 = [0u8; 32];
+    esp!(unsafe { esp_partition_get_sha256(partition, sha256.as_mut_ptr()) })?;
+    let label = unsafe { CStr::from_ptr((*partition).label.as_ptr()) };
+    let hex = sha256.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok((label.to_string_lossy().into_owned(), hex))
+}
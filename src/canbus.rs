@@ -0,0 +1,144 @@
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::can::{CanDriver, Flags, Frame};
+use esp_idf_hal::delay::TickType;
+use esp_idf_sys::ESP_ERR_TIMEOUT;
+
+use crate::lock::LockRecover;
+use crate::modbus::ExpanderInput;
+use crate::{AlarmCommand, AlarmState, CommandSource};
+
+const MSG_ZONES: u32 = 0x1;
+const MSG_KEY: u32 = 0x2;
+const MSG_LEDS: u32 = 0x3;
+
+const BROADCAST_ADDRESS: u32 = 0;
+
+const KEY_ARM: u8 = b'A';
+const KEY_ARM_INSTANTLY: u8 = b'I';
+const KEY_DISARM: u8 = b'D';
+const KEY_TRIGGER: u8 = b'T';
+
+const LED_ARMED: u8 = 1 << 0;
+const LED_ARMING: u8 = 1 << 1;
+const LED_PENDING: u8 = 1 << 2;
+const LED_TRIGGERED: u8 = 1 << 3;
+
+/// Keypads that were powered up later pick up the LED state on the next refresh
+const LED_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+const RECEIVE_TIMEOUT_MS: u64 = 250;
+
+fn frame_id(message: u32, address: u32) -> u32 {
+    (message << 7) | (address & 0x7F)
+}
+
+fn leds_for_state(state: &AlarmState) -> u8 {
+    match state {
+        AlarmState::Disarmed => 0,
+        AlarmState::Arming(_) => LED_ARMING,
+        AlarmState::Armed(_) => LED_ARMED,
+        AlarmState::Pending(_) => LED_ARMED | LED_PENDING,
+        AlarmState::Triggered => LED_ARMED | LED_TRIGGERED,
+    }
+}
+
+/// Links remote keypads and zone expanders on a CAN bus to the alarm
+///
+/// Frames use standard 11 bit identifiers: the upper 4 bits select the message
+/// type, the lower 7 bits hold the address of the remote node.
+///
+/// - `ZONES` (node -> panel): bitmask of the node's inputs, LSB first
+/// - `KEY` (node -> panel): a single key code
+/// - `LEDS` (panel -> nodes, broadcast): bitmask of the keypad LEDs
+pub fn can_task(
+    mut driver: CanDriver,
+    inputs: Vec<ExpanderInput>,
+    alarm_command_tx: Sender<(CommandSource, AlarmCommand)>,
+    alarm_state: Arc<Mutex<AlarmState>>,
+) -> ! {
+    driver.start().expect("Failed to start CAN driver");
+
+    let timeout = TickType::new_millis(RECEIVE_TIMEOUT_MS).ticks();
+    let mut last_leds = None;
+    let mut last_led_update = Instant::now();
+
+    loop {
+        match driver.receive(timeout) {
+            Ok(frame) => handle_frame(&frame, &inputs, &alarm_command_tx),
+            Err(e) => {
+                if e.code() != ESP_ERR_TIMEOUT as i32 {
+                    log::warn!("Failed to receive CAN frame: {:?}", e);
+                }
+            }
+        }
+
+        let leds = leds_for_state(&alarm_state.lock_recover());
+        if last_leds != Some(leds) || last_led_update.elapsed() >= LED_REFRESH_INTERVAL {
+            let frame = Frame::new(
+                frame_id(MSG_LEDS, BROADCAST_ADDRESS),
+                Flags::None.into(),
+                &[leds],
+            )
+            .expect("Invalid LED frame");
+            match driver.transmit(&frame, timeout) {
+                Ok(()) => last_leds = Some(leds),
+                Err(e) => log::warn!("Failed to send keypad LED state: {:?}", e),
+            }
+            last_led_update = Instant::now();
+        }
+    }
+}
+
+fn handle_frame(
+    frame: &Frame,
+    inputs: &[ExpanderInput],
+    alarm_command_tx: &Sender<(CommandSource, AlarmCommand)>,
+) {
+    let message = frame.identifier() >> 7;
+    let address = (frame.identifier() & 0x7F) as u8;
+    let data = frame.data();
+
+    match message {
+        MSG_ZONES => {
+            for input in inputs.iter().filter(|i| i.point.address == address) {
+                let index = input.point.index as usize;
+                let Some(byte) = data.get(index / 8) else {
+                    log::warn!("CAN node {} did not report input {}", address, index);
+                    continue;
+                };
+                input
+                    .state
+                    .store(byte & (1 << (index % 8)) != 0, Ordering::Relaxed);
+            }
+        }
+        MSG_KEY => {
+            let command = match data.first() {
+                Some(&KEY_ARM) => AlarmCommand::Arm,
+                Some(&KEY_ARM_INSTANTLY) => AlarmCommand::ArmInstantly,
+                // Keypads which read a code send its digits after the key
+                Some(&KEY_DISARM) => AlarmCommand::Disarm(
+                    Some(String::from_utf8_lossy(&data[1..]).into_owned())
+                        .filter(|code| !code.is_empty()),
+                ),
+                Some(&KEY_TRIGGER) => AlarmCommand::ManualTrigger,
+                _ => {
+                    log::warn!("Unknown key from CAN node {}: {:?}", address, data);
+                    return;
+                }
+            };
+            log::info!("Key press on CAN node {}", address);
+            alarm_command_tx
+                .send((CommandSource::Keypad, command))
+                .unwrap_or_else(|e| {
+                    log::error!("Failed to send alarm command: {}", e);
+                });
+        }
+        _ => {
+            log::debug!("Ignoring CAN frame {:#x}", frame.identifier());
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "simulation")]
+pub use ha_types::timers::ManualClock;
+pub use ha_types::timers::{Clock, SystemClock};
+
+/// Unix time before which the clock is considered not synchronized yet
+pub const MIN_VALID_TIME: u64 = 1_577_836_800;
+/// Steps of the wall clock larger than this, in milliseconds, are reported
+const TIME_JUMP_THRESHOLD_MS: i64 = 10_000;
+
+static SNTP_SYNCHRONIZED: AtomicBool = AtomicBool::new(false);
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn is_synchronized() -> bool {
+    unix_time() >= MIN_VALID_TIME
+}
+
+/// Steps the wall clock, e.g. to the time of the RTC at boot
+pub fn set_unix_time(time: u64) -> anyhow::Result<()> {
+    let tv = esp_idf_sys::timeval {
+        tv_sec: time as _,
+        tv_usec: 0,
+    };
+    if unsafe { esp_idf_sys::settimeofday(&tv, std::ptr::null()) } != 0 {
+        anyhow::bail!("settimeofday failed");
+    }
+    Ok(())
+}
+
+/// Called by SNTP whenever it has synchronized the clock
+pub fn notify_sntp_sync() {
+    SNTP_SYNCHRONIZED.store(true, Ordering::Relaxed);
+}
+
+/// Whether SNTP has synchronized the clock since the last call
+pub fn take_sntp_sync() -> bool {
+    SNTP_SYNCHRONIZED.swap(false, Ordering::Relaxed)
+}
+
+/// Day of the week, 0 being Monday, hour and minute of the time in UTC
+pub fn weekday_time(time: u64) -> (u8, u8, u8) {
+    // The epoch was on a Thursday
+    let weekday = (time / 86400 + 3) % 7;
+    let hour = time / 3600 % 24;
+    let minute = time / 60 % 60;
+    (weekday as u8, hour as u8, minute as u8)
+}
+
+/// Sets the POSIX TZ rule of the local time, e.g. `CET-1CEST,M3.5.0,M10.5.0/3`
+pub fn set_timezone(rule: &str) -> anyhow::Result<()> {
+    let rule = CString::new(rule)?;
+    if unsafe { esp_idf_sys::setenv(c"TZ".as_ptr(), rule.as_ptr(), 1) } != 0 {
+        anyhow::bail!("setenv TZ failed");
+    }
+    unsafe { esp_idf_sys::tzset() };
+    Ok(())
+}
+
+/// Day of the week, 0 being Monday, hour and minute of the time in the local time of the
+/// timezone setting
+pub fn local_weekday_time(time: u64) -> (u8, u8, u8) {
+    let time = time as esp_idf_sys::time_t;
+    // Plain C struct, filled in by localtime_r
+    let mut tm: esp_idf_sys::tm = unsafe { std::mem::zeroed() };
+    if unsafe { esp_idf_sys::localtime_r(&time, &mut tm) }.is_null() {
+        return weekday_time(time as u64);
+    }
+    // tm counts the days of the week from Sunday
+    (
+        ((tm.tm_wday + 6) % 7) as u8,
+        tm.tm_hour as u8,
+        tm.tm_min as u8,
+    )
+}
+
+/// Year, month and day of the time in UTC
+pub fn civil_date(time: u64) -> (i64, i64, i64) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (time / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// Unix time of a date and time in UTC, the inverse of [`civil_date`]
+pub fn from_civil(year: i64, month: i64, day: i64, hour: u8, minute: u8, second: u8) -> u64 {
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    days.max(0) as u64 * 86400 + u64::from(hour) * 3600 + u64::from(minute) * 60 + u64::from(second)
+}
+
+/// The time in UTC in ISO 8601 format, e.g. `2024-05-01T12:30:00Z`
+pub fn iso8601(time: u64) -> String {
+    let (year, month, day) = civil_date(time);
+    let (_, hour, minute) = weekday_time(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        time % 60
+    )
+}
+
+fn unix_time_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Detects steps of the wall clock, e.g. by an SNTP correction, against the monotonic clock
+///
+/// The first synchronization is not reported.
+pub struct TimeJumpDetector {
+    last: (Instant, i64),
+}
+
+impl TimeJumpDetector {
+    pub fn new() -> Self {
+        Self {
+            last: (Instant::now(), unix_time_ms()),
+        }
+    }
+
+    /// Expected and actual unix time in milliseconds, if the wall clock has stepped
+    pub fn poll(&mut self) -> Option<(i64, i64)> {
+        let (last_instant, last_time) = self.last;
+        let now = (Instant::now(), unix_time_ms());
+        self.last = now;
+        let expected = last_time + now.0.duration_since(last_instant).as_millis() as i64;
+        let synchronized = last_time >= MIN_VALID_TIME as i64 * 1000;
+        (synchronized && (now.1 - expected).abs() > TIME_JUMP_THRESHOLD_MS)
+            .then_some((expected, now.1))
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use ha_types::{HAEntity, SETTINGS_SCHEMA_VERSION};
+use serde_json::json;
+
+use crate::alarm;
+use crate::settings::Settings;
+
+/// Summary of the configuration the device is running, for support
+///
+/// Names, topics, codes and the values of the settings are left out, only counts,
+/// the enabled features and which settings are overridden are published.
+pub fn config_snapshot(
+    entities: &[HAEntity],
+    features: &[String],
+    nvs: &EspDefaultNvsPartition,
+    settings: &Settings,
+) -> String {
+    let mut variants = BTreeMap::new();
+    for entity in entities {
+        *variants.entry(entity.variant.to_string()).or_insert(0) += 1;
+    }
+    let zones = entities.iter().filter(|entity| entity.is_zone()).count();
+    let profiles = entities
+        .iter()
+        .flat_map(|entity| entity.arming_profiles.iter().flatten())
+        .count();
+    let timeouts: BTreeMap<&str, u32> = alarm::timing_settings(nvs)
+        .into_iter()
+        .map(|(setting, value)| (setting.key(), value))
+        .collect();
+
+    json!({
+        "firmware_version": env!("CARGO_PKG_VERSION"),
+        "settings_schema_version": SETTINGS_SCHEMA_VERSION,
+        "entities": variants,
+        "zones": zones,
+        "arming_profiles": profiles,
+        "timeouts": timeouts,
+        "features": features,
+        "overridden_settings": settings.overridden(),
+    })
+    .to_string()
+}
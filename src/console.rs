@@ -0,0 +1,125 @@
+use std::io::BufRead;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use esp_idf_sys::*;
+use serde_json::json;
+
+use crate::alarm::AlarmState;
+use crate::cpu_load::IdleSample;
+use crate::lock::LockRecover;
+use crate::mqtt_connection::MqttPublisher;
+use crate::settings::Settings;
+use crate::StatusEvent;
+
+const HELP: &str = "commands:
+  status             show the state of the panel
+  cpu-load           show how idle each core was over a second
+  get <key>          show a setting
+  set <key> <value>  change a setting, applied after a reboot
+  reset              remove every changed setting
+  reboot             restart the panel once the alarm is disarmed
+  reboot confirm     restart the panel in any alarm state";
+
+/// Line based console on the serial port, to recover a panel with a broken network configuration
+pub fn console_task(
+    mut settings: Settings,
+    mqtt_endpoint: String,
+    alarm_state: Arc<Mutex<AlarmState>>,
+    publisher: MqttPublisher,
+    audit_topic: Option<String>,
+    status_tx: mpsc::Sender<StatusEvent>,
+) -> anyhow::Result<()> {
+    // Records every change of the settings, `None` for a reset of every setting
+    let audit = |key: Option<&str>, result: &Result<(), String>| {
+        if let Err(e) = result {
+            log::warn!(
+                "Rejected change of {}: {}",
+                key.unwrap_or("every setting"),
+                e
+            );
+        }
+        if let Some(topic) = audit_topic.as_ref() {
+            let payload = json!({
+                "key": key,
+                "source": "console",
+                "accepted": result.is_ok(),
+                "reason": result.as_ref().err(),
+            });
+            publisher.publish(topic, payload.to_string());
+        }
+    };
+
+    // stdin is non-blocking until the UART driver is installed
+    let uart = CONFIG_ESP_CONSOLE_UART_NUM as i32;
+    esp!(unsafe { uart_driver_install(uart, 256, 0, 0, std::ptr::null_mut(), 0) })?;
+    unsafe { esp_vfs_dev_uart_use_driver(uart) };
+
+    log::info!("Serial console ready, type \"help\" for the commands");
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        let mut args = line.split_whitespace();
+        match (args.next(), args.next(), args.next()) {
+            (None, _, _) => {}
+            (Some("status"), None, _) => {
+                let state = alarm_state.lock_recover().nvs_code();
+                println!("firmware: {}", env!("CARGO_PKG_VERSION"));
+                println!(
+                    "alarm: {}",
+                    AlarmState::nvs_code_name(state).unwrap_or("unknown")
+                );
+                println!("mqtt_endpoint: {}", mqtt_endpoint);
+                println!("free_heap: {}", unsafe { esp_get_free_heap_size() });
+                let (eth_backoff, mqtt_backoff) = crate::network::backoffs();
+                println!("eth_backoff: {:?}", eth_backoff);
+                println!("mqtt_backoff: {:?}", mqtt_backoff);
+            }
+            (Some("cpu-load"), None, _) => {
+                let sample = IdleSample::take();
+                std::thread::sleep(Duration::from_secs(1));
+                let [core0, core1] = IdleSample::take().idle_percent(&sample);
+                println!("core0 idle: {}%", core0);
+                println!("core1 idle: {}%", core1);
+            }
+            (Some("get"), Some(key), None) => match settings.get(key) {
+                Ok(Some(value)) => println!("{}: {}", key, value),
+                Ok(None) => println!("{} is not set", key),
+                Err(e) => println!("error: {}", e),
+            },
+            (Some("set"), Some(key), Some(value)) if args.next().is_none() => {
+                let result = Settings::check_unlocked(Some(key), &alarm_state.lock_recover())
+                    .map_err(str::to_string)
+                    .and_then(|()| settings.set(key, value).map_err(|e| e.to_string()));
+                audit(Some(key), &result);
+                match result {
+                    Ok(()) => println!("{} set, reboot to apply", key),
+                    Err(e) => println!("error: {}", e),
+                }
+            }
+            (Some("reset"), None, _) => {
+                let result = Settings::check_unlocked(None, &alarm_state.lock_recover())
+                    .map_err(str::to_string)
+                    .and_then(|()| settings.reset().map_err(|e| e.to_string()));
+                audit(None, &result);
+                match result {
+                    Ok(()) => println!(
+                        "settings reset, reboot to apply: {}",
+                        ha_types::SETTINGS_KEYS.join(", ")
+                    ),
+                    Err(e) => println!("error: {}", e),
+                }
+            }
+            // Like the reboot over MQTT, only while disarmed unless it is confirmed
+            (Some("reboot"), None, _) if *alarm_state.lock_recover() != AlarmState::Disarmed => {
+                println!("error: the alarm is not disarmed, disarm it or use \"reboot confirm\"");
+            }
+            (Some("reboot"), None | Some("confirm"), None) => {
+                log::warn!("Restarting on serial console request");
+                crate::network::request_restart(&status_tx, "serial console request")?;
+            }
+            _ => println!("{}", HELP),
+        }
+    }
+
+    anyhow::bail!("Serial console closed");
+}
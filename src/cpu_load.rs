@@ -0,0 +1,42 @@
+use esp_idf_sys::*;
+
+/// Run time counters of the idle task of each core, in microseconds
+///
+/// Needs CONFIG_FREERTOS_GENERATE_RUN_TIME_STATS, the counters wrap after about 71 minutes.
+#[derive(Clone, Copy)]
+pub struct IdleSample {
+    idle: [u32; 2],
+    total: u32,
+}
+
+impl IdleSample {
+    pub fn take() -> Self {
+        // Leaves room for tasks spawned in the meantime
+        let capacity = unsafe { uxTaskGetNumberOfTasks() } as usize + 4;
+        let mut tasks: Vec<TaskStatus_t> = Vec::with_capacity(capacity);
+        let mut total = 0;
+        unsafe {
+            let count =
+                uxTaskGetSystemState(tasks.as_mut_ptr(), capacity as UBaseType_t, &mut total);
+            tasks.set_len(count as usize);
+        }
+
+        let mut idle = [0; 2];
+        for (core, idle) in idle.iter_mut().enumerate() {
+            let handle = unsafe { xTaskGetIdleTaskHandleForCPU(core as UBaseType_t) };
+            if let Some(task) = tasks.iter().find(|task| task.xHandle == handle) {
+                *idle = task.ulRunTimeCounter;
+            }
+        }
+        Self { idle, total }
+    }
+
+    /// Percentage of the time each core was idle since an earlier sample
+    pub fn idle_percent(&self, earlier: &IdleSample) -> [u32; 2] {
+        let total = u64::from(self.total.wrapping_sub(earlier.total).max(1));
+        [0, 1].map(|core| {
+            let idle = u64::from(self.idle[core].wrapping_sub(earlier.idle[core]));
+            (idle * 100 / total).min(100) as u32
+        })
+    }
+}
@@ -0,0 +1,205 @@
+use std::mem::discriminant;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver};
+use esp_idf_sys::esp_timer_get_time;
+use ha_types::*;
+
+use crate::event_queue::EventQueue;
+use crate::lock::LockRecover;
+use crate::{AlarmCommand, AlarmEvent, AlarmState, CommandSource};
+
+const MAX_COMMAND_BYTES: usize = 16;
+
+/// The clock line idles high for a few milliseconds between commands
+const COMMAND_GAP_US: i64 = 2000;
+
+const CMD_STATUS: u8 = 0x05;
+
+/// Commands carrying open zone bitmasks, with the number of the zones preceding them
+const ZONE_COMMANDS: [(u8, u8); 4] = [(0x27, 0), (0x2D, 8), (0x34, 16), (0x3E, 24)];
+const ZONE_COMMAND_LEN: usize = 8;
+
+const LIGHT_ARMED: u8 = 1 << 1;
+const LIGHT_FIRE: u8 = 1 << 6;
+
+const STATUS_EXIT_DELAY: u8 = 0x08;
+const STATUS_ENTRY_DELAY: u8 = 0x0C;
+const STATUS_ALARM: u8 = 0x11;
+
+/// Last complete command captured by the clock interrupt
+#[derive(Default)]
+struct CapturedCommand {
+    bytes: [AtomicU8; MAX_COMMAND_BYTES],
+    len: AtomicUsize,
+    ready: AtomicBool,
+}
+
+/// Bridges an existing DSC panel to Home Assistant by listening on its keypad bus
+///
+/// Panel data is sampled on the falling edge of the clock line. The first byte
+/// of each command is followed by a stop bit, which is skipped. Zone states of
+/// the panel are published as the configured `dsc_zone` entities and the panel's
+/// arm status as the alarm entity, which is shared with the other tasks like the state
+/// of the alarm task. Commands can't be sent to the panel.
+pub fn dsc_task(
+    mut clock_pin: PinDriver<'static, AnyIOPin, Input>,
+    data_pin: PinDriver<'static, AnyIOPin, Input>,
+    zones: Vec<(u8, Arc<HAEntity>)>,
+    alarm_entity: HAEntity,
+    event_queue: Arc<EventQueue>,
+    command_rx: Receiver<(CommandSource, AlarmCommand)>,
+    shared_state: Arc<Mutex<AlarmState>>,
+) -> ! {
+    let captured = Arc::new(CapturedCommand::default());
+    let captured_isr = captured.clone();
+
+    let mut buffer = [0u8; MAX_COMMAND_BYTES];
+    let mut bit_count = 0usize;
+    let mut last_edge = 0i64;
+
+    clock_pin
+        .set_interrupt_type(InterruptType::NegEdge)
+        .expect("Failed to set DSC clock interrupt type");
+    // SAFETY: the callback only uses ISR safe calls and does not allocate
+    unsafe {
+        clock_pin.subscribe(move || {
+            let now = esp_timer_get_time();
+            if now - last_edge > COMMAND_GAP_US {
+                let len = bit_count.saturating_sub(1) / 8;
+                if len > 0 && !captured_isr.ready.load(Ordering::Acquire) {
+                    for (slot, byte) in captured_isr.bytes.iter().zip(buffer.iter()) {
+                        slot.store(*byte, Ordering::Relaxed);
+                    }
+                    captured_isr.len.store(len, Ordering::Relaxed);
+                    captured_isr.ready.store(true, Ordering::Release);
+                }
+                buffer = [0; MAX_COMMAND_BYTES];
+                bit_count = 0;
+            }
+            last_edge = now;
+
+            if bit_count != 8 {
+                let index = if bit_count > 8 {
+                    bit_count - 1
+                } else {
+                    bit_count
+                };
+                if index / 8 < MAX_COMMAND_BYTES && data_pin.is_high() {
+                    buffer[index / 8] |= 0x80 >> (index % 8);
+                }
+            }
+            bit_count += 1;
+        })
+    }
+    .expect("Failed to subscribe to DSC clock interrupt");
+    clock_pin
+        .enable_interrupt()
+        .expect("Failed to enable DSC clock interrupt");
+
+    let mut zone_states = vec![false; zones.len()];
+    let mut alarm_state = None;
+
+    loop {
+        if captured.ready.load(Ordering::Acquire) {
+            let len = captured.len.load(Ordering::Relaxed);
+            let command = captured.bytes[..len]
+                .iter()
+                .map(|byte| byte.load(Ordering::Relaxed))
+                .collect::<Vec<_>>();
+            captured.ready.store(false, Ordering::Release);
+
+            if command.first() == Some(&CMD_STATUS) {
+                if let Some(state) = parse_status(&command) {
+                    let changed = alarm_state
+                        .as_ref()
+                        .map_or(true, |last| discriminant(last) != discriminant(&state));
+                    if changed {
+                        log::info!("DSC panel state: {:?}", state);
+                        *shared_state.lock_recover() = state.clone();
+                        event_queue.push(AlarmEvent::AlarmStateChanged((
+                            alarm_entity.clone(),
+                            state.clone(),
+                            "panel".to_string(),
+                        )));
+                        alarm_state = Some(state);
+                    }
+                }
+            } else if let Some(open_zones) = parse_zones(&command) {
+                for ((zone, entity), last) in zones.iter().zip(zone_states.iter_mut()) {
+                    let Some(open) = open_zones(*zone) else {
+                        continue;
+                    };
+                    if open == *last {
+                        continue;
+                    }
+                    log::info!("DSC zone {} ({}): {}", zone, entity.name, open);
+                    *last = open;
+                    if open {
+                        event_queue.push(AlarmEvent::MotionDetected(entity.clone()));
+                    } else {
+                        event_queue.push(AlarmEvent::MotionCleared(entity.clone()));
+                    }
+                }
+            }
+        }
+
+        match command_rx.try_recv() {
+            Ok((source, command)) => {
+                log::warn!("Alarm commands are not supported in DSC interface mode");
+                event_queue.push(AlarmEvent::CommandResult((
+                    source,
+                    command,
+                    Err("not supported in DSC interface mode"),
+                )));
+            }
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => panic!("command_rx disconnected"),
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn parse_status(command: &[u8]) -> Option<AlarmState> {
+    let lights = *command.get(2)?;
+    let status = *command.get(3)?;
+
+    let state = match status {
+        STATUS_ALARM => AlarmState::Triggered,
+        STATUS_EXIT_DELAY => AlarmState::Arming((Instant::now(), ArmMode::away)),
+        STATUS_ENTRY_DELAY => AlarmState::Pending(Instant::now()),
+        _ if lights & LIGHT_FIRE != 0 => AlarmState::Triggered,
+        _ if lights & LIGHT_ARMED != 0 => AlarmState::Armed((Instant::now(), ArmMode::away)),
+        _ => AlarmState::Disarmed,
+    };
+    Some(state)
+}
+
+/// Returns a lookup of the zones reported in a zone status command
+fn parse_zones(command: &[u8]) -> Option<impl Fn(u8) -> Option<bool>> {
+    let (_, offset) = ZONE_COMMANDS
+        .iter()
+        .find(|(cmd, _)| Some(cmd) == command.first())?;
+    if command.len() < ZONE_COMMAND_LEN {
+        return None;
+    }
+
+    let checksum = command[..ZONE_COMMAND_LEN - 1]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    if checksum != command[ZONE_COMMAND_LEN - 1] {
+        log::warn!("DSC zone command checksum mismatch");
+        return None;
+    }
+
+    let offset = *offset;
+    let bits = command[6];
+    Some(move |zone: u8| {
+        let bit = zone.checked_sub(offset + 1)?;
+        (bit < 8).then(|| bits & (1 << bit) != 0)
+    })
+}
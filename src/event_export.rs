@@ -0,0 +1,88 @@
+use std::sync::mpsc::Receiver;
+
+use ha_types::{EventExportConfig, EventExportFormat};
+use serde_json::Value;
+
+use crate::archive::event_record;
+use crate::clock;
+use crate::mqtt_connection::MqttPublisher;
+use crate::AlarmEvent;
+
+/// Keys of the archive record which become tags, the rest are fields
+const TAGS: [&str; 3] = ["event", "entity", "source"];
+
+/// Escapes commas, spaces and equal signs of tag keys, tag values and field keys
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | ' ' | '=' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn field_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Number(number) if number.is_f64() => Some(number.to_string()),
+        Value::Number(number) => Some(format!("{}i", number)),
+        Value::String(value) => Some(string_field(value)),
+        value => Some(string_field(&value.to_string())),
+    }
+}
+
+fn string_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The archive record as a line of InfluxDB line protocol, `None` if it has no fields
+///
+/// The timestamp is left to the database while the clock is not synchronized.
+fn line_protocol(measurement: &str, record: &Value) -> Option<String> {
+    let record = record.as_object()?;
+    let mut line = measurement.replace(',', "\\,").replace(' ', "\\ ");
+    for tag in TAGS {
+        if let Some(Value::String(value)) = record.get(tag) {
+            line.push_str(&format!(",{}={}", tag, escape(value)));
+        }
+    }
+    let fields: Vec<String> = record
+        .iter()
+        .filter(|(key, _)| !TAGS.contains(&key.as_str()) && *key != "time" && *key != "type")
+        .filter_map(|(key, value)| Some(format!("{}={}", escape(key), field_value(value)?)))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    line.push(' ');
+    line.push_str(&fields.join(","));
+    if let Some(time) = record.get("time").and_then(Value::as_u64) {
+        if time >= clock::MIN_VALID_TIME {
+            line.push_str(&format!(" {}000000000", time));
+        }
+    }
+    Some(line)
+}
+
+/// Publishes every alarm event on the export topic in the configured format
+pub fn event_export_task(
+    config: EventExportConfig,
+    event_rx: Receiver<AlarmEvent>,
+    publisher: MqttPublisher,
+) {
+    for event in event_rx.iter() {
+        let record = event_record(&event);
+        let payload = match config.format {
+            EventExportFormat::line_protocol => match line_protocol(&config.measurement, &record) {
+                Some(line) => line,
+                None => continue,
+            },
+            EventExportFormat::json => record.to_string(),
+        };
+        publisher.publish(&config.topic, payload);
+    }
+    log::error!("Event export stopped, the scheduler has ended");
+}
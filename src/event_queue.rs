@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use ha_types::{MqttStat, TimingStat};
+
+use crate::alarm::AlarmEvent;
+use crate::lock::LockRecover;
+use crate::mqtt_stats;
+use crate::timing_stats;
+use crate::zone_counters;
+
+/// Events kept before the oldest notices are dropped, e.g. command results
+const CAPACITY: usize = 64;
+
+/// Alarm events waiting for the scheduler
+///
+/// An event reporting the state of an entity, e.g. a zone or an output, replaces the one of
+/// the same entity still waiting, so only its latest state is published and a chattering
+/// zone can't flood the queue. Changes of the alarm state are never dropped.
+pub struct EventQueue {
+    events: Mutex<VecDeque<AlarmEvent>>,
+    /// Critical changes signalled but neither published nor processed yet, in the order
+    /// of `Critical`
+    critical: [AtomicBool; 2],
+}
+
+/// Changes which are published ahead of the queue with payloads prepared in advance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Critical {
+    /// The alarm entered the triggered state
+    Triggered,
+    /// The siren started sounding
+    Siren,
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        Self {
+            events: Mutex::new(VecDeque::with_capacity(CAPACITY)),
+            critical: [AtomicBool::new(false), AtomicBool::new(false)],
+        }
+    }
+
+    /// Signals a critical change, the full event is pushed as well
+    pub fn signal(&self, critical: Critical) {
+        self.critical[critical as usize].store(true, Ordering::Release);
+    }
+
+    /// Whether the critical change was signalled since the last call, also clears it once
+    /// its event is processed
+    pub fn take_signal(&self, critical: Critical) -> bool {
+        self.critical[critical as usize].swap(false, Ordering::AcqRel)
+    }
+
+    pub fn push(&self, event: AlarmEvent) {
+        // Counted before it may replace a waiting change of the zone
+        if let AlarmEvent::MotionDetected(entity) = &event {
+            zone_counters::zone_activated(&entity.unique_id);
+        }
+        let mut events = self.events.lock_recover();
+        if let Some(entity) = latest_state_of(&event) {
+            let waiting = events
+                .iter_mut()
+                .find(|waiting| latest_state_of(waiting) == Some(entity));
+            if let Some(waiting) = waiting {
+                // Keeps its place, the change is published as soon as the first one would be
+                *waiting = event;
+                return;
+            }
+        }
+        // Events reporting states are bounded by the entities, only the notices can pile up
+        if events.len() >= CAPACITY {
+            if let Some(index) = events.iter().position(is_notice) {
+                log::warn!("Event queue full, dropping {:?}", events.remove(index));
+                mqtt_stats::count(MqttStat::lost_events);
+            }
+        }
+        if let AlarmEvent::MotionDetected(entity) | AlarmEvent::MotionCleared(entity) = &event {
+            timing_stats::zone_changed(&entity.unique_id);
+        }
+        events.push_back(event);
+    }
+
+    pub fn extend(&self, events: impl IntoIterator<Item = AlarmEvent>) {
+        events.into_iter().for_each(|event| self.push(event));
+    }
+
+    /// Doesn't block, returns `None` also if the queue is locked by another task
+    pub fn try_pop(&self) -> Option<AlarmEvent> {
+        let mut events = self.events.try_lock_recover()?;
+        timing_stats::record(TimingStat::queue_depth, events.len() as u32);
+        events.pop_front()
+    }
+}
+
+/// The kind and unique id of the entity whose state the event reports
+fn latest_state_of(event: &AlarmEvent) -> Option<(&'static str, &str)> {
+    match event {
+        AlarmEvent::MotionDetected(entity) | AlarmEvent::MotionCleared(entity) => {
+            Some(("zone", &entity.unique_id))
+        }
+        AlarmEvent::OutputStateChanged((entity, _)) => Some(("output", &entity.unique_id)),
+        AlarmEvent::SettingChanged((entity, _)) => Some(("setting", &entity.unique_id)),
+        AlarmEvent::ZonesLearned((entity, _)) => Some(("learned", &entity.unique_id)),
+        _ => None,
+    }
+}
+
+/// Events which report no state, only lost when the queue is full
+fn is_notice(event: &AlarmEvent) -> bool {
+    matches!(
+        event,
+        AlarmEvent::CommandResult(_) | AlarmEvent::CommandConflict(_)
+    )
+}
@@ -0,0 +1,221 @@
+use std::ffi::CString;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use esp_idf_sys::*;
+
+use crate::lock::LockRecover;
+
+const PARTITION_LABEL: &str = "logs";
+
+const SECTOR_SIZE: u32 = SPI_FLASH_SEC_SIZE;
+const SECTOR_MAGIC: u32 = 0x4C4F_4731;
+const HEADER_SIZE: u32 = 8;
+
+const ERASED_LEN: u16 = 0xFFFF;
+const MAX_RECORD_LEN: usize = 512;
+
+/// Ring buffer of log records in the `logs` flash partition
+///
+/// Every sector starts with a header holding a magic and a sequence number, the
+/// sector with the highest sequence number is written to. Records are stored as a
+/// little endian length followed by the record itself, an erased length marks the
+/// end of the records in a sector. Once a sector is full the next one is erased,
+/// so sectors wear evenly.
+pub struct FlashLog {
+    partition: *const esp_partition_t,
+    sectors: u32,
+    sector: u32,
+    offset: u32,
+    sequence: u32,
+}
+
+// SAFETY: the partition table is read-only and lives for the lifetime of the program
+unsafe impl Send for FlashLog {}
+
+impl FlashLog {
+    pub fn open() -> anyhow::Result<Self> {
+        let label = CString::new(PARTITION_LABEL)?;
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                label.as_ptr(),
+            )
+        };
+        if partition.is_null() {
+            bail!("{} partition not found", PARTITION_LABEL);
+        }
+
+        let sectors = unsafe { (*partition).size } / SECTOR_SIZE;
+        if sectors < 2 {
+            bail!("{} partition is too small", PARTITION_LABEL);
+        }
+
+        let mut log = Self {
+            partition,
+            sectors,
+            sector: 0,
+            offset: HEADER_SIZE,
+            sequence: 0,
+        };
+
+        let mut current = None;
+        for sector in 0..sectors {
+            if let Some(sequence) = log.sector_sequence(sector)? {
+                if current.map_or(true, |(_, last)| sequence > last) {
+                    current = Some((sector, sequence));
+                }
+            }
+        }
+
+        match current {
+            Some((sector, sequence)) => {
+                log.sector = sector;
+                log.sequence = sequence;
+                log.offset = log.end_of_records(sector)?;
+            }
+            None => log.start_sector(0, 0)?,
+        }
+        Ok(log)
+    }
+
+    pub fn append(&mut self, record: &[u8]) -> anyhow::Result<()> {
+        let record = &record[..record.len().min(MAX_RECORD_LEN)];
+        let len = 2 + record.len() as u32;
+        if self.offset + len > SECTOR_SIZE {
+            let sector = (self.sector + 1) % self.sectors;
+            self.start_sector(sector, self.sequence.wrapping_add(1))?;
+        }
+
+        let address = self.sector * SECTOR_SIZE + self.offset;
+        self.write(address, &(record.len() as u16).to_le_bytes())?;
+        self.write(address + 2, record)?;
+        self.offset += len;
+        Ok(())
+    }
+
+    /// Rescans the partition, in case a panic interrupted an append
+    pub fn reopen(&mut self) {
+        match Self::open() {
+            Ok(log) => *self = log,
+            Err(e) => log::error!("Failed to reopen flash log: {:?}", e),
+        }
+    }
+
+    /// Calls `f` with every stored record, oldest first
+    pub fn for_each_record(
+        &self,
+        mut f: impl FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let mut buffer = vec![0u8; SECTOR_SIZE as usize];
+        for i in 1..=self.sectors {
+            let sector = (self.sector + i) % self.sectors;
+            if self.sector_sequence(sector)?.is_none() {
+                continue;
+            }
+            self.read(sector * SECTOR_SIZE, &mut buffer)?;
+
+            let mut offset = HEADER_SIZE as usize;
+            while offset + 2 <= buffer.len() {
+                let len = u16::from_le_bytes([buffer[offset], buffer[offset + 1]]);
+                if len == ERASED_LEN {
+                    break;
+                }
+                let Some(record) = buffer.get(offset + 2..offset + 2 + len as usize) else {
+                    break;
+                };
+                f(record)?;
+                offset += 2 + len as usize;
+            }
+        }
+        Ok(())
+    }
+
+    fn sector_sequence(&self, sector: u32) -> anyhow::Result<Option<u32>> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        self.read(sector * SECTOR_SIZE, &mut header)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let sequence = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        Ok((magic == SECTOR_MAGIC).then_some(sequence))
+    }
+
+    fn end_of_records(&self, sector: u32) -> anyhow::Result<u32> {
+        let mut offset = HEADER_SIZE;
+        while offset + 2 <= SECTOR_SIZE {
+            let mut len = [0u8; 2];
+            self.read(sector * SECTOR_SIZE + offset, &mut len)?;
+            let len = u16::from_le_bytes(len);
+            if len == ERASED_LEN {
+                return Ok(offset);
+            }
+            offset += 2 + len as u32;
+        }
+        // The sector is full, the next append starts a new one
+        Ok(SECTOR_SIZE)
+    }
+
+    fn start_sector(&mut self, sector: u32, sequence: u32) -> anyhow::Result<()> {
+        esp!(unsafe {
+            esp_partition_erase_range(
+                self.partition,
+                (sector * SECTOR_SIZE) as usize,
+                SECTOR_SIZE as usize,
+            )
+        })?;
+        let mut header = [0u8; HEADER_SIZE as usize];
+        header[..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&sequence.to_le_bytes());
+        self.write(sector * SECTOR_SIZE, &header)?;
+
+        self.sector = sector;
+        self.sequence = sequence;
+        self.offset = HEADER_SIZE;
+        Ok(())
+    }
+
+    fn read(&self, address: u32, buffer: &mut [u8]) -> anyhow::Result<()> {
+        esp!(unsafe {
+            esp_partition_read(
+                self.partition,
+                address as usize,
+                buffer.as_mut_ptr() as *mut _,
+                buffer.len(),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        esp!(unsafe {
+            esp_partition_write(
+                self.partition,
+                address as usize,
+                data.as_ptr() as *const _,
+                data.len(),
+            )
+        })?;
+        Ok(())
+    }
+}
+
+pub fn flash_log_task(flash_log: Arc<Mutex<FlashLog>>, log_rx: Receiver<String>) -> ! {
+    let mut failing = false;
+    loop {
+        let record = log_rx.recv().expect("log_rx disconnected");
+        let result = flash_log
+            .lock_recover_with(FlashLog::reopen)
+            .append(record.as_bytes());
+        match result {
+            Ok(()) => failing = false,
+            Err(e) => {
+                // Only report the first failure, the report itself would be logged again
+                if !failing {
+                    log::error!("Failed to write flash log: {:?}", e);
+                }
+                failing = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::{Mutex, MutexGuard, TryLockError};
+
+/// Locking which recovers from poisoned mutexes
+///
+/// A mutex is poisoned when a task panics while holding it. Without recovery every
+/// other task using the mutex would panic on `lock().unwrap()` as well, taking the
+/// whole panel down. The poison is cleared instead, after giving the caller a chance
+/// to revalidate the data the panicking task may have left half updated.
+pub trait LockRecover<T> {
+    fn lock_recover_with(&self, revalidate: impl FnOnce(&mut T)) -> MutexGuard<'_, T>;
+
+    /// For data which can't be left in an invalid state, e.g. queues or plain values
+    fn lock_recover(&self) -> MutexGuard<'_, T> {
+        self.lock_recover_with(|_| {})
+    }
+
+    /// Doesn't block, returns `None` if the mutex is locked by another task
+    fn try_lock_recover(&self) -> Option<MutexGuard<'_, T>>;
+}
+
+impl<T> LockRecover<T> for Mutex<T> {
+    fn lock_recover_with(&self, revalidate: impl FnOnce(&mut T)) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|e| {
+            log::error!(
+                "Recovering poisoned mutex of {}",
+                std::any::type_name::<T>()
+            );
+            let mut guard = e.into_inner();
+            revalidate(&mut guard);
+            self.clear_poison();
+            guard
+        })
+    }
+
+    fn try_lock_recover(&self) -> Option<MutexGuard<'_, T>> {
+        match self.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(_)) => Some(self.lock_recover()),
+        }
+    }
+}
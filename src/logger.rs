@@ -0,0 +1,73 @@
+use std::sync::mpsc::SyncSender;
+use std::sync::OnceLock;
+
+use esp_idf_svc::log::EspLogger;
+use serde_json::json;
+
+use crate::clock::unix_time;
+
+pub const SINK_QUEUE_SIZE: usize = 64;
+
+/// Receives log records of at least `level` as JSON lines
+///
+/// Records are dropped if the receiver can't keep up.
+pub struct LogSink {
+    pub level: log::Level,
+    pub tx: SyncSender<String>,
+}
+
+/// Forwards log records to the default logger and to the sinks
+struct Logger {
+    inner: EspLogger,
+    sinks: OnceLock<Vec<LogSink>>,
+}
+
+static LOGGER: Logger = Logger {
+    inner: EspLogger::new(),
+    sinks: OnceLock::new(),
+};
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.inner.log(record);
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let Some(sinks) = self.sinks.get() else {
+            return;
+        };
+        let mut line = None;
+        for sink in sinks.iter().filter(|sink| record.level() <= sink.level) {
+            let line = line.get_or_insert_with(|| {
+                json!({
+                    "time": unix_time(),
+                    "type": "log",
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                })
+                .to_string()
+            });
+            let _ = sink.tx.try_send(line.clone());
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Binds the log crate to the ESP logging facilities and the given sinks
+pub fn initialize(sinks: Vec<LogSink>) {
+    LOGGER
+        .sinks
+        .set(sinks)
+        .unwrap_or_else(|_| panic!("Logger already initialized"));
+    log::set_logger(&LOGGER).expect("Failed to set logger");
+    log::set_max_level(log::LevelFilter::Info);
+}
@@ -0,0 +1,76 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_sys::esp_random;
+use ha_types::LoopbackConfig;
+
+pub enum LoopbackPoll {
+    Idle,
+    /// The nonce has to be published to the loopback topic
+    Send(String),
+    /// The last nonce did not come back in time
+    Failed,
+}
+
+/// Verifies that the broker still delivers messages to our subscriptions
+///
+/// A half-dead session keeps the connection open and accepts publishes, but
+/// never delivers anything to the device, so it has to be detected by a round trip.
+pub struct LoopbackTest {
+    config: LoopbackConfig,
+    pending: Option<(String, Instant)>,
+    last_sent: Instant,
+}
+
+impl LoopbackTest {
+    pub fn new(config: LoopbackConfig) -> Self {
+        Self {
+            config,
+            pending: None,
+            last_sent: Instant::now(),
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.config.topic
+    }
+
+    /// Restarts the test, e.g. after reconnecting
+    pub fn reset(&mut self) {
+        self.pending = None;
+        self.last_sent = Instant::now();
+    }
+
+    /// Returns true if the message was sent to the loopback topic
+    pub fn handle_message(&mut self, topic: &str, payload: &str) -> bool {
+        if topic != self.config.topic {
+            return false;
+        }
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|(nonce, _)| nonce == payload)
+        {
+            self.pending = None;
+        }
+        true
+    }
+
+    pub fn poll(&mut self) -> LoopbackPoll {
+        if let Some((_, sent)) = self.pending.as_ref() {
+            if sent.elapsed() >= Duration::from_secs(self.config.timeout) {
+                self.reset();
+                return LoopbackPoll::Failed;
+            }
+            return LoopbackPoll::Idle;
+        }
+
+        if self.last_sent.elapsed() < Duration::from_secs(self.config.interval) {
+            return LoopbackPoll::Idle;
+        }
+
+        let nonce = format!("{:08x}", unsafe { esp_random() });
+        self.pending = Some((nonce.clone(), Instant::now()));
+        self.last_sent = Instant::now();
+        LoopbackPoll::Send(nonce)
+    }
+}
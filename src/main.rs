@@ -1,41 +1,75 @@
 use std::{
-    collections::VecDeque,
     sync::{
+        atomic::AtomicBool,
         mpsc::{self},
-        Arc,
+        Arc, Mutex,
     },
     thread::JoinHandle,
+    time::Duration,
 };
 
 use esp_idf_hal::peripheral::Peripheral;
 use esp_idf_hal::{
+    can::{
+        config::{Config as CanDriverConfig, Timing},
+        CanDriver,
+    },
     cpu::Core,
-    gpio::{AnyIOPin, PinDriver},
+    gpio::{AnyIOPin, AnyOutputPin, PinDriver},
+    i2c::{config::Config as I2cConfig, I2cDriver},
     ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver},
     peripherals::Peripherals,
     prelude::*,
     task::thread::ThreadSpawnConfiguration,
+    uart::{config::Config as UartConfig, UartDriver},
 };
 use esp_idf_svc::hal::spi::Dma;
 use esp_idf_svc::hal::spi::SpiDriver;
 use esp_idf_svc::hal::spi::SpiDriverConfig;
 
 use esp_idf_svc::{
-    eventloop::EspSystemEventLoop,
-    mqtt::client::{ConnState, MessageImpl},
-    nvs::EspDefaultNvsPartition,
-    timer::EspTaskTimerService,
+    eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition, timer::EspTaskTimerService,
 };
-use esp_idf_sys::{esp_restart, EspError};
+use esp_idf_sys::esp_restart;
 use ha_types::*;
-use log::{error, info};
+use log::{error, info, warn};
 use seq_macro::seq;
 
 mod alarm;
+mod archive;
+mod boot_report;
+mod canbus;
+mod clock;
+mod config_snapshot;
+mod console;
+mod cpu_load;
+mod dsc;
+mod event_export;
+mod event_queue;
+mod flash_log;
+mod lock;
+mod logger;
+mod loopback;
+mod modbus;
+mod mqtt_connection;
+mod mqtt_stats;
+mod native_api;
 mod network;
+mod power;
+mod presence;
+mod provisioning;
+mod rtc;
 mod scheduler;
+mod settings;
+mod siren;
+mod throughput;
+mod timing_stats;
+mod zone_bootstrap;
+mod zone_counters;
+mod zone_learn;
 
-use alarm::{AlarmCommand, AlarmEvent, AlarmState};
+use alarm::{AlarmCommand, AlarmEvent, AlarmState, CommandSource};
+use event_queue::EventQueue;
 
 /// Helper which spawns a task with a name
 fn spawn_task(
@@ -70,17 +104,63 @@ macro_rules! gpio_pin_num_to_peripheral {
     })};
 }
 
+/// Looks up a GPIO pin which is free to be used by configurable entities
+///
+/// Has to be used in an unsafe block: the caller must guarantee that
+/// the returned pin is not used anywhere else.
+macro_rules! gpio_pin {
+    ($pins:ident, $pin:expr) => {{
+        let pin: u8 = $pin;
+        let pin: Option<AnyIOPin> = gpio_pin_num_to_peripheral!(pin, $pins, 0, 2)
+            .or_else(|| gpio_pin_num_to_peripheral!(pin, $pins, 3, 5))
+            .or_else(|| gpio_pin_num_to_peripheral!(pin, $pins, 6, 18))
+            .or_else(|| gpio_pin_num_to_peripheral!(pin, $pins, 21, 23))
+            .or_else(|| gpio_pin_num_to_peripheral!(pin, $pins, 25, 26))
+            .or_else(|| gpio_pin_num_to_peripheral!(pin, $pins, 32, 33));
+        pin
+    }};
+}
+
 #[allow(unreachable_code)]
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_svc::sys::link_patches();
 
-    // Bind the log crate to the ESP Logging facilities
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // Bind the log crate to the ESP Logging facilities and the persistent logs
+    let sd_card: Option<SdCardConfig> = include!(concat!(env!("OUT_DIR"), "/sd_card.rs"));
+    let flash_log: Option<FlashLogConfig> = include!(concat!(env!("OUT_DIR"), "/flash_log.rs"));
+    let flash_log = flash_log.map(|config| (config, flash_log::FlashLog::open()));
+    let mut log_sinks = Vec::new();
+    let (archive_log_tx, archive_log_rx) = mpsc::sync_channel(logger::SINK_QUEUE_SIZE);
+    if sd_card.is_some() {
+        log_sinks.push(logger::LogSink {
+            level: log::Level::Info,
+            tx: archive_log_tx,
+        });
+    }
+    let (flash_log_tx, flash_log_rx) = mpsc::sync_channel(logger::SINK_QUEUE_SIZE);
+    if let Some((_, Ok(_))) = flash_log {
+        log_sinks.push(logger::LogSink {
+            level: log::Level::Warn,
+            tx: flash_log_tx,
+        });
+    }
+    logger::initialize(log_sinks);
+
+    let flash_log = match flash_log {
+        Some((config, Ok(flash_log))) => Some((config, Arc::new(Mutex::new(flash_log)))),
+        Some((_, Err(e))) => {
+            error!("Failed to open flash log: {:?}", e);
+            None
+        }
+        None => None,
+    };
 
     #[cfg(feature = "simulation")]
     {
+        // The simulation keeps no persistent logs, their lines are dropped
+        drop((archive_log_rx, flash_log_rx, flash_log));
         return simulation();
     }
 
@@ -89,6 +169,55 @@ fn main() -> anyhow::Result<()> {
     let sysloop = EspSystemEventLoop::take()?;
     let timer = EspTaskTimerService::new()?;
     let nvs = EspDefaultNvsPartition::take()?;
+    let power = power::PowerReport::record_boot(nvs.clone());
+
+    // The clock is seeded from the RTC before anything is timestamped
+    let rtc: Option<RtcConfig> = include!(concat!(env!("OUT_DIR"), "/rtc.rs"));
+    let rtc = if let Some(rtc) = rtc {
+        // SAFETY: pins of the I2C bus are only used by the RTC
+        let (sda, scl) = unsafe {
+            (
+                gpio_pin!(pins, rtc.sda_pin).expect("Invalid rtc sda_pin provided"),
+                gpio_pin!(pins, rtc.scl_pin).expect("Invalid rtc scl_pin provided"),
+            )
+        };
+        let i2c = I2cDriver::new(
+            peripherals.i2c0,
+            sda,
+            scl,
+            &I2cConfig::new().baudrate(100.kHz().into()),
+        )?;
+        let mut rtc = rtc::Ds3231::new(i2c);
+        rtc::seed_system_clock(&mut rtc);
+        Some(rtc)
+    } else {
+        None
+    };
+
+    let boot_report_topic: Option<String> =
+        include!(concat!(env!("OUT_DIR"), "/boot_report_topic.rs"));
+    let boot_report = boot_report_topic.map(|topic| MqttMessage {
+        topic,
+        payload: boot_report::boot_report(&nvs, &power),
+    });
+    let settings = settings::Settings::open(nvs.clone())?;
+    // The windows of the quiet hours, the bell test and the auto-bypass are in local time
+    clock::set_timezone(&settings.timezone())
+        .unwrap_or_else(|e| error!("Failed to set the timezone: {:?}", e));
+    let mqtt_endpoint = settings.mqtt_endpoint();
+    let provisioning_ap: Option<ProvisioningApConfig> =
+        include!(concat!(env!("OUT_DIR"), "/provisioning_ap.rs"));
+    // The build fails if neither the endpoint nor the access point is configured
+    if let (true, Some(provisioning_ap)) = (mqtt_endpoint.is_empty(), provisioning_ap) {
+        warn!("No MQTT endpoint is configured, starting the provisioning access point");
+        return provisioning::provisioning_ap(
+            peripherals.modem,
+            sysloop,
+            nvs,
+            settings,
+            provisioning_ap,
+        );
+    }
 
     let led = {
         let timer = LedcTimerDriver::new(
@@ -107,14 +236,14 @@ fn main() -> anyhow::Result<()> {
                 pins.gpio18,
                 pins.gpio19,
                 Some(pins.gpio23),
-                &SpiDriverConfig::new().dma(Dma::Auto(4096)),
+                &SpiDriverConfig::new().dma(Dma::Auto(settings.eth_dma_size())),
             )?,
             pins.gpio26,
             Some(pins.gpio5),
             Some(pins.gpio33),
             esp_idf_svc::eth::SpiEthChipset::W5500,
-            20.MHz().into(),
-            Some(&[0x02, 0x00, 0x00, 0xfc, 0x18, 0x01]),
+            settings.eth_spi_mhz().MHz().into(),
+            Some(&settings.eth_mac()),
             None,
             sysloop.clone(),
         )?,
@@ -130,48 +259,109 @@ fn main() -> anyhow::Result<()> {
     // }
 
     let mut tasks = Vec::new();
-    let alarm_event_queue = Arc::new(std::sync::Mutex::new(VecDeque::new()));
+    let alarm_event_queue = Arc::new(EventQueue::new());
 
     // Alarm task
-    let (alarm_command_tx, alarm_command_rx) = mpsc::channel::<alarm::AlarmCommand>();
+    let (alarm_command_tx, alarm_command_rx) = mpsc::channel::<(CommandSource, AlarmCommand)>();
     let _alarm_event_queue = alarm_event_queue.clone();
 
     // TODO: make siren a configurable entity
-    let mut siren_pin = PinDriver::output(pins.gpio27)?;
-    siren_pin.set_low()?;
+    let siren_pwm: Option<SirenPwmConfig> = include!(concat!(env!("OUT_DIR"), "/siren_pwm.rs"));
+    let siren: Box<dyn siren::SirenOutput + Send> = match siren_pwm {
+        Some(config) => Box::new(siren::SirenPwm::new(
+            config,
+            peripherals.ledc.timer1,
+            peripherals.ledc.channel1,
+            pins.gpio27,
+        )?),
+        None => {
+            let mut siren_pin = PinDriver::output(pins.gpio27)?;
+            siren_pin.set_low()?;
+            Box::new(siren_pin)
+        }
+    };
 
+    let languages: Vec<Language> = include!(concat!(env!("OUT_DIR"), "/languages.rs"));
+    let language = settings.language().and_then(|code| {
+        let language = languages
+            .into_iter()
+            .find(|language| language.language == code);
+        if language.is_none() {
+            log::warn!("Language {} is not in the configuration", code);
+        }
+        language
+    });
     let entities: Vec<HAEntity> = include!(concat!(env!("OUT_DIR"), "/entities.rs"));
+    let entities: Vec<HAEntity> = entities
+        .into_iter()
+        .map(
+            |entity| match language.as_ref().and_then(|l| l.name(&entity.unique_id)) {
+                Some(name) => HAEntity {
+                    name: name.to_string(),
+                    ..entity
+                },
+                None => entity,
+            },
+        )
+        .collect();
+    let dsc: Option<DscConfig> = include!(concat!(env!("OUT_DIR"), "/dsc.rs"));
+    // A DSC panel is armed and disarmed at its own keypads, HA is only offered its state
+    let entities: Vec<HAEntity> = entities
+        .into_iter()
+        .map(|entity| match entity.variant {
+            HAEntityVariant::alarm_control_panel if dsc.is_some() => HAEntity {
+                command_topic: None,
+                dual_disarm: None,
+                ..entity
+            },
+            _ => entity,
+        })
+        .collect();
+    let mut expander_inputs = Vec::new();
+    let mut can_inputs = Vec::new();
+    let mut virtual_zones = Vec::new();
     let mut motion_entites = entities
         .clone()
         .into_iter()
         .filter_map(|entity| {
-            let pin = match entity.gpio_pin {
-                // SAFETY: clone_unchecked() calls are safe because
-                // we guarantee that the offending GPIO pins are only used by
+            let input: Box<dyn alarm::ZoneInput> = if let Some(pin) = entity.gpio_pin {
+                // SAFETY: we guarantee that the offending GPIO pins are only used by
                 // the alarm task throughout the lifetime of the program.
-                Some(pin) => unsafe {
-                    let pin: Option<AnyIOPin> = gpio_pin_num_to_peripheral!(pin, pins, 0, 2)
-                        .or_else(|| gpio_pin_num_to_peripheral!(pin, pins, 3, 5))
-                        .or_else(|| gpio_pin_num_to_peripheral!(pin, pins, 6, 18))
-                        .or_else(|| gpio_pin_num_to_peripheral!(pin, pins, 21, 23))
-                        .or_else(|| gpio_pin_num_to_peripheral!(pin, pins, 25, 26))
-                        .or_else(|| gpio_pin_num_to_peripheral!(pin, pins, 32, 33));
-                    pin.expect("Invalid GPIO pin provided")
-                },
-                None => return None,
+                let pin = unsafe { gpio_pin!(pins, pin) }.expect("Invalid GPIO pin provided");
+                let mut pin_driver = PinDriver::input(pin).unwrap();
+                pin_driver
+                    .set_pull(esp_idf_svc::hal::gpio::Pull::Up)
+                    .unwrap();
+                Box::new(alarm::GpioZone::new(pin_driver))
+            } else if let Some(point) = entity.modbus_input.clone() {
+                let state = Arc::new(AtomicBool::new(false));
+                expander_inputs.push(modbus::ExpanderInput {
+                    point,
+                    state: state.clone(),
+                });
+                Box::new(state)
+            } else if let Some(point) = entity.can_input.clone() {
+                let state = Arc::new(AtomicBool::new(false));
+                can_inputs.push(modbus::ExpanderInput {
+                    point,
+                    state: state.clone(),
+                });
+                Box::new(state)
+            } else if let Some(topic) = entity.virtual_topic.clone() {
+                let state = Arc::new(AtomicBool::new(false));
+                virtual_zones.push((topic, state.clone()));
+                Box::new(state)
+            } else {
+                return None;
             };
-            let mut pin_driver = PinDriver::input(pin).unwrap();
-            pin_driver
-                .set_pull(esp_idf_svc::hal::gpio::Pull::Up)
-                .unwrap();
 
             Some(alarm::AlarmMotionEntity {
-                entity,
-                pin_driver,
+                entity: Arc::new(entity),
+                input,
                 motion: false,
             })
         })
-        .collect::<Vec<alarm::AlarmMotionEntity<_, _>>>();
+        .collect::<Vec<alarm::AlarmMotionEntity>>();
 
     let alarm_entity = entities
         .iter()
@@ -179,42 +369,379 @@ fn main() -> anyhow::Result<()> {
         .expect("Alarm entity not found")
         .clone();
 
-    tasks.push(spawn_task(
-        move || {
-            alarm::alarm_task(
-                _alarm_event_queue,
-                alarm_command_rx,
-                nvs,
-                &mut motion_entites,
-                alarm_entity,
-                siren_pin,
-            );
-        },
-        "alarm\0",
-        Some(Core::Core1),
-    )?);
+    // Modbus expander task
+    let modbus: Option<ModbusConfig> = include!(concat!(env!("OUT_DIR"), "/modbus.rs"));
+    let expander_command_tx = if let Some(modbus) = modbus {
+        // SAFETY: pins of the RS485 bus are only used by the expander task
+        let (tx, rx, de) = unsafe {
+            (
+                gpio_pin!(pins, modbus.tx_pin).expect("Invalid modbus tx_pin provided"),
+                gpio_pin!(pins, modbus.rx_pin).expect("Invalid modbus rx_pin provided"),
+                gpio_pin!(pins, modbus.de_pin).expect("Invalid modbus de_pin provided"),
+            )
+        };
+        let uart = UartDriver::new(
+            peripherals.uart1,
+            tx,
+            rx,
+            Option::<AnyIOPin>::None,
+            Option::<AnyIOPin>::None,
+            &UartConfig::default().baudrate(Hertz(modbus.baudrate)),
+        )?;
+        let master = modbus::ModbusMaster::new(uart, PinDriver::output(AnyOutputPin::from(de))?);
+
+        let (expander_command_tx, expander_command_rx) = mpsc::channel();
+        let alarm_event_queue_expander = alarm_event_queue.clone();
+        tasks.push(spawn_task(
+            move || {
+                modbus::expander_task(
+                    master,
+                    expander_inputs,
+                    expander_command_rx,
+                    alarm_event_queue_expander,
+                    Duration::from_millis(modbus.poll_interval),
+                );
+            },
+            "expander\0",
+            Some(Core::Core1),
+        )?);
+        Some(expander_command_tx)
+    } else {
+        None
+    };
+
+    let follow_outputs = entities
+        .iter()
+        .filter_map(|entity| {
+            let zones = entity.follow_zones.clone()?;
+            let expander_command_tx = expander_command_tx.clone()?;
+            Some(alarm::FollowOutput::new(
+                entity.clone(),
+                zones,
+                Duration::from_secs(entity.follow_duration.unwrap_or(0)),
+                expander_command_tx,
+            ))
+        })
+        .collect::<Vec<_>>();
+    let dialer_outputs = entities
+        .iter()
+        .filter_map(|entity| {
+            let contact = entity.dialer_contact.clone()?;
+            let expander_command_tx = expander_command_tx.clone()?;
+            Some(alarm::DialerOutput::new(
+                entity.clone(),
+                contact,
+                expander_command_tx,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    let siren_entity = entities
+        .iter()
+        .find(|entity| entity.siren.unwrap_or(false))
+        .cloned();
+    let setting_entities = entities
+        .iter()
+        .filter(|entity| {
+            entity.alarm_setting.is_some()
+                || entity.alarm_toggle.is_some()
+                || entity.bypass_zone.is_some()
+                || entity.arming_profiles.is_some()
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let alarm_state = Arc::new(std::sync::Mutex::new(AlarmState::Disarmed));
+    let alarm_state_alarm = alarm_state.clone();
+    // The alarm task and the presence grace period of the scheduler run on the same clock
+    let clock = Arc::new(clock::SystemClock);
+    let clock_alarm = clock.clone();
+    if let Some(dsc) = dsc.as_ref() {
+        // In panel interface mode the DSC panel is the alarm, we only bridge its state
+        // SAFETY: pins of the keybus are only used by the DSC task
+        let (clock, data) = unsafe {
+            (
+                gpio_pin!(pins, dsc.clock_pin).expect("Invalid dsc clock_pin provided"),
+                gpio_pin!(pins, dsc.data_pin).expect("Invalid dsc data_pin provided"),
+            )
+        };
+        let clock_pin = PinDriver::input(clock)?;
+        let data_pin = PinDriver::input(data)?;
+        let zones = entities
+            .iter()
+            .filter_map(|entity| entity.dsc_zone.map(|zone| (zone, Arc::new(entity.clone()))))
+            .collect::<Vec<_>>();
+
+        tasks.push(spawn_task(
+            move || {
+                dsc::dsc_task(
+                    clock_pin,
+                    data_pin,
+                    zones,
+                    alarm_entity,
+                    _alarm_event_queue,
+                    alarm_command_rx,
+                    alarm_state_alarm,
+                );
+            },
+            "dsc\0",
+            Some(Core::Core1),
+        )?);
+    } else {
+        let nvs_alarm = nvs.clone();
+        let transitions: TransitionTable = include!(concat!(env!("OUT_DIR"), "/transitions.rs"));
+        let quiet_hours = settings.quiet_hours();
+        let disarm_code = settings.disarm_code();
+        tasks.push(spawn_task(
+            move || {
+                alarm::alarm_task(
+                    _alarm_event_queue,
+                    alarm_command_rx,
+                    nvs_alarm,
+                    &mut motion_entites,
+                    alarm_entity,
+                    siren,
+                    alarm_state_alarm,
+                    follow_outputs,
+                    dialer_outputs,
+                    siren_entity,
+                    setting_entities,
+                    transitions,
+                    quiet_hours,
+                    disarm_code,
+                    clock_alarm,
+                );
+            },
+            "alarm\0",
+            Some(Core::Core1),
+        )?);
+    }
+
+    // CAN bus task
+    let can: Option<CanConfig> = include!(concat!(env!("OUT_DIR"), "/can.rs"));
+    if let Some(can) = can {
+        // SAFETY: pins of the CAN bus are only used by the CAN task
+        let (tx, rx) = unsafe {
+            (
+                gpio_pin!(pins, can.tx_pin).expect("Invalid can tx_pin provided"),
+                gpio_pin!(pins, can.rx_pin).expect("Invalid can rx_pin provided"),
+            )
+        };
+        let driver = CanDriver::new(
+            peripherals.can,
+            tx,
+            rx,
+            &CanDriverConfig::new().timing(Timing::B125K),
+        )?;
+
+        let alarm_command_tx_can = alarm_command_tx.clone();
+        let alarm_state_can = alarm_state.clone();
+        tasks.push(spawn_task(
+            move || {
+                canbus::can_task(driver, can_inputs, alarm_command_tx_can, alarm_state_can);
+            },
+            "can\0",
+            Some(Core::Core1),
+        )?);
+    }
+
+    // RTC task
+    if let Some(rtc) = rtc {
+        tasks.push(spawn_task(
+            move || {
+                rtc::rtc_task(rtc);
+            },
+            "rtc\0",
+            Some(Core::Core0),
+        )?);
+    }
+
+    // Flash log task
+    if let Some((_, flash_log)) = flash_log.as_ref() {
+        let flash_log = flash_log.clone();
+        tasks.push(spawn_task(
+            move || {
+                flash_log::flash_log_task(flash_log, flash_log_rx);
+            },
+            "flash_log\0",
+            Some(Core::Core0),
+        )?);
+    }
+
+    let (mqtt_connection, mqtt_publisher) = mqtt_connection::MqttConnection::new();
+    let mut event_subscribers = Vec::new();
+
+    // Native API task
+    let native_api: Option<NativeApiConfig> = include!(concat!(env!("OUT_DIR"), "/native_api.rs"));
+    if let Some(native_api) = native_api {
+        let (native_api_tx, native_api_rx) = mpsc::channel();
+        let entities_native_api = entities.clone();
+        let alarm_command_tx_native_api = alarm_command_tx.clone();
+        let expander_command_tx_native_api = expander_command_tx.clone();
+        let requires_code = dsc.is_none()
+            && (settings.disarm_code().is_some()
+                || entities.iter().any(|entity| entity.dual_disarm.is_some()));
+        tasks.push(spawn_task(
+            move || {
+                native_api::native_api_task(
+                    native_api.port,
+                    native_api.password,
+                    entities_native_api,
+                    native_api_rx,
+                    alarm_command_tx_native_api,
+                    expander_command_tx_native_api,
+                    requires_code,
+                );
+            },
+            "native_api\0",
+            Some(Core::Core0),
+        )?);
+        event_subscribers.push(native_api_tx);
+    }
+
+    // SD card archive task
+    if let Some(sd_card) = sd_card.as_ref() {
+        match archive::mount(sd_card.cs_pin) {
+            Ok(()) => {
+                let (archive_tx, archive_rx) = mpsc::channel();
+                tasks.push(spawn_task(
+                    move || {
+                        archive::archive_task(archive_rx, archive_log_rx);
+                    },
+                    "archive\0",
+                    Some(Core::Core0),
+                )?);
+                event_subscribers.push(archive_tx);
+            }
+            Err(e) => error!("Failed to mount SD card, archiving is disabled: {:?}", e),
+        }
+    }
+
+    // Event export task
+    let event_export: Option<EventExportConfig> =
+        include!(concat!(env!("OUT_DIR"), "/event_export.rs"));
+    if let Some(event_export) = event_export {
+        let (event_export_tx, event_export_rx) = mpsc::channel();
+        let mqtt_publisher_event_export = mqtt_publisher.clone();
+        tasks.push(spawn_task(
+            move || {
+                event_export::event_export_task(
+                    event_export,
+                    event_export_rx,
+                    mqtt_publisher_event_export,
+                );
+            },
+            "event_export\0",
+            Some(Core::Core0),
+        )?);
+        event_subscribers.push(event_export_tx);
+    }
 
     // Scheduler task
+    let restart_eth = Arc::new(AtomicBool::new(false));
     let (status_tx, status_rx) = mpsc::channel::<StatusEvent>();
-    let status_tx_scheduler = status_tx.clone();
+    let (message_tx, message_rx) = mpsc::sync_channel::<MqttMessage>(MESSAGE_CHANNEL_SIZE);
     let alarm_command_tx_scheduler = alarm_command_tx.clone();
     let alarm_event_queue_scheduler = alarm_event_queue.clone();
+    // Sent once the broker is connected
+    if let Some(report) = boot_report {
+        mqtt_publisher.publish_retained(&report.topic, report.payload);
+    }
+    let config_snapshot_topic: Option<String> =
+        include!(concat!(env!("OUT_DIR"), "/config_snapshot_topic.rs"));
+    if let Some(topic) = config_snapshot_topic {
+        let features: Vec<String> = include!(concat!(env!("OUT_DIR"), "/features.rs"));
+        let snapshot = config_snapshot::config_snapshot(&entities, &features, &nvs, &settings);
+        mqtt_publisher.publish_retained(&topic, snapshot);
+    }
+    let settings_audit_topic: Option<String> =
+        include!(concat!(env!("OUT_DIR"), "/settings_audit_topic.rs"));
+    let presence: Option<PresenceConfig> = include!(concat!(env!("OUT_DIR"), "/presence.rs"));
+    let presence = presence.map(|mut presence| {
+        if let Err(e) = presence.check_auto_disarm(settings.disarm_code().is_some(), false) {
+            error!("{}, auto_disarm is turned off", e);
+            presence.auto_disarm = false;
+        }
+        presence
+    });
+    let scheduler_options = scheduler::SchedulerOptions {
+        presence,
+        expander_command_tx,
+        sd_card,
+        flash_log,
+        loopback: include!(concat!(env!("OUT_DIR"), "/loopback.rs")),
+        net_command_topic: include!(concat!(env!("OUT_DIR"), "/net_command_topic.rs")),
+        restart_eth: restart_eth.clone(),
+        alarm_state: alarm_state.clone(),
+        resend_command_topic: include!(concat!(env!("OUT_DIR"), "/resend_command_topic.rs")),
+        time_jump_topic: include!(concat!(env!("OUT_DIR"), "/time_jump_topic.rs")),
+        dedupe_publishes: env!("ESP_DEDUPE_PUBLISHES") == "true",
+        bell_test: include!(concat!(env!("OUT_DIR"), "/bell_test.rs")),
+        virtual_zones,
+        nvs,
+        mqtt_endpoint: mqtt_endpoint.clone(),
+        disarm_code: settings.disarm_code(),
+        settings_audit_topic: settings_audit_topic.clone(),
+        language,
+        mqtt_connection,
+        power,
+        provisioning_topic: include!(concat!(env!("OUT_DIR"), "/provisioning_topic.rs")),
+        clock,
+    };
     tasks.push(spawn_task(
         move || {
             scheduler::scheduler_task(
                 &entities,
                 status_rx,
-                status_tx_scheduler,
+                message_rx,
                 alarm_event_queue_scheduler,
                 alarm_command_tx_scheduler,
+                event_subscribers,
+                scheduler_options,
             );
         },
         "scheduler\0",
         Some(Core::Core0),
     )?);
 
+    // Serial console
+    let network_settings = network::NetworkSettings {
+        mqtt_endpoint: mqtt_endpoint.clone(),
+        hostname: settings.hostname(),
+        mqtt_core: settings.mqtt_core(),
+    };
+    let console_publisher = mqtt_publisher.clone();
+    let console_status_tx = status_tx.clone();
+    tasks.push(spawn_task(
+        move || {
+            console::console_task(
+                settings,
+                mqtt_endpoint,
+                alarm_state,
+                console_publisher,
+                settings_audit_topic,
+                console_status_tx,
+            )
+            .unwrap_or_else(|e| {
+                error!("Serial console failed: {:?}", e);
+            });
+        },
+        "console\0",
+        Some(Core::Core0),
+    )?);
+
     // Network stack
-    network::init(eth, sysloop.clone(), timer, status_tx.clone(), &mut tasks)?;
+    network::init(
+        eth,
+        sysloop.clone(),
+        timer,
+        status_tx.clone(),
+        message_tx,
+        mqtt_publisher,
+        restart_eth,
+        network_settings,
+        &mut tasks,
+    )?;
 
     // Wait for tasks to exit
     for task in tasks {
@@ -228,17 +755,18 @@ fn main() -> anyhow::Result<()> {
     }
 }
 
+/// Lifecycle of the network and the MQTT connection, inbound messages have a channel of their own
 enum StatusEvent {
     EthConnected,
     EthDisconnected,
-    MqttConnected(
-        esp_idf_svc::mqtt::client::EspMqttClient<'static, ConnState<MessageImpl, EspError>>,
-    ),
-    MqttReconnected,
-    MqttDisconnected,
-    MqttMessage(MqttMessage),
+    Mqtt(mqtt_connection::ConnectionEvent),
+    /// An intentional restart, which the scheduler performs after going offline
+    RestartRequested(String),
 }
 
+/// Inbound messages waiting for the scheduler, further ones are dropped until it catches up
+const MESSAGE_CHANNEL_SIZE: usize = 32;
+
 #[derive(Debug, Clone)]
 struct MqttMessage {
     topic: String,
@@ -256,13 +784,29 @@ fn simulation() -> anyhow::Result<()> {
 
     let (alarm_command_tx, alarm_command_rx) = channel();
 
+    // Time runs ten times faster, so the exit and entry delays pass quickly
+    let clock = Arc::new(clock::ManualClock::new());
+    let clock_alarm = clock.clone();
+    spawn_task(
+        move || loop {
+            thread::sleep(Duration::from_millis(100));
+            clock.advance(Duration::from_secs(1));
+        },
+        "clock\0",
+        None,
+    )?;
+
     // generate some alarm commands
     spawn_task(
         move || loop {
             thread::sleep(std::time::Duration::from_secs(5));
-            alarm_command_tx.send(AlarmCommand::Arm).unwrap();
+            alarm_command_tx
+                .send((CommandSource::Mqtt, AlarmCommand::Arm))
+                .unwrap();
             thread::sleep(std::time::Duration::from_secs(20));
-            alarm_command_tx.send(AlarmCommand::Disarm).unwrap();
+            alarm_command_tx
+                .send((CommandSource::Mqtt, AlarmCommand::Disarm(None)))
+                .unwrap();
         },
         "alarm_command_generator\0",
         None,
@@ -297,14 +841,16 @@ fn simulation() -> anyhow::Result<()> {
                 .unwrap();
 
             Some(alarm::AlarmMotionEntity {
-                entity,
-                pin_driver,
+                entity: Arc::new(entity),
+                input: Box::new(pin_driver),
                 motion: false,
             })
         })
-        .collect::<Vec<alarm::AlarmMotionEntity<_, _>>>();
+        .collect::<Vec<alarm::AlarmMotionEntity>>();
 
-    let queue = Arc::new(std::sync::Mutex::new(VecDeque::new()));
+    let siren_pin = PinDriver::output(pins.gpio27)?;
+    let alarm_state = Arc::new(std::sync::Mutex::new(AlarmState::Disarmed));
+    let queue = Arc::new(EventQueue::new());
 
     let alarm_event_queue = queue.clone();
     spawn_task(
@@ -315,6 +861,16 @@ fn simulation() -> anyhow::Result<()> {
                 nvs,
                 &mut motion_entites,
                 alarm_entity,
+                Box::new(siren_pin),
+                alarm_state,
+                Vec::new(),
+                Vec::new(),
+                None,
+                Vec::new(),
+                TransitionTable::default(),
+                DailyWindow::parse("22:00-07:00").unwrap(),
+                None,
+                clock_alarm,
             );
         },
         "alarm\0",
@@ -323,10 +879,8 @@ fn simulation() -> anyhow::Result<()> {
 
     loop {
         // empty the queue
-        if let Ok(mut queue) = queue.try_lock() {
-            if let Some(event) = queue.pop_front() {
-                println!("Popped alarm event: {:?}", event);
-            }
+        if let Some(event) = queue.try_pop() {
+            println!("Popped alarm event: {:?}", event);
         }
         thread::sleep(std::time::Duration::from_secs(1));
     }
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::bail;
+use esp_idf_hal::delay::TickType;
+use esp_idf_hal::gpio::{AnyOutputPin, Output, PinDriver};
+use esp_idf_hal::uart::UartDriver;
+use ha_types::*;
+
+use crate::event_queue::EventQueue;
+use crate::AlarmEvent;
+
+const RESPONSE_TIMEOUT_MS: u64 = 100;
+
+const READ_DISCRETE_INPUTS: u8 = 0x02;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+
+/// Modbus RTU master on a half-duplex RS485 bus
+pub struct ModbusMaster<'d> {
+    uart: UartDriver<'d>,
+    de_pin: PinDriver<'d, AnyOutputPin, Output>,
+}
+
+impl<'d> ModbusMaster<'d> {
+    pub fn new(uart: UartDriver<'d>, mut de_pin: PinDriver<'d, AnyOutputPin, Output>) -> Self {
+        de_pin.set_low().unwrap_or_else(|e| {
+            log::error!("Failed to set RS485 DE pin low: {:?}", e);
+        });
+        Self { uart, de_pin }
+    }
+
+    pub fn read_discrete_inputs(
+        &mut self,
+        address: u8,
+        start: u16,
+        count: u16,
+    ) -> anyhow::Result<Vec<bool>> {
+        let [start_hi, start_lo] = start.to_be_bytes();
+        let [count_hi, count_lo] = count.to_be_bytes();
+        let request = [
+            address,
+            READ_DISCRETE_INPUTS,
+            start_hi,
+            start_lo,
+            count_hi,
+            count_lo,
+        ];
+
+        let byte_count = (count as usize).div_ceil(8);
+        let mut response = vec![0u8; 3 + byte_count + 2];
+        self.transaction(&request, &mut response)?;
+        if response[2] as usize != byte_count {
+            bail!("unexpected byte count in response: {}", response[2]);
+        }
+
+        Ok((0..count as usize)
+            .map(|i| response[3 + i / 8] & (1 << (i % 8)) != 0)
+            .collect())
+    }
+
+    pub fn write_single_coil(&mut self, address: u8, coil: u16, on: bool) -> anyhow::Result<()> {
+        let [coil_hi, coil_lo] = coil.to_be_bytes();
+        let [value_hi, value_lo] = if on { 0xFF00u16 } else { 0 }.to_be_bytes();
+        let request = [
+            address,
+            WRITE_SINGLE_COIL,
+            coil_hi,
+            coil_lo,
+            value_hi,
+            value_lo,
+        ];
+
+        // the response is an echo of the request
+        let mut response = [0u8; 8];
+        self.transaction(&request, &mut response)?;
+        if response[..request.len()] != request {
+            bail!("unexpected response to write single coil");
+        }
+        Ok(())
+    }
+
+    fn transaction(&mut self, request: &[u8], response: &mut [u8]) -> anyhow::Result<()> {
+        let mut frame = request.to_vec();
+        frame.extend_from_slice(&crc16(request).to_le_bytes());
+
+        let timeout = TickType::new_millis(RESPONSE_TIMEOUT_MS).ticks();
+
+        self.uart.clear_rx()?;
+        self.de_pin.set_high()?;
+        let written = self
+            .uart
+            .write(&frame)
+            .and_then(|_| self.uart.wait_tx_done(timeout));
+        self.de_pin.set_low()?;
+        written?;
+
+        let mut read = 0;
+        while read < response.len() {
+            let n = self.uart.read(&mut response[read..], timeout)?;
+            if n == 0 {
+                bail!("timed out waiting for response from {}", request[0]);
+            }
+            read += n;
+
+            // exception responses are only 5 bytes long
+            if read >= 5 && response[1] & 0x80 != 0 {
+                if crc16(&response[..3]).to_le_bytes() != response[3..5] {
+                    bail!("CRC mismatch in exception response");
+                }
+                bail!("exception response from {}: {}", request[0], response[2]);
+            }
+        }
+
+        let (payload, crc) = response.split_at(response.len() - 2);
+        if crc16(payload).to_le_bytes() != crc {
+            bail!("CRC mismatch in response from {}", request[0]);
+        }
+        if payload[0] != request[0] || payload[1] != request[1] {
+            bail!("response does not match request");
+        }
+        Ok(())
+    }
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// An expander input which is mirrored into a zone
+pub struct ExpanderInput {
+    pub point: ExpanderPoint,
+    pub state: Arc<AtomicBool>,
+}
+
+pub enum ExpanderCommand {
+    SetRelay(HAEntity, bool),
+}
+
+pub fn expander_task(
+    mut master: ModbusMaster,
+    inputs: Vec<ExpanderInput>,
+    command_rx: Receiver<ExpanderCommand>,
+    event_queue: Arc<EventQueue>,
+    poll_interval: Duration,
+) -> ! {
+    // Read every board's inputs with a single request covering all configured ones
+    let mut boards: BTreeMap<u8, (u16, u16)> = BTreeMap::new();
+    for input in inputs.iter() {
+        let range = boards
+            .entry(input.point.address)
+            .or_insert((input.point.index, input.point.index));
+        range.0 = range.0.min(input.point.index);
+        range.1 = range.1.max(input.point.index);
+    }
+
+    loop {
+        for (address, (first, last)) in boards.iter() {
+            match master.read_discrete_inputs(*address, *first, last - first + 1) {
+                Ok(states) => {
+                    for input in inputs.iter().filter(|i| i.point.address == *address) {
+                        let state = states[(input.point.index - first) as usize];
+                        input.state.store(state, Ordering::Relaxed);
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Failed to read inputs of expander {}: {:?}", address, e);
+                }
+            }
+        }
+
+        loop {
+            match command_rx.try_recv() {
+                Ok(ExpanderCommand::SetRelay(entity, on)) => {
+                    let Some(point) = entity.modbus_relay.as_ref() else {
+                        log::warn!("{} has no modbus relay", entity.name);
+                        continue;
+                    };
+                    match master.write_single_coil(point.address, point.index, on) {
+                        Ok(()) => {
+                            log::info!("Relay {}: {}", entity.name, on);
+                            event_queue.push(AlarmEvent::OutputStateChanged((entity, on)));
+                        }
+                        Err(e) => {
+                            log::error!("Failed to set relay {}: {:?}", entity.name, e);
+                        }
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => panic!("command_rx disconnected"),
+            }
+        }
+
+        std::thread::sleep(poll_interval);
+    }
+}
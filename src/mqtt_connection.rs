@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use esp_idf_svc::mqtt::client::{ConnState, EspMqttClient, MessageImpl, QoS};
+use esp_idf_sys::EspError;
+use ha_types::MqttStat;
+
+use crate::mqtt_stats;
+
+pub type MqttClient = EspMqttClient<'static, ConnState<MessageImpl, EspError>>;
+
+static NEXT_CLIENT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Id of a new client of the network task, events of replaced clients are ignored
+pub fn next_client_id() -> u32 {
+    NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Lifecycle of a client of the network task, tagged with the id of the client
+pub enum ConnectionEvent {
+    /// The client connected for the first time and is handed over
+    Connected((u32, MqttClient)),
+    /// The client reconnected by itself after losing the connection
+    Reconnected(u32),
+    /// The connection was lost, the client keeps reconnecting by itself
+    Disconnected(u32),
+    /// The client has ended, the network task creates a new one
+    Closed(u32),
+}
+
+impl ConnectionEvent {
+    fn client_id(&self) -> u32 {
+        match self {
+            ConnectionEvent::Connected((id, _))
+            | ConnectionEvent::Reconnected(id)
+            | ConnectionEvent::Disconnected(id)
+            | ConnectionEvent::Closed(id) => *id,
+        }
+    }
+}
+
+enum State {
+    /// Waiting for the network task to connect a client
+    Down,
+    Connected {
+        id: u32,
+        client: MqttClient,
+    },
+    /// Messages published meanwhile wait in the outbox of the client
+    Reconnecting {
+        id: u32,
+        client: MqttClient,
+    },
+}
+
+/// Owns the MQTT client of the scheduler and follows the state of its connection
+pub struct MqttConnection {
+    state: State,
+    queue: Receiver<Publish>,
+    outbox: VecDeque<Publish>,
+}
+
+/// Messages kept while the broker is unreachable, the oldest are dropped beyond this
+const OUTBOX_SIZE: usize = 32;
+
+struct Publish {
+    topic: String,
+    payload: String,
+    retain: bool,
+}
+
+/// Publishes through the connection of the scheduler from any task
+///
+/// Messages are queued while the broker is unreachable and sent in order once
+/// it is connected again, failed sends are retried.
+#[derive(Clone)]
+pub struct MqttPublisher(Sender<Publish>);
+
+impl MqttPublisher {
+    pub fn publish(&self, topic: &str, payload: String) {
+        self.send(topic, payload, false);
+    }
+
+    pub fn publish_retained(&self, topic: &str, payload: String) {
+        self.send(topic, payload, true);
+    }
+
+    fn send(&self, topic: &str, payload: String, retain: bool) {
+        let message = Publish {
+            topic: topic.to_string(),
+            payload,
+            retain,
+        };
+        self.0.send(message).unwrap_or_else(|e| {
+            log::error!("Failed to queue MQTT message: {}", e);
+        });
+    }
+}
+
+impl MqttConnection {
+    pub fn new() -> (Self, MqttPublisher) {
+        let (tx, rx) = mpsc::channel();
+        let connection = Self {
+            state: State::Down,
+            queue: rx,
+            outbox: VecDeque::new(),
+        };
+        (connection, MqttPublisher(tx))
+    }
+
+    /// Follows an event of the network task, returns the client if it has just
+    /// connected and has to be initialized
+    pub fn handle(&mut self, event: ConnectionEvent) -> Option<&mut MqttClient> {
+        let current_id = match &self.state {
+            State::Down => None,
+            State::Connected { id, .. } | State::Reconnecting { id, .. } => Some(*id),
+        };
+        let event_id = event.client_id();
+        let state = std::mem::replace(&mut self.state, State::Down);
+        let (state, connected) = match (state, event) {
+            // A new client replaces the previous one
+            (_, ConnectionEvent::Connected((id, client))) => {
+                log::info!("MQTT client {} connected", id);
+                (State::Connected { id, client }, true)
+            }
+            // Dropped clients can still report until their task has ended
+            (state, _) if current_id != Some(event_id) => {
+                log::debug!("Ignoring an event of the replaced MQTT client {}", event_id);
+                (state, false)
+            }
+            (State::Reconnecting { id, client }, ConnectionEvent::Reconnected(_)) => {
+                log::info!("MQTT client {} reconnected", id);
+                (State::Connected { id, client }, true)
+            }
+            (State::Connected { id, client }, ConnectionEvent::Disconnected(_)) => {
+                log::info!("MQTT client {} disconnected", id);
+                mqtt_stats::count(MqttStat::disconnects);
+                (State::Reconnecting { id, client }, false)
+            }
+            (State::Connected { id, .. }, ConnectionEvent::Closed(_)) => {
+                log::info!("MQTT client {} closed", id);
+                mqtt_stats::count(MqttStat::disconnects);
+                (State::Down, false)
+            }
+            (State::Reconnecting { id, .. }, ConnectionEvent::Closed(_)) => {
+                log::info!("MQTT client {} closed", id);
+                (State::Down, false)
+            }
+            (state, _) => (state, false),
+        };
+        self.state = state;
+
+        if connected {
+            mqtt_stats::count(MqttStat::connects);
+        }
+        match &mut self.state {
+            State::Connected { client, .. } if connected => Some(client),
+            _ => None,
+        }
+    }
+
+    /// The client, also while it is reconnecting
+    pub fn client(&mut self) -> Option<&mut MqttClient> {
+        match &mut self.state {
+            State::Down => None,
+            State::Connected { client, .. } | State::Reconnecting { client, .. } => Some(client),
+        }
+    }
+
+    pub fn has_client(&self) -> bool {
+        !matches!(self.state, State::Down)
+    }
+
+    /// Drops the client, which ends its connection, then the network task connects a new one
+    pub fn close(&mut self) {
+        if let State::Connected { id, .. } = self.state {
+            log::info!("Closing MQTT client {}", id);
+            mqtt_stats::count(MqttStat::disconnects);
+        }
+        self.state = State::Down;
+    }
+
+    /// Sends the messages of other tasks while connected, keeps them otherwise
+    pub fn flush_outbox(&mut self) {
+        while let Ok(message) = self.queue.try_recv() {
+            if self.outbox.len() == OUTBOX_SIZE {
+                if let Some(dropped) = self.outbox.pop_front() {
+                    log::warn!("MQTT outbox is full, dropping message to {}", dropped.topic);
+                }
+            }
+            self.outbox.push_back(message);
+        }
+        let State::Connected { client, .. } = &mut self.state else {
+            return;
+        };
+        while let Some(message) = self.outbox.front() {
+            let result = client.publish(
+                &message.topic,
+                QoS::AtLeastOnce,
+                message.retain,
+                message.payload.as_bytes(),
+            );
+            if let Err(e) = result {
+                // Retried on the next flush
+                mqtt_stats::count(MqttStat::publish_errors);
+                log::warn!("Failed to publish to {}: {}", message.topic, e);
+                break;
+            }
+            self.outbox.pop_front();
+        }
+    }
+}
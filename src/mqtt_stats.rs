@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use ha_types::{HAEntity, MqttStat};
+use serde_json::json;
+
+const NVS_NAMESPACE: &str = "mqtt_stats";
+/// The lifetime counts are written at most this often, so reconnect storms don't wear the flash
+const PERSIST_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Counts since boot, in the order of `MqttStat::ALL`
+static COUNTS: [AtomicU32; 5] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+/// Counts an event of the MQTT connection, from any task
+pub fn count(stat: MqttStat) {
+    COUNTS[stat as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+fn counts() -> [u32; 5] {
+    MqttStat::ALL.map(|stat| COUNTS[stat as usize].load(Ordering::Relaxed))
+}
+
+/// Publishes the counts as sensor states and keeps the lifetime counts in NVS
+pub struct MqttStats {
+    nvs: Option<EspNvs<NvsDefault>>,
+    /// Lifetime counts before this boot
+    previous: [u32; 5],
+    published: Option<[u32; 5]>,
+    persisted: ([u32; 5], Instant),
+}
+
+impl MqttStats {
+    pub fn load(partition: EspDefaultNvsPartition) -> Self {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)
+            .map_err(|e| log::error!("Failed to open MQTT stats NVS namespace: {:?}", e))
+            .ok();
+        let previous = MqttStat::ALL.map(|stat| {
+            nvs.as_ref()
+                .and_then(|nvs| {
+                    nvs.get_u32(stat.key())
+                        .map_err(|e| log::error!("Failed to read {}: {:?}", stat.key(), e))
+                        .ok()
+                        .flatten()
+                })
+                .unwrap_or(0)
+        });
+        Self {
+            nvs,
+            previous,
+            published: None,
+            persisted: ([0; 5], Instant::now()),
+        }
+    }
+
+    /// States and attributes of the counter sensors which changed since the last poll
+    pub fn poll(&mut self, entities: &[HAEntity]) -> Vec<(String, String)> {
+        let counts = counts();
+        let lifetime = [0, 1, 2, 3, 4].map(|index| self.previous[index] + counts[index]);
+
+        if counts != self.persisted.0 && self.persisted.1.elapsed() >= PERSIST_INTERVAL {
+            if let Some(nvs) = self.nvs.as_ref() {
+                for (stat, count) in MqttStat::ALL.iter().zip(lifetime) {
+                    nvs.set_u32(stat.key(), count).unwrap_or_else(|e| {
+                        log::error!("Failed to persist {}: {:?}", stat.key(), e);
+                    });
+                }
+            }
+            self.persisted = (counts, Instant::now());
+        }
+
+        if self.published == Some(counts) {
+            return Vec::new();
+        }
+        self.published = Some(counts);
+        entities
+            .iter()
+            .filter_map(|entity| Some((entity, entity.mqtt_stat? as usize)))
+            .flat_map(|(entity, index)| {
+                let attributes = entity
+                    .attributes_topic()
+                    .map(|topic| (topic, json!({ "lifetime": lifetime[index] }).to_string()));
+                [(entity.state_topic.clone(), counts[index].to_string())]
+                    .into_iter()
+                    .chain(attributes)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,573 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::time::Duration;
+
+use anyhow::bail;
+use ha_types::*;
+
+use crate::modbus::ExpanderCommand;
+use crate::{AlarmCommand, AlarmEvent, AlarmState, CommandSource};
+
+const API_VERSION_MAJOR: u32 = 1;
+const API_VERSION_MINOR: u32 = 9;
+
+const HELLO_REQUEST: u32 = 1;
+const HELLO_RESPONSE: u32 = 2;
+const CONNECT_REQUEST: u32 = 3;
+const CONNECT_RESPONSE: u32 = 4;
+const DISCONNECT_REQUEST: u32 = 5;
+const DISCONNECT_RESPONSE: u32 = 6;
+const PING_REQUEST: u32 = 7;
+const PING_RESPONSE: u32 = 8;
+const DEVICE_INFO_REQUEST: u32 = 9;
+const DEVICE_INFO_RESPONSE: u32 = 10;
+const LIST_ENTITIES_REQUEST: u32 = 11;
+const LIST_ENTITIES_BINARY_SENSOR_RESPONSE: u32 = 12;
+const LIST_ENTITIES_SWITCH_RESPONSE: u32 = 17;
+const LIST_ENTITIES_DONE_RESPONSE: u32 = 19;
+const SUBSCRIBE_STATES_REQUEST: u32 = 20;
+const BINARY_SENSOR_STATE_RESPONSE: u32 = 21;
+const SWITCH_STATE_RESPONSE: u32 = 26;
+const SWITCH_COMMAND_REQUEST: u32 = 33;
+const LIST_ENTITIES_ALARM_CONTROL_PANEL_RESPONSE: u32 = 94;
+const ALARM_CONTROL_PANEL_STATE_RESPONSE: u32 = 95;
+const ALARM_CONTROL_PANEL_COMMAND_REQUEST: u32 = 96;
+
+const ALARM_STATE_DISARMED: u32 = 0;
+const ALARM_STATE_ARMED_HOME: u32 = 1;
+const ALARM_STATE_ARMED_AWAY: u32 = 2;
+const ALARM_STATE_ARMED_NIGHT: u32 = 3;
+const ALARM_STATE_PENDING: u32 = 6;
+const ALARM_STATE_ARMING: u32 = 7;
+const ALARM_STATE_TRIGGERED: u32 = 9;
+
+const ALARM_COMMAND_DISARM: u64 = 0;
+const ALARM_COMMAND_ARM_AWAY: u64 = 1;
+const ALARM_COMMAND_ARM_HOME: u64 = 2;
+const ALARM_COMMAND_ARM_NIGHT: u64 = 3;
+const ALARM_COMMAND_ARM_CUSTOM_BYPASS: u64 = 5;
+const ALARM_COMMAND_TRIGGER: u64 = 6;
+
+const ALARM_FEATURE_ARM_HOME: u32 = 1;
+const ALARM_FEATURE_ARM_AWAY: u32 = 2;
+const ALARM_FEATURE_ARM_NIGHT: u32 = 4;
+const ALARM_FEATURE_TRIGGER: u32 = 8;
+const ALARM_FEATURE_ARM_CUSTOM_BYPASS: u32 = 16;
+
+const MAX_MESSAGE_SIZE: usize = 1024;
+
+#[derive(Clone, Copy)]
+enum EntityState {
+    Binary(bool),
+    Alarm(u32),
+}
+
+/// Builder of protobuf encoded messages
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(((field as u64) << 3) | wire_type as u64);
+    }
+
+    fn uint32(&mut self, field: u32, value: u32) -> &mut Self {
+        self.key(field, 0);
+        self.varint(value as u64);
+        self
+    }
+
+    fn bool(&mut self, field: u32, value: bool) -> &mut Self {
+        self.uint32(field, value as u32)
+    }
+
+    fn fixed32(&mut self, field: u32, value: u32) -> &mut Self {
+        self.key(field, 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, field: u32, value: &str) -> &mut Self {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value.as_bytes());
+        self
+    }
+}
+
+enum FieldValue {
+    Varint(u64),
+    Fixed32(u32),
+    /// Strings and nested messages
+    Bytes(Vec<u8>),
+    /// Fields of other types are skipped, none of the handled requests use them
+    Other,
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> anyhow::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let Some(byte) = data.get(*pos) else {
+            bail!("truncated varint");
+        };
+        *pos += 1;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("varint too long");
+}
+
+fn parse_fields(data: &[u8]) -> anyhow::Result<Vec<(u32, FieldValue)>> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let key = read_varint(data, &mut pos)?;
+        let field = (key >> 3) as u32;
+        let value = match key & 0x7 {
+            0 => FieldValue::Varint(read_varint(data, &mut pos)?),
+            1 => {
+                if data.len() < pos + 8 {
+                    bail!("truncated fixed64");
+                }
+                pos += 8;
+                FieldValue::Other
+            }
+            2 => {
+                let len = read_varint(data, &mut pos)? as usize;
+                let Some(bytes) = data.get(pos..pos + len) else {
+                    bail!("truncated length delimited field");
+                };
+                pos += len;
+                FieldValue::Bytes(bytes.to_vec())
+            }
+            5 => {
+                let Some(bytes) = data.get(pos..pos + 4) else {
+                    bail!("truncated fixed32");
+                };
+                pos += 4;
+                FieldValue::Fixed32(u32::from_le_bytes(bytes.try_into()?))
+            }
+            wire_type => bail!("unsupported wire type {}", wire_type),
+        };
+        fields.push((field, value));
+    }
+    Ok(fields)
+}
+
+/// FNV-1a hash of the unique id, used as the entity key
+fn entity_key(entity: &HAEntity) -> u32 {
+    entity.unique_id.bytes().fold(0x811C9DC5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn object_id(entity: &HAEntity) -> String {
+    entity
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn alarm_state_value(state: &AlarmState) -> u32 {
+    match state {
+        AlarmState::Disarmed => ALARM_STATE_DISARMED,
+        AlarmState::Arming(_) => ALARM_STATE_ARMING,
+        AlarmState::Armed((_, ArmMode::away)) => ALARM_STATE_ARMED_AWAY,
+        AlarmState::Armed((_, ArmMode::home)) => ALARM_STATE_ARMED_HOME,
+        AlarmState::Armed((_, ArmMode::night)) => ALARM_STATE_ARMED_NIGHT,
+        AlarmState::Pending(_) => ALARM_STATE_PENDING,
+        AlarmState::Triggered => ALARM_STATE_TRIGGERED,
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    /// Only hello and connect requests are served before the client connected
+    connected: bool,
+    subscribed: bool,
+}
+
+impl Client {
+    fn send(&mut self, message_type: u32, message: &Message) -> anyhow::Result<()> {
+        let mut frame = Message::default();
+        frame.0.push(0x00);
+        frame.varint(message.0.len() as u64);
+        frame.varint(message_type as u64);
+        frame.0.extend_from_slice(&message.0);
+        self.stream.write_all(&frame.0)?;
+        Ok(())
+    }
+
+    /// Returns the next complete frame which has been received
+    fn next_frame(&mut self) -> anyhow::Result<Option<(u32, Vec<u8>)>> {
+        let mut chunk = [0u8; 256];
+        match self.stream.read(&mut chunk) {
+            Ok(0) => bail!("connection closed"),
+            Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        if self.buffer[0] != 0x00 {
+            bail!("encrypted connections are not supported");
+        }
+
+        let mut pos = 1;
+        let (len, message_type) = match (
+            read_varint(&self.buffer, &mut pos),
+            read_varint(&self.buffer, &mut pos),
+        ) {
+            (Ok(len), Ok(message_type)) => (len as usize, message_type as u32),
+            _ => return Ok(None),
+        };
+        if len > MAX_MESSAGE_SIZE {
+            bail!("message too large: {} bytes", len);
+        }
+        if self.buffer.len() < pos + len {
+            return Ok(None);
+        }
+
+        let payload = self.buffer[pos..pos + len].to_vec();
+        self.buffer.drain(..pos + len);
+        Ok(Some((message_type, payload)))
+    }
+}
+
+/// Serves the ESPHome native API (plaintext) so Home Assistant can connect without a broker
+pub fn native_api_task(
+    port: u16,
+    password: Option<String>,
+    entities: Vec<HAEntity>,
+    event_rx: Receiver<AlarmEvent>,
+    alarm_command_tx: Sender<(CommandSource, AlarmCommand)>,
+    expander_command_tx: Option<Sender<ExpanderCommand>>,
+    requires_code: bool,
+) -> ! {
+    let listener = TcpListener::bind(("0.0.0.0", port)).expect("Failed to bind native API port");
+    listener
+        .set_nonblocking(true)
+        .expect("Failed to set native API listener non-blocking");
+    log::info!("Native API listening on port {}", port);
+
+    let mut states: HashMap<u32, EntityState> = HashMap::new();
+    let mut client: Option<Client> = None;
+
+    loop {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                log::info!("Native API client connected: {}", addr);
+                let connected = stream
+                    .set_nonblocking(false)
+                    .and_then(|_| stream.set_read_timeout(Some(Duration::from_millis(10))));
+                match connected {
+                    Ok(()) => {
+                        client = Some(Client {
+                            stream,
+                            buffer: Vec::new(),
+                            connected: false,
+                            subscribed: false,
+                        })
+                    }
+                    Err(e) => log::warn!("Failed to set up native API client: {}", e),
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => log::warn!("Failed to accept native API client: {}", e),
+        }
+
+        loop {
+            let event = match event_rx.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => panic!("event_rx disconnected"),
+            };
+            let (entity, state) = match &event {
+                AlarmEvent::MotionDetected(entity) => (entity.as_ref(), EntityState::Binary(true)),
+                AlarmEvent::MotionCleared(entity) => (entity.as_ref(), EntityState::Binary(false)),
+                AlarmEvent::AlarmStateChanged((entity, state, _)) => {
+                    (entity, EntityState::Alarm(alarm_state_value(state)))
+                }
+                AlarmEvent::OutputStateChanged((entity, state)) => {
+                    (entity, EntityState::Binary(*state))
+                }
+                AlarmEvent::FireAlarmChanged(_)
+                | AlarmEvent::CommandResult(_)
+                | AlarmEvent::ZonesLearned(_)
+                | AlarmEvent::SettingChanged(_)
+                | AlarmEvent::PartialDisarmChanged(_)
+                | AlarmEvent::CommandConflict(_) => continue,
+            };
+            let key = entity_key(entity);
+            states.insert(key, state);
+
+            if let Some(c) = client.as_mut().filter(|c| c.subscribed) {
+                if let Err(e) = send_state(c, entity, key, state) {
+                    log::warn!("Native API client error: {:?}", e);
+                    client = None;
+                }
+            }
+        }
+
+        if let Some(c) = client.as_mut() {
+            let result = handle_client(
+                c,
+                &entities,
+                &states,
+                password.as_deref(),
+                &alarm_command_tx,
+                expander_command_tx.as_ref(),
+                requires_code,
+            );
+            match result {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::info!("Native API client disconnected");
+                    client = None;
+                }
+                Err(e) => {
+                    log::warn!("Native API client error: {:?}", e);
+                    client = None;
+                }
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn send_state(
+    client: &mut Client,
+    entity: &HAEntity,
+    key: u32,
+    state: EntityState,
+) -> anyhow::Result<()> {
+    match (state, &entity.variant) {
+        (EntityState::Alarm(state), _) => client.send(
+            ALARM_CONTROL_PANEL_STATE_RESPONSE,
+            Message::default().fixed32(1, key).uint32(2, state),
+        ),
+        (EntityState::Binary(state), HAEntityVariant::switch) => client.send(
+            SWITCH_STATE_RESPONSE,
+            Message::default().fixed32(1, key).bool(2, state),
+        ),
+        (EntityState::Binary(state), _) => client.send(
+            BINARY_SENSOR_STATE_RESPONSE,
+            Message::default().fixed32(1, key).bool(2, state),
+        ),
+    }
+}
+
+fn list_entities(
+    client: &mut Client,
+    entities: &[HAEntity],
+    requires_code: bool,
+) -> anyhow::Result<()> {
+    for entity in entities.iter() {
+        let key = entity_key(entity);
+        let mut message = Message::default();
+        message
+            .string(1, &object_id(entity))
+            .fixed32(2, key)
+            .string(3, &entity.name)
+            .string(4, &entity.unique_id);
+
+        match entity.variant {
+            HAEntityVariant::binary_sensor => {
+                if let Some(device_class) = &entity.device_class {
+                    message.string(5, device_class);
+                }
+                if let Some(icon) = &entity.icon {
+                    message.string(8, icon);
+                }
+                client.send(LIST_ENTITIES_BINARY_SENSOR_RESPONSE, &message)?;
+            }
+            HAEntityVariant::switch => {
+                if let Some(icon) = &entity.icon {
+                    message.string(5, icon);
+                }
+                client.send(LIST_ENTITIES_SWITCH_RESPONSE, &message)?;
+            }
+            HAEntityVariant::alarm_control_panel => {
+                if let Some(icon) = &entity.icon {
+                    message.string(5, icon);
+                }
+                // Without a command topic the alarm only shows its state, e.g. of a DSC panel
+                let features = match entity.command_topic {
+                    Some(_) => {
+                        ALARM_FEATURE_ARM_HOME
+                            | ALARM_FEATURE_ARM_AWAY
+                            | ALARM_FEATURE_ARM_NIGHT
+                            | ALARM_FEATURE_TRIGGER
+                            | ALARM_FEATURE_ARM_CUSTOM_BYPASS
+                    }
+                    None => 0,
+                };
+                message.uint32(8, features);
+                message.bool(9, requires_code);
+                client.send(LIST_ENTITIES_ALARM_CONTROL_PANEL_RESPONSE, &message)?;
+            }
+            HAEntityVariant::sensor
+            | HAEntityVariant::number
+            | HAEntityVariant::select
+            | HAEntityVariant::text
+            | HAEntityVariant::button => {}
+        }
+    }
+    client.send(LIST_ENTITIES_DONE_RESPONSE, &Message::default())
+}
+
+/// Handles pending requests of the client, returns false if it has disconnected
+fn handle_client(
+    client: &mut Client,
+    entities: &[HAEntity],
+    states: &HashMap<u32, EntityState>,
+    password: Option<&str>,
+    alarm_command_tx: &Sender<(CommandSource, AlarmCommand)>,
+    expander_command_tx: Option<&Sender<ExpanderCommand>>,
+    requires_code: bool,
+) -> anyhow::Result<bool> {
+    while let Some((message_type, payload)) = client.next_frame()? {
+        if !client.connected && !matches!(message_type, HELLO_REQUEST | CONNECT_REQUEST) {
+            bail!("request {} before connecting", message_type);
+        }
+        match message_type {
+            HELLO_REQUEST => client.send(
+                HELLO_RESPONSE,
+                Message::default()
+                    .uint32(1, API_VERSION_MAJOR)
+                    .uint32(2, API_VERSION_MINOR)
+                    .string(3, concat!("rusty-esp-alarm ", env!("CARGO_PKG_VERSION")))
+                    .string(4, "alarm"),
+            )?,
+            CONNECT_REQUEST => {
+                let fields = parse_fields(&payload)?;
+                let sent = fields.iter().find_map(|(field, value)| match value {
+                    FieldValue::Bytes(password) if *field == 1 => Some(password.as_slice()),
+                    _ => None,
+                });
+                client.connected =
+                    password.map_or(true, |password| sent == Some(password.as_bytes()));
+                client.send(
+                    CONNECT_RESPONSE,
+                    Message::default().bool(1, !client.connected),
+                )?;
+                if !client.connected {
+                    log::warn!("Native API client sent an invalid password");
+                    return Ok(false);
+                }
+            }
+            DISCONNECT_REQUEST => {
+                client.send(DISCONNECT_RESPONSE, &Message::default())?;
+                return Ok(false);
+            }
+            PING_REQUEST => client.send(PING_RESPONSE, &Message::default())?,
+            DEVICE_INFO_REQUEST => client.send(
+                DEVICE_INFO_RESPONSE,
+                Message::default()
+                    .bool(1, false)
+                    .string(2, "alarm")
+                    .string(6, "rusty-esp-alarm")
+                    .string(8, "akosnad.rusty-esp-alarm")
+                    .string(9, env!("CARGO_PKG_VERSION")),
+            )?,
+            LIST_ENTITIES_REQUEST => list_entities(client, entities, requires_code)?,
+            SUBSCRIBE_STATES_REQUEST => {
+                client.subscribed = true;
+                for entity in entities.iter() {
+                    let key = entity_key(entity);
+                    if let Some(state) = states.get(&key) {
+                        send_state(client, entity, key, *state)?;
+                    }
+                }
+            }
+            ALARM_CONTROL_PANEL_COMMAND_REQUEST => {
+                let fields = parse_fields(&payload)?;
+                let mut command = None;
+                let mut code = None;
+                for (field, value) in fields.iter() {
+                    match (field, value) {
+                        (2, FieldValue::Varint(value)) => command = Some(*value),
+                        (3, FieldValue::Bytes(value)) if !value.is_empty() => {
+                            code = Some(String::from_utf8_lossy(value).into_owned())
+                        }
+                        _ => {}
+                    }
+                }
+                // A missing field would be the disarm command by the protobuf default
+                let Some(command) = command else {
+                    log::warn!("Rejected native API alarm command without a command");
+                    continue;
+                };
+                let command = match command {
+                    ALARM_COMMAND_DISARM if requires_code && code.is_none() => {
+                        log::warn!("Rejected native API disarm without a code");
+                        continue;
+                    }
+                    // The alarm task checks the code
+                    ALARM_COMMAND_DISARM => AlarmCommand::Disarm(code),
+                    ALARM_COMMAND_ARM_AWAY => AlarmCommand::Arm,
+                    ALARM_COMMAND_ARM_HOME => AlarmCommand::ArmHome,
+                    ALARM_COMMAND_ARM_NIGHT => AlarmCommand::ArmNight,
+                    ALARM_COMMAND_ARM_CUSTOM_BYPASS => AlarmCommand::ArmInstantly,
+                    ALARM_COMMAND_TRIGGER => AlarmCommand::ManualTrigger,
+                    command => {
+                        log::warn!("Unsupported native API alarm command: {}", command);
+                        continue;
+                    }
+                };
+                alarm_command_tx.send((CommandSource::NativeApi, command))?;
+            }
+            SWITCH_COMMAND_REQUEST => {
+                let fields = parse_fields(&payload)?;
+                let mut key = None;
+                let mut state = false;
+                for (field, value) in fields.iter() {
+                    match (field, value) {
+                        (1, FieldValue::Fixed32(value)) => key = Some(*value),
+                        (2, FieldValue::Varint(value)) => state = *value != 0,
+                        _ => {}
+                    }
+                }
+                let entity = entities.iter().find(|entity| {
+                    entity.variant == HAEntityVariant::switch && Some(entity_key(entity)) == key
+                });
+                if let Some(command) =
+                    entity.and_then(|entity| AlarmCommand::from_switch(entity, state))
+                {
+                    alarm_command_tx.send((CommandSource::NativeApi, command))?;
+                } else if let (Some(entity), Some(expander_command_tx)) =
+                    (entity, expander_command_tx)
+                {
+                    expander_command_tx.send(ExpanderCommand::SetRelay(entity.clone(), state))?;
+                }
+            }
+            message_type => {
+                log::debug!("Ignoring native API message {}", message_type);
+            }
+        }
+    }
+    Ok(true)
+}
@@ -1,7 +1,10 @@
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use std::{sync::mpsc, thread::JoinHandle};
 
-use anyhow::bail;
+use anyhow::{bail, Context};
 use esp_idf_hal::{cpu::Core, task::block_on};
 use esp_idf_svc::handle::RawHandle;
 use esp_idf_svc::{
@@ -11,30 +14,107 @@ use esp_idf_svc::{
         Details, EspMqttClient, InitialChunkData, LwtConfiguration, Message as _, MessageImpl,
         MqttClientConfiguration, QoS, SubsequentChunkData,
     },
-    sys::{esp_netif_set_hostname, ESP_OK},
+    sntp::EspSntp,
+    sys::{esp_netif_set_hostname, esp_random, esp_restart, ESP_OK},
     timer::EspTaskTimerService,
 };
 use esp_ota::OtaUpdate;
+use ha_types::payload;
 use log::info;
+use serde_json::json;
 
-use crate::{spawn_task, StatusEvent};
+use crate::clock;
+use crate::mqtt_connection::{next_client_id, ConnectionEvent, MqttPublisher};
+use crate::{spawn_task, MqttMessage, StatusEvent};
 
-const MQTT_ENDPOINT: &str = env!("ESP_MQTT_ENDPOINT");
+const MQTT_PERSISTENT_SESSION: &str = env!("ESP_MQTT_PERSISTENT_SESSION");
 const AVAILABILITY_TOPIC: &str = env!("ESP_AVAILABILITY_TOPIC");
 const OTA_TOPIC: &str = env!("ESP_OTA_TOPIC");
+/// Receive buffer of the MQTT client, the largest payload with room for its topic
+const MQTT_BUFFER_SIZE: usize = payload::MAX_PAYLOAD_SIZE + 512;
 
+const BACKOFF_MIN: Duration = Duration::from_secs(2);
+const BACKOFF_MAX: Duration = Duration::from_secs(120);
+
+/// Last retry delay of the network and the MQTT task in milliseconds, 0 while connected
+static ETH_BACKOFF_MS: AtomicU32 = AtomicU32::new(0);
+static MQTT_BACKOFF_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Current retry delays of the network and the MQTT connection
+pub fn backoffs() -> (Duration, Duration) {
+    (
+        Duration::from_millis(ETH_BACKOFF_MS.load(Ordering::Relaxed).into()),
+        Duration::from_millis(MQTT_BACKOFF_MS.load(Ordering::Relaxed).into()),
+    )
+}
+
+/// Exponential backoff with jitter, so panels don't reconnect in lockstep after a broker restart
+struct Backoff {
+    attempt: u32,
+    current_ms: &'static AtomicU32,
+}
+
+impl Backoff {
+    fn new(current_ms: &'static AtomicU32) -> Self {
+        Self {
+            attempt: 0,
+            current_ms,
+        }
+    }
+
+    /// Random delay between the half and the whole of the doubled delay, up to the cap
+    fn next(&mut self) -> Duration {
+        let max = BACKOFF_MIN
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(BACKOFF_MAX);
+        self.attempt += 1;
+        let half = max.as_millis() as u32 / 2;
+        let delay = half + unsafe { esp_random() } % (half + 1);
+        self.current_ms.store(delay, Ordering::Relaxed);
+        Duration::from_millis(delay.into())
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+        self.current_ms.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Network configuration read from the settings at boot
+#[derive(Clone)]
+pub struct NetworkSettings {
+    pub mqtt_endpoint: String,
+    pub hostname: String,
+    pub mqtt_core: Option<Core>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn init<T>(
     eth: &'static mut EspEth<'_, T>,
     sys_loop: EspSystemEventLoop,
     timer: EspTaskTimerService,
     status_tx: mpsc::Sender<StatusEvent>,
+    message_tx: mpsc::SyncSender<MqttMessage>,
+    publisher: MqttPublisher,
+    restart_eth: Arc<AtomicBool>,
+    settings: NetworkSettings,
     tasks: &mut Vec<JoinHandle<()>>,
 ) -> anyhow::Result<()> {
     let eth = AsyncEth::wrap(eth, sys_loop, timer)?;
+    // The clock is synchronized once the network is up
+    let sntp = EspSntp::new_with_callback(&Default::default(), |_| clock::notify_sntp_sync())?;
     let status_tx_eth = status_tx.clone();
     tasks.push(spawn_task(
         move || {
-            block_on(eth_task(eth, status_tx_eth));
+            let _sntp = sntp;
+            block_on(eth_task(
+                eth,
+                status_tx_eth,
+                message_tx,
+                publisher,
+                restart_eth,
+                settings,
+            ));
         },
         "eth\0",
         Some(Core::Core0),
@@ -43,10 +123,20 @@ pub fn init<T>(
     Ok(())
 }
 
-fn create_mqtt_client_config() -> MqttClientConfiguration<'static> {
+fn create_mqtt_client_config(client_id: &str) -> MqttClientConfiguration<'_> {
     MqttClientConfiguration {
-        client_id: Some("alarm"),
+        client_id: Some(client_id),
         keep_alive_interval: Some(Duration::from_secs(15)),
+        // The client reconnects by itself, spread out over 5 to 15 seconds
+        reconnect_timeout: Some(Duration::from_millis(
+            5000 + u64::from(unsafe { esp_random() } % 10000),
+        )),
+        // esp-idf-svc only speaks MQTT 3.1 and 3.1.1, without session expiry a persistent
+        // session keeps the subscriptions over short disconnects instead, and without
+        // reason codes the last error of the client is logged as the disconnect reason
+        disable_clean_session: MQTT_PERSISTENT_SESSION == "true",
+        // Larger messages arrive in chunks, which are only accepted for OTA images
+        buffer_size: MQTT_BUFFER_SIZE,
         lwt: Some(LwtConfiguration {
             topic: AVAILABILITY_TOPIC,
             payload: b"offline",
@@ -60,21 +150,22 @@ fn create_mqtt_client_config() -> MqttClientConfiguration<'static> {
 async fn eth_task<T>(
     mut eth: AsyncEth<&mut EspEth<'_, T>>,
     status_tx: mpsc::Sender<StatusEvent>,
+    message_tx: mpsc::SyncSender<MqttMessage>,
+    publisher: MqttPublisher,
+    restart_eth: Arc<AtomicBool>,
+    settings: NetworkSettings,
 ) -> ! {
+    let mut eth_backoff = Backoff::new(&ETH_BACKOFF_MS);
+    let mut mqtt_backoff = Backoff::new(&MQTT_BACKOFF_MS);
     loop {
         eth.stop().await.unwrap_or_else(|e| {
             info!("failed to stop ethernet: {}", e);
         });
         info!("Starting Ethernet...");
         async {
-            const HOSTNAME: &str = "alarm\0";
+            let hostname = CString::new(settings.hostname.as_str())?;
             unsafe {
-                let result = esp_netif_set_hostname(
-                    eth.eth().netif().handle(),
-                    core::ffi::CStr::from_bytes_with_nul(HOSTNAME.as_bytes())
-                        .unwrap()
-                        .as_ptr(),
-                );
+                let result = esp_netif_set_hostname(eth.eth().netif().handle(), hostname.as_ptr());
                 if result != ESP_OK {
                     bail!("Failed to set hostname");
                 }
@@ -83,9 +174,11 @@ async fn eth_task<T>(
 
             info!("Connecting network...");
             while eth.wait_netif_up().await.is_err() {
-                info!("Failed to connect to network, retrying in 5 seconds...");
-                std::thread::sleep(Duration::from_secs(5));
+                let delay = eth_backoff.next();
+                info!("Failed to connect to network, retrying in {:?}...", delay);
+                std::thread::sleep(delay);
             }
+            eth_backoff.reset();
 
             status_tx
                 .send(StatusEvent::EthConnected)
@@ -95,35 +188,58 @@ async fn eth_task<T>(
 
             loop {
                 let status_tx = status_tx.clone();
+                let message_tx = message_tx.clone();
+                let publisher = publisher.clone();
+                let settings = settings.clone();
+                let connected = Arc::new(AtomicBool::new(false));
+                let connected_task = connected.clone();
                 let mqtt_task_handle = spawn_task(
                     move || {
-                        let status_tx_task = status_tx.clone();
-                        let result = mqtt_task(status_tx_task, create_mqtt_client_config());
-                        if result.is_err() {
-                            status_tx
-                                .send(StatusEvent::MqttDisconnected)
-                                .unwrap_or_else(|e| {
-                                    info!("failed to send status: {}", e);
-                                });
-                        }
+                        let id = next_client_id();
+                        mqtt_task(
+                            id,
+                            status_tx.clone(),
+                            message_tx,
+                            publisher,
+                            &settings.mqtt_endpoint,
+                            create_mqtt_client_config(&settings.hostname),
+                            &connected_task,
+                        )
+                        .unwrap_or_else(|e| info!("MQTT client {} ended: {}", id, e));
+                        status_tx
+                            .send(StatusEvent::Mqtt(ConnectionEvent::Closed(id)))
+                            .unwrap_or_else(|e| {
+                                info!("failed to send status: {}", e);
+                            });
                     },
                     "mqtt\0",
-                    Some(Core::Core0),
+                    settings.mqtt_core,
                 )?;
 
                 mqtt_task_handle.join().unwrap();
 
+                if restart_eth.swap(false, Ordering::Relaxed) {
+                    info!("Restarting ethernet on request");
+                    break;
+                }
                 if !eth.is_connected()? {
                     break;
                 }
+                if connected.load(Ordering::Relaxed) {
+                    mqtt_backoff.reset();
+                }
+                let delay = mqtt_backoff.next();
+                info!("Restarting MQTT in {:?}...", delay);
+                std::thread::sleep(delay);
             }
 
             anyhow::bail!("Ethernet disconnected");
         }
         .await
         .unwrap_or_else(|_e: anyhow::Error| {
-            info!("Restarting network in 5 seconds...");
-            std::thread::sleep(Duration::from_secs(5));
+            let delay = eth_backoff.next();
+            info!("Restarting network in {:?}...", delay);
+            std::thread::sleep(delay);
             status_tx
                 .send(StatusEvent::EthDisconnected)
                 .unwrap_or_else(|e| {
@@ -134,31 +250,43 @@ async fn eth_task<T>(
 }
 
 fn mqtt_task(
+    id: u32,
     status_tx: mpsc::Sender<StatusEvent>,
+    message_tx: mpsc::SyncSender<MqttMessage>,
+    publisher: MqttPublisher,
+    mqtt_endpoint: &str,
     mqtt_client_config: MqttClientConfiguration<'_>,
+    connected: &AtomicBool,
 ) -> anyhow::Result<()> {
     info!("Starting MQTT...");
     let (client, mut connection) =
-        EspMqttClient::new_with_conn(MQTT_ENDPOINT, &mqtt_client_config)?;
+        EspMqttClient::new_with_conn(mqtt_endpoint, &mqtt_client_config)?;
     let mut client = Some(client);
     let mut ota = None;
+    // Reported by the client before it disconnects, e.g. a refused connection or a socket error
+    let mut last_error = None;
 
     while let Some(msg) = connection.next() {
         match msg {
-            Err(e) => info!("MQTT Message ERROR: {}", e),
+            Err(e) => {
+                log::warn!("MQTT client error: {}", e);
+                last_error = Some(e);
+            }
             Ok(msg) => {
                 let event: esp_idf_svc::mqtt::client::Event<MessageImpl> = msg;
 
                 if let esp_idf_svc::mqtt::client::Event::Connected(_) = event {
+                    last_error = None;
+                    connected.store(true, Ordering::Relaxed);
                     if let Some(client) = client.take() {
                         status_tx
-                            .send(StatusEvent::MqttConnected(client))
+                            .send(StatusEvent::Mqtt(ConnectionEvent::Connected((id, client))))
                             .unwrap_or_else(|e| {
                                 info!("failed to send status: {}", e);
                             });
                     } else {
                         status_tx
-                            .send(StatusEvent::MqttReconnected)
+                            .send(StatusEvent::Mqtt(ConnectionEvent::Reconnected(id)))
                             .unwrap_or_else(|e| {
                                 info!("failed to send status: {}", e);
                             });
@@ -166,16 +294,21 @@ fn mqtt_task(
                 };
 
                 if let esp_idf_svc::mqtt::client::Event::Disconnected = event {
+                    match last_error.take() {
+                        Some(e) => log::warn!("MQTT disconnected: {}", e),
+                        None => log::warn!("MQTT disconnected, no error reported"),
+                    }
                     status_tx
-                        .send(StatusEvent::MqttDisconnected)
+                        .send(StatusEvent::Mqtt(ConnectionEvent::Disconnected(id)))
                         .unwrap_or_else(|e| {
                             info!("failed to send status: {}", e);
                         });
                 };
 
-                handle_mqtt_message(event, status_tx.clone(), &mut ota).unwrap_or_else(|e| {
-                    info!("MQTT Message handling error: {}", e);
-                })
+                handle_mqtt_message(event, &status_tx, &message_tx, &publisher, &mut ota)
+                    .unwrap_or_else(|e| {
+                        info!("MQTT Message handling error: {}", e);
+                    })
             }
         }
     }
@@ -185,7 +318,9 @@ fn mqtt_task(
 
 fn handle_mqtt_message(
     event: esp_idf_svc::mqtt::client::Event<MessageImpl>,
-    status_tx: mpsc::Sender<StatusEvent>,
+    status_tx: &mpsc::Sender<StatusEvent>,
+    message_tx: &mpsc::SyncSender<MqttMessage>,
+    publisher: &MqttPublisher,
     ota: &mut Option<OtaUpdate>,
 ) -> anyhow::Result<()> {
     if let esp_idf_svc::mqtt::client::Event::Received(msg) = event {
@@ -198,20 +333,34 @@ fn handle_mqtt_message(
         // handles them) contain no topic. We can only guess if it's an OTA message by checking if
         // the OTA is in progress.
         if topic == Some(OTA_TOPIC) || ota.is_some() {
-            return handle_ota_message(msg, ota);
+            return handle_ota_message(msg, ota, status_tx, publisher);
         }
 
-        let content = String::from_utf8(msg.data().into())?;
+        // Messages larger than the buffer of the client arrive in chunks, only OTA
+        // images are expected to be that large
+        if !matches!(msg.details(), Details::Complete) {
+            bail!("Ignoring a chunked message on {:?}", topic);
+        }
+        let content = payload::decode(msg.data())
+            .with_context(|| format!("Ignoring a message on {:?}", topic))?
+            .to_string();
         if let Some(topic) = topic {
-            info!("MQTT Message on topic {}: {}", topic, content);
-            status_tx
-                .send(StatusEvent::MqttMessage(crate::MqttMessage {
-                    topic: String::from(topic),
-                    payload: content,
-                }))
-                .expect("Failed to send status event");
+            // Payloads may carry codes, they are not logged
+            info!("MQTT Message on topic {}, {} bytes", topic, content.len());
+            // Blocking would also block the publishes of the scheduler on the lock of the
+            // client, a flood of messages is dropped instead
+            match message_tx.try_send(MqttMessage {
+                topic: String::from(topic),
+                payload: content,
+            }) {
+                Ok(()) => {}
+                Err(mpsc::TrySendError::Full(msg)) => {
+                    log::warn!("Scheduler is behind, dropping a message on {}", msg.topic);
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => bail!("message_rx disconnected"),
+            }
         } else {
-            info!("MQTT Message: {}", content);
+            info!("MQTT Message without a topic, {} bytes", content.len());
         }
         Ok(())
     } else {
@@ -219,7 +368,32 @@ fn handle_mqtt_message(
     }
 }
 
-fn handle_ota_message(msg: MessageImpl, ota: &mut Option<OtaUpdate>) -> anyhow::Result<()> {
+/// Asks the scheduler to restart gracefully, or restarts anyway after a timeout
+///
+/// This thread has to keep handling MQTT events meanwhile, as the MQTT client
+/// is blocked until its events are consumed.
+pub fn request_restart(status_tx: &mpsc::Sender<StatusEvent>, reason: &str) -> anyhow::Result<()> {
+    const RESTART_TIMEOUT: Duration = Duration::from_secs(10);
+
+    status_tx
+        .send(StatusEvent::RestartRequested(reason.to_string()))
+        .unwrap_or_else(|e| info!("failed to send status: {}", e));
+    std::thread::Builder::new().spawn(|| {
+        std::thread::sleep(RESTART_TIMEOUT);
+        log::warn!("Graceful restart timed out, restarting...");
+        unsafe {
+            esp_restart();
+        }
+    })?;
+    Ok(())
+}
+
+fn handle_ota_message(
+    msg: MessageImpl,
+    ota: &mut Option<OtaUpdate>,
+    status_tx: &mpsc::Sender<StatusEvent>,
+    publisher: &MqttPublisher,
+) -> anyhow::Result<()> {
     let data = msg.data();
     if let Some(mut in_progress_ota) = ota.take() {
         match msg.details() {
@@ -235,6 +409,10 @@ fn handle_ota_message(msg: MessageImpl, ota: &mut Option<OtaUpdate>) -> anyhow::
                 in_progress_ota
                     .write(data)
                     .expect("Failed to write OTA data");
+                // Report every 10%, every chunk would flood the broker
+                if current * 10 / total_data_size != current_data_offset * 10 / total_data_size {
+                    publish_ota_progress(publisher, current, *total_data_size);
+                }
 
                 if current == *total_data_size {
                     log::info!("OTA complete, applying...");
@@ -243,7 +421,7 @@ fn handle_ota_message(msg: MessageImpl, ota: &mut Option<OtaUpdate>) -> anyhow::
                     if completed_ota.set_as_boot_partition().is_err() {
                         anyhow::bail!("Failed to set OTA as boot partition");
                     } else {
-                        completed_ota.restart();
+                        request_restart(status_tx, "OTA update applied")
                     }
                 } else {
                     ota.replace(in_progress_ota);
@@ -256,7 +434,7 @@ fn handle_ota_message(msg: MessageImpl, ota: &mut Option<OtaUpdate>) -> anyhow::
                 if completed_ota.set_as_boot_partition().is_err() {
                     anyhow::bail!("Failed to set OTA as boot partition");
                 } else {
-                    completed_ota.restart();
+                    request_restart(status_tx, "OTA update applied")
                 }
             }
         }
@@ -265,6 +443,7 @@ fn handle_ota_message(msg: MessageImpl, ota: &mut Option<OtaUpdate>) -> anyhow::
         match msg.details() {
             Details::InitialChunk(InitialChunkData { total_data_size }) => {
                 log::info!("OTA data: 0/{}", total_data_size);
+                publish_ota_progress(publisher, data.len(), *total_data_size);
                 let mut new_ota = OtaUpdate::begin().expect("Failed to start OTA");
                 new_ota.write(data).expect("Failed to write OTA data");
                 ota.replace(new_ota);
@@ -276,3 +455,9 @@ fn handle_ota_message(msg: MessageImpl, ota: &mut Option<OtaUpdate>) -> anyhow::
         }
     }
 }
+
+/// Lets the uploader follow the update, it ends with a restart once everything is received
+fn publish_ota_progress(publisher: &MqttPublisher, received: usize, total: usize) {
+    let payload = json!({ "received": received, "total": total });
+    publisher.publish(&format!("{}/status", OTA_TOPIC), payload.to_string());
+}
@@ -0,0 +1,85 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_sys::{esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT};
+use ha_types::{HAEntity, PowerStat};
+
+const NVS_NAMESPACE: &str = "power";
+const BROWNOUTS_KEY: &str = "brownouts";
+/// Brown-out resets of the last boots, one bit per boot, the latest in the lowest bit
+const HISTORY_KEY: &str = "history";
+/// Brown-outs within the last 8 boots which raise the trouble condition
+const TROUBLE_THRESHOLD: u32 = 2;
+
+/// Brown-out resets, counted at boot as the detector resets the chip right away
+pub struct PowerReport {
+    pub brownouts: u32,
+    history: u8,
+}
+
+impl PowerReport {
+    /// Counts the reset of this boot if it was caused by a brown-out
+    pub fn record_boot(partition: EspDefaultNvsPartition) -> Self {
+        let brownout = unsafe { esp_reset_reason() } == esp_reset_reason_t_ESP_RST_BROWNOUT;
+        let nvs = match EspNvs::new(partition, NVS_NAMESPACE, true) {
+            Ok(nvs) => nvs,
+            Err(e) => {
+                log::error!("Failed to open power NVS namespace: {:?}", e);
+                return Self {
+                    brownouts: brownout.into(),
+                    history: brownout.into(),
+                };
+            }
+        };
+        let brownouts = nvs
+            .get_u32(BROWNOUTS_KEY)
+            .unwrap_or_else(|e| {
+                log::error!("Failed to read {}: {:?}", BROWNOUTS_KEY, e);
+                None
+            })
+            .unwrap_or(0)
+            + u32::from(brownout);
+        let history = nvs
+            .get_u8(HISTORY_KEY)
+            .unwrap_or_else(|e| {
+                log::error!("Failed to read {}: {:?}", HISTORY_KEY, e);
+                None
+            })
+            .unwrap_or(0)
+            << 1
+            | u8::from(brownout);
+        nvs.set_u32(BROWNOUTS_KEY, brownouts)
+            .and_then(|_| nvs.set_u8(HISTORY_KEY, history))
+            .unwrap_or_else(|e| log::error!("Failed to persist brown-outs: {:?}", e));
+
+        let report = Self { brownouts, history };
+        if brownout {
+            log::warn!("Booted after a brown-out, {} in total", brownouts);
+        }
+        if report.trouble() {
+            log::warn!(
+                "{} of the last 8 boots were brown-outs, check the power supply",
+                history.count_ones()
+            );
+        }
+        report
+    }
+
+    pub fn trouble(&self) -> bool {
+        self.history.count_ones() >= TROUBLE_THRESHOLD
+    }
+
+    /// States of the brown-out entities, they don't change until the next boot
+    pub fn states(&self, entities: &[HAEntity]) -> Vec<(String, String)> {
+        entities
+            .iter()
+            .filter_map(|entity| {
+                let state = match entity.power_stat? {
+                    PowerStat::brownouts => self.brownouts.to_string(),
+                    PowerStat::brownout_trouble => {
+                        if self.trouble() { "ON" } else { "OFF" }.to_string()
+                    }
+                };
+                Some((entity.state_topic.clone(), state))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ha_types::PresenceConfig;
+
+use crate::clock::Clock;
+use crate::AlarmCommand;
+
+pub struct PresenceAction {
+    pub command: AlarmCommand,
+    pub reason: String,
+}
+
+/// Tracks the state of the configured presence topics and decides when the
+/// alarm should be armed or disarmed automatically.
+pub struct PresenceMonitor {
+    config: PresenceConfig,
+    home: HashMap<String, bool>,
+    anyone_home: Option<bool>,
+    everyone_left_at: Option<Instant>,
+    /// Same as the alarm task's, the grace period runs like the exit delay
+    clock: Arc<dyn Clock + Sync>,
+}
+
+impl PresenceMonitor {
+    pub fn new(config: PresenceConfig, clock: Arc<dyn Clock + Sync>) -> Self {
+        Self {
+            config,
+            home: HashMap::new(),
+            anyone_home: None,
+            everyone_left_at: None,
+            clock,
+        }
+    }
+
+    pub fn topics(&self) -> &[String] {
+        &self.config.topics
+    }
+
+    pub fn reason_topic(&self) -> &str {
+        &self.config.reason_topic
+    }
+
+    pub fn is_presence_topic(&self, topic: &str) -> bool {
+        self.config.topics.iter().any(|t| t == topic)
+    }
+
+    pub fn handle_message(&mut self, topic: &str, payload: &str) -> Option<PresenceAction> {
+        if !self.is_presence_topic(topic) {
+            return None;
+        }
+
+        let home = payload == self.config.home_payload;
+        self.home.insert(topic.to_string(), home);
+
+        // Don't act on partial information, e.g. right after connecting
+        // when only some of the retained states have arrived yet.
+        if self.home.len() < self.config.topics.len() {
+            return None;
+        }
+
+        let anyone_home = self.home.values().any(|home| *home);
+        let was_anyone_home = self.anyone_home.replace(anyone_home);
+
+        match (was_anyone_home, anyone_home) {
+            (Some(false), true) => {
+                self.everyone_left_at = None;
+                if self.config.auto_disarm {
+                    log::info!("Presence: {} arrived, disarming", topic);
+                    return Some(PresenceAction {
+                        command: AlarmCommand::Disarm(None),
+                        reason: format!("auto_disarm: {} arrived", topic),
+                    });
+                }
+            }
+            (Some(true), false) => {
+                log::info!(
+                    "Presence: everyone left, arming in {} seconds",
+                    self.config.grace_period
+                );
+                self.everyone_left_at = Some(self.clock.now());
+            }
+            (_, true) => {
+                self.everyone_left_at = None;
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    pub fn poll(&mut self) -> Option<PresenceAction> {
+        let left_at = self.everyone_left_at?;
+        if self.clock.now().duration_since(left_at) < Duration::from_secs(self.config.grace_period)
+        {
+            return None;
+        }
+        self.everyone_left_at = None;
+
+        if !self.config.auto_arm {
+            return None;
+        }
+        log::info!("Presence: grace period over, arming");
+        Some(PresenceAction {
+            command: AlarmCommand::Arm,
+            reason: "auto_arm: everyone left".to_string(),
+        })
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use embedded_svc::{
+    http::Method,
+    io::{Read, Write},
+    wifi::{AccessPointConfiguration, AuthMethod, Configuration},
+};
+use esp_idf_hal::modem::Modem;
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    http::server::EspHttpServer,
+    nvs::EspDefaultNvsPartition,
+    sys::esp_restart,
+    wifi::{BlockingWifi, EspWifi},
+};
+use ha_types::ProvisioningApConfig;
+use log::info;
+
+use crate::lock::LockRecover;
+use crate::settings::{self, Settings};
+
+const FORM: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta name="viewport" content="width=device-width"><title>Alarm provisioning</title></head>
+<body>
+<form method="post">
+<label>MQTT endpoint <input name="mqtt_endpoint" placeholder="mqtt://192.168.1.2:1883"></label>
+<button>Save and restart</button>
+</form>
+</body>
+</html>"#;
+
+/// Serves a form on a Wi-Fi access point to enter the MQTT endpoint, then restarts into normal operation
+pub fn provisioning_ap(
+    modem: Modem,
+    sysloop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    settings: Settings,
+    config: ProvisioningApConfig,
+) -> anyhow::Result<()> {
+    let mut wifi = BlockingWifi::wrap(EspWifi::new(modem, sysloop.clone(), Some(nvs))?, sysloop)?;
+    let (auth_method, password) = match config.password.as_deref() {
+        Some(password) => (AuthMethod::WPA2Personal, password),
+        None => (AuthMethod::None, ""),
+    };
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: config
+            .ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow!("Invalid provisioning ssid"))?,
+        auth_method,
+        password: password
+            .try_into()
+            .map_err(|_| anyhow!("Invalid provisioning password"))?,
+        channel: 1,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+    info!("Provisioning access point {} started", config.ssid);
+
+    let settings = Arc::new(Mutex::new(settings));
+    let mut server = EspHttpServer::new(&Default::default())?;
+    server.fn_handler("/", Method::Get, |req| {
+        req.into_ok_response()?.write_all(FORM.as_bytes())?;
+        Ok(())
+    })?;
+    server.fn_handler("/", Method::Post, move |mut req| {
+        let mut body = [0u8; 512];
+        let mut len = 0;
+        while len < body.len() {
+            match req.read(&mut body[len..])? {
+                0 => break,
+                read => len += read,
+            }
+        }
+        let mqtt_endpoint = String::from_utf8_lossy(&body[..len])
+            .split('&')
+            .find_map(|field| field.strip_prefix("mqtt_endpoint="))
+            .map(form_decode)
+            .unwrap_or_default();
+
+        match settings
+            .lock_recover()
+            .set(settings::MQTT_ENDPOINT_KEY, &mqtt_endpoint)
+        {
+            Ok(()) => {
+                req.into_ok_response()?
+                    .write_all(b"Saved, the panel is restarting")?;
+                info!("Provisioned, restarting...");
+                std::thread::spawn(|| {
+                    // Lets the response reach the browser
+                    std::thread::sleep(Duration::from_secs(1));
+                    unsafe { esp_restart() };
+                });
+            }
+            Err(e) => {
+                req.into_response(400, Some("Bad Request"), &[])?
+                    .write_all(e.to_string().as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Decodes an application/x-www-form-urlencoded value
+fn form_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [iter.next().unwrap_or(b'0'), iter.next().unwrap_or(b'0')];
+                let hex = std::str::from_utf8(&hex).unwrap_or("00");
+                bytes.push(u8::from_str_radix(hex, 16).unwrap_or(b'?'));
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
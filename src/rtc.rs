@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use esp_idf_hal::{delay::BLOCK, i2c::I2cDriver};
+use log::{error, info, warn};
+
+use crate::clock;
+
+const DS3231_ADDRESS: u8 = 0x68;
+const TIME_REGISTER: u8 = 0x00;
+const STATUS_REGISTER: u8 = 0x0F;
+/// Set when the oscillator has stopped, e.g. the backup battery is flat, the time is invalid
+const OSCILLATOR_STOP_FLAG: u8 = 0x80;
+const CENTURY_FLAG: u8 = 0x80;
+const HOUR_12_FLAG: u8 = 0x40;
+const HOUR_PM_FLAG: u8 = 0x20;
+/// SNTP synchronizes hourly, the RTC is written after each synchronization
+const SNTP_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Ds3231 {
+    i2c: I2cDriver<'static>,
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+impl Ds3231 {
+    pub fn new(i2c: I2cDriver<'static>) -> Self {
+        Self { i2c }
+    }
+
+    /// Unix time kept by the RTC, `None` if it has not been set since it lost power
+    pub fn read(&mut self) -> anyhow::Result<Option<u64>> {
+        let mut status = [0u8];
+        self.i2c
+            .write_read(DS3231_ADDRESS, &[STATUS_REGISTER], &mut status, BLOCK)?;
+        if status[0] & OSCILLATOR_STOP_FLAG != 0 {
+            return Ok(None);
+        }
+        let mut time = [0u8; 7];
+        self.i2c
+            .write_read(DS3231_ADDRESS, &[TIME_REGISTER], &mut time, BLOCK)?;
+        let second = from_bcd(time[0] & 0x7F);
+        let minute = from_bcd(time[1] & 0x7F);
+        let hour = if time[2] & HOUR_12_FLAG != 0 {
+            from_bcd(time[2] & 0x1F) % 12 + if time[2] & HOUR_PM_FLAG != 0 { 12 } else { 0 }
+        } else {
+            from_bcd(time[2] & 0x3F)
+        };
+        // time[3] is the day of the week
+        let day = from_bcd(time[4] & 0x3F);
+        let month = from_bcd(time[5] & 0x1F);
+        let century = if time[5] & CENTURY_FLAG != 0 {
+            2100
+        } else {
+            2000
+        };
+        let year = century + i64::from(from_bcd(time[6]));
+        Ok(Some(clock::from_civil(
+            year,
+            month.into(),
+            day.into(),
+            hour,
+            minute,
+            second,
+        )))
+    }
+
+    pub fn write(&mut self, time: u64) -> anyhow::Result<()> {
+        let (year, month, day) = clock::civil_date(time);
+        let (weekday, hour, minute) = clock::weekday_time(time);
+        let century = if year >= 2100 { CENTURY_FLAG } else { 0 };
+        self.i2c.write(
+            DS3231_ADDRESS,
+            &[
+                TIME_REGISTER,
+                to_bcd((time % 60) as u8),
+                to_bcd(minute),
+                // 24 hour mode
+                to_bcd(hour),
+                weekday + 1,
+                to_bcd(day as u8),
+                to_bcd(month as u8) | century,
+                to_bcd((year % 100) as u8),
+            ],
+            BLOCK,
+        )?;
+        // The time is valid again
+        let mut status = [0u8];
+        self.i2c
+            .write_read(DS3231_ADDRESS, &[STATUS_REGISTER], &mut status, BLOCK)?;
+        self.i2c.write(
+            DS3231_ADDRESS,
+            &[STATUS_REGISTER, status[0] & !OSCILLATOR_STOP_FLAG],
+            BLOCK,
+        )?;
+        Ok(())
+    }
+}
+
+/// Sets the system clock from the RTC, so timestamps are valid before the network is up
+pub fn seed_system_clock(rtc: &mut Ds3231) {
+    match rtc.read() {
+        Ok(Some(time)) if time >= clock::MIN_VALID_TIME => match clock::set_unix_time(time) {
+            Ok(()) => info!("Clock set from the RTC to {}", clock::iso8601(time)),
+            Err(e) => error!("Failed to set the clock from the RTC: {:?}", e),
+        },
+        Ok(_) => warn!("The RTC has lost its time, waiting for SNTP"),
+        Err(e) => error!("Failed to read the RTC: {:?}", e),
+    }
+}
+
+/// Writes the time to the RTC whenever SNTP has synchronized the clock
+pub fn rtc_task(mut rtc: Ds3231) {
+    loop {
+        if clock::take_sntp_sync() {
+            let time = clock::unix_time();
+            match rtc.write(time) {
+                Ok(()) => info!("RTC set to {}", clock::iso8601(time)),
+                Err(e) => error!("Failed to set the RTC: {:?}", e),
+            }
+        }
+        std::thread::sleep(SNTP_POLL_INTERVAL);
+    }
+}
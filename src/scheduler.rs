@@ -1,105 +1,850 @@
+use crate::archive;
+use crate::boot_report;
+use crate::clock::{self, Clock};
+use crate::cpu_load::IdleSample;
+use crate::event_queue::{Critical, EventQueue};
+use crate::flash_log::FlashLog;
+use crate::lock::LockRecover;
+use crate::loopback::{LoopbackPoll, LoopbackTest};
+use crate::modbus::ExpanderCommand;
+use crate::mqtt_connection::MqttConnection;
+use crate::mqtt_stats::{self, MqttStats};
+use crate::power::PowerReport;
+use crate::presence::{PresenceAction, PresenceMonitor};
+use crate::throughput::ThroughputTest;
+use crate::timing_stats::{self, TimingStats};
+use crate::zone_bootstrap::{self, ZoneBootstrap};
+use crate::zone_counters::ZoneCounters;
 use crate::AlarmCommand;
 use crate::AlarmEvent;
 use crate::AlarmState;
+use crate::CommandSource;
+use crate::MqttMessage;
 use crate::StatusEvent;
+use anyhow::bail;
+use esp_idf_svc::handle::RawHandle;
 use esp_idf_svc::mqtt::client::{ConnState, EspMqttClient, MessageImpl, QoS};
-use esp_idf_sys::EspError;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::{esp_mqtt_client_publish, esp_restart, EspError};
+use ha_types::payload::*;
+use ha_types::provisioning::ZoneOverride;
+use ha_types::router::Router;
 use ha_types::*;
-use std::collections::VecDeque;
-use std::sync::mpsc::{Receiver, Sender};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const HA_STATUS_TOPIC: &str = "homeassistant/status";
+const DISCOVERY_NVS_NAMESPACE: &str = "discovery";
+const DISCOVERY_HASH_KEY: &str = "hash";
+/// Availability during deliberate restarts, e.g. to apply an update
+const AVAILABILITY_MAINTENANCE: &str = "maintenance";
+/// Alarm events published in one pass of the loop, so many zones changing together are
+/// published within a few passes while the other work of the loop is not held up
+const MAX_EVENTS_PER_PASS: usize = 16;
+/// Inbound messages handled in one pass of the loop, the rest wait in their channel
+const MAX_MESSAGES_PER_PASS: usize = 8;
+
+/// Optional features handled by the scheduler
+pub struct SchedulerOptions {
+    pub presence: Option<PresenceConfig>,
+    pub expander_command_tx: Option<Sender<ExpanderCommand>>,
+    pub sd_card: Option<SdCardConfig>,
+    pub flash_log: Option<(FlashLogConfig, Arc<Mutex<FlashLog>>)>,
+    pub loopback: Option<LoopbackConfig>,
+    pub net_command_topic: Option<String>,
+    /// Tells the network task to restart ethernet once the MQTT connection has ended
+    pub restart_eth: Arc<AtomicBool>,
+    pub alarm_state: Arc<Mutex<AlarmState>>,
+    pub resend_command_topic: Option<String>,
+    pub time_jump_topic: Option<String>,
+    pub dedupe_publishes: bool,
+    pub bell_test: Option<BellTestConfig>,
+    /// Topics setting the state of virtual zones
+    pub virtual_zones: Vec<(String, Arc<AtomicBool>)>,
+    /// Remembers the published discovery, so it is only republished when it changes
+    pub nvs: EspDefaultNvsPartition,
+    /// A new broker gets the discovery even if it is unchanged
+    pub mqtt_endpoint: String,
+    /// Code HA has to send with DISARM, from the settings
+    pub disarm_code: Option<String>,
+    /// Records the changes of the alarm settings, like the serial console does
+    pub settings_audit_topic: Option<String>,
+    /// Announcements of the alarm states, from the language setting
+    pub language: Option<Language>,
+    pub mqtt_connection: MqttConnection,
+    pub power: PowerReport,
+    /// Areas of the provisioning HA publishes for bootstrapping the zones
+    pub provisioning_topic: Option<String>,
+    /// Monotonic time of the alarm task, for the presence grace period
+    pub clock: Arc<dyn Clock + Sync>,
+}
 
 pub fn scheduler_task(
     entities: &[HAEntity],
     status_rx: Receiver<StatusEvent>,
-    _status_tx: Sender<StatusEvent>,
-    alarm_event_queue: Arc<Mutex<VecDeque<AlarmEvent>>>,
-    alarm_command_tx: Sender<AlarmCommand>,
+    message_rx: Receiver<MqttMessage>,
+    alarm_event_queue: Arc<EventQueue>,
+    alarm_command_tx: Sender<(CommandSource, AlarmCommand)>,
+    event_subscribers: Vec<Sender<AlarmEvent>>,
+    options: SchedulerOptions,
 ) -> ! {
+    let SchedulerOptions {
+        presence,
+        clock,
+        expander_command_tx,
+        sd_card,
+        flash_log,
+        loopback,
+        net_command_topic,
+        restart_eth,
+        alarm_state,
+        resend_command_topic,
+        time_jump_topic,
+        dedupe_publishes,
+        bell_test,
+        virtual_zones,
+        nvs,
+        mqtt_endpoint,
+        disarm_code,
+        settings_audit_topic,
+        language,
+        mut mqtt_connection,
+        power,
+        provisioning_topic,
+    } = options;
+
     let alarm_entity = entities
         .iter()
         .find(|entity| entity.variant == HAEntityVariant::alarm_control_panel)
         .expect("Alarm entity not found")
         .clone();
-    let alarm_entity_command_topic = alarm_entity
-        .command_topic
-        .expect("Alarm entity has no command topic");
+    // A dual disarm takes its own codes
+    let disarm_code = disarm_code.filter(|_| alarm_entity.dual_disarm.is_none());
+    let critical_publisher = CriticalPublisher::new(entities, &alarm_entity);
+    // Missing while a DSC panel is bridged, it is armed at its own keypads
+    let alarm_entity_command_topic = alarm_entity.command_topic;
+    let alarm_command_result_topic = alarm_entity_command_topic
+        .as_ref()
+        .map(|topic| format!("{}/result", topic));
+    let disarm_code = disarm_code.filter(|_| alarm_entity_command_topic.is_some());
+
+    let mut presence = presence.map(|config| PresenceMonitor::new(config, clock.clone()));
+    let mut subscriptions = presence
+        .as_ref()
+        .map(|presence| presence.topics().to_vec())
+        .unwrap_or_default();
+    if let Some(sd_card) = sd_card.as_ref() {
+        subscriptions.push(sd_card.command_topic.clone());
+    }
+    if let Some((flash_log_config, _)) = flash_log.as_ref() {
+        subscriptions.push(flash_log_config.command_topic.clone());
+    }
+    if let Some(loopback) = loopback.as_ref() {
+        subscriptions.push(loopback.topic.clone());
+    }
+    if let Some(net_command_topic) = net_command_topic.as_ref() {
+        subscriptions.push(net_command_topic.clone());
+    }
+    let mut throughput_test = net_command_topic
+        .as_ref()
+        .map(|topic| ThroughputTest::new(format!("{}/throughput", topic)));
+    if let Some(throughput_test) = throughput_test.as_ref() {
+        subscriptions.push(throughput_test.topic().to_string());
+    }
+    subscriptions.push(HA_STATUS_TOPIC.to_string());
+    if let Some(resend_command_topic) = resend_command_topic.as_ref() {
+        subscriptions.push(resend_command_topic.clone());
+    }
+    subscriptions.extend(virtual_zones.iter().map(|(topic, _)| topic.clone()));
+    let link_checks: Vec<(String, &HAEntity)> = entities
+        .iter()
+        .filter_map(|entity| Some((entity.challenge_topic()?, entity)))
+        .collect();
+    subscriptions.extend(link_checks.iter().map(|(topic, _)| topic.clone()));
+    let mut loopback_test = loopback.map(LoopbackTest::new);
+    let net_status_topic = net_command_topic
+        .as_ref()
+        .map(|topic| format!("{}/status", topic));
+    let mut scheduled_reboot = None;
+    // Reboots wait for the alarm to be disarmed, unless they are confirmed
+    let mut pending_reboot: Option<String> = None;
+
+    let mut alarm_json = alarm_entity
+        .json_state
+        .unwrap_or(false)
+        .then(|| AlarmJsonState::new(&alarm_entity.state_topic));
+    let mut event_history = alarm_entity
+        .event_history
+        .map(|length| EventHistory::new(&alarm_entity.state_topic, length.into()));
+    let arm_note_entity = entities
+        .iter()
+        .find(|entity| entity.arm_note.unwrap_or(false));
+    let mut arm_note: Option<String> = None;
+
+    // Added in the precedence the messages are handled with
+    let mut router = Router::new();
+    if let Some(loopback_test) = loopback_test.as_ref() {
+        router.add(loopback_test.topic(), Route::Loopback);
+    }
+    if let Some(throughput_test) = throughput_test.as_ref() {
+        router.add(throughput_test.topic(), Route::Throughput);
+    }
+    router.add(HA_STATUS_TOPIC, Route::HaStatus);
+    if let Some(topic) = resend_command_topic.as_ref() {
+        router.add(topic, Route::Resend);
+    }
+    if let Some(topic) = net_command_topic.as_ref() {
+        router.add(topic, Route::NetCommand);
+    }
+    for (index, (topic, _)) in virtual_zones.iter().enumerate() {
+        router.add(topic, Route::VirtualZone(index));
+    }
+    for (index, (topic, _)) in link_checks.iter().enumerate() {
+        router.add(topic, Route::LinkCheck(index));
+    }
+    if let Some(topic) = arm_note_entity.and_then(|entity| entity.command_topic.as_ref()) {
+        router.add(topic, Route::ArmNote);
+    }
+    for (index, entity) in entities.iter().enumerate() {
+        let Some(topic) = entity.command_topic.as_ref() else {
+            continue;
+        };
+        let route = match entity.variant {
+            HAEntityVariant::button => entity.button_action.map(Route::Button),
+            HAEntityVariant::switch => Some(Route::Switch(index)),
+            HAEntityVariant::number => entity.alarm_setting.map(Route::Number),
+            HAEntityVariant::select => Some(Route::Select),
+            _ => None,
+        };
+        if let Some(route) = route {
+            router.add(topic, route);
+        }
+    }
+    if let Some(topic) = alarm_entity_command_topic.as_ref() {
+        router.add(topic, Route::AlarmCommand);
+    }
+    if let Some(sd_card) = sd_card.as_ref() {
+        router.add(&sd_card.command_topic, Route::Archive);
+    }
+    if let Some((flash_log_config, _)) = flash_log.as_ref() {
+        router.add(&flash_log_config.command_topic, Route::FlashLog);
+    }
+    for topic in presence.iter().flat_map(PresenceMonitor::topics) {
+        router.add(topic, Route::Presence);
+    }
+    // One area in each message, so they fit in the buffer of the MQTT client
+    let provisioning_filter = provisioning_topic
+        .as_ref()
+        .map(|topic| format!("{}/+", topic));
+    if let Some(filter) = provisioning_filter.as_ref() {
+        router.add(filter, Route::Provisioning);
+    }
 
-    let mut mqtt_client = None;
+    // Minute of the last scheduled bell test, so it only runs once
+    let mut last_bell_test = 0;
+    let mut time_jump_detector = clock::TimeJumpDetector::new();
+    let mut idle_sample = IdleSample::take();
+    let mut state_cache = StateCache::new(dedupe_publishes);
+    let mut zone_times = BTreeMap::new();
+    let mut mqtt_stats = MqttStats::load(nvs.clone());
+    let mut timing_stats = TimingStats::new();
+    let mut zone_counters = ZoneCounters::load(nvs.clone());
+    let mut discovery_nvs = EspNvs::new(nvs, DISCOVERY_NVS_NAMESPACE, true)
+        .map_err(|e| log::error!("Failed to open discovery NVS namespace: {:?}", e))
+        .ok();
+    let mut zone_overrides = discovery_nvs
+        .as_ref()
+        .map(|nvs| {
+            zone_bootstrap::load(nvs).unwrap_or_else(|e| {
+                log::error!("Failed to load the bootstrapped zones: {:?}", e);
+                BTreeMap::new()
+            })
+        })
+        .unwrap_or_default();
+    let mut zone_bootstrap: Option<ZoneBootstrap> = None;
+    if let Some(entity) = arm_note_entity {
+        state_cache.update(&entity.state_topic, "");
+    }
+    // Sent once the broker is connected
+    for (topic, payload) in power.states(entities) {
+        state_cache.update(&topic, &payload);
+    }
     loop {
         let loop_result = || -> anyhow::Result<()> {
             loop {
-                match status_rx.try_recv() {
-                    Ok(event) => match event {
+                if let Some(client) = mqtt_connection.client() {
+                    critical_publisher.publish(client, &alarm_event_queue)?;
+                }
+                // Connection events are handled first, so the client handed over on connecting
+                // is never held up by a backlog of messages
+                loop {
+                    let event = match status_rx.try_recv() {
+                        Ok(event) => event,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => anyhow::bail!("status_rx disconnected"),
+                    };
+                    match event {
                         StatusEvent::EthConnected => {
                             log::info!("EthConnected");
                         }
                         StatusEvent::EthDisconnected => {
                             log::info!("EthDisconnected");
                         }
-                        StatusEvent::MqttConnected(mut client) => {
-                            init_mqtt(&mut client, entities)?;
-                            mqtt_client = Some(client);
-                            log::info!("MqttConnected");
-                        }
-                        StatusEvent::MqttReconnected => {
-                            if let Some(mut client) = mqtt_client.take() {
-                                init_mqtt(&mut client, entities)?;
-                                mqtt_client = Some(client);
-                            } else {
-                                anyhow::bail!("MqttReconnected: mqtt client is None");
+                        StatusEvent::Mqtt(event) => {
+                            if let Some(client) = mqtt_connection.handle(event) {
+                                init_mqtt(
+                                    client,
+                                    entities,
+                                    &subscriptions,
+                                    discovery_nvs.as_ref(),
+                                    &mqtt_endpoint,
+                                    disarm_code.is_some(),
+                                    &zone_overrides,
+                                )?;
+                                state_cache.resend(client)?;
+                                if let Some(loopback_test) = loopback_test.as_mut() {
+                                    loopback_test.reset();
+                                }
                             }
-                            log::info!("MqttReconnected");
                         }
-                        StatusEvent::MqttDisconnected => {
-                            log::info!("MqttDisconnected");
+                        StatusEvent::RestartRequested(reason) => {
+                            log::info!("Restarting: {}", reason);
+                            restart_gracefully(mqtt_connection.client());
                         }
-                        StatusEvent::MqttMessage(msg) => {
-                            if msg.topic == alarm_entity_command_topic {
-                                handle_alarm_command(&msg.payload, &alarm_command_tx)?;
+                    }
+                }
+                for msg in message_rx.try_iter().take(MAX_MESSAGES_PER_PASS) {
+                    let Some(route) = router.route(&msg.topic) else {
+                        continue;
+                    };
+                    match route {
+                        Route::Loopback => {
+                            if let Some(loopback_test) = loopback_test.as_mut() {
+                                loopback_test.handle_message(&msg.topic, &msg.payload);
                             }
                         }
-                    },
-                    Err(e) => {
-                        if e == std::sync::mpsc::TryRecvError::Disconnected {
-                            anyhow::bail!("status_rx disconnected");
+                        Route::Throughput => {
+                            // Counted by the test
+                            if let Some(throughput_test) = throughput_test.as_mut() {
+                                throughput_test.handle_message(&msg.topic, &msg.payload);
+                            }
                         }
-                    }
-                }
-
-                // Skip processing events from the queue if the mqtt client is not available
-                if let Some(mut client) = mqtt_client.take() {
-                    match alarm_event_queue.try_lock() {
-                        Ok(mut queue) => match queue.pop_front() {
-                            Some(event) => match event {
-                                AlarmEvent::MotionDetected(entity) => {
-                                    send_binary_sensor_state(true, &entity, &mut client)?;
+                        Route::HaStatus | Route::Resend => {
+                            // HA forgets the states when it restarts, its birth message
+                            // is the sign to send them again
+                            let ha_online = route == Route::HaStatus && msg.payload == "online";
+                            let requested = route == Route::Resend || ha_online;
+                            if let (true, Some(client)) = (requested, mqtt_connection.client()) {
+                                // The broker may have lost the retained discovery
+                                if ha_online {
+                                    publish_discovery(
+                                        client,
+                                        &discovery_messages(
+                                            entities,
+                                            disarm_code.is_some(),
+                                            &zone_overrides,
+                                        )
+                                        .0,
+                                    )?;
                                 }
-                                AlarmEvent::MotionCleared(entity) => {
-                                    send_binary_sensor_state(false, &entity, &mut client)?;
+                                state_cache.resend(client)?;
+                            }
+                        }
+                        Route::NetCommand => match parse_net_command(&msg.payload) {
+                            Ok(NetCommand::ReconnectMqtt) => {
+                                log::info!("Reconnecting MQTT on request");
+                                mqtt_connection.close();
+                            }
+                            Ok(NetCommand::RestartEth) => {
+                                restart_eth.store(true, Ordering::Relaxed);
+                                mqtt_connection.close();
+                            }
+                            Ok(NetCommand::Reboot) => {
+                                pending_reboot = Some("requested".to_string());
+                                publish_net_status(
+                                            mqtt_connection.client(),
+                                            net_status_topic.as_deref(),
+                                            "reboot pending until the alarm is disarmed or the reboot is confirmed",
+                                        )?;
+                            }
+                            Ok(NetCommand::ConfirmReboot) => {
+                                let reason = pending_reboot
+                                    .take()
+                                    .unwrap_or_else(|| "requested".to_string());
+                                reboot(
+                                    mqtt_connection.client(),
+                                    net_status_topic.as_deref(),
+                                    &format!("{}, confirmed", reason),
+                                );
+                            }
+                            Ok(NetCommand::CancelReboot) => {
+                                pending_reboot = None;
+                                scheduled_reboot = None;
+                                publish_net_status(
+                                    mqtt_connection.client(),
+                                    net_status_topic.as_deref(),
+                                    "reboot cancelled",
+                                )?;
+                            }
+                            Ok(NetCommand::RebootAt(time)) => {
+                                if !clock::is_synchronized() {
+                                    log::warn!("Can't schedule reboot, clock not synced");
+                                } else if time <= clock::unix_time() {
+                                    log::warn!("Reboot time {} is in the past", time);
+                                } else {
+                                    log::info!("Reboot scheduled at {}", time);
+                                    scheduled_reboot = Some(time);
                                 }
-                                AlarmEvent::AlarmStateChanged((entity, state)) => {
-                                    send_alarm_state_change(&state, &entity, &mut client)?;
+                            }
+                            Ok(NetCommand::CpuLoad) => {
+                                let sample = IdleSample::take();
+                                let [core0, core1] = sample.idle_percent(&idle_sample);
+                                idle_sample = sample;
+                                publish_net_status(
+                                    mqtt_connection.client(),
+                                    net_status_topic.as_deref(),
+                                    &format!(
+                                        "idle since the last cpu-load: core0 {}%, core1 {}%",
+                                        core0, core1
+                                    ),
+                                )?;
+                            }
+                            Ok(NetCommand::FirmwareHash) => {
+                                let status = match boot_report::firmware_sha256() {
+                                    Ok((partition, sha256)) => {
+                                        format!("firmware sha256 of {}: {}", partition, sha256)
+                                    }
+                                    Err(e) => format!("failed to hash the firmware: {}", e),
+                                };
+                                publish_net_status(
+                                    mqtt_connection.client(),
+                                    net_status_topic.as_deref(),
+                                    &status,
+                                )?;
+                            }
+                            Ok(NetCommand::Throughput) => {
+                                if let (Some(test), Some(client)) =
+                                    (throughput_test.as_mut(), mqtt_connection.client())
+                                {
+                                    match test.start() {
+                                        Some(payloads) => {
+                                            for payload in payloads {
+                                                client.publish(
+                                                    test.topic(),
+                                                    QoS::AtLeastOnce,
+                                                    false,
+                                                    payload.as_bytes(),
+                                                )?;
+                                            }
+                                        }
+                                        None => publish_net_status(
+                                            Some(client),
+                                            net_status_topic.as_deref(),
+                                            "throughput test is already running",
+                                        )?,
+                                    }
                                 }
-                            },
-                            None => {
-                                // No new event to process
+                            }
+                            Ok(NetCommand::BootstrapZones) => {
+                                let status = match (
+                                    provisioning_filter.as_ref(),
+                                    mqtt_connection.client(),
+                                ) {
+                                    (None, _) => "no provisioning_topic is configured",
+                                    (Some(_), _) if zone_bootstrap.is_some() => {
+                                        "zone bootstrap is already running"
+                                    }
+                                    (Some(_), None) => "not connected",
+                                    (Some(filter), Some(client)) => {
+                                        // HA publishes the areas retained, they arrive right
+                                        // after subscribing
+                                        client.subscribe(filter, QoS::AtLeastOnce).inspect_err(
+                                            |_| mqtt_stats::count(MqttStat::subscribe_failures),
+                                        )?;
+                                        zone_bootstrap = Some(ZoneBootstrap::new(Instant::now()));
+                                        "bootstrapping zones"
+                                    }
+                                };
+                                publish_net_status(
+                                    mqtt_connection.client(),
+                                    net_status_topic.as_deref(),
+                                    status,
+                                )?;
+                            }
+                            Ok(NetCommand::ClearZones) => {
+                                zone_overrides.clear();
+                                apply_zone_overrides(
+                                    mqtt_connection.client(),
+                                    entities,
+                                    discovery_nvs.as_mut(),
+                                    &mqtt_endpoint,
+                                    disarm_code.is_some(),
+                                    &zone_overrides,
+                                )?;
+                                publish_net_status(
+                                    mqtt_connection.client(),
+                                    net_status_topic.as_deref(),
+                                    "zones are named by the configuration again",
+                                )?;
+                            }
+                            Err(e) => {
+                                log::warn!("Invalid net command {}: {}", msg.payload, e)
+                            }
+                        },
+                        Route::VirtualZone(index) => match parse_switch(&msg.payload) {
+                            Ok(on) => virtual_zones[index].1.store(on, Ordering::Relaxed),
+                            Err(e) => {
+                                log::warn!("Invalid virtual zone state {}: {}", msg.payload, e)
                             }
                         },
-                        Err(e) => match e {
-                            std::sync::TryLockError::WouldBlock => {
-                                // Don't block this thread
+                        Route::LinkCheck(index) => {
+                            if let Some(client) = mqtt_connection.client() {
+                                answer_link_challenge(client, link_checks[index].1, &msg.payload)?;
                             }
-                            std::sync::TryLockError::Poisoned(e) => {
-                                anyhow::bail!("alarm_event_queue lock poisoned: {}", e);
+                        }
+                        Route::ArmNote => {
+                            let entity =
+                                arm_note_entity.expect("Arm note routed without its entity");
+                            arm_note = Some(msg.payload.clone()).filter(|note| !note.is_empty());
+                            publish_state(
+                                mqtt_connection.client(),
+                                &mut state_cache,
+                                &entity.state_topic,
+                                &msg.payload,
+                            )?;
+                        }
+                        Route::Button(action) => match action {
+                            ButtonAction::self_test => alarm_command_tx
+                                .send((CommandSource::Mqtt, AlarmCommand::SelfTest))?,
+                            ButtonAction::siren_chirp => alarm_command_tx
+                                .send((CommandSource::Mqtt, AlarmCommand::SirenChirp))?,
+                            ButtonAction::learn_zones => alarm_command_tx
+                                .send((CommandSource::Mqtt, AlarmCommand::LearnZones))?,
+                            ButtonAction::confirm_learned_zones => alarm_command_tx
+                                .send((CommandSource::Mqtt, AlarmCommand::ConfirmLearnedZones))?,
+                            ButtonAction::resend => {
+                                if let Some(client) = mqtt_connection.client() {
+                                    state_cache.resend(client)?;
+                                }
                             }
                         },
+                        Route::AlarmCommand => {
+                            // Routed only with a command topic
+                            let Some(result_topic) = alarm_command_result_topic.as_ref() else {
+                                continue;
+                            };
+                            handle_alarm_command(
+                                &msg.payload,
+                                &alarm_command_tx,
+                                mqtt_connection.client(),
+                                result_topic,
+                            )?;
+                        }
+                        Route::Switch(index) => {
+                            handle_switch_command(
+                                &msg.payload,
+                                &entities[index],
+                                expander_command_tx.as_ref(),
+                                &alarm_command_tx,
+                            )?;
+                        }
+                        Route::Number(setting) => {
+                            handle_number_command(&msg.payload, setting, &alarm_command_tx)?;
+                        }
+                        Route::Select => {
+                            alarm_command_tx.send((
+                                CommandSource::Mqtt,
+                                AlarmCommand::SelectProfile(msg.payload.clone()),
+                            ))?;
+                        }
+                        Route::Archive => {
+                            if let (Some(sd_card), Some(client)) =
+                                (sd_card.as_ref(), mqtt_connection.client())
+                            {
+                                handle_archive_command(&msg.payload, sd_card, client)?;
+                            }
+                        }
+                        Route::FlashLog => {
+                            if let (Some((flash_log_config, flash_log)), Some(client)) =
+                                (flash_log.as_ref(), mqtt_connection.client())
+                            {
+                                handle_flash_log_command(
+                                    &msg.payload,
+                                    flash_log_config,
+                                    flash_log,
+                                    client,
+                                )?;
+                            }
+                        }
+                        Route::Presence => {
+                            let Some(presence) = presence.as_mut() else {
+                                continue;
+                            };
+                            if let Some(action) = presence.handle_message(&msg.topic, &msg.payload)
+                            {
+                                handle_presence_action(
+                                    action,
+                                    presence.reason_topic(),
+                                    &alarm_command_tx,
+                                    mqtt_connection.client(),
+                                )?;
+                            }
+                        }
+                        Route::Provisioning => {
+                            if let Some(zone_bootstrap) = zone_bootstrap.as_mut() {
+                                zone_bootstrap.handle_message(&msg.topic, &msg.payload, entities);
+                            }
+                        }
+                    }
+                }
+
+                if zone_bootstrap
+                    .as_ref()
+                    .is_some_and(|zone_bootstrap| zone_bootstrap.is_done(Instant::now()))
+                {
+                    if let Some(zone_bootstrap) = zone_bootstrap.take() {
+                        if let (Some(client), Some(filter)) =
+                            (mqtt_connection.client(), provisioning_filter.as_ref())
+                        {
+                            client.unsubscribe(filter).unwrap_or_else(|e| {
+                                log::error!("Failed to unsubscribe from {}: {:?}", filter, e);
+                                0
+                            });
+                        }
+                        let (overrides, status) = zone_bootstrap.finish();
+                        if let Some(overrides) = overrides {
+                            zone_overrides = overrides;
+                            apply_zone_overrides(
+                                mqtt_connection.client(),
+                                entities,
+                                discovery_nvs.as_mut(),
+                                &mqtt_endpoint,
+                                disarm_code.is_some(),
+                                &zone_overrides,
+                            )?;
+                        }
+                        publish_net_status(
+                            mqtt_connection.client(),
+                            net_status_topic.as_deref(),
+                            &status,
+                        )?;
+                    }
+                }
+
+                if let Some(presence) = presence.as_mut() {
+                    if let Some(action) = presence.poll() {
+                        handle_presence_action(
+                            action,
+                            presence.reason_topic(),
+                            &alarm_command_tx,
+                            mqtt_connection.client(),
+                        )?;
+                    }
+                }
+
+                if let (Some(loopback_test), Some(client)) =
+                    (loopback_test.as_mut(), mqtt_connection.client())
+                {
+                    match loopback_test.poll() {
+                        LoopbackPoll::Idle => {}
+                        LoopbackPoll::Send(nonce) => {
+                            client.publish(
+                                loopback_test.topic(),
+                                QoS::AtLeastOnce,
+                                false,
+                                nonce.as_bytes(),
+                            )?;
+                        }
+                        LoopbackPoll::Failed => {
+                            // Dropping the client ends the connection, which is then
+                            // restarted by the network task with a fresh client
+                            log::warn!("Loopback test failed, forcing MQTT reconnect");
+                            mqtt_connection.close();
+                        }
+                    }
+                }
+
+                if let Some(result) = throughput_test.as_mut().and_then(ThroughputTest::poll) {
+                    publish_net_status(
+                        mqtt_connection.client(),
+                        net_status_topic.as_deref(),
+                        &result,
+                    )?;
+                }
+
+                if let Some((expected, actual)) = time_jump_detector.poll() {
+                    log::warn!(
+                        "Clock stepped by {}ms to {}",
+                        actual - expected,
+                        actual / 1000
+                    );
+                    if let (Some(client), Some(topic)) =
+                        (mqtt_connection.client(), time_jump_topic.as_ref())
+                    {
+                        let payload = json!({
+                            "expected": expected / 1000,
+                            "actual": actual / 1000,
+                            "step_ms": actual - expected,
+                        });
+                        client.publish(
+                            topic,
+                            QoS::AtLeastOnce,
+                            false,
+                            payload.to_string().as_bytes(),
+                        )?;
+                    }
+                }
+
+                if let Some(bell_test) = bell_test.as_ref().filter(|_| clock::is_synchronized()) {
+                    let minute = clock::unix_time() / 60;
+                    let (weekday, hour, min) = clock::local_weekday_time(minute * 60);
+                    if minute != last_bell_test
+                        && (weekday, hour, min)
+                            == (bell_test.weekday, bell_test.hour, bell_test.minute)
+                    {
+                        last_bell_test = minute;
+                        log::info!("Starting scheduled bell test");
+                        alarm_command_tx.send((CommandSource::Schedule, AlarmCommand::BellTest))?;
+                    }
+                }
+
+                if scheduled_reboot.is_some_and(|time| clock::unix_time() >= time) {
+                    scheduled_reboot = None;
+                    pending_reboot = Some("scheduled".to_string());
+                    publish_net_status(
+                        mqtt_connection.client(),
+                        net_status_topic.as_deref(),
+                        "scheduled reboot pending until the alarm is disarmed",
+                    )?;
+                }
+                if let Some(reason) = pending_reboot.as_ref() {
+                    if *alarm_state.lock_recover() == AlarmState::Disarmed {
+                        reboot(
+                            mqtt_connection.client(),
+                            net_status_topic.as_deref(),
+                            reason,
+                        );
+                    }
+                }
+
+                // Skip processing events from the queue if there is no transport available
+                if mqtt_connection.has_client() || !event_subscribers.is_empty() {
+                    let events = std::iter::from_fn(|| alarm_event_queue.try_pop());
+                    for event in events.take(MAX_EVENTS_PER_PASS) {
+                        let zone = match &event {
+                            AlarmEvent::MotionDetected(entity)
+                            | AlarmEvent::MotionCleared(entity) => Some(entity.clone()),
+                            _ => None,
+                        };
+                        for subscriber in event_subscribers.iter() {
+                            subscriber.send(event.clone())?;
+                        }
+                        critical_publisher.processed(&event, &alarm_event_queue);
+                        if let AlarmEvent::CommandResult((source, command, result)) = &event {
+                            if let (Some(client), Some(result_topic)) = (
+                                mqtt_connection.client(),
+                                alarm_command_result_topic.as_ref(),
+                            ) {
+                                publish_command_result(
+                                    client,
+                                    result_topic,
+                                    command.name(),
+                                    *result,
+                                )?;
+                            }
+                            let key = match command {
+                                AlarmCommand::UpdateSettings((setting, _)) => Some(setting.key()),
+                                AlarmCommand::SetToggle((toggle, _)) => Some(toggle.key()),
+                                _ => None,
+                            };
+                            if let (Some(key), Some(client), Some(audit_topic)) =
+                                (key, mqtt_connection.client(), settings_audit_topic.as_ref())
+                            {
+                                let payload = json!({
+                                    "key": key,
+                                    "source": source.name(),
+                                    "accepted": result.is_ok(),
+                                    "reason": result.err(),
+                                });
+                                client.publish(
+                                    audit_topic,
+                                    QoS::AtLeastOnce,
+                                    false,
+                                    payload.to_string().as_bytes(),
+                                )?;
+                            }
+                        }
+                        if let AlarmEvent::AlarmStateChanged((entity, state, _)) = &event {
+                            let text = language
+                                .as_ref()
+                                .and_then(|language| language.announcement(state.name()));
+                            if let (Some(text), Some(client)) = (text, mqtt_connection.client()) {
+                                client.publish(
+                                    &format!("{}/announcement", entity.state_topic),
+                                    QoS::AtLeastOnce,
+                                    false,
+                                    text.as_bytes(),
+                                )?;
+                            }
+                        }
+                        // The note is attached to the next state change only
+                        let state_changed = matches!(event, AlarmEvent::AlarmStateChanged(_));
+                        if let (true, Some(alarm_json)) = (state_changed, alarm_json.as_mut()) {
+                            alarm_json.note = arm_note.take();
+                        }
+                        let attributes = event_attributes(&event, &mut zone_times);
+                        let history = event_history
+                            .as_mut()
+                            .and_then(|history| history.record(&event));
+                        // With other subscribers available, events are not held back
+                        // for the mqtt client to reconnect, the cached states are
+                        // resent once it does
+                        for (topic, payload) in event_states(event, alarm_json.as_mut()) {
+                            publish_event_state(
+                                mqtt_connection.client(),
+                                &mut state_cache,
+                                &topic,
+                                &payload,
+                            )?;
+                        }
+                        for (topic, payload) in attributes.into_iter().chain(history) {
+                            publish_state(
+                                mqtt_connection.client(),
+                                &mut state_cache,
+                                &topic,
+                                &payload,
+                            )?;
+                        }
+                        if let (true, Some(entity)) = (state_changed, arm_note_entity) {
+                            publish_state(
+                                mqtt_connection.client(),
+                                &mut state_cache,
+                                &entity.state_topic,
+                                "",
+                            )?;
+                        }
+                        if let Some(zone) = zone {
+                            timing_stats::zone_published(&zone.unique_id);
+                        }
                     }
+                }
 
-                    // Done processing events, put the client back
-                    mqtt_client = Some(client);
+                for (topic, payload) in mqtt_stats
+                    .poll(entities)
+                    .into_iter()
+                    .chain(timing_stats.poll(entities))
+                    .chain(zone_counters.poll(entities))
+                {
+                    publish_state(mqtt_connection.client(), &mut state_cache, &topic, &payload)?;
                 }
 
+                mqtt_connection.flush_outbox();
+
                 std::thread::sleep(std::time::Duration::from_millis(250));
             }
         }();
@@ -113,94 +858,951 @@ pub fn scheduler_task(
 fn init_mqtt(
     client: &mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>,
     entities: &[HAEntity],
+    subscriptions: &[String],
+    discovery_nvs: Option<&EspNvs<NvsDefault>>,
+    mqtt_endpoint: &str,
+    disarm_code: bool,
+    zone_overrides: &BTreeMap<String, ZoneOverride>,
 ) -> anyhow::Result<()> {
     const AVAILABILITY_TOPIC: &str = env!("ESP_AVAILABILITY_TOPIC");
     const OTA_TOPIC: &str = env!("ESP_OTA_TOPIC");
 
-    // send entity config messages
-    for entity in entities.iter() {
-        let entity = HAEntity {
-            availability: Some(HADeviceAvailability {
-                payload_available: Some("online".to_string()),
-                payload_not_available: Some("offline".to_string()),
-                topic: AVAILABILITY_TOPIC.to_string(),
-                value_template: None,
-            }),
-            ..entity.clone()
-        };
-        let topic = format!(
-            "{}/{}/{}/config",
-            "homeassistant", entity.variant, entity.unique_id
-        );
-        let entity_out: HAEntityOut = entity.into();
-        let payload = serde_json::to_string(&entity_out).unwrap();
-        client.publish(&topic, QoS::AtLeastOnce, true, payload.as_bytes())?;
-
-        if let Some(command_topic) = entity_out.command_topic {
-            client.subscribe(&command_topic, QoS::ExactlyOnce)?;
-        }
+    update_discovery(
+        client,
+        entities,
+        discovery_nvs,
+        mqtt_endpoint,
+        disarm_code,
+        zone_overrides,
+    )?;
+    for command_topic in entities.iter().filter_map(|e| e.command_topic.as_ref()) {
+        client
+            .subscribe(command_topic, QoS::ExactlyOnce)
+            .inspect_err(|_| mqtt_stats::count(MqttStat::subscribe_failures))?;
     }
 
     // birth message
     client.publish(AVAILABILITY_TOPIC, QoS::AtLeastOnce, true, b"online")?;
 
     // subscribe to ota
-    client.subscribe(OTA_TOPIC, QoS::ExactlyOnce)?;
+    client
+        .subscribe(OTA_TOPIC, QoS::ExactlyOnce)
+        .inspect_err(|_| mqtt_stats::count(MqttStat::subscribe_failures))?;
+
+    for topic in subscriptions.iter() {
+        client
+            .subscribe(topic, QoS::AtLeastOnce)
+            .inspect_err(|_| mqtt_stats::count(MqttStat::subscribe_failures))?;
+    }
 
     Ok(())
 }
 
-fn send_binary_sensor_state(
-    state: bool,
-    entity: &HAEntity,
+/// Retained discovery outlives the connection, it is only sent again when it or the
+/// broker changed
+fn update_discovery(
     client: &mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>,
+    entities: &[HAEntity],
+    discovery_nvs: Option<&EspNvs<NvsDefault>>,
+    mqtt_endpoint: &str,
+    disarm_code: bool,
+    zone_overrides: &BTreeMap<String, ZoneOverride>,
 ) -> anyhow::Result<()> {
-    let payload = if state { "ON" } else { "OFF" };
-    client.publish(
-        &entity.state_topic,
-        QoS::AtLeastOnce,
-        true,
-        payload.as_bytes(),
-    )?;
+    let (messages, stale, hash) = discovery_messages(entities, disarm_code, zone_overrides);
+    let hash = fnv1a(hash, mqtt_endpoint.as_bytes());
+    let published_hash = discovery_nvs.and_then(|nvs| {
+        nvs.get_u64(DISCOVERY_HASH_KEY)
+            .map_err(|e| log::error!("Failed to read discovery hash: {:?}", e))
+            .ok()
+            .flatten()
+    });
+    if published_hash == Some(hash) {
+        log::info!("Discovery is unchanged, not publishing it");
+        return Ok(());
+    }
+    // Switching between device and entity discovery, or moving zones to other devices,
+    // leaves configs retained which HA would show next to the new ones
+    log::info!("Clearing {} replaced discovery messages", stale.len());
+    for topic in stale.iter() {
+        client
+            .publish(topic, QoS::AtLeastOnce, true, &[])
+            .inspect_err(|_| mqtt_stats::count(MqttStat::publish_errors))?;
+    }
+    publish_discovery(client, &messages)?;
+    if let Some(nvs) = discovery_nvs {
+        nvs.set_u64(DISCOVERY_HASH_KEY, hash)
+            .unwrap_or_else(|e| log::error!("Failed to store discovery hash: {:?}", e));
+    }
     Ok(())
 }
 
-fn send_alarm_state_change(
-    state: &AlarmState,
-    entity: &HAEntity,
+/// Persists the names and devices of the bootstrapped zones and publishes the discovery
+/// with them, or once connected
+fn apply_zone_overrides(
+    client: Option<&mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>>,
+    entities: &[HAEntity],
+    mut discovery_nvs: Option<&mut EspNvs<NvsDefault>>,
+    mqtt_endpoint: &str,
+    disarm_code: bool,
+    zone_overrides: &BTreeMap<String, ZoneOverride>,
+) -> anyhow::Result<()> {
+    if let Some(nvs) = discovery_nvs.as_deref_mut() {
+        zone_bootstrap::store(nvs, zone_overrides)
+            .unwrap_or_else(|e| log::error!("Failed to store the bootstrapped zones: {:?}", e));
+    }
+    if let Some(client) = client {
+        update_discovery(
+            client,
+            entities,
+            discovery_nvs.as_deref(),
+            mqtt_endpoint,
+            disarm_code,
+            zone_overrides,
+        )?;
+    }
+    Ok(())
+}
+
+/// Config topics and payloads of the entities, the config topics they replace and the hash
+/// of their content
+///
+/// With a disarm code in the settings, the alarm entity asks for it on disarming. With
+/// device discovery the entities of a device are published together in one message, entities
+/// without a device keep their own config topic. Bootstrapped zones get the name and the
+/// device of their area.
+fn discovery_messages(
+    entities: &[HAEntity],
+    disarm_code: bool,
+    zone_overrides: &BTreeMap<String, ZoneOverride>,
+) -> (Vec<(String, String)>, Vec<String>, u64) {
+    const AVAILABILITY_TOPIC: &str = env!("ESP_AVAILABILITY_TOPIC");
+    let device_discovery = env!("ESP_DEVICE_DISCOVERY") == "true";
+
+    // Devices of config.yml, which the bootstrapped zones may have left
+    let mut device_topics = entities
+        .iter()
+        .filter_map(|entity| device_object_id(entity.device.as_ref()?.identifiers.as_deref()?))
+        .map(|object_id| device_discovery_topic(&object_id))
+        .collect::<BTreeSet<_>>();
+    let entities_out = entities
+        .iter()
+        .map(|entity| {
+            let zone_override = zone_overrides.get(&entity.unique_id);
+            let entity = HAEntity {
+                name: zone_override.map_or_else(|| entity.name.clone(), |o| o.name.clone()),
+                device: zone_override
+                    .and_then(|o| o.device.clone())
+                    .or_else(|| entity.device.clone()),
+                availability: Some(HADeviceAvailability {
+                    payload_available: Some("online".to_string()),
+                    payload_not_available: Some("offline".to_string()),
+                    topic: AVAILABILITY_TOPIC.to_string(),
+                    // Entities stay available while the panel restarts on purpose, the
+                    // topic itself tells maintenance apart for automations
+                    value_template: Some(format!(
+                        "{{{{ 'online' if value == '{}' else value }}}}",
+                        AVAILABILITY_MAINTENANCE
+                    )),
+                }),
+                ..entity.clone()
+            };
+            let variant = entity.variant.to_string();
+            let topic = format!(
+                "{}/{}/{}/config",
+                "homeassistant", variant, entity.unique_id
+            );
+            let requires_code =
+                disarm_code && entity.variant == HAEntityVariant::alarm_control_panel;
+            let entity_out = HAEntityOut::from(entity);
+            if requires_code {
+                (variant, topic, entity_out.with_disarm_code())
+            } else {
+                (variant, topic, entity_out)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut hash = FNV_OFFSET_BASIS;
+    for (_, topic, entity_out) in entities_out.iter() {
+        hash = fnv1a(hash, topic.as_bytes());
+        hash = fnv1a(hash, serde_json::to_string(entity_out).unwrap().as_bytes());
+    }
+    if device_discovery {
+        hash = fnv1a(hash, b"device");
+    }
+
+    let mut messages = Vec::new();
+    // Config topics of the entities replaced by device discovery
+    let mut stale = Vec::new();
+    // Object id of each device and its discovery
+    let mut devices: Vec<(String, serde_json::Value)> = Vec::new();
+    for (variant, topic, entity_out) in entities_out {
+        let entity_out = HAEntityOut {
+            config_hash: Some(format!("{:016x}", hash)),
+            ..entity_out
+        };
+        let object_id = entity_out
+            .device
+            .as_ref()
+            .and_then(|device| device_object_id(device.identifiers.as_deref()?));
+        let Some(object_id) = object_id else {
+            messages.push((topic, serde_json::to_string(&entity_out).unwrap()));
+            continue;
+        };
+        if !device_discovery {
+            device_topics.insert(device_discovery_topic(&object_id));
+            messages.push((topic, serde_json::to_string(&entity_out).unwrap()));
+            continue;
+        }
+        stale.push(topic);
+        let mut component = serde_json::to_value(&entity_out).unwrap();
+        let device = component
+            .as_object_mut()
+            .and_then(|object| object.remove("device"));
+        component["platform"] = json!(variant);
+        match devices.iter_mut().find(|(id, _)| *id == object_id) {
+            Some((_, discovery)) => {
+                discovery["components"][&entity_out.unique_id] = component;
+            }
+            None => devices.push((
+                object_id,
+                json!({
+                    "device": device,
+                    "origin": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "sw_version": env!("CARGO_PKG_VERSION"),
+                    },
+                    "components": { entity_out.unique_id.clone(): component },
+                }),
+            )),
+        }
+    }
+    messages.extend(
+        devices.into_iter().map(|(object_id, discovery)| {
+            (device_discovery_topic(&object_id), discovery.to_string())
+        }),
+    );
+    // Devices without entities, or every device without device discovery
+    stale.extend(
+        device_topics
+            .into_iter()
+            .filter(|device_topic| !messages.iter().any(|(topic, _)| topic == device_topic)),
+    );
+    (messages, stale, hash)
+}
+
+/// Object id of the device discovery of a device, from its first identifier
+fn device_object_id(identifiers: &[String]) -> Option<String> {
+    let id = identifiers.first()?;
+    Some(
+        id.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+    )
+}
+
+fn device_discovery_topic(object_id: &str) -> String {
+    format!("homeassistant/device/{}/config", object_id)
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a, which is stable across builds unlike the std hasher
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn publish_discovery(
     client: &mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>,
+    messages: &[(String, String)],
 ) -> anyhow::Result<()> {
-    let payload = match state {
+    log::info!("Publishing {} discovery messages", messages.len());
+    for (topic, payload) in messages.iter() {
+        client
+            .publish(topic, QoS::AtLeastOnce, true, payload.as_bytes())
+            .inspect_err(|_| mqtt_stats::count(MqttStat::publish_errors))?;
+    }
+    Ok(())
+}
+
+/// State topic and payload which represent the event
+fn event_state(event: AlarmEvent) -> Option<(String, String)> {
+    let (topic, payload) = match event {
+        AlarmEvent::MotionDetected(entity) => {
+            (entity.state_topic.clone(), binary_sensor_payload(true))
+        }
+        AlarmEvent::MotionCleared(entity) => {
+            (entity.state_topic.clone(), binary_sensor_payload(false))
+        }
+        AlarmEvent::AlarmStateChanged((entity, state, _)) => {
+            (entity.state_topic, alarm_state_payload(&state))
+        }
+        AlarmEvent::OutputStateChanged((entity, state)) => {
+            (entity.state_topic, binary_sensor_payload(state))
+        }
+        AlarmEvent::FireAlarmChanged((entity, zone)) => (
+            format!("{}/fire", entity.state_topic),
+            binary_sensor_payload(zone.is_some()),
+        ),
+        AlarmEvent::SettingChanged((entity, value)) => return Some((entity.state_topic, value)),
+        AlarmEvent::PartialDisarmChanged((entity, state, partial)) => {
+            (entity.state_topic, partial_disarm_payload(&state, partial))
+        }
+        AlarmEvent::ZonesLearned((entity, learned)) => {
+            return Some((
+                format!("{}/learned", entity.state_topic),
+                serde_json::to_string(&learned).ok()?,
+            ))
+        }
+        AlarmEvent::CommandResult(_) | AlarmEvent::CommandConflict(_) => return None,
+    };
+    Some((topic, payload.to_string()))
+}
+
+/// States to publish for the event, zone changes also update the attributes of
+/// the alarm state when it is published as JSON
+fn event_states(
+    event: AlarmEvent,
+    alarm_json: Option<&mut AlarmJsonState>,
+) -> Vec<(String, String)> {
+    let Some(alarm_json) = alarm_json else {
+        return event_state(event).into_iter().collect();
+    };
+    match event {
+        AlarmEvent::AlarmStateChanged((entity, state, changed_by)) => {
+            alarm_json.state = Some(alarm_state_payload(&state));
+            alarm_json.changed_at = clock::is_synchronized().then(clock::unix_time);
+            alarm_json.changed_by = changed_by;
+            vec![(entity.state_topic, alarm_json.payload())]
+        }
+        AlarmEvent::PartialDisarmChanged((entity, state, partial)) => {
+            alarm_json.state = Some(partial_disarm_payload(&state, partial));
+            vec![(entity.state_topic, alarm_json.payload())]
+        }
+        AlarmEvent::MotionDetected(ref entity) | AlarmEvent::MotionCleared(ref entity) => {
+            let changed = if matches!(event, AlarmEvent::MotionDetected(_)) {
+                alarm_json.open_zones.insert(entity.name.clone())
+            } else {
+                alarm_json.open_zones.remove(&entity.name)
+            };
+            let mut states = event_state(event).into_iter().collect::<Vec<_>>();
+            if changed && alarm_json.state.is_some() {
+                states.push((alarm_json.topic.clone(), alarm_json.payload()));
+            }
+            states
+        }
+        event => event_state(event).into_iter().collect(),
+    }
+}
+
+/// What an inbound message is for, by the topic it arrived on
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Route {
+    Loopback,
+    Throughput,
+    HaStatus,
+    Resend,
+    NetCommand,
+    /// Index of the virtual zone
+    VirtualZone(usize),
+    /// Index of the link check
+    LinkCheck(usize),
+    ArmNote,
+    Button(ButtonAction),
+    AlarmCommand,
+    /// Index of the switch entity
+    Switch(usize),
+    Number(AlarmSetting),
+    Select,
+    Archive,
+    FlashLog,
+    Presence,
+    Provisioning,
+}
+
+/// Last change and last activation of a zone, in ISO 8601, unknown while the clock
+/// is not synchronized
+#[derive(Default)]
+struct ZoneTimes {
+    last_changed: Option<String>,
+    last_triggered: Option<String>,
+}
+
+/// Attributes of the entity of the event, the icon of the new state for entities with
+/// state icons and the activity times of zones
+fn event_attributes(
+    event: &AlarmEvent,
+    zone_times: &mut BTreeMap<String, ZoneTimes>,
+) -> Option<(String, String)> {
+    let (entity, state) = match event {
+        AlarmEvent::MotionDetected(entity) => (entity.as_ref(), true),
+        AlarmEvent::MotionCleared(entity) => (entity.as_ref(), false),
+        AlarmEvent::OutputStateChanged((entity, state)) => (entity, *state),
+        _ => return None,
+    };
+    let mut attributes = serde_json::Map::new();
+    if let Some(icons) = entity.state_icons.as_ref() {
+        let icon = if state { &icons.on } else { &icons.off };
+        attributes.insert("icon".to_string(), json!(icon));
+    }
+    if entity.is_zone() {
+        let now = clock::is_synchronized().then(|| clock::iso8601(clock::unix_time()));
+        let times = zone_times.entry(entity.unique_id.clone()).or_default();
+        if state {
+            times.last_triggered = now.clone();
+        }
+        times.last_changed = now;
+        attributes.insert("last_changed".to_string(), json!(times.last_changed));
+        attributes.insert("last_triggered".to_string(), json!(times.last_triggered));
+    }
+    if attributes.is_empty() {
+        return None;
+    }
+    Some((
+        entity.attributes_topic()?,
+        serde_json::Value::Object(attributes).to_string(),
+    ))
+}
+
+fn binary_sensor_payload(state: bool) -> &'static str {
+    if state {
+        "ON"
+    } else {
+        "OFF"
+    }
+}
+
+/// A partial disarm is shown as disarming until the second code is entered
+fn partial_disarm_payload(state: &AlarmState, partial: bool) -> &'static str {
+    if partial {
+        "disarming"
+    } else {
+        alarm_state_payload(state)
+    }
+}
+
+fn alarm_state_payload(state: &AlarmState) -> &'static str {
+    match state {
         AlarmState::Disarmed => "disarmed",
         AlarmState::Arming(_) => "arming",
-        AlarmState::Armed(_) => "armed_away",
+        AlarmState::Armed((_, ArmMode::away)) => "armed_away",
+        AlarmState::Armed((_, ArmMode::home)) => "armed_home",
+        AlarmState::Armed((_, ArmMode::night)) => "armed_night",
         AlarmState::Pending(_) => "pending",
         AlarmState::Triggered => "triggered",
+    }
+}
+
+/// Alarm state which is published as a JSON object with its attributes
+struct AlarmJsonState {
+    topic: String,
+    /// Unknown until the first state change
+    state: Option<&'static str>,
+    changed_at: Option<u64>,
+    changed_by: String,
+    open_zones: BTreeSet<String>,
+    /// Arm note of the last state change
+    note: Option<String>,
+}
+
+impl AlarmJsonState {
+    fn new(topic: &str) -> Self {
+        Self {
+            topic: topic.to_string(),
+            state: None,
+            changed_at: None,
+            changed_by: String::new(),
+            open_zones: BTreeSet::new(),
+            note: None,
+        }
+    }
+
+    fn payload(&self) -> String {
+        json!({
+            "state": self.state,
+            "changed_at": self.changed_at,
+            "changed_by": self.changed_by,
+            "open_zones": self.open_zones,
+            "note": self.note,
+        })
+        .to_string()
+    }
+}
+
+/// Recent zone and alarm state changes, published as a JSON array with the newest first
+struct EventHistory {
+    topic: String,
+    length: usize,
+    entries: VecDeque<serde_json::Value>,
+}
+
+impl EventHistory {
+    fn new(state_topic: &str, length: usize) -> Self {
+        Self {
+            topic: format!("{}/history", state_topic),
+            length,
+            entries: VecDeque::with_capacity(length),
+        }
+    }
+
+    /// The topic and the new payload if the event belongs in the history
+    fn record(&mut self, event: &AlarmEvent) -> Option<(String, String)> {
+        let (entity, state) = match event {
+            AlarmEvent::MotionDetected(entity) => (entity.as_ref(), binary_sensor_payload(true)),
+            AlarmEvent::MotionCleared(entity) => (entity.as_ref(), binary_sensor_payload(false)),
+            AlarmEvent::AlarmStateChanged((entity, state, _)) => {
+                (entity, alarm_state_payload(state))
+            }
+            _ => return None,
+        };
+        if self.entries.len() >= self.length {
+            self.entries.pop_back();
+        }
+        self.entries.push_front(json!({
+            "entity": entity.unique_id,
+            "name": entity.name,
+            "state": state,
+            // Unknown while the clock is not synchronized
+            "time": clock::is_synchronized().then(|| clock::iso8601(clock::unix_time())),
+        }));
+        Some((self.topic.clone(), json!(self.entries).to_string()))
+    }
+}
+
+/// Publishes the critical changes ahead of the event queue
+///
+/// The topics are C strings and the payloads static, both prepared when the scheduler
+/// starts, and they are handed to esp-mqtt directly, so nothing is allocated on the heap
+/// on the way to its buffer. A signal is dropped once its queued event is processed, so
+/// it is never published after a later state, e.g. when the event went only to the cache
+/// and other subscribers while the client was down. The queued events follow with the
+/// attributes and go through the state cache as usual.
+struct CriticalPublisher {
+    alarm_topic: CString,
+    triggered_payload: &'static str,
+    siren_topic: Option<CString>,
+}
+
+impl CriticalPublisher {
+    fn new(entities: &[HAEntity], alarm_entity: &HAEntity) -> Self {
+        let triggered_payload = match alarm_entity.json_state.unwrap_or(false) {
+            true => r#"{"state":"triggered"}"#,
+            false => alarm_state_payload(&AlarmState::Triggered),
+        };
+        // build.rs rejects topics with a NUL
+        let topic = |topic: &str| CString::new(topic).expect("topic contains a NUL");
+        Self {
+            alarm_topic: topic(&alarm_entity.state_topic),
+            triggered_payload,
+            siren_topic: entities
+                .iter()
+                .find(|entity| entity.siren.unwrap_or(false))
+                .map(|entity| topic(&entity.state_topic)),
+        }
+    }
+
+    /// Drops the signal of the critical change the event reports, it is published with it
+    fn processed(&self, event: &AlarmEvent, queue: &EventQueue) {
+        match event {
+            AlarmEvent::AlarmStateChanged((_, AlarmState::Triggered, _)) => {
+                queue.take_signal(Critical::Triggered);
+            }
+            // The siren turning off may have replaced the waiting change which turned it on
+            AlarmEvent::OutputStateChanged((entity, _))
+                if self
+                    .siren_topic
+                    .as_ref()
+                    .is_some_and(|topic| topic.as_bytes() == entity.state_topic.as_bytes()) =>
+            {
+                queue.take_signal(Critical::Siren);
+            }
+            _ => {}
+        }
+    }
+
+    fn publish(
+        &self,
+        client: &mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>,
+        queue: &EventQueue,
+    ) -> anyhow::Result<()> {
+        let siren = self
+            .siren_topic
+            .as_ref()
+            .filter(|_| queue.take_signal(Critical::Siren))
+            .map(|topic| (topic, binary_sensor_payload(true)));
+        let triggered = queue
+            .take_signal(Critical::Triggered)
+            .then_some((&self.alarm_topic, self.triggered_payload));
+        for (topic, payload) in triggered.into_iter().chain(siren) {
+            // SAFETY: the handle is valid while the client is borrowed, esp-mqtt copies the
+            // topic and the payload before it returns
+            let id = unsafe {
+                esp_mqtt_client_publish(
+                    client.handle(),
+                    topic.as_ptr(),
+                    payload.as_ptr().cast(),
+                    payload.len() as _,
+                    QoS::AtLeastOnce as _,
+                    1,
+                )
+            };
+            if id < 0 {
+                mqtt_stats::count(MqttStat::publish_errors);
+                bail!("Failed to publish the critical change to {:?}", topic);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Publishes a state unless it is the same as the last one and publishes are deduplicated
+fn publish_state(
+    client: Option<&mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>>,
+    state_cache: &mut StateCache,
+    topic: &str,
+    payload: &str,
+) -> anyhow::Result<()> {
+    let changed = state_cache.update(topic, payload);
+    if let (true, Some(client)) = (changed, client) {
+        client
+            .publish(topic, QoS::AtLeastOnce, true, payload.as_bytes())
+            .inspect_err(|_| mqtt_stats::count(MqttStat::publish_errors))?;
+    }
+    Ok(())
+}
+
+/// Publishes the state of an alarm event, the event is lost if its state is replaced before
+/// any client published it
+fn publish_event_state(
+    client: Option<&mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>>,
+    state_cache: &mut StateCache,
+    topic: &str,
+    payload: &str,
+) -> anyhow::Result<()> {
+    let replaced = state_cache.states.get(topic).map(String::as_str) != Some(payload);
+    if replaced && state_cache.unpublished.remove(topic) {
+        mqtt_stats::count(MqttStat::lost_events);
+    }
+    let published = client.is_some();
+    let result = publish_state(client, state_cache, topic, payload);
+    if replaced && (!published || result.is_err()) {
+        state_cache.unpublished.insert(topic.to_string());
+    }
+    result
+}
+
+/// Last known state of every entity
+struct StateCache {
+    states: BTreeMap<String, String>,
+    /// Skip publishing states which are identical to the last one
+    dedupe: bool,
+    /// Topics of alarm events whose state no client published yet
+    unpublished: BTreeSet<String>,
+}
+
+impl StateCache {
+    fn new(dedupe: bool) -> Self {
+        Self {
+            states: BTreeMap::new(),
+            dedupe,
+            unpublished: BTreeSet::new(),
+        }
+    }
+
+    /// Stores the state, returns false if it does not have to be published
+    fn update(&mut self, topic: &str, payload: &str) -> bool {
+        let previous = self.states.insert(topic.to_string(), payload.to_string());
+        !self.dedupe || previous.as_deref() != Some(payload)
+    }
+
+    /// Republishes the last known state of every entity
+    fn resend(
+        &mut self,
+        client: &mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>,
+    ) -> anyhow::Result<()> {
+        log::info!("Resending {} entity states", self.states.len());
+        for (topic, payload) in self.states.iter() {
+            client.publish(topic, QoS::AtLeastOnce, true, payload.as_bytes())?;
+        }
+        self.unpublished.clear();
+        Ok(())
+    }
+}
+
+fn publish_net_status(
+    client: Option<&mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>>,
+    status_topic: Option<&str>,
+    status: &str,
+) -> anyhow::Result<()> {
+    log::info!("{}", status);
+    if let (Some(client), Some(status_topic)) = (client, status_topic) {
+        client.publish(status_topic, QoS::AtLeastOnce, false, status.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Restarts the device, the alarm state has already been persisted by the alarm task
+fn reboot(
+    mut client: Option<&mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>>,
+    status_topic: Option<&str>,
+    reason: &str,
+) -> ! {
+    publish_net_status(
+        client.as_deref_mut(),
+        status_topic,
+        &format!("rebooting: {}", reason),
+    )
+    .unwrap_or_else(|e| log::error!("Failed to publish reboot status: {:?}", e));
+    restart_gracefully(client)
+}
+
+/// Marks the device as under maintenance before restarting, so HA doesn't raise
+/// alerts for it
+///
+/// Should the device not come back, the broker delivers the `offline` last will once
+/// the keep-alive has timed out.
+fn restart_gracefully(
+    client: Option<&mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>>,
+) -> ! {
+    const AVAILABILITY_TOPIC: &str = env!("ESP_AVAILABILITY_TOPIC");
+    // There is no way to know when the outbox is empty, give it some time to be sent
+    const FLUSH_DELAY: Duration = Duration::from_secs(1);
+
+    if let Some(client) = client {
+        client
+            .publish(
+                AVAILABILITY_TOPIC,
+                QoS::AtLeastOnce,
+                true,
+                AVAILABILITY_MAINTENANCE.as_bytes(),
+            )
+            .unwrap_or_else(|e| {
+                log::error!("Failed to publish maintenance: {:?}", e);
+                0
+            });
+        std::thread::sleep(FLUSH_DELAY);
+    }
+    unsafe {
+        esp_restart();
+    }
+}
+
+fn handle_alarm_command(
+    payload: &str,
+    alarm_command_tx: &Sender<(CommandSource, AlarmCommand)>,
+    client: Option<&mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>>,
+    result_topic: &str,
+) -> anyhow::Result<()> {
+    // With a disarm code or dual disarm, HA sends the action and the entered code as JSON,
+    // only the action is logged and published, never the code
+    let parsed = parse_alarm_payload(payload).map_err(|e| (None, e));
+    let parsed = parsed.and_then(|(action, code)| {
+        let command = match action.as_str() {
+            "ARM_AWAY" => AlarmCommand::Arm,
+            "ARM_HOME" => AlarmCommand::ArmHome,
+            "ARM_NIGHT" => AlarmCommand::ArmNight,
+            "ARM_CUSTOM_BYPASS" => AlarmCommand::ArmInstantly,
+            "ARM_FORCE" => AlarmCommand::ArmForce,
+            // The alarm task checks the code
+            "DISARM" => AlarmCommand::Disarm(code),
+            "TRIGGER" => AlarmCommand::ManualTrigger,
+            "UNTRIGGER" => AlarmCommand::Untrigger,
+            "BELL_TEST" => AlarmCommand::BellTest,
+            "FIRE_ACK" => AlarmCommand::FireAck,
+            "PANIC" => AlarmCommand::Panic,
+            _ => return Err((Some(action), PayloadError::UnknownCommand)),
+        };
+        Ok(command)
+    });
+    let command = match parsed {
+        Ok(command) => command,
+        Err((action, e)) => {
+            let action = action.unwrap_or_default();
+            log::warn!("Invalid alarm command {}: {}", action, e);
+            if let Some(client) = client {
+                publish_command_result(client, result_topic, &action, Err(&e.to_string()))?;
+            }
+            return Ok(());
+        }
+    };
+    // The result is published once the command has been processed
+    alarm_command_tx.send((CommandSource::Mqtt, command))?;
+    Ok(())
+}
+
+/// Acknowledges a command, so automations can verify that it took effect
+fn publish_command_result(
+    client: &mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>,
+    result_topic: &str,
+    command: &str,
+    result: Result<(), &str>,
+) -> anyhow::Result<()> {
+    let payload = match result {
+        Ok(()) => json!({ "command": command, "status": "accepted" }),
+        Err(reason) => json!({ "command": command, "status": "rejected", "reason": reason }),
     };
     client.publish(
-        &entity.state_topic,
+        result_topic,
         QoS::AtLeastOnce,
-        true,
-        payload.as_bytes(),
+        false,
+        payload.to_string().as_bytes(),
     )?;
     Ok(())
 }
 
-fn handle_alarm_command(
+/// Publishes the state of a link check, so HA sees that its challenge made the whole
+/// round trip through the broker and the panel
+///
+/// The state bypasses the state cache, every answer has to reach HA to renew the entity.
+fn answer_link_challenge(
+    client: &mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>,
+    entity: &HAEntity,
+    challenge: &str,
+) -> anyhow::Result<()> {
+    if let Err(e) = check_size(challenge.as_bytes(), PayloadClass::Command) {
+        log::warn!("Invalid link challenge: {}", e);
+        return Ok(());
+    }
+    client.publish(&entity.state_topic, QoS::AtLeastOnce, false, b"ON")?;
+    if let Some(attributes_topic) = entity.attributes_topic() {
+        let attributes = json!({
+            "challenge": challenge,
+            "answered": clock::is_synchronized().then(|| clock::iso8601(clock::unix_time())),
+        });
+        client.publish(
+            &attributes_topic,
+            QoS::AtLeastOnce,
+            false,
+            attributes.to_string().as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+fn handle_switch_command(
     payload: &str,
-    alarm_command_tx: &Sender<AlarmCommand>,
+    entity: &HAEntity,
+    expander_command_tx: Option<&Sender<ExpanderCommand>>,
+    alarm_command_tx: &Sender<(CommandSource, AlarmCommand)>,
 ) -> anyhow::Result<()> {
-    let command = match payload {
-        "ARM_AWAY" => AlarmCommand::Arm,
-        "ARM_CUSTOM_BYPASS" => AlarmCommand::ArmInstantly,
-        "DISARM" => AlarmCommand::Disarm,
-        "TRIGGER" => AlarmCommand::ManualTrigger,
-        "UNTRIGGER" => AlarmCommand::Untrigger,
-        _ => {
-            log::warn!("Unknown command: {}", payload);
+    let on = match parse_switch(payload) {
+        Ok(on) => on,
+        Err(e) => {
+            log::warn!("Invalid switch command {}: {}", payload, e);
             return Ok(());
         }
     };
-    alarm_command_tx.send(command)?;
+    if let Some(command) = AlarmCommand::from_switch(entity, on) {
+        alarm_command_tx.send((CommandSource::Mqtt, command))?;
+    } else if let Some(expander_command_tx) = expander_command_tx {
+        expander_command_tx.send(ExpanderCommand::SetRelay(entity.clone(), on))?;
+    }
+    Ok(())
+}
+
+fn handle_number_command(
+    payload: &str,
+    setting: AlarmSetting,
+    alarm_command_tx: &Sender<(CommandSource, AlarmCommand)>,
+) -> anyhow::Result<()> {
+    let value = match parse_number(payload) {
+        Ok(value) => value,
+        Err(e) => {
+            log::warn!("Invalid {} value {}: {}", setting.key(), payload, e);
+            return Ok(());
+        }
+    };
+    alarm_command_tx.send((
+        CommandSource::Mqtt,
+        AlarmCommand::UpdateSettings((setting, value)),
+    ))?;
+    Ok(())
+}
+
+fn handle_presence_action(
+    action: PresenceAction,
+    reason_topic: &str,
+    alarm_command_tx: &Sender<(CommandSource, AlarmCommand)>,
+    client: Option<&mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>>,
+) -> anyhow::Result<()> {
+    alarm_command_tx.send((CommandSource::Presence, action.command))?;
+    if let Some(client) = client {
+        client.publish(
+            reason_topic,
+            QoS::AtLeastOnce,
+            false,
+            action.reason.as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+fn handle_archive_command(
+    payload: &str,
+    sd_card: &SdCardConfig,
+    client: &mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>,
+) -> anyhow::Result<()> {
+    let mut args = payload.split_whitespace();
+    match (args.next(), args.next()) {
+        (Some("list"), None) => {
+            let days = serde_json::to_string(&archive::list_days()?)?;
+            client.publish(
+                &sd_card.response_topic,
+                QoS::AtLeastOnce,
+                false,
+                days.as_bytes(),
+            )?;
+        }
+        (Some("fetch"), Some(day)) => {
+            archive::fetch_day(day, |chunk| {
+                client.publish(&sd_card.response_topic, QoS::AtLeastOnce, false, chunk)?;
+                Ok(())
+            })?;
+            client.publish(&sd_card.response_topic, QoS::AtLeastOnce, false, &[])?;
+        }
+        _ => {
+            log::warn!("Unknown archive command: {}", payload);
+        }
+    }
+    Ok(())
+}
+
+fn handle_flash_log_command(
+    payload: &str,
+    config: &FlashLogConfig,
+    flash_log: &Mutex<FlashLog>,
+    client: &mut EspMqttClient<'_, ConnState<MessageImpl, EspError>>,
+) -> anyhow::Result<()> {
+    if payload.trim() != "dump" {
+        log::warn!("Unknown flash log command: {}", payload);
+        return Ok(());
+    }
+
+    // Records are batched so a dump doesn't flood the broker with tiny messages
+    const MAX_CHUNK_SIZE: usize = 4096;
+    let mut chunk = Vec::new();
+    flash_log
+        .lock_recover_with(FlashLog::reopen)
+        .for_each_record(|record| {
+            if !chunk.is_empty() && chunk.len() + record.len() >= MAX_CHUNK_SIZE {
+                client.publish(&config.response_topic, QoS::AtLeastOnce, false, &chunk)?;
+                chunk.clear();
+            }
+            chunk.extend_from_slice(record);
+            chunk.push(b'\n');
+            Ok(())
+        })?;
+    if !chunk.is_empty() {
+        client.publish(&config.response_topic, QoS::AtLeastOnce, false, &chunk)?;
+    }
+    client.publish(&config.response_topic, QoS::AtLeastOnce, false, &[])?;
     Ok(())
 }
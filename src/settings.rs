@@ -0,0 +1,156 @@
+use anyhow::{anyhow, Context};
+use esp_idf_hal::cpu::Core;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use ha_types::{
+    parse_mac, validate_setting, validate_setting_key, DailyWindow, SETTINGS_DISARM_CODE,
+    SETTINGS_ETH_DMA_SIZE, SETTINGS_ETH_MAC, SETTINGS_ETH_SPI_MHZ, SETTINGS_HOSTNAME,
+    SETTINGS_KEYS, SETTINGS_LANGUAGE, SETTINGS_MAX_VALUE_LEN, SETTINGS_MQTT_CORE,
+    SETTINGS_NAMESPACE, SETTINGS_PROTECTED, SETTINGS_QUIET_HOURS, SETTINGS_TIMEZONE,
+};
+
+use crate::alarm::AlarmState;
+
+pub use ha_types::SETTINGS_MQTT_ENDPOINT as MQTT_ENDPOINT_KEY;
+
+const MQTT_ENDPOINT: &str = env!("ESP_MQTT_ENDPOINT");
+const HOSTNAME: &str = "alarm";
+const ETH_MAC: [u8; 6] = [0x02, 0x00, 0x00, 0xfc, 0x18, 0x01];
+const ETH_SPI_MHZ: u32 = 20;
+const ETH_DMA_SIZE: usize = 4096;
+const QUIET_HOURS: &str = "22:00-07:00";
+const TIMEZONE: &str = "UTC0";
+const LOCK_SETTINGS_WHILE_ARMED: &str = env!("ESP_LOCK_SETTINGS_WHILE_ARMED");
+pub const LOCKED_REASON: &str = "locked while the alarm is armed";
+/// Longest string NVS stores, with the terminating zero
+pub const MAX_JSON_LEN: usize = 4000;
+
+/// Overrides of the built-in configuration, persisted in NVS
+pub struct Settings {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl Settings {
+    pub fn open(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, SETTINGS_NAMESPACE, true)?,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        validate_setting_key(key).map_err(|e| anyhow!(e))?;
+        let mut buf = [0u8; SETTINGS_MAX_VALUE_LEN + 1];
+        Ok(self.nvs.get_str(key, &mut buf)?.map(str::to_string))
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        validate_setting(key, value).map_err(|e| anyhow!(e))?;
+        self.nvs
+            .set_str(key, value)
+            .with_context(|| format!("Failed to write {}", key))
+    }
+
+    /// Keys of the settings which override the built-in configuration
+    pub fn overridden(&self) -> Vec<&'static str> {
+        SETTINGS_KEYS
+            .iter()
+            .copied()
+            .filter(|key| self.nvs.contains(key).unwrap_or(false))
+            .collect()
+    }
+
+    /// Removes every override, the built-in configuration is used from the next boot
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        for key in SETTINGS_KEYS {
+            self.nvs.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// The broker to connect to, the override if set or the one in config.yml
+    pub fn mqtt_endpoint(&self) -> String {
+        self.get_or_log(MQTT_ENDPOINT_KEY)
+            .unwrap_or_else(|| MQTT_ENDPOINT.to_string())
+    }
+
+    pub fn hostname(&self) -> String {
+        self.get_or_log(SETTINGS_HOSTNAME)
+            .unwrap_or_else(|| HOSTNAME.to_string())
+    }
+
+    pub fn eth_mac(&self) -> [u8; 6] {
+        self.get_or_log(SETTINGS_ETH_MAC)
+            .and_then(|mac| parse_mac(&mac))
+            .unwrap_or(ETH_MAC)
+    }
+
+    pub fn eth_spi_mhz(&self) -> u32 {
+        self.get_or_log(SETTINGS_ETH_SPI_MHZ)
+            .and_then(|mhz| mhz.parse().ok())
+            .unwrap_or(ETH_SPI_MHZ)
+    }
+
+    /// Largest SPI transfer of the ethernet controller, larger ones take fewer transactions
+    pub fn eth_dma_size(&self) -> usize {
+        self.get_or_log(SETTINGS_ETH_DMA_SIZE)
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(ETH_DMA_SIZE)
+    }
+
+    /// Core of the MQTT task, `None` if it may run on either core
+    ///
+    /// It shares Core0 with the network and the scheduler by default, Core1 runs the alarm.
+    pub fn mqtt_core(&self) -> Option<Core> {
+        match self.get_or_log(SETTINGS_MQTT_CORE).as_deref() {
+            Some("1") => Some(Core::Core1),
+            Some("any") => None,
+            _ => Some(Core::Core0),
+        }
+    }
+
+    pub fn quiet_hours(&self) -> DailyWindow {
+        self.get_or_log(SETTINGS_QUIET_HOURS)
+            .and_then(|value| DailyWindow::parse(&value))
+            .or_else(|| DailyWindow::parse(QUIET_HOURS))
+            .expect("Invalid built-in quiet hours")
+    }
+
+    /// Code required to disarm from HA, `None` if disarming needs no code
+    pub fn disarm_code(&self) -> Option<String> {
+        self.get_or_log(SETTINGS_DISARM_CODE)
+    }
+
+    /// Code of the language of the names and announcements, `None` keeps config.yml as it is
+    pub fn language(&self) -> Option<String> {
+        self.get_or_log(SETTINGS_LANGUAGE)
+    }
+
+    /// POSIX TZ rule of the local time, UTC if unset
+    pub fn timezone(&self) -> String {
+        self.get_or_log(SETTINGS_TIMEZONE)
+            .unwrap_or_else(|| TIMEZONE.to_string())
+    }
+
+    /// Rejects changes of the protected settings while the alarm is armed or triggered,
+    /// if configured, `None` changes every setting
+    pub fn check_unlocked(key: Option<&str>, state: &AlarmState) -> Result<(), &'static str> {
+        let protected = key.map_or(true, |key| SETTINGS_PROTECTED.contains(&key));
+        if protected && Self::locked(state) {
+            return Err(LOCKED_REASON);
+        }
+        Ok(())
+    }
+
+    /// Whether changes are rejected in the state, the alarm task applies it to the timings
+    /// and the automatic re-arming too
+    pub fn locked(state: &AlarmState) -> bool {
+        LOCK_SETTINGS_WHILE_ARMED == "true" && !matches!(state, AlarmState::Disarmed)
+    }
+
+    /// Unreadable settings fall back to the built-in configuration
+    fn get_or_log(&self, key: &str) -> Option<String> {
+        self.get(key).unwrap_or_else(|e| {
+            log::error!("Failed to read {}: {:?}", key, e);
+            None
+        })
+    }
+}
@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use esp_idf_hal::gpio::{Output, OutputPin, PinDriver};
+use esp_idf_hal::ledc::{config::TimerConfig, LedcChannel, LedcDriver, LedcTimerDriver, TIMER1};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::units::Hertz;
+use esp_idf_sys::{esp, ledc_mode_t_LEDC_LOW_SPEED_MODE, ledc_set_freq, ledc_timer_t_LEDC_TIMER_1};
+use ha_types::{SirenPattern, SirenPwmConfig, SirenTone};
+
+/// What the siren is sounding, each has its own tone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SirenSound {
+    Alarm,
+    Chirp,
+}
+
+/// Drives the siren, called on every pass of the alarm task with the current sound and
+/// how long it has been sounding
+pub trait SirenOutput {
+    fn set(&mut self, sound: Option<SirenSound>, elapsed: Duration) -> anyhow::Result<()>;
+}
+
+/// A siren with its own sounder, switched on and off by a relay or a transistor
+impl<P: OutputPin> SirenOutput for PinDriver<'_, P, Output> {
+    fn set(&mut self, sound: Option<SirenSound>, _elapsed: Duration) -> anyhow::Result<()> {
+        match sound {
+            Some(_) => self.set_high()?,
+            None => self.set_low()?,
+        }
+        Ok(())
+    }
+}
+
+/// A piezo sounder driven with the tones of the configuration
+pub struct SirenPwm {
+    config: SirenPwmConfig,
+    driver: LedcDriver<'static>,
+    /// Frequency of the timer and whether the output is on, only changes are written
+    output: (u32, bool),
+}
+
+impl SirenPwm {
+    /// The frequency is changed through the timer, so it is not shared with other channels
+    pub fn new<C: LedcChannel>(
+        config: SirenPwmConfig,
+        timer: TIMER1,
+        channel: impl Peripheral<P = C> + 'static,
+        pin: impl Peripheral<P = impl OutputPin> + 'static,
+    ) -> anyhow::Result<Self> {
+        let frequency = config.alarm.frequency;
+        let timer = LedcTimerDriver::new(timer, &TimerConfig::new().frequency(Hertz(frequency)))?;
+        let mut driver = LedcDriver::new(channel, timer, pin)?;
+        driver.set_duty(0)?;
+        Ok(Self {
+            config,
+            driver,
+            output: (frequency, false),
+        })
+    }
+
+    fn tone(&self, sound: SirenSound) -> SirenTone {
+        match sound {
+            SirenSound::Alarm => self.config.alarm,
+            SirenSound::Chirp => self.config.chirp,
+        }
+    }
+}
+
+impl SirenOutput for SirenPwm {
+    fn set(&mut self, sound: Option<SirenSound>, elapsed: Duration) -> anyhow::Result<()> {
+        let (frequency, on) = match sound.map(|sound| self.tone(sound)) {
+            None => (self.output.0, false),
+            Some(tone) => {
+                let quarter = elapsed.as_millis() / 250;
+                match tone.pattern {
+                    SirenPattern::continuous => (tone.frequency, true),
+                    SirenPattern::pulse => (tone.frequency, quarter % 4 < 2),
+                    SirenPattern::warble if quarter % 2 == 0 => (tone.frequency, true),
+                    SirenPattern::warble => (tone.frequency * 3 / 4, true),
+                }
+            }
+        };
+        if frequency != self.output.0 {
+            // SAFETY: the timer is owned by the driver and only used by this channel
+            esp!(unsafe {
+                ledc_set_freq(
+                    ledc_mode_t_LEDC_LOW_SPEED_MODE,
+                    ledc_timer_t_LEDC_TIMER_1,
+                    frequency,
+                )
+            })?;
+        }
+        if (frequency, on) != self.output {
+            let duty = if on {
+                self.driver.get_max_duty() / 2
+            } else {
+                0
+            };
+            self.driver.set_duty(duty)?;
+        }
+        self.output = (frequency, on);
+        Ok(())
+    }
+}
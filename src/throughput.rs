@@ -0,0 +1,88 @@
+use std::time::{Duration, Instant};
+
+/// Messages of a test, they are sent at once and have to come back from the broker
+const MESSAGE_COUNT: usize = 32;
+/// Payload size of a message, below the limit of received payloads
+const MESSAGE_SIZE: usize = 1000;
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Measures the round trip throughput of the network and the broker, e.g. after changing
+/// the SPI frequency or the DMA buffer size of the ethernet controller
+pub struct ThroughputTest {
+    topic: String,
+    /// Id of the running test, messages of earlier tests are ignored
+    running: Option<(u32, Instant)>,
+    next_id: u32,
+    received: usize,
+}
+
+impl ThroughputTest {
+    pub fn new(topic: String) -> Self {
+        Self {
+            topic,
+            running: None,
+            next_id: 0,
+            received: 0,
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Payloads to publish to the test topic, `None` while a test is running
+    pub fn start(&mut self) -> Option<Vec<String>> {
+        if self.running.is_some() {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.running = Some((id, Instant::now()));
+        self.received = 0;
+        let payloads = (0..MESSAGE_COUNT)
+            .map(|index| {
+                let header = format!("{:08x} {:02} ", id, index);
+                format!("{:-<1$}", header, MESSAGE_SIZE)
+            })
+            .collect();
+        Some(payloads)
+    }
+
+    /// Returns true if the message was sent to the test topic
+    pub fn handle_message(&mut self, topic: &str, payload: &str) -> bool {
+        if topic != self.topic {
+            return false;
+        }
+        let current = self
+            .running
+            .is_some_and(|(id, _)| payload.starts_with(&format!("{:08x} ", id)));
+        if current {
+            self.received += 1;
+        }
+        true
+    }
+
+    /// The result once every message came back or the test timed out
+    pub fn poll(&mut self) -> Option<String> {
+        let (_, start) = self.running?;
+        let elapsed = start.elapsed();
+        if self.received == MESSAGE_COUNT {
+            self.running = None;
+            let bytes = MESSAGE_COUNT * MESSAGE_SIZE;
+            return Some(format!(
+                "throughput: {} bytes sent and received in {} ms, {:.1} kB/s",
+                bytes,
+                elapsed.as_millis(),
+                bytes as f32 / elapsed.as_secs_f32().max(0.001) / 1000.0
+            ));
+        }
+        if elapsed >= TIMEOUT {
+            self.running = None;
+            return Some(format!(
+                "throughput test timed out, {} of {} messages came back",
+                self.received, MESSAGE_COUNT
+            ));
+        }
+        None
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ha_types::{HAEntity, TimingStat};
+
+use crate::lock::LockRecover;
+
+/// The peaks are published and reset this often
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+/// Zone changes waiting to be published, the oldest are not measured beyond this
+const PENDING_SIZE: usize = 64;
+
+/// Peaks since the last publish, in the order of `TimingStat::ALL`
+static PEAKS: [AtomicU32; 3] = [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)];
+/// Zone changes in the event queue and when they were detected
+static PENDING: Mutex<VecDeque<(String, Instant)>> = Mutex::new(VecDeque::new());
+
+/// Records a measurement, from any task, only the peak is published
+///
+/// Scan times are in microseconds and latencies in milliseconds.
+pub fn record(stat: TimingStat, value: u32) {
+    PEAKS[stat as usize].fetch_max(value, Ordering::Relaxed);
+}
+
+/// Notes a zone change queued for the scheduler, for its latency
+pub fn zone_changed(unique_id: &str) {
+    let mut pending = PENDING.lock_recover();
+    if pending.len() == PENDING_SIZE {
+        pending.pop_front();
+    }
+    pending.push_back((unique_id.to_string(), Instant::now()));
+}
+
+/// Records the latency of the oldest queued change of the zone, once it is published
+pub fn zone_published(unique_id: &str) {
+    let mut pending = PENDING.lock_recover();
+    let Some(index) = pending.iter().position(|(id, _)| id == unique_id) else {
+        return;
+    };
+    if let Some((_, detected)) = pending.remove(index) {
+        record(
+            TimingStat::event_latency,
+            detected.elapsed().as_millis() as u32,
+        );
+    }
+}
+
+/// Publishes the peaks as sensor states
+pub struct TimingStats {
+    published: Instant,
+}
+
+impl TimingStats {
+    pub fn new() -> Self {
+        Self {
+            published: Instant::now(),
+        }
+    }
+
+    /// States of the timing sensors, once per interval
+    pub fn poll(&mut self, entities: &[HAEntity]) -> Vec<(String, String)> {
+        if self.published.elapsed() < PUBLISH_INTERVAL {
+            return Vec::new();
+        }
+        self.published = Instant::now();
+        let peaks = TimingStat::ALL.map(|stat| PEAKS[stat as usize].swap(0, Ordering::Relaxed));
+        entities
+            .iter()
+            .filter_map(|entity| {
+                let peak = peaks[entity.timing_stat? as usize];
+                Some((entity.state_topic.clone(), peak.to_string()))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use ha_types::provisioning::{bootstrap_area, Area, ZoneOverride};
+use ha_types::HAEntity;
+
+use crate::settings::MAX_JSON_LEN;
+
+/// How long the retained areas are collected after subscribing to them
+const COLLECT_DURATION: Duration = Duration::from_secs(5);
+/// Names and devices of the bootstrapped zones by their unique id, as JSON
+const NVS_ZONES_KEY: &str = "zones";
+
+/// Collects the areas HA published for the zones, the network can't take the whole
+/// provisioning in one message
+pub struct ZoneBootstrap {
+    started: Instant,
+    overrides: BTreeMap<String, ZoneOverride>,
+    /// Zones of the provisioning without an input on the panel, they need the firmware
+    /// rebuilt with the import-zones command of the settings generator
+    unwired: Vec<String>,
+    areas: usize,
+}
+
+impl ZoneBootstrap {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            overrides: BTreeMap::new(),
+            unwired: Vec::new(),
+            areas: 0,
+        }
+    }
+
+    pub fn handle_message(&mut self, topic: &str, payload: &str, entities: &[HAEntity]) {
+        let area: Area = match serde_json::from_str(payload) {
+            Ok(area) => area,
+            Err(e) => {
+                log::warn!("Ignoring the invalid area on {}: {}", topic, e);
+                return;
+            }
+        };
+        let (overrides, unwired) = bootstrap_area(&area, entities);
+        self.overrides.extend(overrides);
+        self.unwired.extend(unwired);
+        self.areas += 1;
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        now.duration_since(self.started) >= COLLECT_DURATION
+    }
+
+    /// The overrides of the zones and the status reported for them, the zones are left as
+    /// they are if HA published no areas
+    pub fn finish(self) -> (Option<BTreeMap<String, ZoneOverride>>, String) {
+        if self.areas == 0 {
+            return (None, "no areas were published for the zones".to_string());
+        }
+        let mut status = format!(
+            "bootstrapped {} zones from {} areas",
+            self.overrides.len(),
+            self.areas
+        );
+        if !self.unwired.is_empty() {
+            status += &format!(
+                ", no input for {}: rebuild the firmware with import-zones",
+                self.unwired.join(", ")
+            );
+        }
+        (Some(self.overrides), status)
+    }
+}
+
+pub fn load(nvs: &EspNvs<NvsDefault>) -> anyhow::Result<BTreeMap<String, ZoneOverride>> {
+    let mut buf = vec![0u8; MAX_JSON_LEN];
+    let Some(json) = nvs.get_str(NVS_ZONES_KEY, &mut buf)? else {
+        return Ok(BTreeMap::new());
+    };
+    Ok(serde_json::from_str(json)?)
+}
+
+/// No overrides remove them, the zones are named by config.yml again
+pub fn store(
+    nvs: &mut EspNvs<NvsDefault>,
+    overrides: &BTreeMap<String, ZoneOverride>,
+) -> anyhow::Result<()> {
+    if overrides.is_empty() {
+        nvs.remove(NVS_ZONES_KEY)?;
+        return Ok(());
+    }
+    let json = serde_json::to_string(overrides)?;
+    if json.len() >= MAX_JSON_LEN {
+        anyhow::bail!("{} bytes of zones don't fit in NVS", json.len());
+    }
+    nvs.set_str(NVS_ZONES_KEY, &json)?;
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use ha_types::{HAEntity, ZoneCount};
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::lock::LockRecover;
+use crate::settings::MAX_JSON_LEN;
+
+const NVS_NAMESPACE: &str = "zone_counts";
+/// The counts by the unique ids of the zones, as JSON
+const NVS_COUNTS_KEY: &str = "counts";
+/// The counts are written at most this often, so a chattering zone doesn't wear the flash
+const PERSIST_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Activations of the zones since the last poll, by their unique id
+static ACTIVATIONS: Mutex<BTreeMap<String, u32>> = Mutex::new(BTreeMap::new());
+/// Times the zones moved the alarm into pending or triggered since the last poll
+static TRIPS: Mutex<BTreeMap<String, u32>> = Mutex::new(BTreeMap::new());
+
+/// Counts an activation of the zone, from any task
+pub fn zone_activated(unique_id: &str) {
+    *ACTIVATIONS
+        .lock_recover()
+        .entry(unique_id.to_string())
+        .or_default() += 1;
+}
+
+/// Counts the zone moving the alarm into pending or triggered, from any task
+pub fn zone_tripped(unique_id: &str) {
+    *TRIPS
+        .lock_recover()
+        .entry(unique_id.to_string())
+        .or_default() += 1;
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Counts {
+    /// Days since the epoch of the counts of today
+    day: u64,
+    /// Trips of the alarm by the zones today
+    today: BTreeMap<String, u32>,
+    /// Lifetime activations of the zones
+    total: BTreeMap<String, u32>,
+}
+
+/// Publishes the activations of the zones as sensor states and keeps them in NVS
+pub struct ZoneCounters {
+    nvs: Option<EspNvs<NvsDefault>>,
+    counts: Counts,
+    changed: bool,
+    persisted: (bool, Instant),
+}
+
+impl ZoneCounters {
+    pub fn load(partition: EspDefaultNvsPartition) -> Self {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)
+            .map_err(|e| log::error!("Failed to open zone counts NVS namespace: {:?}", e))
+            .ok();
+        let counts = nvs
+            .as_ref()
+            .and_then(|nvs| {
+                read_counts(nvs)
+                    .map_err(|e| log::error!("Failed to read the zone counts: {:?}", e))
+                    .ok()
+                    .flatten()
+            })
+            .unwrap_or_default();
+        Self {
+            nvs,
+            counts,
+            changed: true,
+            persisted: (true, Instant::now()),
+        }
+    }
+
+    /// States of the counter sensors, when a count changed since the last poll
+    pub fn poll(&mut self, entities: &[HAEntity]) -> Vec<(String, String)> {
+        // The day is kept while the clock is not synchronized
+        let day = clock::is_synchronized().then(|| clock::unix_time() / 86400);
+        if let Some(day) = day.filter(|day| *day != self.counts.day) {
+            self.counts.day = day;
+            self.counts.today.clear();
+            self.changed = true;
+            self.persisted.0 = false;
+        }
+        let trips = std::mem::take(&mut *TRIPS.lock_recover());
+        for (zone, count) in trips {
+            *self.counts.today.entry(zone).or_default() += count;
+            self.changed = true;
+            self.persisted.0 = false;
+        }
+        let activations = std::mem::take(&mut *ACTIVATIONS.lock_recover());
+        for (zone, count) in activations {
+            *self.counts.total.entry(zone).or_default() += count;
+            self.changed = true;
+            self.persisted.0 = false;
+        }
+
+        if !self.persisted.0 && self.persisted.1.elapsed() >= PERSIST_INTERVAL {
+            if let Some(nvs) = self.nvs.as_mut() {
+                serde_json::to_string(&self.counts)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|json| Ok(nvs.set_str(NVS_COUNTS_KEY, &json)?))
+                    .unwrap_or_else(|e| log::error!("Failed to persist the zone counts: {:?}", e));
+            }
+            self.persisted = (true, Instant::now());
+        }
+
+        if !self.changed {
+            return Vec::new();
+        }
+        self.changed = false;
+        entities
+            .iter()
+            .filter_map(|entity| {
+                let counter = entity.zone_counter.as_ref()?;
+                let counts = match counter.count {
+                    ZoneCount::triggers_today => &self.counts.today,
+                    ZoneCount::motion_events => &self.counts.total,
+                };
+                let count = counts.get(&counter.zone).copied().unwrap_or(0);
+                Some((entity.state_topic.clone(), count.to_string()))
+            })
+            .collect()
+    }
+}
+
+fn read_counts(nvs: &EspNvs<NvsDefault>) -> anyhow::Result<Option<Counts>> {
+    let mut buf = vec![0u8; MAX_JSON_LEN];
+    let Some(json) = nvs.get_str(NVS_COUNTS_KEY, &mut buf)? else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_str(json)?))
+}
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use serde::{Deserialize, Serialize};
+
+use crate::alarm::AlarmMotionEntity;
+use crate::settings::MAX_JSON_LEN;
+
+/// How long the zones are observed, long enough to walk past every sensor
+pub const LEARN_DURATION: Duration = Duration::from_secs(600);
+/// Suggestions waiting for confirmation, as JSON
+const NVS_LEARNED_KEY: &str = "learned";
+/// Confirmed polarity of the zones by their unique id, as JSON, it overrides config.yml
+const NVS_POLARITY_KEY: &str = "polarity";
+/// Pulses kept per zone for the typical pulse width
+const MAX_PULSES: usize = 32;
+
+/// Suggested configuration of a zone
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LearnedZone {
+    pub unique_id: String,
+    /// The input rested high, so it is active when low
+    pub inverted: bool,
+    /// Median of the pulses away from the resting level, in ms
+    pub pulse_ms: Option<u32>,
+    pub changes: u32,
+}
+
+#[derive(Default)]
+struct Observation {
+    samples: u32,
+    high_samples: u32,
+    /// Level and since when, unknown before the first sample
+    level: Option<(bool, Instant)>,
+    changes: u32,
+    /// Finished periods of each level, in ms
+    high_pulses: Vec<u32>,
+    low_pulses: Vec<u32>,
+}
+
+/// Observes the raw levels of the zone inputs to infer how they are wired
+pub struct ZoneLearner {
+    started: Instant,
+    zones: Vec<Observation>,
+}
+
+impl ZoneLearner {
+    pub fn new(zones: usize, now: Instant) -> Self {
+        Self {
+            started: now,
+            zones: (0..zones).map(|_| Observation::default()).collect(),
+        }
+    }
+
+    /// Records the level of the zone at the index of the motion entities
+    pub fn sample(&mut self, index: usize, high: bool, now: Instant) {
+        let zone = &mut self.zones[index];
+        zone.samples += 1;
+        zone.high_samples += u32::from(high);
+        match zone.level {
+            Some((level, since)) if level != high => {
+                let pulses = if level {
+                    &mut zone.high_pulses
+                } else {
+                    &mut zone.low_pulses
+                };
+                if pulses.len() < MAX_PULSES {
+                    pulses.push(now.duration_since(since).as_millis() as u32);
+                }
+                zone.changes += 1;
+                zone.level = Some((high, now));
+            }
+            Some(_) => {}
+            None => zone.level = Some((high, now)),
+        }
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        now.duration_since(self.started) >= LEARN_DURATION
+    }
+
+    /// Suggestions for the zones with a wired input, virtual and DSC zones have no polarity
+    pub fn finish(self, motion_entities: &[AlarmMotionEntity]) -> Vec<LearnedZone> {
+        motion_entities
+            .iter()
+            .zip(self.zones)
+            .filter(|(e, zone)| has_polarity(e) && zone.samples > 0)
+            .map(|(e, mut zone)| {
+                let inverted = zone.high_samples * 2 > zone.samples;
+                let pulses = if inverted {
+                    &mut zone.low_pulses
+                } else {
+                    &mut zone.high_pulses
+                };
+                pulses.sort_unstable();
+                LearnedZone {
+                    unique_id: e.entity.unique_id.clone(),
+                    inverted,
+                    pulse_ms: pulses.get(pulses.len() / 2).copied(),
+                    changes: zone.changes,
+                }
+            })
+            .collect()
+    }
+}
+
+fn has_polarity(e: &AlarmMotionEntity) -> bool {
+    e.entity.gpio_pin.is_some() || e.entity.modbus_input.is_some() || e.entity.can_input.is_some()
+}
+
+/// Keeps the suggestions until they are confirmed
+pub fn store_learned(nvs: &mut EspNvs<NvsDefault>, learned: &[LearnedZone]) -> anyhow::Result<()> {
+    nvs.set_str(NVS_LEARNED_KEY, &serde_json::to_string(learned)?)?;
+    Ok(())
+}
+
+/// Makes the stored suggestions the polarity of the zones, returns the confirmed ones
+pub fn confirm_learned(nvs: &mut EspNvs<NvsDefault>) -> anyhow::Result<Vec<LearnedZone>> {
+    let learned: Vec<LearnedZone> = match read_json(nvs, NVS_LEARNED_KEY)? {
+        Some(learned) => learned,
+        None => return Ok(Vec::new()),
+    };
+    let mut polarity = load_polarity(nvs)?;
+    polarity.extend(
+        learned
+            .iter()
+            .map(|zone| (zone.unique_id.clone(), zone.inverted)),
+    );
+    nvs.set_str(NVS_POLARITY_KEY, &serde_json::to_string(&polarity)?)?;
+    nvs.remove(NVS_LEARNED_KEY)?;
+    Ok(learned)
+}
+
+/// Confirmed polarity of the zones by their unique id
+pub fn load_polarity(nvs: &EspNvs<NvsDefault>) -> anyhow::Result<BTreeMap<String, bool>> {
+    Ok(read_json(nvs, NVS_POLARITY_KEY)?.unwrap_or_default())
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(
+    nvs: &EspNvs<NvsDefault>,
+    key: &str,
+) -> anyhow::Result<Option<T>> {
+    let mut buf = vec![0u8; MAX_JSON_LEN];
+    let Some(value) = nvs.get_str(key, &mut buf)? else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_str(value)?))
+}